@@ -0,0 +1,106 @@
+//! The addresses assigned to an interface.
+//!
+//! Read with `ip -j addr show` where iproute2 supports JSON output, and from
+//! the one-line text format (`ip -o`) where it does not.
+
+use crate::{ipjson, CommandRunner, Interface};
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+#[derive(Deserialize)]
+struct Link {
+    #[serde(default)]
+    addr_info: Vec<AddrInfo>,
+}
+
+#[derive(Deserialize)]
+struct AddrInfo {
+    // On point-to-point links `address` is the peer; `local` is ours
+    local: IpAddr,
+    prefixlen: u8,
+}
+
+/// Global-scope addresses of `iface` (both families), each with its prefix
+/// length, in the order the kernel lists them.
+pub fn get_iface_addrs(runner: &dyn CommandRunner, iface: &Interface) -> Result<Vec<IpNet>> {
+    let json = ipjson::run_json(runner, &["addr", "show", "dev", iface, "scope", "global"])
+        .and_then(|out| parse_json(&out).ok());
+    if let Some(addrs) = json {
+        return Ok(addrs);
+    }
+    let out = runner
+        .run(
+            "ip",
+            &["-o", "addr", "show", "dev", iface, "scope", "global"],
+        )
+        .with_context(|| format!("list addresses of {}", iface))?;
+    Ok(parse_text(&out))
+}
+
+fn parse_json(out: &str) -> Result<Vec<IpNet>> {
+    let links: Vec<Link> = serde_json::from_str(out)?;
+    links
+        .into_iter()
+        .flat_map(|link| link.addr_info)
+        .map(|info| {
+            IpNet::new(info.local, info.prefixlen)
+                .with_context(|| format!("bad prefix length {}", info.prefixlen))
+        })
+        .collect()
+}
+
+/// The `inet`/`inet6` values of `ip -o addr show` lines.
+fn parse_text(out: &str) -> Vec<IpNet> {
+    out.lines()
+        .filter_map(|line| {
+            let mut tokens = line.split_whitespace();
+            tokens.find(|t| *t == "inet" || *t == "inet6")?;
+            tokens.next()?.parse().ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"[{"ifindex":2,"ifname":"eth0","flags":["BROADCAST","MULTICAST","UP","LOWER_UP"],"mtu":1500,"qdisc":"fq_codel","operstate":"UP","group":"default","txqlen":1000,"link_type":"ether","address":"02:00:00:00:00:01","broadcast":"ff:ff:ff:ff:ff:ff","addr_info":[{"family":"inet","local":"192.0.2.10","prefixlen":24,"broadcast":"192.0.2.255","scope":"global","dynamic":true,"label":"eth0","valid_life_time":86100,"preferred_life_time":86100},{"family":"inet","local":"198.51.100.5","prefixlen":32,"scope":"global","label":"eth0:1","valid_life_time":4294967295,"preferred_life_time":4294967295},{"family":"inet6","local":"2001:db8::10","prefixlen":64,"scope":"global","dynamic":true,"mngtmpaddr":true,"valid_life_time":2591999,"preferred_life_time":604799}]}]"#;
+
+    #[test]
+    fn json_addresses_keep_host_and_prefix() {
+        let addrs: Vec<String> = parse_json(JSON)
+            .unwrap()
+            .iter()
+            .map(IpNet::to_string)
+            .collect();
+        assert_eq!(
+            addrs,
+            ["192.0.2.10/24", "198.51.100.5/32", "2001:db8::10/64"]
+        );
+        // A point-to-point link lists the peer as `address`
+        let ppp = r#"[{"ifname":"ppp0","addr_info":[{"family":"inet","local":"100.64.0.5","address":"100.64.0.1","prefixlen":32,"scope":"global"}]}]"#;
+        assert_eq!(
+            parse_json(ppp).unwrap(),
+            ["100.64.0.5/32".parse::<IpNet>().unwrap()]
+        );
+        // Without addresses (a down link) there is no addr_info at all
+        assert!(parse_json(r#"[{"ifname":"eth1"}]"#).unwrap().is_empty());
+        assert!(parse_json("[]").unwrap().is_empty());
+        assert!(parse_json("2: eth0    inet 192.0.2.10/24").is_err());
+    }
+
+    #[test]
+    fn text_output_is_read_when_json_is_unavailable() {
+        let out = "2: eth0    inet 192.0.2.10/24 brd 192.0.2.255 scope global dynamic eth0\\       valid_lft 86100sec preferred_lft 86100sec\n\
+                   2: eth0    inet6 2001:db8::10/64 scope global dynamic mngtmpaddr \\       valid_lft 2591999sec preferred_lft 604799sec\n";
+        assert_eq!(
+            parse_text(out),
+            [
+                "192.0.2.10/24".parse::<IpNet>().unwrap(),
+                "2001:db8::10/64".parse().unwrap()
+            ]
+        );
+    }
+}
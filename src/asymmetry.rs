@@ -0,0 +1,153 @@
+//! Heuristic check for asymmetric routing after a switch.
+//!
+//! A host pinned to a WAN only works if traffic leaving that WAN is NATed to
+//! its address and replies to the host come back in through the LAN. Neither
+//! is something the service controls, so a switch never fails on them; it
+//! only returns warnings. ASYMMETRY_CHECK=false turns the check off.
+
+use crate::{CommandRunner, Config, Interface};
+
+/// Warnings for pinning `ip` to `iface`. Checks that cannot run (no nft or
+/// iptables, no permission) are skipped rather than reported.
+pub fn check(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    ip: &str,
+    iface: &Interface,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    match nat_rules(runner) {
+        Some(rules) if !nat_covers(&rules, iface) => warnings.push(format!(
+            "No masquerade/SNAT rule found for {}; replies may be dropped upstream",
+            iface
+        )),
+        Some(_) => {}
+        None => eprintln!("Asymmetry check: could not list NAT rules; skipped"),
+    }
+    if let Some(dev) = route_dev(runner, ip) {
+        if dev != *config.lan {
+            warnings.push(format!(
+                "Traffic to {} is routed out {} rather than the LAN ({}); replies will not reach it",
+                ip, dev, config.lan
+            ));
+        }
+    }
+    warnings
+}
+
+/// The NAT ruleset, from nft or else iptables.
+fn nat_rules(runner: &dyn CommandRunner) -> Option<String> {
+    runner
+        .run("nft", &["list", "ruleset"])
+        .or_else(|_| runner.run("iptables", &["-t", "nat", "-S", "POSTROUTING"]))
+        .ok()
+}
+
+/// Whether some masquerade/SNAT rule applies to traffic leaving `iface`:
+/// one matching its name (or a wildcard covering it), or one with no
+/// output interface match at all.
+fn nat_covers(rules: &str, iface: &str) -> bool {
+    rules.lines().any(|line| {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        // iptables `-j MASQUERADE`/`-j SNAT`, nft `masquerade`/`snat`
+        let nat = tokens
+            .iter()
+            .any(|t| matches!(*t, "MASQUERADE" | "SNAT" | "masquerade" | "snat"));
+        if !nat {
+            return false;
+        }
+        match out_interfaces(&tokens) {
+            None => true,
+            Some((negated, names)) => negated != names.iter().any(|n| iface_matches(n, iface)),
+        }
+    })
+}
+
+/// The output interface match of a rule line, if any, as (negated, names):
+/// iptables `[!] -o eth1`, nft `oifname [!=] "eth1"` or
+/// `oifname { "eth0", "eth1" }`.
+fn out_interfaces(tokens: &[&str]) -> Option<(bool, Vec<String>)> {
+    let at = tokens
+        .iter()
+        .position(|t| matches!(*t, "-o" | "oifname" | "oif"))?;
+    let mut negated = at > 0 && tokens[at - 1] == "!";
+    let mut rest = tokens[at + 1..].iter().peekable();
+    if rest.peek() == Some(&&"!=") {
+        negated = true;
+        rest.next();
+    }
+    let mut names = Vec::new();
+    if rest.peek() == Some(&&"{") {
+        rest.next();
+        for name in rest.take_while(|t| **t != "}") {
+            names.push(name.trim_matches(|c| c == '"' || c == ',').to_string());
+        }
+    } else if let Some(name) = rest.next() {
+        names.push(name.trim_matches('"').to_string());
+    }
+    Some((negated, names))
+}
+
+/// iptables writes prefix wildcards as `eth+`, nft as `eth*`.
+fn iface_matches(pattern: &str, iface: &str) -> bool {
+    match pattern
+        .strip_suffix('+')
+        .or_else(|| pattern.strip_suffix('*'))
+    {
+        Some(prefix) => iface.starts_with(prefix),
+        None => pattern == iface,
+    }
+}
+
+/// Device the kernel would send traffic for `ip` out of.
+fn route_dev(runner: &dyn CommandRunner, ip: &str) -> Option<String> {
+    let out = runner.run("ip", &["-4", "route", "get", ip]).ok()?;
+    let tokens: Vec<&str> = out.split_whitespace().collect();
+    tokens
+        .windows(2)
+        .find(|pair| pair[0] == "dev")
+        .map(|pair| pair[1].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iptables_nat_rules_are_matched_per_interface() {
+        let rules = "-P POSTROUTING ACCEPT\n\
+                     -A POSTROUTING -o eth0 -j MASQUERADE\n\
+                     -A POSTROUTING -s 10.9.0.0/16 -o wg0 -j ACCEPT\n\
+                     -A POSTROUTING -o ppp+ -j SNAT --to-source 203.0.113.5\n";
+        assert!(nat_covers(rules, "eth0"));
+        assert!(!nat_covers(rules, "eth1"));
+        assert!(!nat_covers(rules, "wg0"));
+        assert!(nat_covers(rules, "ppp0"));
+        assert!(nat_covers("-A POSTROUTING -j MASQUERADE\n", "eth1"));
+        let all_but_lan = "-A POSTROUTING ! -o eth2 -j MASQUERADE\n";
+        assert!(nat_covers(all_but_lan, "eth1"));
+        assert!(!nat_covers(all_but_lan, "eth2"));
+    }
+
+    #[test]
+    fn nft_nat_rules_are_matched_per_interface() {
+        let rules = r#"table ip nat {
+	chain postrouting {
+		type nat hook postrouting priority srcnat; policy accept;
+		oifname { "eth0", "lte*" } masquerade
+		oifname "eth1" counter accept
+	}
+}
+"#;
+        assert!(nat_covers(rules, "eth0"));
+        assert!(nat_covers(rules, "lte1"));
+        assert!(!nat_covers(rules, "eth1"));
+        assert!(nat_covers(
+            "oifname \"eth1\" snat ip to 198.51.100.7\n",
+            "eth1"
+        ));
+        let all_but_lan = "oifname != \"eth2\" counter packets 0 bytes 0 masquerade\n";
+        assert!(nat_covers(all_but_lan, "eth1"));
+        assert!(!nat_covers(all_but_lan, "eth2"));
+    }
+}
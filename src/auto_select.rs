@@ -0,0 +1,183 @@
+//! `nic=auto`: pin a host to whichever WAN currently probes best.
+//!
+//! The choice is made from the health probes' rolling RTT. After every
+//! health check, auto pins are looked at again and moved only if their WAN
+//! went down or another WAN's RTT beats it by more than AUTO_HYSTERESIS_MS,
+//! so two links of similar quality don't trade hosts back and forth.
+
+use crate::health::WanHealth;
+use crate::mapping::{self, Origin};
+use crate::{persist, repin_host, unix_now, AppState};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Where an auto pin went and why, as reported in `/status`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AutoPin {
+    pub nic: String,
+    pub reason: String,
+    // Unix time of the choice
+    pub chosen_at: u64,
+}
+
+/// Healthy WANs with an RTT to compare, best first.
+fn ranked(wans: &BTreeMap<String, WanHealth>) -> Vec<(&str, f64)> {
+    let mut ranked: Vec<(&str, f64)> = ["wan0", "wan1"]
+        .into_iter()
+        .filter_map(|nic| {
+            let wan = wans.get(nic).filter(|w| w.healthy)?;
+            Some((nic, wan.rtt_ms?))
+        })
+        .collect();
+    ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+    ranked
+}
+
+/// The WAN a new auto pin goes to, and why. Without usable probe results
+/// the host stays on `fallback`, the WAN the base rule currently uses.
+pub fn pick(wans: &BTreeMap<String, WanHealth>, fallback: &str) -> (String, String) {
+    match ranked(wans)[..] {
+        [] => (
+            fallback.to_string(),
+            "no healthy WAN with probe results; using the base WAN".to_string(),
+        ),
+        [(nic, rtt)] => (nic.to_string(), format!("only healthy WAN ({:.1} ms)", rtt)),
+        [(best, best_rtt), (other, other_rtt), ..] => (
+            best.to_string(),
+            format!(
+                "lowest RTT: {:.1} ms vs {} {:.1} ms",
+                best_rtt, other, other_rtt
+            ),
+        ),
+    }
+}
+
+/// Where an auto pin on `current` should move, if anywhere: off a WAN that
+/// is down, or to one faster by more than `hysteresis_ms`.
+pub fn better(
+    wans: &BTreeMap<String, WanHealth>,
+    current: &str,
+    hysteresis_ms: f64,
+) -> Option<(String, String)> {
+    let (best, best_rtt) = *ranked(wans).first()?;
+    if best == current {
+        return None;
+    }
+    let current_health = wans.get(current);
+    if current_health.is_none_or(WanHealth::is_down) {
+        return Some((
+            best.to_string(),
+            format!(
+                "{} is down; {} answers in {:.1} ms",
+                current, best, best_rtt
+            ),
+        ));
+    }
+    let current_rtt = current_health.and_then(|w| w.rtt_ms)?;
+    (best_rtt + hysteresis_ms < current_rtt).then(|| {
+        (
+            best.to_string(),
+            format!(
+                "lower RTT: {:.1} ms vs {} {:.1} ms",
+                best_rtt, current, current_rtt
+            ),
+        )
+    })
+}
+
+/// Move every auto pin for which `better` finds a WAN. Pins whose host was
+/// since switched elsewhere are dropped; hosts moved by host failover or
+/// locked are left alone.
+pub async fn reevaluate(state: &AppState, hysteresis_ms: f64) {
+    if state.auto_pins.lock().unwrap().is_empty() {
+        return;
+    }
+    let mut dns_steered = state.dns_steered.lock().await;
+    let mut mappings = state.mappings.lock().await;
+    let auto_assigned = state.auto_assigned.lock().await;
+    let (wans, moved_hosts) = {
+        let health = state.health.lock().await;
+        (health.effective_wans(), health.moved_hosts.clone())
+    };
+    let pins = state.auto_pins.lock().unwrap().clone();
+    let mut changed = false;
+    for (host, pin) in pins {
+        if moved_hosts.contains_key(&host) || state.locked.lock().unwrap().contains(&host) {
+            continue;
+        }
+        if mapping::nic(&mappings, &host) != Some(pin.nic.as_str()) {
+            state.auto_pins.lock().unwrap().remove(&host);
+            changed = true;
+            continue;
+        }
+        let Some((nic, reason)) = better(&wans, &pin.nic, hysteresis_ms) else {
+            continue;
+        };
+        match repin_host(state, &mut dns_steered, &host, &nic, false) {
+            Ok(priority) => {
+                println!(
+                    "Auto select: {} moved from {} to {} ({})",
+                    host, pin.nic, nic, reason
+                );
+                mapping::pin(&mut mappings, &host, &nic, priority, Origin::AutoSelect);
+                let chosen_at = unix_now();
+                state.auto_pins.lock().unwrap().insert(
+                    host,
+                    AutoPin {
+                        nic,
+                        reason,
+                        chosen_at,
+                    },
+                );
+                changed = true;
+            }
+            Err(e) => eprintln!("Auto select: moving {} to {} failed: {:#}", host, nic, e),
+        }
+    }
+    if changed {
+        persist::save(state, &mappings, &auto_assigned);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wans(wan0: Option<f64>, wan1: Option<f64>) -> BTreeMap<String, WanHealth> {
+        let wan = |rtt: Option<f64>| {
+            let mut wan = WanHealth::default();
+            wan.healthy = rtt.is_some();
+            wan.consecutive_failures = if rtt.is_some() { 0 } else { 3 };
+            wan.rtt_ms = rtt;
+            wan
+        };
+        [
+            ("wan0".to_string(), wan(wan0)),
+            ("wan1".to_string(), wan(wan1)),
+        ]
+        .into()
+    }
+
+    #[test]
+    fn pick_prefers_the_lowest_rtt_among_healthy_wans() {
+        let (nic, reason) = pick(&wans(Some(30.0), Some(12.0)), "wan0");
+        assert_eq!(nic, "wan1");
+        assert_eq!(reason, "lowest RTT: 12.0 ms vs wan0 30.0 ms");
+        assert_eq!(pick(&wans(None, Some(80.0)), "wan0").0, "wan1");
+        assert_eq!(pick(&BTreeMap::new(), "wan1").0, "wan1");
+    }
+
+    #[test]
+    fn pins_move_only_past_the_hysteresis_or_off_a_down_wan() {
+        assert_eq!(better(&wans(Some(30.0), Some(15.0)), "wan0", 20.0), None);
+        let (nic, reason) = better(&wans(Some(40.0), Some(15.0)), "wan0", 20.0).unwrap();
+        assert_eq!(nic, "wan1");
+        assert_eq!(reason, "lower RTT: 15.0 ms vs wan0 40.0 ms");
+        assert_eq!(better(&wans(Some(10.0), Some(15.0)), "wan0", 0.0), None);
+        let (nic, reason) = better(&wans(Some(10.0), None), "wan1", 20.0).unwrap();
+        assert_eq!(nic, "wan0");
+        assert!(reason.starts_with("wan1 is down"));
+        // Nowhere better to go
+        assert_eq!(better(&wans(None, None), "wan0", 20.0), None);
+    }
+}
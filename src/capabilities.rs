@@ -0,0 +1,133 @@
+//! `GET /capabilities`: what this instance supports, for clients that adapt
+//! to it instead of probing endpoints.
+//!
+//! Modes and features come from the effective configuration. The kernel
+//! release and the iproute2 version are read once at startup (`Versions`);
+//! they do not change while the service runs.
+
+use crate::{ipjson, version, CommandRunner, Config, LanRuleMode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// What the system under the service runs.
+#[derive(Clone, Debug, Serialize)]
+pub struct Versions {
+    // Kernel release, as in `uname -r`
+    pub kernel: Option<String>,
+    // From `ip -V`, e.g. 6.1.0
+    pub iproute2: Option<String>,
+    // Whether `ip -j` output is read instead of the text format
+    pub ip_json: bool,
+}
+
+impl Versions {
+    pub fn probe(runner: &dyn CommandRunner) -> Self {
+        Versions {
+            kernel: std::fs::read_to_string("/proc/sys/kernel/osrelease")
+                .map(|r| r.trim().to_string())
+                .ok()
+                .filter(|r| !r.is_empty()),
+            iproute2: runner
+                .run("ip", &["-V"])
+                .ok()
+                .and_then(|banner| ipjson::iproute2_version(&banner).map(str::to_string)),
+            ip_json: runner.ip_json(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub instance: String,
+    pub wan_count: usize,
+    // WAN -> interface
+    pub wans: BTreeMap<&'static str, String>,
+    // NIC_ALIASES, alias -> WAN
+    pub nic_aliases: BTreeMap<String, String>,
+    pub modes: Modes,
+    // Optional features, on or off
+    pub features: BTreeMap<&'static str, bool>,
+    pub system: Versions,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Modes {
+    // "subnet" or "iif" (LAN_RULE_MODE)
+    pub lan_rule: &'static str,
+    // Keys host rules match on besides the source: "iif", "fwmark"
+    pub host_match: Vec<&'static str>,
+    // WAN -> "replace" or "add" (ROUTE_MODE_WAN0/ROUTE_MODE_WAN1)
+    pub route: BTreeMap<&'static str, &'static str>,
+    // WAN carrying the LAN base rule when not failed over
+    pub base_wan: &'static str,
+}
+
+/// The capabilities of the instance running `config` on `versions`.
+pub fn describe(config: &Config, versions: &Versions) -> Capabilities {
+    let health = config.health.as_ref();
+    let mut host_match = Vec::new();
+    if config.host_match.iif.is_some() {
+        host_match.push("iif");
+    }
+    if config.host_match.fwmark.is_some() {
+        host_match.push("fwmark");
+    }
+    let features = BTreeMap::from([
+        ("adopt_rules", config.adopt_rules),
+        ("asymmetry_check", config.asymmetry_check),
+        ("auto_balance", config.auto_balance.is_some()),
+        ("chaos", config.chaos.is_some()),
+        ("cleanup_on_exit", config.cleanup_on_exit),
+        ("diag_token", config.diag_token.is_some()),
+        ("dns_steering", config.dns_steering),
+        ("failover", health.is_some_and(|h| h.failover)),
+        ("fallback_to_primary", config.fallback_to_primary),
+        ("health_checks", health.is_some()),
+        (
+            "host_failover",
+            health.is_some_and(|h| !h.host_failover.is_empty()),
+        ),
+        ("ipv6", config.ipv6),
+        ("leases", config.leases.is_some()),
+        ("lock_token", config.lock_token.is_some()),
+        ("pin_primary", config.pin_primary),
+        ("post_switch_hook", config.post_switch_hook.is_some()),
+        ("preflight", config.preflight),
+        ("probe_table", config.probe_table()),
+        ("read_only", config.read_only),
+        ("require_confirm", config.require_confirm.is_some()),
+        ("safe_mode", config.safe_mode),
+        ("state_file", config.state_file.is_some()),
+        ("strict_deletes", config.strict_deletes),
+        ("strict_tables", config.strict_tables),
+        ("switch_debounce", config.switch_debounce.is_some()),
+        ("syslog", config.syslog.is_some()),
+        ("ui", config.ui_enabled),
+        ("unix_socket", config.listen_uds.is_some()),
+    ]);
+    Capabilities {
+        version: version::VERSION,
+        instance: config.instance_name.clone(),
+        wan_count: 2,
+        wans: BTreeMap::from([
+            ("wan0", config.wan0.to_string()),
+            ("wan1", config.wan1.to_string()),
+        ]),
+        nic_aliases: config.nic_aliases.clone(),
+        modes: Modes {
+            lan_rule: match config.lan_rule_mode {
+                LanRuleMode::Subnet => "subnet",
+                LanRuleMode::Iif => "iif",
+            },
+            host_match,
+            route: BTreeMap::from([
+                ("wan0", config.route_mode_wan0.verb()),
+                ("wan1", config.route_mode_wan1.verb()),
+            ]),
+            base_wan: config.base_wan,
+        },
+        features,
+        system: versions.clone(),
+    }
+}
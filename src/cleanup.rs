@@ -0,0 +1,108 @@
+//! Undoing the service's effect on the kernel at shutdown.
+//!
+//! Before initialization touches anything, the WAN routing tables and any
+//! addresses init is about to delete are recorded. With CLEANUP_ON_EXIT, a
+//! graceful shutdown removes every rule pointing at the service's tables and
+//! puts the snapshot back.
+
+use crate::{
+    addrs, ip_rules, route_entries, rule_del_prio_cmd, CommandRunner, Config, IpRule, TABLE_WAN0,
+    TABLE_WAN1,
+};
+use anyhow::Result;
+
+/// Kernel state as found at startup.
+#[derive(Debug, Default)]
+pub struct StartupSnapshot {
+    // Routes of each WAN table, as `ip route show table <id>` printed them
+    // (multipath nexthops joined onto their route)
+    tables: Vec<(&'static str, Vec<String>)>,
+    // (iface, address/len) that init's address cleanup is going to remove
+    addrs: Vec<(String, String)>,
+    // Rules that already pointed at a WAN table; cleanup leaves these alone
+    rules: Vec<IpRule>,
+}
+
+impl StartupSnapshot {
+    pub fn take(runner: &dyn CommandRunner, config: &Config) -> Result<Self> {
+        let mut snapshot = StartupSnapshot {
+            rules: ip_rules(runner)?
+                .into_iter()
+                .filter(|r| r.table == TABLE_WAN0 || r.table == TABLE_WAN1)
+                .collect(),
+            ..StartupSnapshot::default()
+        };
+        for table in [TABLE_WAN0, TABLE_WAN1] {
+            let routes = runner.run("ip", &["route", "show", "table", table])?;
+            snapshot.tables.push((table, route_entries(&routes)));
+        }
+        for iface in [&config.wan0, &config.wan1] {
+            for addr in addrs::get_iface_addrs(runner, iface)? {
+                if config.lan_subnets.contains(&addr) {
+                    snapshot.addrs.push((iface.to_string(), addr.to_string()));
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Remove every rule that looks up a WAN table (other than those found at
+/// startup), then restore the tables and
+/// addresses from `snapshot`. Errors are logged and skipped so as much as
+/// possible is undone.
+pub fn restore(runner: &dyn CommandRunner, snapshot: &StartupSnapshot) {
+    match ip_rules(runner) {
+        Ok(rules) => {
+            for rule in rules {
+                if rule.table != TABLE_WAN0 && rule.table != TABLE_WAN1
+                    || snapshot.rules.contains(&rule)
+                {
+                    continue;
+                }
+                let prio = rule.priority;
+                match rule_del_prio_cmd(&rule.from, &rule.table, prio).run(runner) {
+                    Ok(_) => println!(
+                        "Removed rule {}: from {} lookup {}",
+                        prio, rule.from, rule.table
+                    ),
+                    Err(e) => eprintln!("Failed to remove rule {}: {:#}", prio, e),
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to list rules for cleanup: {:#}", e),
+    }
+
+    for (table, routes) in &snapshot.tables {
+        if let Err(e) = runner.run("ip", &["route", "flush", "table", table]) {
+            eprintln!("Failed to flush table {}: {:#}", table, e);
+            continue;
+        }
+        for route in routes {
+            // The table goes before any nexthop specs, which must come last
+            let tokens: Vec<&str> = route.split_whitespace().collect();
+            let at = tokens
+                .iter()
+                .position(|t| *t == "nexthop")
+                .unwrap_or(tokens.len());
+            let mut args = vec!["route", "replace"];
+            args.extend(&tokens[..at]);
+            args.extend(["table", table]);
+            args.extend(&tokens[at..]);
+            match runner.run("ip", &args) {
+                Ok(_) => println!("Restored table {} route: {}", table, route),
+                Err(e) => eprintln!("Failed to restore table {} route {}: {:#}", table, route, e),
+            }
+        }
+        if routes.is_empty() {
+            println!("Table {} restored to empty", table);
+        }
+    }
+
+    for (iface, addr) in &snapshot.addrs {
+        match runner.run("ip", &["addr", "replace", addr, "dev", iface]) {
+            Ok(_) => println!("Restored address {} on {}", addr, iface),
+            Err(e) => eprintln!("Failed to restore address {} on {}: {:#}", addr, iface, e),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! A bound on how many external commands run at once.
+//!
+//! A burst of switches would otherwise start an `ip` process per rule
+//! change all at the same time. Commands beyond MAX_CONCURRENT_COMMANDS
+//! (4 by default) wait for a running one to finish, in no particular order.
+//!
+//! Callers are synchronous, often on a runtime worker. A wait there moves
+//! the worker's other tasks to another thread first (`block_in_place`), so
+//! a queue of `ip` calls never stalls unrelated requests.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// Commands currently waiting for a slot, for /metrics and /status.
+pub static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+pub struct CommandLimit {
+    max: usize,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A held slot; dropping it lets the next waiting command run.
+pub struct Slot<'a>(&'a CommandLimit);
+
+impl CommandLimit {
+    pub fn new(max: usize) -> Self {
+        CommandLimit {
+            max: max.max(1),
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Block until fewer than `max` commands are running, then take a slot.
+    pub fn acquire(&self) -> Slot<'_> {
+        let mut running = self.running.lock().unwrap();
+        if *running >= self.max {
+            QUEUED.fetch_add(1, Ordering::SeqCst);
+            running = blocking(|| {
+                self.freed
+                    .wait_while(running, |running| *running >= self.max)
+                    .unwrap()
+            });
+            QUEUED.fetch_sub(1, Ordering::SeqCst);
+        }
+        *running += 1;
+        Slot(self)
+    }
+}
+
+/// Run `f`, which blocks, without holding up a multi-threaded runtime's
+/// worker. Elsewhere (blocking pool, plain threads, current-thread runtimes)
+/// it just runs.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn commands_past_the_limit_wait_for_a_slot() {
+        let limit = CommandLimit::new(2);
+        let first = limit.acquire();
+        let _second = limit.acquire();
+        let (started, ran) = mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _slot = limit.acquire();
+                started.send(()).unwrap();
+            });
+            assert!(ran.recv_timeout(Duration::from_millis(100)).is_err());
+            assert_eq!(*limit.running.lock().unwrap(), 2);
+            drop(first);
+            ran.recv_timeout(Duration::from_secs(5)).unwrap();
+        });
+        assert_eq!(*limit.running.lock().unwrap(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn a_waiting_command_does_not_hold_up_its_worker() {
+        let limit = std::sync::Arc::new(CommandLimit::new(1));
+        let held = limit.acquire();
+        let waiter = {
+            let limit = limit.clone();
+            tokio::spawn(async move {
+                drop(limit.acquire());
+            })
+        };
+        // With the only worker parked in the wait, this would never run
+        let other = tokio::spawn(async { 1 });
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(5), other)
+                .await
+                .unwrap()
+                .unwrap(),
+            1
+        );
+        drop(held);
+        waiter.await.unwrap();
+    }
+}
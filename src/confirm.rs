@@ -0,0 +1,193 @@
+//! Two-step confirmation of network-wide operations (REQUIRE_CONFIRM=true).
+//!
+//! The first call only describes what would change and hands out a token.
+//! The operation runs when repeated with `confirm=<token>` within the
+//! window, and only if it would still make exactly the changes described.
+
+use crate::ApiError;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Json, Response};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// An issued token: what it confirms and until when.
+#[derive(Debug)]
+pub struct Pending {
+    operation: &'static str,
+    changes: Vec<String>,
+    expires: Instant,
+}
+
+pub type Pendings = HashMap<String, Pending>;
+
+/// Body of the first step (202 Accepted).
+#[derive(Debug, Serialize)]
+pub struct ConfirmRequired {
+    status: &'static str,
+    operation: &'static str,
+    token: String,
+    // Seconds the token stays valid
+    expires_in: u64,
+    changes: Vec<String>,
+}
+
+impl IntoResponse for ConfirmRequired {
+    fn into_response(self) -> Response {
+        (StatusCode::ACCEPTED, Json(self)).into_response()
+    }
+}
+
+/// Drop the tokens that ran out by `now`; returns how many.
+pub fn prune(pendings: &mut Pendings, now: Instant) -> usize {
+    let before = pendings.len();
+    pendings.retain(|_, p| p.expires > now);
+    before - pendings.len()
+}
+
+/// Whether `operation` may go ahead with `changes`. Without a token a new
+/// one is issued (`Ok(Some(..))`); a valid token is used up (`Ok(None)`).
+/// An operation that changes nothing never needs confirming.
+pub fn check(
+    pendings: &mut Pendings,
+    window: Duration,
+    operation: &'static str,
+    changes: Vec<String>,
+    token: Option<&str>,
+    now: Instant,
+) -> Result<Option<ConfirmRequired>, ApiError> {
+    prune(pendings, now);
+    let Some(token) = token else {
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        let token = format!("{:032x}", rand::random::<u128>());
+        pendings.insert(
+            token.clone(),
+            Pending {
+                operation,
+                changes: changes.clone(),
+                expires: now + window,
+            },
+        );
+        return Ok(Some(ConfirmRequired {
+            status: "confirm_required",
+            operation,
+            token,
+            expires_in: window.as_secs(),
+            changes,
+        }));
+    };
+    match pendings.remove(token) {
+        Some(p) if p.operation == operation && p.changes == changes => Ok(None),
+        Some(p) if p.operation == operation => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "confirm_stale",
+            format!(
+                "{} would now make different changes than confirmed; request a new token",
+                operation
+            ),
+        )),
+        _ => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "confirm_invalid",
+            format!("Unknown or expired {} confirmation token", operation),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn token_is_single_use_and_expires() {
+        let mut pendings = Pendings::new();
+        let window = Duration::from_secs(30);
+        let now = Instant::now();
+        let issued = check(&mut pendings, window, "gc", changes(&["a"]), None, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(issued.expires_in, 30);
+        let token = issued.token.as_str();
+        assert!(check(
+            &mut pendings,
+            window,
+            "gc",
+            changes(&["a"]),
+            Some(token),
+            now
+        )
+        .unwrap()
+        .is_none());
+        let err = check(
+            &mut pendings,
+            window,
+            "gc",
+            changes(&["a"]),
+            Some(token),
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(err.code, "confirm_invalid");
+
+        let issued = check(&mut pendings, window, "gc", changes(&["a"]), None, now)
+            .unwrap()
+            .unwrap();
+        let later = now + Duration::from_secs(31);
+        let err = check(
+            &mut pendings,
+            window,
+            "gc",
+            changes(&["a"]),
+            Some(&issued.token),
+            later,
+        )
+        .unwrap_err();
+        assert_eq!(err.code, "confirm_invalid");
+        assert!(pendings.is_empty());
+    }
+
+    #[test]
+    fn changed_plan_or_other_operation_is_refused() {
+        let mut pendings = Pendings::new();
+        let window = Duration::from_secs(30);
+        let now = Instant::now();
+        let issued = check(&mut pendings, window, "gc", changes(&["a"]), None, now)
+            .unwrap()
+            .unwrap();
+        let err = check(
+            &mut pendings,
+            window,
+            "gc",
+            changes(&["a", "b"]),
+            Some(&issued.token),
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(err.code, "confirm_stale");
+
+        let issued = check(&mut pendings, window, "gc", changes(&["a"]), None, now)
+            .unwrap()
+            .unwrap();
+        let err = check(
+            &mut pendings,
+            window,
+            "reset",
+            changes(&["a"]),
+            Some(&issued.token),
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(err.code, "confirm_invalid");
+
+        // Nothing to change, nothing to confirm
+        assert!(check(&mut pendings, window, "gc", Vec::new(), None, now)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,155 @@
+//! DSCP policies: `POST /policy/dscp?dscp=<class>&nic=<wan>`.
+//!
+//! Some traffic classes, such as VoIP or video marked EF or AF41 by the
+//! hosts sending them, should leave through one WAN whichever host sends
+//! them. Each policy is an `iif <lan> tos <dscp << 2> lookup <table>` rule at
+//! PRIO_SPECIFIC - 3, so it is looked at before the exceptions, DNS rules
+//! and host pins. Policies stand apart from the mappings: switches leave them
+//! alone and `/gc` does not count them as stale.
+
+use crate::{nic_table, ApiError, AppState, Config, IpCommand, IpRule, TABLE_WAN0, TABLE_WAN1};
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// WAN per DSCP value.
+pub type DscpPolicies = BTreeMap<u8, String>;
+
+/// A DSCP value by number (1-63) or class name: `ef`, `cs1`-`cs7` or
+/// `af11`-`af43`, in either case. 0 is refused: the kernel takes a rule's
+/// `tos 0` as matching everything.
+pub fn parse(value: &str) -> Result<u8, ApiError> {
+    let value = value.trim();
+    by_name(value)
+        .or_else(|| value.parse().ok().filter(|dscp| (1..64).contains(dscp)))
+        .ok_or_else(|| {
+            ApiError::bad_request(format!(
+                "dscp: '{}' is not a DSCP value (1-63, ef, csN or afXY)",
+                value
+            ))
+        })
+}
+
+fn by_name(name: &str) -> Option<u8> {
+    let name = name.to_ascii_lowercase();
+    if name == "ef" {
+        return Some(46);
+    }
+    if let Some(class) = name.strip_prefix("cs") {
+        return class
+            .parse::<u8>()
+            .ok()
+            .filter(|c| (1..=7).contains(c))
+            .map(|c| c * 8);
+    }
+    let digits = name.strip_prefix("af")?.as_bytes();
+    match digits {
+        [class @ b'1'..=b'4', drop @ b'1'..=b'3'] => Some((class - b'0') * 8 + (drop - b'0') * 2),
+        _ => None,
+    }
+}
+
+/// The `tos` selector for `dscp`, as `ip rule show` prints it without a name
+/// for it in rt_dsfield.
+fn tos(dscp: u8) -> String {
+    format!("{:#04x}", dscp << 2)
+}
+
+/// The DSCP value of a rule's `tos` selector: hex, or a class name when
+/// rt_dsfield has one.
+fn printed_dscp(tos: &str) -> Option<u8> {
+    match tos.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16)
+            .ok()
+            .filter(|tos| tos & 0x3 == 0)
+            .map(|tos| tos >> 2),
+        None => by_name(tos),
+    }
+}
+
+/// `ip rule <action>` for one policy; without `table`, a delete matches
+/// whichever WAN the policy pointed at.
+fn rule_cmd(action: &str, config: &Config, dscp: u8, table: Option<&str>) -> IpCommand {
+    let tos = tos(dscp);
+    let prio = config.priorities.dscp().to_string();
+    let mut args = vec!["rule", action, "iif", &config.lan, "tos", &tos];
+    if let Some(table) = table {
+        args.extend(["lookup", table]);
+    }
+    args.extend(["priority", &prio]);
+    IpCommand::new(&args)
+}
+
+/// The command adding the policy sending `dscp` to `nic`.
+pub fn add_cmd(config: &Config, dscp: u8, nic: &str) -> IpCommand {
+    rule_cmd("add", config, dscp, Some(nic_table(nic)))
+}
+
+/// Send traffic marked `dscp` to `nic`, replacing any policy it had.
+pub fn install(state: &AppState, dscp: u8, nic: &str) -> Result<()> {
+    let runner = state.runner.as_ref();
+    // Left over from a previous run, or the policy being replaced
+    let _ = rule_cmd("del", &state.config, dscp, None).run(runner);
+    add_cmd(&state.config, dscp, nic).run(runner)?;
+    state
+        .dscp_policies
+        .lock()
+        .unwrap()
+        .insert(dscp, nic.to_string());
+    Ok(())
+}
+
+/// Remove the policy for `dscp`; returns the WAN it pointed at, if it was
+/// recorded. The rule is deleted either way, in case an earlier run left it.
+pub fn remove(state: &AppState, dscp: u8) -> Result<Option<String>> {
+    let removed = state.dscp_policies.lock().unwrap().remove(&dscp);
+    let deleted = rule_cmd("del", &state.config, dscp, None).run(state.runner.as_ref());
+    match (deleted, &removed) {
+        (Err(e), None) => Err(e),
+        _ => Ok(removed),
+    }
+}
+
+/// The policy `rule` is, if it has the shape of one.
+pub fn policy_of(config: &Config, rule: &IpRule) -> Option<(u8, &'static str)> {
+    let nic = match rule.table.as_str() {
+        TABLE_WAN0 => "wan0",
+        TABLE_WAN1 => "wan1",
+        _ => return None,
+    };
+    if rule.priority != config.priorities.dscp()
+        || rule.from != "all"
+        || rule.iif.as_deref() != Some(&*config.lan)
+    {
+        return None;
+    }
+    Some((printed_dscp(rule.tos.as_deref()?)?, nic))
+}
+
+/// Policies implied by the rules in place.
+pub fn adopt(config: &Config, rules: &[IpRule]) -> DscpPolicies {
+    rules
+        .iter()
+        .filter_map(|rule| policy_of(config, rule))
+        .map(|(dscp, nic)| (dscp, nic.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dscp_values_and_class_names() {
+        assert_eq!(parse("ef").unwrap(), 46);
+        assert_eq!(parse("AF41").unwrap(), 34);
+        assert_eq!(parse("cs1").unwrap(), 8);
+        assert_eq!(parse("26").unwrap(), 26);
+        for bad in ["0", "64", "af44", "af51", "cs0", "voice"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(tos(46), "0xb8");
+        assert_eq!(printed_dscp("0xb8"), Some(46));
+        assert_eq!(printed_dscp("AF41"), Some(34));
+        assert_eq!(printed_dscp("0xb9"), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! Destination exceptions: `/switch?...&except=<prefix>[,<prefix>...]`.
+//!
+//! A host pinned to one WAN may still need some destinations, such as a VPN
+//! endpoint that only accepts the other WAN's address, to leave the other
+//! way. Each exception is a `from <host> to <prefix>` rule pointing at the
+//! other WAN's table, placed at PRIO_SPECIFIC - 2 so it is looked at before
+//! the host's own rule and its DNS rules. Exceptions belong to the mapping:
+//! a new switch replaces them, moves carry them along.
+
+use crate::{
+    mapping, nic_table, normalize_selector, parse_host_ip, ApiError, AppState, CommandRunner,
+    Config, HostMatch, IpCommand, IpRule, Priorities,
+};
+use anyhow::{bail, Result};
+use ipnet::Ipv4Net;
+use std::collections::{BTreeMap, HashMap};
+
+/// Exception prefixes per host, in the order the kernel sees them.
+pub type Exceptions = BTreeMap<String, Vec<Ipv4Net>>;
+
+/// Parse the `except` list. Prefixes are truncated to their network and
+/// deduplicated; the default route is refused, as it would undo the pin.
+pub fn parse(value: &str) -> Result<Vec<Ipv4Net>, ApiError> {
+    let mut prefixes = Vec::new();
+    for item in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let prefix = item
+            .parse::<Ipv4Net>()
+            .ok()
+            .or_else(|| parse_host_ip(item).map(Ipv4Net::from))
+            .ok_or_else(|| {
+                ApiError::bad_request(format!("except: '{}' is not an IPv4 prefix", item))
+            })?
+            .trunc();
+        if prefix.prefix_len() == 0 {
+            return Err(ApiError::bad_request(
+                "except: 0.0.0.0/0 would send everything to the other WAN",
+            ));
+        }
+        prefixes.push(prefix);
+    }
+    prefixes.sort();
+    prefixes.dedup();
+    Ok(prefixes)
+}
+
+/// The table a host pinned to `nic` reaches its exceptions through.
+pub fn other_table(nic: &str) -> &'static str {
+    nic_table(if nic == "wan1" { "wan0" } else { "wan1" })
+}
+
+/// Exceptions only win if the host's own rule sits after them; a rule that
+/// already existed at some other priority may not.
+pub fn check_order(prio: Priorities, host_priority: Option<u32>) -> Result<(), String> {
+    match host_priority {
+        Some(host) if host <= prio.exception() => Err(format!(
+            "the host's rule sits at priority {}, not after the exceptions at {}",
+            host,
+            prio.exception()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// `ip rule <action>` for one exception; without `table`, a delete matches
+/// whichever WAN the exception pointed at.
+fn rule_cmd(
+    action: &str,
+    host: &str,
+    host_match: &HostMatch,
+    prefix: &Ipv4Net,
+    table: Option<&str>,
+    prio: u32,
+) -> IpCommand {
+    let target = mapping::selector(host);
+    let prefix = prefix.to_string();
+    let prio = prio.to_string();
+    let mut args = vec!["rule", action, "from", &target, "to", &prefix];
+    args.extend(host_match.args());
+    if let Some(table) = table {
+        args.extend(["lookup", table]);
+    }
+    args.extend(["priority", &prio]);
+    IpCommand::new(&args)
+}
+
+/// The command adding `host`'s exception for `prefix` towards `table`.
+pub fn add_cmd(config: &Config, host: &str, prefix: &Ipv4Net, table: &str) -> IpCommand {
+    let prio = config.priorities.exception();
+    rule_cmd("add", host, &config.host_match, prefix, Some(table), prio)
+}
+
+/// Add `host`'s exception rules for a pin on `nic` and record them. On
+/// failure the rules added so far are taken out again.
+pub fn install(state: &AppState, host: &str, nic: &str, prefixes: &[Ipv4Net]) -> Result<()> {
+    let runner = state.runner.as_ref();
+    let host_match = &state.config.host_match;
+    let prio = state.config.priorities.exception();
+    let table = other_table(nic);
+    for (i, prefix) in prefixes.iter().enumerate() {
+        // Left over from a previous run, possibly towards the other table
+        let _ = rule_cmd("del", host, host_match, prefix, None, prio).run(runner);
+        if let Err(e) = rule_cmd("add", host, host_match, prefix, Some(table), prio).run(runner) {
+            delete(runner, &state.config, host, &prefixes[..i]);
+            return Err(e);
+        }
+    }
+    state
+        .exceptions
+        .lock()
+        .unwrap()
+        .insert(host.to_string(), prefixes.to_vec());
+    Ok(())
+}
+
+/// Like `install`, checking the host rule's priority first.
+pub fn reinstall(
+    state: &AppState,
+    host: &str,
+    nic: &str,
+    prefixes: &[Ipv4Net],
+    host_priority: Option<u32>,
+) -> Result<()> {
+    if let Err(e) = check_order(state.config.priorities, host_priority) {
+        bail!("{}: dropping its destination exceptions", e);
+    }
+    install(state, host, nic, prefixes)
+}
+
+fn delete(runner: &dyn CommandRunner, config: &Config, host: &str, prefixes: &[Ipv4Net]) {
+    let prio = config.priorities.exception();
+    for prefix in prefixes {
+        // Best-effort delete; ignore errors
+        let _ = rule_cmd("del", host, &config.host_match, prefix, None, prio).run(runner);
+    }
+}
+
+/// Remove `host`'s exception rules and forget them. Returns the prefixes,
+/// for callers that put them back elsewhere. Must run before a plain `rule
+/// del` of the host's rule, which would otherwise match an exception too.
+pub fn clear(state: &AppState, host: &str) -> Vec<Ipv4Net> {
+    let prefixes = state
+        .exceptions
+        .lock()
+        .unwrap()
+        .remove(host)
+        .unwrap_or_default();
+    delete(state.runner.as_ref(), &state.config, host, &prefixes);
+    prefixes
+}
+
+/// Whether `rule` is an exception the current state accounts for.
+pub fn is_known(
+    config: &Config,
+    exceptions: &Exceptions,
+    mappings: &HashMap<String, String>,
+    rule: &IpRule,
+) -> bool {
+    let (Some(to), Some(nic)) = (&rule.to, mappings.get(&rule.from)) else {
+        return false;
+    };
+    let to = normalize_selector(to);
+    rule.priority == config.priorities.exception()
+        && rule.table == other_table(nic)
+        && rule.iif.as_deref() == config.host_match.iif.as_deref()
+        && rule.fwmark == config.host_match.fwmark
+        && rule.ipproto.is_none()
+        && exceptions.get(&rule.from).is_some_and(|prefixes| {
+            prefixes
+                .iter()
+                .any(|p| normalize_selector(&p.to_string()) == to)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_are_normalized_and_checked() {
+        let prefixes = parse("198.51.100.7, 203.0.113.9/24,198.51.100.7/32,").unwrap();
+        let rendered: Vec<String> = prefixes.iter().map(Ipv4Net::to_string).collect();
+        assert_eq!(rendered, ["198.51.100.7/32", "203.0.113.0/24"]);
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("vpn.example.com").is_err());
+        assert!(parse("0.0.0.0/0").is_err());
+    }
+
+    #[test]
+    fn exceptions_must_come_before_the_host_rule() {
+        let prio = Priorities {
+            specific: 1000,
+            lan_default: 2000,
+        };
+        assert!(check_order(prio, None).is_ok());
+        assert!(check_order(prio, Some(1000)).is_ok());
+        assert!(check_order(prio, Some(998)).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Extra static routes for the WAN tables: EXTRA_ROUTES_WAN0 and
+//! EXTRA_ROUTES_WAN1.
+//!
+//! Each is a comma-separated list of `<prefix> via <gateway> [dev <iface>]`
+//! or `<prefix> dev <iface>`, e.g. a management subnet only reachable through
+//! one WAN. Initialization installs them next to the default and link
+//! routes, `/plan` lists them, READ_ONLY reports a missing one as drift and
+//! STRICT_TABLES does not count them as foreign.
+
+use crate::{route_entries, CommandRunner, Interface, IpCommand};
+use anyhow::{bail, Context, Result};
+use ipnet::Ipv4Net;
+use std::net::Ipv4Addr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtraRoute {
+    pub dest: Ipv4Net,
+    pub via: Option<Ipv4Addr>,
+    // Left for the kernel to pick from the gateway when unset
+    pub dev: Option<Interface>,
+}
+
+impl ExtraRoute {
+    /// The destination as `ip route show` prints it: hosts without `/32`.
+    fn shown_dest(&self) -> String {
+        if self.dest.prefix_len() == 32 {
+            self.dest.addr().to_string()
+        } else {
+            self.dest.to_string()
+        }
+    }
+
+    pub fn cmd(&self, table: &str) -> IpCommand {
+        let dest = self.shown_dest();
+        let via = self.via.map(|gw| gw.to_string());
+        let mut args = vec!["route", "replace", &dest];
+        if let Some(via) = &via {
+            args.extend(["via", via]);
+        }
+        if let Some(dev) = &self.dev {
+            args.extend(["dev", dev]);
+        }
+        args.extend(["table", table]);
+        IpCommand::new(&args)
+    }
+
+    /// Whether `route`, one entry of an `ip route show` listing, is this
+    /// route.
+    pub fn matches(&self, route: &str) -> bool {
+        let tokens: Vec<&str> = route.split_whitespace().collect();
+        let has = |key: &str, value: &str| tokens.windows(2).any(|p| p[0] == key && p[1] == value);
+        tokens.first() == Some(&self.shown_dest().as_str())
+            && self.via.is_none_or(|gw| has("via", &gw.to_string()))
+            && self.dev.as_ref().is_none_or(|dev| has("dev", dev))
+    }
+}
+
+impl std::fmt::Display for ExtraRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.shown_dest())?;
+        if let Some(gw) = self.via {
+            write!(f, " via {}", gw)?;
+        }
+        if let Some(dev) = &self.dev {
+            write!(f, " dev {}", dev)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_route(spec: &str) -> Result<ExtraRoute> {
+    let mut tokens = spec.split_whitespace();
+    let dest = tokens.next().unwrap_or_default();
+    let dest = dest
+        .parse::<Ipv4Net>()
+        .or_else(|_| dest.parse::<Ipv4Addr>().map(Ipv4Net::from))
+        .with_context(|| format!("'{}' is not an IPv4 prefix", dest))?
+        .trunc();
+    if dest.prefix_len() == 0 {
+        bail!("the default route is the service's own");
+    }
+    let mut route = ExtraRoute {
+        dest,
+        via: None,
+        dev: None,
+    };
+    while let Some(key) = tokens.next() {
+        let Some(value) = tokens.next() else {
+            bail!("'{}' needs a value", key);
+        };
+        match key {
+            "via" if route.via.is_none() => {
+                route.via = Some(
+                    value
+                        .parse()
+                        .with_context(|| format!("'{}' is not an IPv4 gateway", value))?,
+                )
+            }
+            "dev" if route.dev.is_none() => route.dev = Some(Interface::new(value)?),
+            "via" | "dev" => bail!("'{}' given twice", key),
+            other => bail!("unknown keyword '{}' (expected via or dev)", other),
+        }
+    }
+    if route.via.is_none() && route.dev.is_none() {
+        bail!("needs via <gateway> or dev <iface>");
+    }
+    Ok(route)
+}
+
+/// Parse an EXTRA_ROUTES_* list.
+pub fn parse(value: &str) -> Result<Vec<ExtraRoute>> {
+    let mut routes: Vec<ExtraRoute> = Vec::new();
+    for spec in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let route = parse_route(spec).with_context(|| format!("route '{}'", spec))?;
+        if routes.iter().any(|r| r.dest == route.dest) {
+            bail!("route '{}': {} is listed twice", spec, route.dest);
+        }
+        routes.push(route);
+    }
+    Ok(routes)
+}
+
+/// Install `routes` into `table`; returns how many there were.
+pub fn install(runner: &dyn CommandRunner, routes: &[ExtraRoute], table: &str) -> Result<usize> {
+    for route in routes {
+        route
+            .cmd(table)
+            .run(runner)
+            .with_context(|| format!("add route {} to table {}", route, table))?;
+    }
+    Ok(routes.len())
+}
+
+/// The commands that would put back the routes missing from `table`.
+pub fn missing(
+    runner: &dyn CommandRunner,
+    routes: &[ExtraRoute],
+    table: &str,
+) -> Result<Vec<IpCommand>> {
+    let out = runner.run("ip", &["route", "show", "table", table])?;
+    let present = route_entries(&out);
+    Ok(routes
+        .iter()
+        .filter(|route| !present.iter().any(|r| route.matches(r)))
+        .map(|route| route.cmd(table))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_runner::MemoryRunner;
+
+    #[test]
+    fn extra_routes_are_validated_and_kept_in_their_table() {
+        let routes = parse("10.99.0.0/24 via 10.40.0.1, 192.0.2.77 dev eth0").unwrap();
+        assert_eq!(
+            routes[0].cmd("100").to_string(),
+            "ip route replace 10.99.0.0/24 via 10.40.0.1 table 100"
+        );
+        assert_eq!(routes[1].to_string(), "192.0.2.77 dev eth0");
+        for bad in [
+            "10.99.0.0/24",
+            "10.99.0.0/24 via",
+            "10.99.0.0/24 via gw",
+            "10.99.0.0/24 gateway 10.40.0.1",
+            "0.0.0.0/0 dev eth0",
+            "10.99.0.0/24 dev eth0, 10.99.0.1/24 dev eth1",
+        ] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+
+        let runner = MemoryRunner::new();
+        assert_eq!(missing(&runner, &routes, "100").unwrap().len(), 2);
+        assert_eq!(install(&runner, &routes, "100").unwrap(), 2);
+        assert!(missing(&runner, &routes, "100").unwrap().is_empty());
+        assert_eq!(missing(&runner, &routes, "200").unwrap().len(), 2);
+    }
+}
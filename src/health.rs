@@ -0,0 +1,728 @@
+//! WAN health probing and failover of the LAN base rule.
+//!
+//! Each WAN is pinged through its own interface on an interval. When the
+//! base WAN (wan0 unless BASE_WAN=wan1) stops answering and the other one is
+//! up, the LAN subnets' base rule is moved to the other WAN's table; it moves
+//! back once the base WAN has recovered. Both directions are
+//! damped so a marginal uplink cannot bounce every LAN connection.
+//!
+//! The last HEALTH_WINDOW probes of each WAN are kept, so `/status` and
+//! `/metrics` can report round-trip time and loss next to up/down.
+//!
+//! With host failover, hosts pinned to a WAN that is down are moved to its
+//! backup WAN and put back on their original WAN once it answers again.
+//! With two WANs the backup can only be the other one, so a down WAN's hosts
+//! all land there; `/status` lists them per WAN under `host_failovers`.
+//!
+//! A WAN disabled with `POST /wan/<name>/disable` counts as down for all of
+//! this, whatever its probes say, until `POST /wan/<name>/enable`.
+//!
+//! With STATE_FILE, the probe results, the base rule's WAN and the moved
+//! hosts are saved along with the mappings and taken up again on startup if
+//! no older than HEALTH_RESTORE_MAX_AGE, so a restart during an outage does
+//! not route through the failed WAN until the first probes catch up.
+
+use crate::mapping::{self, Origin};
+use crate::syslog::{self, Severity};
+use crate::{
+    add_base_rule, auto_select, base_rule_cmd, nic_table, persist, repin_host, AppState,
+    CommandRunner, Interface,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// Consecutive failed probes before a WAN counts as down
+const FAIL_THRESHOLD: u32 = 3;
+
+#[derive(Clone, Debug)]
+pub struct HealthConfig {
+    // Address pinged through each WAN
+    pub target: String,
+    pub interval: Duration,
+    // Probes kept per WAN for the RTT and loss figures
+    pub window: usize,
+    // Move the LAN base rule when wan0 is down
+    pub failover: bool,
+    // How long wan0 must stay healthy before failing back
+    pub hold_down: Duration,
+    // Minimum time between two base rule moves
+    pub min_interval: Duration,
+    // WAN -> backup WAN its pinned hosts move to while it is down
+    pub host_failover: BTreeMap<String, String>,
+    // RTT advantage another WAN needs before an auto pin moves to it
+    pub auto_hysteresis_ms: f64,
+    // Saved health older than this is not restored
+    pub restore_max_age: Duration,
+    // Route each WAN's own outgoing traffic (`oif`) through its table, so a
+    // probe bound to the interface does not follow the main table
+    pub probe_table: bool,
+}
+
+/// Probe results for one WAN, as reported in `/status`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WanHealth {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    // Mean round-trip time of the answered probes in the window
+    pub rtt_ms: Option<f64>,
+    // Share of the probes in the window that went unanswered
+    pub loss_ratio: f64,
+    #[serde(skip)]
+    pub healthy_since: Option<Instant>,
+    // Latest probes, newest last; None for an unanswered one
+    #[serde(skip)]
+    samples: VecDeque<Option<f64>>,
+}
+
+impl WanHealth {
+    /// Probed and failing, as opposed to not probed yet.
+    pub fn is_down(&self) -> bool {
+        !self.healthy && self.consecutive_failures >= FAIL_THRESHOLD
+    }
+
+    /// Record one probe: its round-trip time, or None if it went unanswered.
+    fn record(&mut self, rtt_ms: Option<f64>, now: Instant, window: usize) {
+        self.samples.push_back(rtt_ms);
+        while self.samples.len() > window {
+            self.samples.pop_front();
+        }
+        let answered: Vec<f64> = self.samples.iter().flatten().copied().collect();
+        self.rtt_ms =
+            (!answered.is_empty()).then(|| answered.iter().sum::<f64>() / answered.len() as f64);
+        self.loss_ratio = 1.0 - answered.len() as f64 / self.samples.len() as f64;
+
+        if rtt_ms.is_some() {
+            if !self.healthy {
+                self.healthy_since = Some(now);
+            }
+            self.healthy = true;
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_failures += 1;
+            if self.consecutive_failures >= FAIL_THRESHOLD {
+                self.healthy = false;
+                self.healthy_since = None;
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Stay,
+    Move(&'static str),
+    // A move was wanted but damping held it back
+    Suppressed(&'static str, &'static str),
+}
+
+/// Which WAN carries the LAN base rule, plus the damping bookkeeping.
+#[derive(Debug)]
+pub struct Failover {
+    pub active: &'static str,
+    // BASE_WAN: where the base rule belongs while it is healthy
+    primary: &'static str,
+    last_change: Option<Instant>,
+    // The same as a unix time, for /status
+    pub changed_at: Option<u64>,
+    last_suppressed: Option<&'static str>,
+}
+
+impl Default for Failover {
+    fn default() -> Self {
+        Failover::new("wan0")
+    }
+}
+
+impl Failover {
+    pub fn new(primary: &'static str) -> Self {
+        Failover {
+            active: primary,
+            primary,
+            last_change: None,
+            changed_at: None,
+            last_suppressed: None,
+        }
+    }
+
+    pub fn active_table(&self) -> &'static str {
+        nic_table(self.active)
+    }
+
+    /// Decide where the base rule should be, given current health.
+    pub fn decide(
+        &self,
+        config: &HealthConfig,
+        wan0: &WanHealth,
+        wan1: &WanHealth,
+        now: Instant,
+    ) -> Decision {
+        let (backup, primary_health, backup_health) = match self.primary {
+            "wan1" => ("wan0", wan1, wan0),
+            _ => ("wan1", wan0, wan1),
+        };
+        let target = if self.active == self.primary {
+            if primary_health.healthy || !backup_health.healthy {
+                return Decision::Stay;
+            }
+            backup
+        } else if primary_health.healthy {
+            self.primary
+        } else {
+            return Decision::Stay;
+        };
+        let rate_limited = self
+            .last_change
+            .is_some_and(|t| now.duration_since(t) < config.min_interval);
+        if rate_limited {
+            return Decision::Suppressed(target, "minimum failover interval");
+        }
+        // Fail back only once the primary has proven itself, unless the
+        // backup is down too
+        if target == self.primary && backup_health.healthy {
+            let stable = primary_health
+                .healthy_since
+                .is_some_and(|t| now.duration_since(t) >= config.hold_down);
+            if !stable {
+                return Decision::Suppressed(target, "hold-down");
+            }
+        }
+        Decision::Move(target)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HealthState {
+    pub wans: BTreeMap<String, WanHealth>,
+    pub failover: Failover,
+    // Hosts moved off a down WAN by host failover, with their original WAN
+    pub moved_hosts: BTreeMap<String, String>,
+    // WANs taken out of service by hand; new pins to them are refused
+    pub disabled: BTreeSet<String>,
+}
+
+impl HealthState {
+    pub fn new(base_wan: &'static str) -> Self {
+        HealthState {
+            failover: Failover::new(base_wan),
+            ..HealthState::default()
+        }
+    }
+
+    /// Probe results as failover and auto selection see them: a disabled WAN
+    /// is down.
+    pub fn effective_wans(&self) -> BTreeMap<String, WanHealth> {
+        let mut wans = self.wans.clone();
+        for nic in &self.disabled {
+            let wan = wans.entry(nic.clone()).or_default();
+            wan.healthy = false;
+            wan.healthy_since = None;
+            wan.consecutive_failures = wan.consecutive_failures.max(FAIL_THRESHOLD);
+        }
+        wans
+    }
+
+    /// The moved hosts grouped by the WAN they were moved off, with where
+    /// they are now.
+    pub fn host_failovers(&self, mappings: &mapping::Mappings) -> BTreeMap<String, HostFailover> {
+        let mut failovers: BTreeMap<String, HostFailover> = BTreeMap::new();
+        for (host, original) in &self.moved_hosts {
+            let failover = failovers.entry(original.clone()).or_default();
+            if let Some(nic) = mapping::nic(mappings, host) {
+                failover.to.insert(host.clone(), nic.to_string());
+            }
+        }
+        failovers
+    }
+}
+
+/// Health as saved in STATE_FILE.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedHealth {
+    // Unix time of the probes this reflects
+    pub checked_at: u64,
+    pub wans: BTreeMap<String, WanHealth>,
+    // WAN carrying the LAN base rule
+    pub base_wan: String,
+    pub moved_hosts: BTreeMap<String, String>,
+}
+
+impl SavedHealth {
+    fn new(health: &HealthState, checked_at: u64) -> Self {
+        SavedHealth {
+            checked_at,
+            wans: health.wans.clone(),
+            base_wan: health.failover.active.to_string(),
+            moved_hosts: health.moved_hosts.clone(),
+        }
+    }
+
+    /// Anything down or failed over, as opposed to all well.
+    fn degraded(&self, primary: &str) -> bool {
+        self.wans.values().any(|w| !w.healthy)
+            || self.base_wan != primary
+            || !self.moved_hosts.is_empty()
+    }
+}
+
+/// Hosts moved off one down WAN, for `/status`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HostFailover {
+    // Host -> WAN it was moved to
+    pub to: BTreeMap<String, String>,
+}
+
+/// Outcome of one probe, as returned by `POST /wan/{name}/probe`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProbeResult {
+    pub reachable: bool,
+    pub latency_ms: Option<f64>,
+}
+
+/// Ping `target` once through `iface`. The latency is ping's own round-trip
+/// time when it reports one, otherwise the time the command took.
+pub fn probe_timed(runner: &dyn CommandRunner, iface: &Interface, target: &str) -> ProbeResult {
+    let started = Instant::now();
+    match runner.run("ping", &["-c", "1", "-W", "1", "-I", iface, target]) {
+        Ok(out) => ProbeResult {
+            reachable: true,
+            latency_ms: Some(
+                ping_rtt_ms(&out).unwrap_or_else(|| started.elapsed().as_secs_f64() * 1000.0),
+            ),
+        },
+        Err(_) => ProbeResult {
+            reachable: false,
+            latency_ms: None,
+        },
+    }
+}
+
+/// The `time=12.3 ms` value of a ping reply line.
+fn ping_rtt_ms(out: &str) -> Option<f64> {
+    out.split_whitespace()
+        .find_map(|t| t.strip_prefix("time="))?
+        .parse()
+        .ok()
+}
+
+/// Point every LAN base rule at `to`'s table.
+fn move_base_rule(state: &AppState, from_table: &str, to_table: &str) -> Result<()> {
+    let runner = state.runner.as_ref();
+    let prio = state.config.priorities.lan_default;
+    for selector in state.config.base_selectors() {
+        // Add first so the LAN is never without a base rule
+        add_base_rule(runner, &selector, to_table, prio)?;
+        let _ = base_rule_cmd("del", &selector, from_table, prio).run(runner);
+    }
+    Ok(())
+}
+
+/// Move hosts pinned to a down WAN onto its backup, and put them back on
+/// their original WAN once it is healthy again. Takes the mapping locks
+/// before the health lock, like `/gc`.
+async fn host_failover(state: &AppState, config: &HealthConfig) {
+    if config.host_failover.is_empty() {
+        return;
+    }
+    let mut dns_steered = state.dns_steered.lock().await;
+    let mut mappings = state.mappings.lock().await;
+    let mut health = state.health.lock().await;
+    let wans = health.effective_wans();
+    let up = |nic: &str| wans.get(nic).is_some_and(|w| w.healthy);
+
+    let returning: Vec<(String, String)> = health
+        .moved_hosts
+        .iter()
+        .filter(|(_, original)| up(original))
+        .map(|(host, original)| (host.clone(), original.clone()))
+        .collect();
+    for (host, original) in returning {
+        match repin_host(state, &mut dns_steered, &host, &original, false) {
+            Ok(priority) => {
+                syslog::log(
+                    &state.config,
+                    Severity::Notice,
+                    &format!("Host failover: {} restored to {}", host, original),
+                    "host_failover_restored",
+                    &[("ip", &host), ("to", &original)],
+                );
+                mapping::pin(&mut mappings, &host, &original, priority, Origin::Failover);
+                health.moved_hosts.remove(&host);
+            }
+            Err(e) => syslog::log(
+                &state.config,
+                Severity::Err,
+                &format!("Host failover: restoring {} failed: {:#}", host, e),
+                "host_failover_error",
+                &[
+                    ("ip", &host),
+                    ("to", &original),
+                    ("error", &format!("{:#}", e)),
+                ],
+            ),
+        }
+    }
+
+    for (nic, backup) in &config.host_failover {
+        let down = wans.get(nic).is_some_and(WanHealth::is_down);
+        if !down || !up(backup) {
+            continue;
+        }
+        let hosts: Vec<String> = mappings
+            .iter()
+            .filter(|(_, m)| m.nic == *nic)
+            .map(|(net, _)| mapping::host(net))
+            .filter(|host| !health.moved_hosts.contains_key(host))
+            .collect();
+        for host in hosts {
+            // Pinned explicitly, whatever PIN_PRIMARY says, so the move does
+            // not depend on where the base rule is
+            match repin_host(state, &mut dns_steered, &host, backup, true) {
+                Ok(priority) => {
+                    syslog::log(
+                        &state.config,
+                        Severity::Warning,
+                        &format!("Host failover: {} moved from {} to {}", host, nic, backup),
+                        "host_failover",
+                        &[("ip", &host), ("from", nic), ("to", backup)],
+                    );
+                    mapping::pin(&mut mappings, &host, backup, priority, Origin::Failover);
+                    health.moved_hosts.insert(host, nic.clone());
+                }
+                Err(e) => syslog::log(
+                    &state.config,
+                    Severity::Err,
+                    &format!("Host failover: moving {} failed: {:#}", host, e),
+                    "host_failover_error",
+                    &[
+                        ("ip", &host),
+                        ("to", backup),
+                        ("error", &format!("{:#}", e)),
+                    ],
+                ),
+            }
+        }
+    }
+}
+
+/// Probe both WANs once and apply any failover decision.
+pub async fn check_once(state: &AppState, config: &HealthConfig, now: Instant) {
+    let runner = state.runner.as_ref();
+    let results = [
+        (
+            "wan0",
+            probe_timed(runner, &state.config.wan0, &config.target),
+        ),
+        (
+            "wan1",
+            probe_timed(runner, &state.config.wan1, &config.target),
+        ),
+    ];
+
+    let mut health = state.health.lock().await;
+    for (nic, result) in results {
+        let wan = health.wans.entry(nic.to_string()).or_default();
+        let was_healthy = wan.healthy;
+        wan.record(result.latency_ms, now, config.window);
+        if wan.healthy != was_healthy {
+            let (now_is, severity) = if wan.healthy {
+                ("healthy", Severity::Notice)
+            } else {
+                ("down", Severity::Warning)
+            };
+            syslog::log(
+                &state.config,
+                severity,
+                &format!("{} is now {}", nic, now_is),
+                "wan_state",
+                &[("wan", nic), ("state", now_is)],
+            );
+        }
+    }
+    drop(health);
+    react(state, config, now).await;
+}
+
+/// Move the base rule and the pinned hosts off WANs that are down or
+/// disabled, and back onto those that are not, as the last probes allow.
+pub async fn react(state: &AppState, config: &HealthConfig, now: Instant) {
+    // A read-only instance only reports what it sees
+    if state.config.read_only {
+        return;
+    }
+    if config.failover {
+        base_failover(state, config, &mut *state.health.lock().await, now);
+    }
+    host_failover(state, config).await;
+    auto_select::reevaluate(state, config.auto_hysteresis_ms).await;
+    save(state).await;
+}
+
+/// Hand the current health to STATE_FILE. Written while anything is down
+/// or failed over, so its timestamp stays fresh through an outage, and once
+/// more when all is well again; not on every probe otherwise.
+async fn save(state: &AppState) {
+    if state.config.state_file.is_none() {
+        return;
+    }
+    let mappings = state.mappings.lock().await;
+    let auto_assigned = state.auto_assigned.lock().await;
+    let saved = SavedHealth::new(&*state.health.lock().await, crate::unix_now());
+    let primary = state.config.base_wan;
+    let previous = state.saved_health.lock().unwrap().replace(saved.clone());
+    if previous.is_none_or(|p| p.degraded(primary)) || saved.degraded(primary) {
+        persist::save(state, &mappings, &auto_assigned);
+    }
+}
+
+/// Take up the health saved by a previous run unless it is older than
+/// HEALTH_RESTORE_MAX_AGE: probe results, the base rule's WAN and the hosts
+/// moved by host failover. The first probes confirm or correct it.
+pub async fn restore(state: &AppState, saved: SavedHealth, now: u64) {
+    let Some(config) = &state.config.health else {
+        return;
+    };
+    let age = now.saturating_sub(saved.checked_at);
+    if config.restore_max_age.is_zero() || age > config.restore_max_age.as_secs() {
+        println!("Discarding saved WAN health from {}s ago", age);
+        return;
+    }
+    let mappings = state.mappings.lock().await;
+    let mut health = state.health.lock().await;
+    for (nic, mut wan) in saved.wans {
+        if nic != "wan0" && nic != "wan1" {
+            continue;
+        }
+        // The hold-down before failing back starts over
+        wan.healthy_since = wan.healthy.then(Instant::now);
+        health.wans.insert(nic, wan);
+    }
+    let base_wan = match saved.base_wan.as_str() {
+        "wan0" => "wan0",
+        "wan1" => "wan1",
+        _ => health.failover.active,
+    };
+    if config.failover && base_wan != health.failover.active {
+        let from_table = health.failover.active_table();
+        match move_base_rule(state, from_table, nic_table(base_wan)) {
+            Ok(()) => {
+                println!("Restored failover: LAN base rule on {}", base_wan);
+                health.failover.active = base_wan;
+            }
+            Err(e) => eprintln!("Restoring failover to {} failed: {:#}", base_wan, e),
+        }
+    }
+    // Only hosts still sitting on their backup can be put back later
+    for (host, original) in saved.moved_hosts {
+        let nic = mapping::nic(&mappings, &host);
+        if config.host_failover.contains_key(&original) && nic.is_some_and(|n| n != original) {
+            health.moved_hosts.insert(host, original);
+        }
+    }
+    *state.saved_health.lock().unwrap() = Some(SavedHealth::new(&health, saved.checked_at));
+    println!("Restored WAN health saved {}s ago", age);
+}
+
+fn base_failover(state: &AppState, config: &HealthConfig, health: &mut HealthState, now: Instant) {
+    let wans = health.effective_wans();
+    let wan0 = wans.get("wan0").cloned().unwrap_or_default();
+    let wan1 = wans.get("wan1").cloned().unwrap_or_default();
+    match health.failover.decide(config, &wan0, &wan1, now) {
+        Decision::Stay => health.failover.last_suppressed = None,
+        Decision::Suppressed(target, reason) => {
+            // Log once per episode rather than on every probe
+            if health.failover.last_suppressed != Some(reason) {
+                println!(
+                    "Suppressed failover to {} ({} not yet elapsed)",
+                    target, reason
+                );
+                health.failover.last_suppressed = Some(reason);
+            }
+        }
+        Decision::Move(target) => {
+            let from_table = health.failover.active_table();
+            let to_table = nic_table(target);
+            match move_base_rule(state, from_table, to_table) {
+                Ok(()) => {
+                    syslog::log(
+                        &state.config,
+                        Severity::Warning,
+                        &format!(
+                            "Failover: LAN base rule moved from {} to {}",
+                            health.failover.active, target
+                        ),
+                        "failover",
+                        &[("from", health.failover.active), ("to", target)],
+                    );
+                    health.failover.active = target;
+                    health.failover.last_change = Some(now);
+                    health.failover.changed_at = Some(crate::unix_now());
+                    health.failover.last_suppressed = None;
+                }
+                Err(e) => syslog::log(
+                    &state.config,
+                    Severity::Err,
+                    &format!("Failover to {} failed: {:#}", target, e),
+                    "failover_error",
+                    &[("to", target), ("error", &format!("{:#}", e))],
+                ),
+            }
+        }
+    }
+}
+
+pub async fn health_loop(state: AppState, config: HealthConfig) {
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        if state.paused.load(Ordering::SeqCst) {
+            continue;
+        }
+        check_once(&state, &config, Instant::now()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> HealthConfig {
+        HealthConfig {
+            target: "192.0.2.53".to_string(),
+            interval: Duration::from_secs(5),
+            window: 10,
+            failover: true,
+            hold_down: Duration::from_secs(60),
+            min_interval: Duration::from_secs(30),
+            host_failover: BTreeMap::new(),
+            auto_hysteresis_ms: 20.0,
+            restore_max_age: Duration::from_secs(300),
+            probe_table: false,
+        }
+    }
+
+    fn wan(healthy: bool, since: Option<Instant>) -> WanHealth {
+        WanHealth {
+            healthy,
+            consecutive_failures: if healthy { 0 } else { FAIL_THRESHOLD },
+            healthy_since: since,
+            ..WanHealth::default()
+        }
+    }
+
+    #[test]
+    fn ping_rtt_is_read_from_reply_line() {
+        let out = "PING 192.0.2.53 (192.0.2.53) from 198.51.100.7 eth1: 56(84) bytes of data.\n\
+                   64 bytes from 192.0.2.53: icmp_seq=1 ttl=57 time=12.4 ms\n";
+        assert_eq!(ping_rtt_ms(out), Some(12.4));
+        assert_eq!(ping_rtt_ms("1 packets transmitted, 1 received"), None);
+    }
+
+    #[test]
+    fn wan_goes_down_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut health = WanHealth::default();
+        health.record(Some(10.0), now, 10);
+        for _ in 1..FAIL_THRESHOLD {
+            health.record(None, now, 10);
+            assert!(health.healthy);
+        }
+        health.record(None, now, 10);
+        assert!(!health.healthy);
+    }
+
+    #[test]
+    fn rtt_and_loss_cover_only_the_window() {
+        let now = Instant::now();
+        let mut health = WanHealth::default();
+        for rtt in [Some(10.0), None, Some(20.0), Some(30.0)] {
+            health.record(rtt, now, 3);
+        }
+        // The first probe has left the window
+        assert_eq!(health.rtt_ms, Some(25.0));
+        assert!((health.loss_ratio - 1.0 / 3.0).abs() < 1e-9);
+
+        for _ in 0..3 {
+            health.record(None, now, 3);
+        }
+        assert_eq!((health.rtt_ms, health.loss_ratio), (None, 1.0));
+    }
+
+    #[test]
+    fn failback_waits_for_hold_down() {
+        let config = config();
+        let t0 = Instant::now();
+        let mut failover = Failover::default();
+        let up = wan(true, Some(t0));
+
+        assert_eq!(
+            failover.decide(&config, &wan(false, None), &up, t0),
+            Decision::Move("wan1")
+        );
+        failover.active = "wan1";
+        failover.last_change = Some(t0);
+
+        // wan0 recovers shortly after: rate limit first, then hold-down
+        let recovered = wan(true, Some(t0 + Duration::from_secs(10)));
+        assert_eq!(
+            failover.decide(&config, &recovered, &up, t0 + Duration::from_secs(20)),
+            Decision::Suppressed("wan0", "minimum failover interval")
+        );
+        assert_eq!(
+            failover.decide(&config, &recovered, &up, t0 + Duration::from_secs(40)),
+            Decision::Suppressed("wan0", "hold-down")
+        );
+        assert_eq!(
+            failover.decide(&config, &recovered, &up, t0 + Duration::from_secs(70)),
+            Decision::Move("wan0")
+        );
+    }
+
+    #[test]
+    fn wan1_base_fails_over_to_wan0_and_back() {
+        let config = config();
+        let t0 = Instant::now();
+        let mut failover = Failover::new("wan1");
+        assert_eq!(failover.active_table(), "200");
+        assert_eq!(
+            failover.decide(&config, &wan(true, Some(t0)), &wan(false, None), t0),
+            Decision::Move("wan0")
+        );
+        // wan0 being down is not a reason to move anything
+        assert_eq!(
+            failover.decide(&config, &wan(false, None), &wan(true, Some(t0)), t0),
+            Decision::Stay
+        );
+
+        failover.active = "wan0";
+        failover.last_change = Some(t0);
+        let now = t0 + Duration::from_secs(31);
+        assert_eq!(
+            failover.decide(&config, &wan(true, Some(t0)), &wan(true, Some(now)), now),
+            Decision::Suppressed("wan1", "hold-down")
+        );
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            failover.decide(&config, &wan(true, Some(t0)), &wan(true, Some(now)), later),
+            Decision::Move("wan1")
+        );
+    }
+
+    #[test]
+    fn failback_skips_hold_down_when_wan1_is_down() {
+        let config = config();
+        let t0 = Instant::now();
+        let failover = Failover {
+            active: "wan1",
+            last_change: Some(t0),
+            ..Failover::default()
+        };
+        let now = t0 + Duration::from_secs(31);
+        assert_eq!(
+            failover.decide(&config, &wan(true, Some(now)), &wan(false, None), now),
+            Decision::Move("wan0")
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! `GET /mapping/{ip}/diff`: one host's desired rules next to what the
+//! kernel has, and the commands that would bring the two in line.
+//!
+//! The desired side comes from the service's state: the host rule its
+//! mapping needs, its DNS rules and its destination exceptions. The actual
+//! side is the host's rules in the WAN tables plus an `ip route get` of its
+//! egress (see `verify`). Rules are compared on their match keys and table,
+//! not their priority, as `/switch` keeps a rule that already exists at
+//! another one.
+
+use crate::mapping::{self, Mapping};
+use crate::{
+    dns_rule_cmds, exceptions, ip_rules, nic_table, normalize_selector, rule_add_cmd,
+    rule_del_exact_cmd, verify, AppState, Config, IpCommand, IpRule, TABLE_WAN0, TABLE_WAN1,
+};
+use anyhow::Result;
+use ipnet::{IpNet, Ipv4Net};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct HostDiff {
+    pub host: String,
+    pub desired: Desired,
+    pub actual: Actual,
+    pub in_sync: bool,
+    // Desired rules the kernel lacks, as the commands that add them
+    pub missing: Vec<String>,
+    // Rules the kernel has that the state does not account for
+    pub unexpected: Vec<IpRule>,
+    // Deletes first, then adds
+    pub commands: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Desired {
+    // The WAN the host should leave through: its mapping's, else the one
+    // carrying the base rule
+    pub nic: String,
+    pub mapped: bool,
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Actual {
+    pub rules: Vec<IpRule>,
+    pub egress: verify::Verification,
+}
+
+/// A rule the host should have, with the command that adds it.
+struct Want {
+    rule: IpRule,
+    cmd: IpCommand,
+}
+
+impl Want {
+    fn new(config: &Config, from: &str, table: &str, prio: u32, cmd: IpCommand) -> Self {
+        Want {
+            rule: IpRule {
+                priority: prio,
+                from: normalize_selector(from).to_string(),
+                to: None,
+                table: table.to_string(),
+                ipproto: None,
+                dport: None,
+                iif: config.host_match.iif.as_ref().map(|i| i.to_string()),
+                fwmark: config.host_match.fwmark.clone(),
+                tos: None,
+                oif: None,
+                extra_match: false,
+            },
+            cmd,
+        }
+    }
+
+    /// Whether `rule` is this one, whatever its priority.
+    fn is(&self, rule: &IpRule) -> bool {
+        let want = &self.rule;
+        normalize_selector(&rule.from) == want.from
+            && rule.to.as_deref().map(normalize_selector) == want.to.as_deref()
+            && rule.table == want.table
+            && rule.ipproto == want.ipproto
+            && rule.dport == want.dport
+            && rule.iif == want.iif
+            && rule.fwmark == want.fwmark
+            && rule.tos.is_none()
+            && rule.oif.is_none()
+            && !rule.extra_match
+    }
+}
+
+/// The rules `net` needs: its own rule if its mapping has one, its DNS
+/// rules and its exceptions.
+fn wanted(
+    config: &Config,
+    net: IpNet,
+    mapping: Option<&Mapping>,
+    dns_table: Option<&str>,
+    except: &[Ipv4Net],
+) -> Vec<Want> {
+    let target = net.to_string();
+    let prio = config.priorities;
+    let mut wants = Vec::new();
+    if let Some((m, priority)) = mapping.and_then(|m| Some((m, m.priority?))) {
+        let table = nic_table(&m.nic);
+        let cmd = rule_add_cmd(&target, &config.host_match, table, priority);
+        wants.push(Want::new(config, &target, table, priority, cmd));
+    }
+    if let Some(table) = dns_table {
+        let cmds = dns_rule_cmds("add", &target, &config.host_match, table, prio.dns());
+        for (proto, cmd) in ["udp", "tcp"].into_iter().zip(cmds) {
+            let mut want = Want::new(config, &target, table, prio.dns(), cmd);
+            want.rule.ipproto = Some(proto.to_string());
+            want.rule.dport = Some("53".to_string());
+            wants.push(want);
+        }
+    }
+    if let Some(m) = mapping {
+        let table = exceptions::other_table(&m.nic);
+        let host = mapping::host(&net);
+        for prefix in except {
+            let cmd = exceptions::add_cmd(config, &host, prefix, table);
+            let mut want = Want::new(config, &target, table, prio.exception(), cmd);
+            want.rule.to = Some(normalize_selector(&prefix.to_string()).to_string());
+            wants.push(want);
+        }
+    }
+    wants
+}
+
+/// The commands adding the rules `net` needs, as `wanted` lists them.
+pub fn host_cmds(
+    config: &Config,
+    net: IpNet,
+    mapping: Option<&Mapping>,
+    dns_table: Option<&str>,
+    except: &[Ipv4Net],
+) -> Vec<IpCommand> {
+    wanted(config, net, mapping, dns_table, except)
+        .into_iter()
+        .map(|want| want.cmd)
+        .collect()
+}
+
+/// `ip rule del` for exactly `rule`, including the DNS rules' keys.
+fn del_cmd(rule: &IpRule) -> IpCommand {
+    let mut cmd = rule_del_exact_cmd(rule);
+    for (key, value) in [("ipproto", &rule.ipproto), ("dport", &rule.dport)] {
+        if let Some(value) = value {
+            cmd.args.extend([key.to_string(), value.clone()]);
+        }
+    }
+    cmd
+}
+
+/// Compare `net`'s desired and actual state.
+pub async fn diff(state: &AppState, net: IpNet) -> Result<HostDiff> {
+    let host_str = mapping::host(&net);
+    // Same lock order as switch_handler
+    let dns_table = state.dns_steered.lock().await.get(&host_str).cloned();
+    let mapping = state.mappings.lock().await.get(&net).cloned();
+    let base_wan = state.health.lock().await.failover.active;
+    let except = state
+        .exceptions
+        .lock()
+        .unwrap()
+        .get(&host_str)
+        .cloned()
+        .unwrap_or_default();
+    let config = &state.config;
+    let runner = state.runner.as_ref();
+
+    let wants = wanted(config, net, mapping.as_ref(), dns_table.as_deref(), &except);
+    let selectors: Vec<String> = wants.iter().map(|w| w.rule.from.clone()).collect();
+    let rules: Vec<IpRule> = ip_rules(runner)?
+        .into_iter()
+        .filter(|r| r.table == TABLE_WAN0 || r.table == TABLE_WAN1)
+        .filter(|r| {
+            let from = normalize_selector(&r.from);
+            from == host_str || selectors.iter().any(|s| s == from)
+        })
+        .collect();
+    let missing: Vec<&Want> = wants
+        .iter()
+        .filter(|want| !rules.iter().any(|rule| want.is(rule)))
+        .collect();
+    let unexpected: Vec<IpRule> = rules
+        .iter()
+        .filter(|rule| !wants.iter().any(|want| want.is(rule)))
+        .cloned()
+        .collect();
+
+    let nic = mapping
+        .as_ref()
+        .map_or(base_wan.to_string(), |m| m.nic.clone());
+    let iface = if nic == "wan1" {
+        &config.wan1
+    } else {
+        &config.wan0
+    };
+    let (egress, _) = verify::egress(runner, config, &net.addr().to_string(), iface);
+    let commands = unexpected
+        .iter()
+        .map(|rule| del_cmd(rule).to_string())
+        .chain(missing.iter().map(|want| want.cmd.to_string()))
+        .collect();
+    Ok(HostDiff {
+        host: host_str,
+        in_sync: missing.is_empty() && unexpected.is_empty() && egress.ok,
+        desired: Desired {
+            nic,
+            mapped: mapping.is_some(),
+            rules: wants.iter().map(|want| want.cmd.to_string()).collect(),
+        },
+        actual: Actual { rules, egress },
+        missing: missing.iter().map(|want| want.cmd.to_string()).collect(),
+        unexpected,
+        commands,
+    })
+}
@@ -0,0 +1,129 @@
+//! Bulk import of static assignments for POST /import/csv.
+//!
+//! One assignment per line, either `ip=nic` or `ip,nic`; a leading `ip,nic`
+//! header, blank lines and `#` comments are skipped. Parsing only checks the
+//! shape of each line; the assignments themselves are validated by the
+//! switch they turn into.
+
+use crate::parse_switch_ip;
+use std::collections::HashMap;
+
+/// One well-formed line.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub line: usize,
+    pub ip: String,
+    pub nic: String,
+}
+
+/// A line that could not be read, with its 1-based number.
+#[derive(Debug, PartialEq)]
+pub struct LineError {
+    pub line: usize,
+    pub text: String,
+    pub message: String,
+}
+
+/// Every non-blank, non-comment line in order. A host listed twice is an
+/// error on the later line rather than a silent override.
+pub fn parse(text: &str) -> Vec<Result<Entry, LineError>> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut results = Vec::new();
+    let mut first = true;
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        let text = raw.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+        let is_header = first && text.eq_ignore_ascii_case("ip,nic");
+        first = false;
+        if is_header {
+            continue;
+        }
+        let error = |message: String| LineError {
+            line,
+            text: text.to_string(),
+            message,
+        };
+        let fields: Vec<&str> = text.split(['=', ',']).map(str::trim).collect();
+        let [ip, nic] = fields[..] else {
+            results.push(Err(error(format!(
+                "expected `ip=nic` or `ip,nic`, got {} fields",
+                fields.len()
+            ))));
+            continue;
+        };
+        if ip.is_empty() || nic.is_empty() {
+            results.push(Err(error("ip and nic must both be set".to_string())));
+            continue;
+        }
+        // Compare hosts, so 10.40.0.3 and 10.40.0.3/20 count as the same
+        let host = parse_switch_ip(ip).map_or_else(|| ip.to_string(), |addr| addr.to_string());
+        if let Some(earlier) = seen.get(&host) {
+            results.push(Err(error(format!(
+                "{} is already assigned on line {}",
+                host, earlier
+            ))));
+            continue;
+        }
+        seen.insert(host, line);
+        results.push(Ok(Entry {
+            line,
+            ip: ip.to_string(),
+            nic: nic.to_string(),
+        }));
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_line_formats_and_header_are_read() {
+        let text = "ip,nic\n\
+                    # office printers\n\
+                    10.40.0.3,wan1\n\
+                    \n\
+                    10.40.0.4 = wan0\n\
+                    10.40.0.5/20,wan1\n";
+        let entries: Vec<(usize, String, String)> = parse(text)
+            .into_iter()
+            .map(|r| r.map(|e| (e.line, e.ip, e.nic)).unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (3, "10.40.0.3".to_string(), "wan1".to_string()),
+                (5, "10.40.0.4".to_string(), "wan0".to_string()),
+                (6, "10.40.0.5/20".to_string(), "wan1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_and_duplicate_lines_are_reported() {
+        let text = "10.40.0.3=wan1\n\
+                    10.40.0.4\n\
+                    10.40.0.5,wan1,extra\n\
+                    ,wan1\n\
+                    10.40.0.3/20=wan0\n\
+                    ip,nic\n";
+        let results = parse(text);
+        let errors: Vec<(usize, String)> = results
+            .iter()
+            .filter_map(|r| r.as_ref().err())
+            .map(|e| (e.line, e.message.clone()))
+            .collect();
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert_eq!(errors[0].0, 2);
+        assert!(errors[1].1.contains("3 fields"));
+        assert_eq!(errors[2].1, "ip and nic must both be set");
+        assert_eq!(errors[3].1, "10.40.0.3 is already assigned on line 1");
+        // Only a first line is taken as the header; later it's left to the switch
+        let last = results.last().unwrap().as_ref().unwrap();
+        assert_eq!((last.line, last.ip.as_str()), (6, "ip"));
+    }
+}
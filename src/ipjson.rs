@@ -0,0 +1,248 @@
+//! iproute2's JSON output (`ip -j`), read with serde instead of picking the
+//! text apart.
+//!
+//! `ip -j` covers routes and rules from iproute2 4.17 on; whether the
+//! installed version has it is found once at startup from `ip -V`. Each
+//! reader returns None when JSON is unavailable or the output does not
+//! parse, and the caller falls back to the text format.
+
+use crate::{normalize_selector, CommandRunner, IpRule, EXTRA_MATCH_KEYS};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+// 4.17, the first release printing routes and rules as JSON; older releases
+// were versioned by snapshot date (iproute2-ssYYMMDD)
+const FIRST_VERSION: (u32, u32) = (4, 17);
+const FIRST_SNAPSHOT: u32 = 180604;
+
+/// The release an `ip -V` banner names, e.g. `6.1.0` or `ss180129`.
+pub fn iproute2_version(banner: &str) -> Option<&str> {
+    banner
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .find_map(|token| token.strip_prefix("iproute2-"))
+}
+
+/// Whether the `ip -V` banner names a release with JSON output.
+pub fn version_supports_json(banner: &str) -> bool {
+    let Some(version) = iproute2_version(banner) else {
+        return false;
+    };
+    if let Some(date) = version.strip_prefix("ss") {
+        return date.parse().is_ok_and(|date: u32| date >= FIRST_SNAPSHOT);
+    }
+    let mut parts = version.split('.').map(str::parse::<u32>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => (major, minor) >= FIRST_VERSION,
+        _ => false,
+    }
+}
+
+/// One entry of `ip -j route show`.
+#[derive(Debug, Deserialize)]
+pub struct Route {
+    pub dst: String,
+    pub gateway: Option<IpAddr>,
+    // Left out when the listing was already filtered by device
+    pub dev: Option<String>,
+    #[serde(default)]
+    pub nexthops: Vec<Hop>,
+}
+
+/// One nexthop of a multipath route.
+#[derive(Debug, Deserialize)]
+pub struct Hop {
+    pub gateway: Option<IpAddr>,
+    pub dev: Option<String>,
+}
+
+impl Route {
+    /// Whether the route leaves through `iface`, directly or by one of its
+    /// nexthops.
+    pub fn uses(&self, iface: &str) -> bool {
+        self.dev.as_deref() == Some(iface)
+            || self
+                .nexthops
+                .iter()
+                .any(|hop| hop.dev.as_deref() == Some(iface))
+    }
+
+    /// The gateway the route uses on `iface`, if any.
+    pub fn gateway_on(&self, iface: &str) -> Option<IpAddr> {
+        if self.dev.as_deref() == Some(iface) {
+            return self.gateway;
+        }
+        self.nexthops
+            .iter()
+            .find(|hop| hop.dev.as_deref() == Some(iface))
+            .and_then(|hop| hop.gateway)
+    }
+}
+
+/// `ip -j <args>` (e.g. `route show default`), when the runner's iproute2
+/// prints JSON.
+pub fn routes(runner: &dyn CommandRunner, args: &[&str]) -> Option<Vec<Route>> {
+    parse_routes(&run_json(runner, args)?)
+}
+
+fn parse_routes(out: &str) -> Option<Vec<Route>> {
+    serde_json::from_str(out).ok()
+}
+
+/// One entry of `ip -j rule show`. Numbers and names vary in how they are
+/// printed across versions, so the match keys are kept as raw values.
+#[derive(Debug, Deserialize)]
+struct Rule {
+    priority: u32,
+    src: Option<String>,
+    srclen: Option<u8>,
+    dst: Option<String>,
+    dstlen: Option<u8>,
+    table: Option<String>,
+    iif: Option<String>,
+    fwmark: Option<Value>,
+    fwmask: Option<Value>,
+    ipproto: Option<Value>,
+    dport: Option<Value>,
+    dport_start: Option<Value>,
+    dport_end: Option<Value>,
+    tos: Option<Value>,
+    oif: Option<String>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+// Keys of JSON rules that match on something IpRule has no field for
+const EXTRA_JSON_KEYS: &[&str] = &["sport_start", "uid_start"];
+
+/// `ip -j rule show`, in the shape `parse_ip_rules` gives the text listing.
+pub fn rules(runner: &dyn CommandRunner) -> Option<Vec<IpRule>> {
+    parse_rules(&run_json(runner, &["rule", "show"])?)
+}
+
+fn parse_rules(out: &str) -> Option<Vec<IpRule>> {
+    let rules: Vec<Rule> = serde_json::from_str(out).ok()?;
+    Some(rules.into_iter().filter_map(Rule::into_ip_rule).collect())
+}
+
+impl Rule {
+    /// Rules without a table to look up (`goto`, `unreachable`, ...) are
+    /// left out, as in the text listing.
+    fn into_ip_rule(self) -> Option<IpRule> {
+        let with_len = |addr: String, len: Option<u8>| match len {
+            Some(len) => format!("{}/{}", addr, len),
+            None => addr,
+        };
+        let from = with_len(self.src.unwrap_or_else(|| "all".to_string()), self.srclen);
+        let dport = match (self.dport, self.dport_start, self.dport_end) {
+            (Some(port), _, _) => Some(text(&port)),
+            (None, Some(start), Some(end)) => Some(format!("{}-{}", text(&start), text(&end))),
+            _ => None,
+        };
+        let fwmark = self.fwmark.as_ref().and_then(number).map(|mark| {
+            match self.fwmask.as_ref().and_then(number) {
+                Some(mask) if mask != u64::from(u32::MAX) => format!("{:#x}/{:#x}", mark, mask),
+                _ => format!("{:#x}", mark),
+            }
+        });
+        Some(IpRule {
+            priority: self.priority,
+            from: normalize_selector(&from).to_string(),
+            to: self.dst.map(|dst| with_len(dst, self.dstlen)),
+            table: self.table?,
+            ipproto: self.ipproto.as_ref().map(text),
+            dport,
+            iif: self.iif,
+            fwmark,
+            tos: self.tos.as_ref().map(text),
+            oif: self.oif,
+            extra_match: self.other.keys().any(|key| {
+                EXTRA_MATCH_KEYS.contains(&key.as_str()) || EXTRA_JSON_KEYS.contains(&key.as_str())
+            }),
+        })
+    }
+}
+
+/// Output of `ip -j <args>`, when the runner's iproute2 prints JSON.
+pub fn run_json(runner: &dyn CommandRunner, args: &[&str]) -> Option<String> {
+    if !runner.ip_json() {
+        return None;
+    }
+    let args: Vec<&str> = ["-j"].iter().chain(args).copied().collect();
+    runner.run("ip", &args).ok()
+}
+
+/// A string or number value as the text listing would print it.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// A number given either as such or as a (hex) string.
+fn number(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_ip_rules;
+
+    #[test]
+    fn json_support_is_read_from_the_version_banner() {
+        assert!(version_supports_json(
+            "ip utility, iproute2-6.1.0, libbpf 1.1.0\n"
+        ));
+        assert!(version_supports_json("ip utility, iproute2-5.4.0\n"));
+        assert!(version_supports_json("ip utility, iproute2-ss200127\n"));
+        assert!(version_supports_json("ip utility, iproute2-4.17.0\n"));
+        assert!(!version_supports_json("ip utility, iproute2-4.15.0\n"));
+        assert!(!version_supports_json("ip utility, iproute2-ss161212\n"));
+        assert!(!version_supports_json(
+            "BusyBox v1.36.1 multi-call binary.\n"
+        ));
+    }
+
+    #[test]
+    fn routes_are_read_with_their_nexthops() {
+        let out = r#"[{"dst":"default","gateway":"192.0.2.1","dev":"eth0","protocol":"dhcp","prefsrc":"192.0.2.10","metric":100,"flags":[]},{"dst":"default","protocol":"static","metric":200,"flags":[],"nexthops":[{"gateway":"198.51.100.1","dev":"eth1","weight":1,"flags":[]},{"gateway":"198.51.100.2","dev":"eth1","weight":1,"flags":[]}]},{"dst":"default","dev":"ppp0","scope":"link","flags":[]}]"#;
+        let routes = parse_routes(out).unwrap();
+        let gateway = |iface| routes.iter().find_map(|r| r.gateway_on(iface));
+        assert_eq!(gateway("eth0"), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(gateway("eth1"), Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(gateway("ppp0"), None);
+        assert!(routes.iter().any(|r| r.uses("ppp0")));
+        assert!(!routes.iter().any(|r| r.uses("eth9")));
+        assert!(parse_routes("default via 192.0.2.1 dev eth0").is_none());
+    }
+
+    #[test]
+    fn rules_match_the_text_listing() {
+        // `ip -j rule show` and `ip rule show` of the same rules
+        let json = r#"[{"priority":0,"src":"all","table":"local"},{"priority":998,"src":"10.40.0.3","dst":"203.0.113.0","dstlen":24,"table":"100"},{"priority":999,"src":"10.40.0.3","ipproto":"udp","dport":53,"table":"200"},{"priority":1000,"src":"10.40.0.3","iif":"eth2","fwmark":"0x1","fwmask":"0xff","table":"200","protocol":"static"},{"priority":1600,"not":null,"src":"10.40.0.9","table":"100","flags":["detached"]},{"priority":1800,"src":"all","action":"unreachable"},{"priority":2000,"src":"10.40.0.0","srclen":20,"table":"100"},{"priority":32766,"src":"all","table":"main"},{"priority":32767,"src":"all","iif":"eth2","tos":"0xb8","table":"200"}]"#;
+        let text = "0:\tfrom all lookup local\n\
+                    998:\tfrom 10.40.0.3 to 203.0.113.0/24 lookup 100\n\
+                    999:\tfrom 10.40.0.3 ipproto udp dport 53 lookup 200\n\
+                    1000:\tfrom 10.40.0.3 iif eth2 fwmark 0x1/0xff lookup 200 proto static\n\
+                    1600:\tnot from 10.40.0.9 lookup 100 [detached]\n\
+                    1800:\tfrom all unreachable\n\
+                    2000:\tfrom 10.40.0.0/20 lookup 100\n\
+                    32766:\tfrom all lookup main\n\
+                    32767:\tfrom all iif eth2 tos 0xb8 lookup 200\n";
+        let rules = parse_rules(json).unwrap();
+        assert_eq!(rules, parse_ip_rules(text));
+        assert!(rules[4].extra_match);
+        assert_eq!(rules[3].fwmark.as_deref(), Some("0x1/0xff"));
+        assert_eq!(rules[7].tos.as_deref(), Some("0xb8"));
+    }
+}
@@ -0,0 +1,109 @@
+//! Periodic cleanup of the service's time-bounded in-memory state, every
+//! JANITOR_INTERVAL seconds (60 by default).
+//!
+//! Expired /gc confirmation tokens and transactions are otherwise only
+//! dropped when another one is looked up, and a gateway that stopped
+//! resolving would be shown by /gateways indefinitely. Interface addresses are cached between sweeps.
+//! The /debug ring buffers are capped as they are written and need no sweep.
+
+use crate::{confirm, txn, unix_now, AppState};
+use std::time::{Duration, Instant};
+
+/// How long /gateways keeps reporting a gateway that no longer resolves.
+pub const GATEWAY_MAX_AGE: u64 = 24 * 60 * 60;
+
+/// What one sweep removed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Swept {
+    pub confirmations: usize,
+    pub transactions: usize,
+    pub gateways: usize,
+}
+
+/// Drop expired confirmation tokens, transactions and gateway cache entries last
+/// refreshed more than GATEWAY_MAX_AGE before `unix_now`. Cached interface
+/// addresses are dropped every time, to be read afresh.
+pub async fn sweep(state: &AppState, now: Instant, unix_now: u64) -> Swept {
+    state.iface_addrs.lock().unwrap().clear();
+    let confirmations = confirm::prune(&mut state.confirmations.lock().unwrap(), now);
+    let transactions = txn::prune(&mut state.transactions.lock().unwrap(), now);
+    let mut cache = state.gateways.lock().await;
+    let before = cache.len();
+    cache.retain(|_, (_, refreshed)| unix_now.saturating_sub(*refreshed) <= GATEWAY_MAX_AGE);
+    Swept {
+        confirmations,
+        transactions,
+        gateways: before - cache.len(),
+    }
+}
+
+pub async fn janitor_loop(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let swept = sweep(&state, Instant::now(), unix_now()).await;
+        if swept != Swept::default() {
+            println!(
+                "Janitor: dropped {} expired confirmation(s), {} expired transaction(s), {} stale gateway(s)",
+                swept.confirmations, swept.transactions, swept.gateways
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_runner::MemoryRunner;
+    use crate::{Config, Nexthop};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn sweep_drops_only_what_has_expired() {
+        let config = Config::from_vars(&|_| None).unwrap();
+        let state = AppState::new(config, Arc::new(MemoryRunner::new()), Default::default());
+        let now = Instant::now();
+        let window = Duration::from_secs(30);
+        for (changes, issued) in [("a", now), ("b", now - window * 2)] {
+            confirm::check(
+                &mut state.confirmations.lock().unwrap(),
+                window,
+                "gc",
+                vec![changes.to_string()],
+                None,
+                issued,
+            )
+            .unwrap();
+        }
+        let unix = 1_700_000_000;
+        {
+            let mut cache = state.gateways.lock().await;
+            cache.insert("wan0".to_string(), (Nexthop::DeviceOnly, unix - 60));
+            cache.insert(
+                "wan1".to_string(),
+                (Nexthop::DeviceOnly, unix - GATEWAY_MAX_AGE - 1),
+            );
+        }
+
+        txn::open(
+            &mut state.transactions.lock().unwrap(),
+            window,
+            now - window * 2,
+        );
+
+        let swept = sweep(&state, now, unix).await;
+        assert_eq!(
+            swept,
+            Swept {
+                confirmations: 1,
+                transactions: 1,
+                gateways: 1
+            }
+        );
+        assert_eq!(state.confirmations.lock().unwrap().len(), 1);
+        let cache = state.gateways.lock().await;
+        assert_eq!(cache.keys().collect::<Vec<_>>(), ["wan0"]);
+        drop(cache);
+        assert_eq!(sweep(&state, now, unix).await, Swept::default());
+    }
+}
@@ -0,0 +1,278 @@
+//! Pinning hosts by their DHCP lease hostname.
+//!
+//! LEASES_FILE is a dnsmasq or ISC dhcpd leases file (the format is detected
+//! from its contents). `/switch?lease=<hostname>` resolves through it, and
+//! the file is re-read on an interval so a pin follows its hostname when the
+//! lease moves to another address.
+
+use crate::mapping::{self, Origin};
+use crate::{
+    del_ip_rule_quiet, dns_rule_cmds, exceptions, persist, repin_host, unix_now, AppState,
+    TABLE_WAN0, TABLE_WAN1,
+};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct LeaseConfig {
+    pub path: PathBuf,
+    // How often lease pins are checked against the file
+    pub interval: Duration,
+}
+
+/// Lowercased hostname -> addresses currently leased under it. More than
+/// one address means several devices share the name.
+pub type Leases = BTreeMap<String, BTreeSet<IpAddr>>;
+
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    Found(IpAddr),
+    Missing,
+    Ambiguous(Vec<IpAddr>),
+}
+
+pub fn lookup(leases: &Leases, hostname: &str) -> Lookup {
+    match leases.get(&hostname.to_ascii_lowercase()) {
+        None => Lookup::Missing,
+        Some(ips) if ips.len() == 1 => Lookup::Found(*ips.iter().next().unwrap()),
+        Some(ips) => Lookup::Ambiguous(ips.iter().copied().collect()),
+    }
+}
+
+pub fn read(path: &Path) -> Result<Leases> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    Ok(parse(&text))
+}
+
+/// Parse either format: ISC files are made of `lease <ip> { ... }` blocks,
+/// dnsmasq files of one `<expiry> <mac> <ip> <hostname> <client-id>` line
+/// per lease.
+pub fn parse(text: &str) -> Leases {
+    let isc = text
+        .lines()
+        .any(|l| l.trim_start().starts_with("lease ") && l.trim_end().ends_with('{'));
+    let mut leases = Leases::new();
+    let entries = if isc {
+        parse_isc(text)
+    } else {
+        parse_dnsmasq(text)
+    };
+    for (hostname, ip) in entries {
+        leases
+            .entry(hostname.to_ascii_lowercase())
+            .or_default()
+            .insert(ip);
+    }
+    leases
+}
+
+fn parse_dnsmasq(text: &str) -> Vec<(String, IpAddr)> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // "duid ..." lines and anything short are not leases
+            let ip = fields.get(2)?.parse().ok()?;
+            let hostname = fields.get(3).filter(|h| **h != "*")?;
+            Some((hostname.to_string(), ip))
+        })
+        .collect()
+}
+
+/// dhcpd appends a new block whenever a lease changes, so the last block
+/// for an address wins; only active leases with a hostname count.
+fn parse_isc(text: &str) -> Vec<(String, IpAddr)> {
+    let mut latest: BTreeMap<IpAddr, (Option<String>, bool)> = BTreeMap::new();
+    let mut current: Option<(IpAddr, Option<String>, bool)> = None;
+    for line in text.lines() {
+        let line = line.trim().trim_end_matches(';');
+        if let Some(rest) = line.strip_prefix("lease ") {
+            current = rest
+                .trim_end_matches('{')
+                .trim()
+                .parse()
+                .ok()
+                .map(|ip| (ip, None, true));
+        } else if let Some((_, hostname, active)) = &mut current {
+            if let Some(state) = line.strip_prefix("binding state ") {
+                *active = state == "active";
+            } else if let Some(name) = line.strip_prefix("client-hostname ") {
+                *hostname = Some(name.trim_matches('"').to_string());
+            } else if line == "}" {
+                let (ip, hostname, active) = current.take().unwrap();
+                latest.insert(ip, (hostname, active));
+            }
+        }
+    }
+    latest
+        .into_iter()
+        .filter_map(|(ip, (hostname, active))| Some((hostname.filter(|_| active)?, ip)))
+        .collect()
+}
+
+/// Re-read the leases file and move every lease pin whose hostname now
+/// resolves to a different address: the old address loses its rules and
+/// mapping, the new one gets the same WAN (and DNS steering).
+pub async fn follow_once(state: &AppState, config: &LeaseConfig) -> Result<()> {
+    let leases = read(&config.path)?;
+    let pins: Vec<(String, String)> = state
+        .lease_pins
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, ip)| (name.clone(), ip.clone()))
+        .collect();
+    // Same lock order as switch_handler
+    let mut dns_steered = state.dns_steered.lock().await;
+    let mut mappings = state.mappings.lock().await;
+    let auto_assigned = state.auto_assigned.lock().await;
+    let runner = state.runner.as_ref();
+    let mut changed = false;
+    for (name, old) in pins {
+        let (new_ip, new) = match lookup(&leases, &name) {
+            Lookup::Found(ip) if ip.to_string() != old => (ip, ip.to_string()),
+            Lookup::Found(_) => continue,
+            Lookup::Missing => continue,
+            Lookup::Ambiguous(ips) => {
+                eprintln!(
+                    "Lease {} is ambiguous ({:?}); keeping it on {}",
+                    name, ips, old
+                );
+                continue;
+            }
+        };
+        let Some(nic) = mapping::nic(&mappings, &old).map(str::to_string) else {
+            // The address was unmapped since; nothing left to follow
+            state.lease_pins.lock().unwrap().remove(&name);
+            continue;
+        };
+
+        let target = mapping::selector(&old);
+        let host_match = &state.config.host_match;
+        // Carried over, like the DNS rules below
+        let except = exceptions::clear(state, &old);
+        if !except.is_empty() {
+            state.exceptions.lock().unwrap().insert(new.clone(), except);
+        }
+        if let Some(table) = dns_steered.remove(&old) {
+            let prio = state.config.priorities.dns();
+            for cmd in dns_rule_cmds("del", &target, host_match, &table, prio) {
+                let _ = cmd.run(runner);
+            }
+            // Carried over so repin_host adds DNS rules for the new address
+            dns_steered.insert(new.clone(), table);
+        }
+        del_ip_rule_quiet(runner, &target, host_match, TABLE_WAN0);
+        del_ip_rule_quiet(runner, &target, host_match, TABLE_WAN1);
+        // The record moves with the pin
+        let record = mapping::remove(&mut mappings, &old);
+        changed = true;
+
+        match repin_host(state, &mut dns_steered, &new, &nic, false) {
+            Ok(priority) => {
+                println!(
+                    "Lease {} moved from {} to {}; pin follows to {}",
+                    name, old, new, nic
+                );
+                if let Some(mut record) = record {
+                    record.priority = priority;
+                    record.modified = unix_now();
+                    record.origin = Origin::Lease;
+                    mappings.insert(new_ip.into(), record);
+                }
+                state.lease_pins.lock().unwrap().insert(name, new);
+            }
+            Err(e) => {
+                eprintln!("Lease {}: pinning {} to {} failed: {:#}", name, new, nic, e);
+                dns_steered.remove(&new);
+            }
+        }
+    }
+    if changed {
+        persist::save(state, &mappings, &auto_assigned);
+    }
+    Ok(())
+}
+
+pub async fn follow_loop(state: AppState, config: LeaseConfig) {
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        if state.paused.load(Ordering::SeqCst) {
+            continue;
+        }
+        if let Err(e) = follow_once(&state, &config).await {
+            eprintln!("Lease follow failed: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dnsmasq_leases_are_parsed() {
+        let text = "1760000000 02:00:00:00:00:01 10.40.0.3 MyLaptop 01:02:00:00:00:00:00:01\n\
+                    1760000100 02:00:00:00:00:02 10.40.0.4 * *\n\
+                    0 02:00:00:00:00:03 10.40.0.5 printer *\n\
+                    duid 00:01:00:01:2c:00:00:00:02:00:00:00:00:01\n";
+        let leases = parse(text);
+        assert_eq!(
+            lookup(&leases, "mylaptop"),
+            Lookup::Found("10.40.0.3".parse().unwrap())
+        );
+        assert_eq!(
+            lookup(&leases, "PRINTER"),
+            Lookup::Found("10.40.0.5".parse().unwrap())
+        );
+        assert_eq!(lookup(&leases, "*"), Lookup::Missing);
+        assert_eq!(leases.len(), 2);
+    }
+
+    #[test]
+    fn isc_leases_use_the_last_active_block() {
+        let text = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+lease 10.40.0.3 {
+  starts 4 2026/10/15 10:00:00;
+  binding state active;
+  next binding state free;
+  client-hostname "laptop";
+}
+lease 10.40.0.3 {
+  binding state free;
+  client-hostname "laptop";
+}
+lease 10.40.0.9 {
+  binding state active;
+  client-hostname "laptop";
+}
+lease 10.40.0.10 {
+  binding state active;
+}
+"#;
+        let leases = parse(text);
+        assert_eq!(
+            lookup(&leases, "laptop"),
+            Lookup::Found("10.40.0.9".parse().unwrap())
+        );
+        assert_eq!(leases.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_hostnames_are_ambiguous() {
+        let text = "0 02:00:00:00:00:01 10.40.0.3 android *\n\
+                    0 02:00:00:00:00:02 10.40.0.4 android *\n";
+        assert_eq!(
+            lookup(&parse(text), "android"),
+            Lookup::Ambiguous(vec![
+                "10.40.0.3".parse().unwrap(),
+                "10.40.0.4".parse().unwrap()
+            ])
+        );
+    }
+}
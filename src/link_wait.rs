@@ -0,0 +1,94 @@
+//! Waiting for the WAN links at startup (IFACE_WAIT=<seconds>).
+//!
+//! Started early in boot, the service can find its WAN interfaces still
+//! down, and gateway discovery then fails. This polls each interface's
+//! operstate until it is up or the wait runs out.
+
+use crate::Interface;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const POLL: Duration = Duration::from_millis(500);
+
+/// Whether `iface` is up according to `<sys_net>/<iface>/operstate`. Links
+/// without carrier detection (PPP, tunnels) report "unknown" while working.
+fn is_up(sys_net: &Path, iface: &str) -> bool {
+    std::fs::read_to_string(sys_net.join(iface).join("operstate"))
+        .is_ok_and(|state| matches!(state.trim(), "up" | "unknown"))
+}
+
+/// Wait until every interface in `ifaces` is up, for at most `timeout`.
+/// Returns the ones still not up when it ran out.
+pub async fn wait_for_links(
+    ifaces: &[&Interface],
+    timeout: Duration,
+    sys_net: &Path,
+) -> Vec<String> {
+    let started = Instant::now();
+    let mut logged = false;
+    loop {
+        let down: Vec<String> = ifaces
+            .iter()
+            .filter(|iface| !is_up(sys_net, iface))
+            .map(|iface| iface.to_string())
+            .collect();
+        if down.is_empty() {
+            if logged {
+                println!("Links up after {:.1?}", started.elapsed());
+            }
+            return down;
+        }
+        if started.elapsed() >= timeout {
+            return down;
+        }
+        if !logged {
+            println!(
+                "Waiting up to {:?} for {} to come up",
+                timeout,
+                down.join(", ")
+            );
+            logged = true;
+        }
+        tokio::time::sleep(POLL.min(timeout.saturating_sub(started.elapsed()))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_operstate(sys_net: &Path, iface: &str, state: &str) {
+        std::fs::create_dir_all(sys_net.join(iface)).unwrap();
+        std::fs::write(
+            sys_net.join(iface).join("operstate"),
+            format!("{}\n", state),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_until_links_are_up_or_time_runs_out() {
+        let sys_net =
+            std::env::temp_dir().join(format!("wan-switcher-links-{}", std::process::id()));
+        set_operstate(&sys_net, "eth0", "up");
+        set_operstate(&sys_net, "ppp0", "unknown");
+        set_operstate(&sys_net, "eth1", "down");
+        let iface = |name| Interface::new(name).unwrap();
+        let (eth0, ppp0, eth1, eth9) = (iface("eth0"), iface("ppp0"), iface("eth1"), iface("eth9"));
+
+        let down = wait_for_links(&[&eth0, &ppp0, &eth1, &eth9], Duration::ZERO, &sys_net).await;
+        assert_eq!(down, ["eth1", "eth9"]);
+
+        let writer = {
+            let sys_net = sys_net.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                set_operstate(&sys_net, "eth1", "up");
+            })
+        };
+        let down = wait_for_links(&[&eth0, &eth1], Duration::from_secs(5), &sys_net).await;
+        assert!(down.is_empty());
+        writer.await.unwrap();
+        std::fs::remove_dir_all(&sys_net).unwrap();
+    }
+}
@@ -1,9 +1,17 @@
 use anyhow::{bail, Context, Result};
-use axum::{extract::Query, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
-use regex::Regex;
+use axum::{
+    extract::{ConnectInfo, Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::net::IpAddr;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -11,310 +19,9523 @@ mod version {
     pub const VERSION: &str = "1.0.0";
 }
 
-#[derive(Clone)]
+mod addrs;
+mod asymmetry;
+mod auto_select;
+mod capabilities;
+mod cleanup;
+mod cmd_limit;
+mod confirm;
+mod dscp;
+mod exceptions;
+mod extra_routes;
+mod health;
+mod host_diff;
+mod import;
+mod ipjson;
+mod janitor;
+mod leases;
+mod link_wait;
+mod mapping;
+#[cfg(test)]
+mod memory_runner;
+mod overall;
+mod page;
+mod persist;
+mod preflight;
+mod readonly;
+mod safe_mode;
+mod schedule;
+mod script;
+mod syslog;
+mod txn;
+mod verify;
+
+#[derive(Clone, Debug)]
 struct Config {
-    wan0: String,
-    wan1: String,
-    lan: String,
+    wan0: Interface,
+    wan1: Interface,
+    lan: Interface,
+    // What the LAN base and fallback rules match on
+    lan_rule_mode: LanRuleMode,
+    // Match keys host rules carry besides `from <ip>`
+    host_match: HostMatch,
+    // Explicit nexthops; when unset the gateway is discovered from the kernel
+    gateway_wan0: Option<Nexthop>,
+    gateway_wan1: Option<Nexthop>,
+    // WAN the LAN base rule points at ("wan0" unless BASE_WAN=wan1); hosts
+    // mapped to the other WAN get their own rule
+    base_wan: &'static str,
+    // Install a rule right below the overrides that falls back to the base WAN's table
+    fallback_to_primary: bool,
+    // Give base WAN pins their own /32 rule instead of relying on the base rule
+    pin_primary: bool,
+    // Guardrails on which hosts /switch may touch; an empty allowlist allows all
+    switch_allow: Vec<IpNet>,
+    switch_deny: Vec<IpNet>,
+    // LAN/VLAN subnets that get a base rule; switch targets must fall inside
+    // one unless SWITCH_SUBNET_POLICY relaxes that
+    lan_subnets: Vec<IpNet>,
+    // What happens to switch targets outside every LAN subnet
+    subnet_policy: SubnetPolicy,
+    // Whether a mapping whose prefix overlaps recorded ones replaces them or is refused
+    overlap_policy: mapping::OverlapPolicy,
+    // /switch refuses prefixes shorter than this
+    min_prefix_len: Option<u8>,
+    // Also install IPv6 default routes (RA-learned, usually link-local) in the WAN tables
+    ipv6: bool,
+    // Refuse to start when a WAN table holds routes the service would not install
+    strict_tables: bool,
+    // Check that a switch's deletes took before adding the new rule; false
+    // deletes best-effort, as cleanup always does
+    strict_deletes: bool,
+    // Probe kernel policy routing and required tools before touching anything
+    preflight: bool,
+    // Refuse to initialize when it would cut the router off its default route
+    // or, if given, its management host
+    safe_mode: bool,
+    management_host: Option<std::net::Ipv4Addr>,
+    // How long to wait at startup for the WAN links to come up
+    iface_wait: Option<std::time::Duration>,
+    // Preferred source address for each WAN table's default route
+    src_wan0: Option<SrcAddr>,
+    src_wan1: Option<SrcAddr>,
+    // Whether each WAN table's default route is overwritten or only added
+    route_mode_wan0: RouteMode,
+    route_mode_wan1: RouteMode,
+    // Static routes installed in each WAN table besides the default and link routes
+    extra_routes_wan0: Vec<extra_routes::ExtraRoute>,
+    extra_routes_wan1: Vec<extra_routes::ExtraRoute>,
+    // Serve the API on this Unix socket instead of TCP
+    listen_uds: Option<std::path::PathBuf>,
+    // Permission bits of the socket file
+    listen_uds_mode: u32,
+    // Warn when a switched host's NAT or return route looks wrong
+    asymmetry_check: bool,
+    // Destination /switch?verify=true looks the host's route up for
+    verify_dest: std::net::Ipv4Addr,
+    // Seconds east of UTC that schedule windows are written in
+    schedule_utc_offset: i64,
+    // Serve the embedded web UI at "/"
+    ui_enabled: bool,
+    // Keep recent rejected /switch requests and each switch's rule diff under /debug
+    debug: bool,
+    // Steer pinned hosts' DNS out their WAN unless a switch says otherwise
+    dns_steering: bool,
+    // Import host rules already present in the managed tables at startup
+    adopt_rules: bool,
+    // Weights for spreading newly seen LAN hosts across the WANs; None disables
+    auto_balance: Option<Vec<(String, u32)>>,
+    // Cap on tracked mappings; auto ones are evicted LRU-first to stay under it
+    max_mappings: Option<usize>,
+    // On SIGINT/SIGTERM, remove the service's rules and restore startup state
+    cleanup_on_exit: bool,
+    // Where mappings are saved and restored from; None disables persistence
+    state_file: Option<std::path::PathBuf>,
+    // Also save on this interval, not only after changes
+    checkpoint_interval: Option<std::time::Duration>,
+    // How often expired tokens and stale cache entries are swept
+    janitor_interval: std::time::Duration,
+    // How long a transaction may stay open before it is dropped
+    txn_ttl: std::time::Duration,
+    // External commands allowed to run at once; the rest wait their turn
+    max_commands: usize,
+    // Watch the kernel's state without changing it; mutating endpoints 403
+    read_only: bool,
+    // How often READ_ONLY re-reads the rules and rechecks drift
+    read_only_interval: std::time::Duration,
+    // What makes /status report `overall: degraded`
+    overall_degraded_on: Vec<overall::Condition>,
+    // Priorities of the host overrides and the LAN base rule
+    priorities: Priorities,
+    // Range every rule priority must stay within, next to a routing daemon
+    priority_band: Option<PriorityBand>,
+    // Coalesce repeated /switch requests for one host within this window
+    switch_debounce: Option<std::time::Duration>,
+    // Fault injection for /switch; only with CHAOS_TESTING=true
+    chaos: Option<ChaosConfig>,
+    // Run after every successful /switch as `<hook> <ip> <old nic> <new nic>`
+    post_switch_hook: Option<String>,
+    // Where switch, failover and error events also go; None keeps them on stdout/stderr
+    syslog: Option<syslog::SyslogConfig>,
+    // Tells this router apart in syslog, /status and /version; the hostname
+    // unless INSTANCE_NAME is set
+    instance_name: String,
+    // NIC_ALIASES: other names the API accepts for wan0/wan1, e.g.
+    // lte-backup -> wan1
+    nic_aliases: std::collections::BTreeMap<String, String>,
+    // Required (as X-Lock-Token) to lock hosts and to switch locked ones
+    lock_token: Option<String>,
+    // Required (as X-Diag-Token) for /route-get; None disables it
+    diag_token: Option<String>,
+    // How long a confirmation token for /gc stays valid; None runs it directly
+    require_confirm: Option<std::time::Duration>,
+    // DHCP leases file for /switch?lease=<hostname>; None disables
+    leases: Option<leases::LeaseConfig>,
+    // Periodic WAN probing (and optional base rule failover); None disables
+    health: Option<health::HealthConfig>,
 }
 
 impl Config {
-    fn from_env() -> Self {
-        Config {
-            wan0: env::var("WAN0").unwrap_or_else(|_| "eth0".to_string()),
-            wan1: env::var("WAN1").unwrap_or_else(|_| "eth1".to_string()),
-            lan: env::var("LAN").unwrap_or_else(|_| "eth2".to_string()),
+    fn from_env() -> Result<Self> {
+        Self::from_vars(&|key| env::var(key).ok())
+    }
+
+    /// Build the config from an arbitrary variable lookup (the environment in
+    /// production, a map in tests).
+    fn from_vars(var: &dyn Fn(&str) -> Option<String>) -> Result<Self> {
+        let config = Config {
+            wan0: iface_var(var, "WAN0", "eth0")?,
+            wan1: iface_var(var, "WAN1", "eth1")?,
+            lan: iface_var(var, "LAN", "eth2")?,
+            lan_rule_mode: match var("LAN_RULE_MODE").as_deref().map(str::trim) {
+                None | Some("") | Some("subnet") => LanRuleMode::Subnet,
+                Some("iif") => LanRuleMode::Iif,
+                Some(other) => bail!(
+                    "invalid LAN_RULE_MODE: {:?} (expected subnet or iif)",
+                    other
+                ),
+            },
+            host_match: HostMatch {
+                iif: match var("HOST_RULE_MATCH").as_deref().map(str::trim) {
+                    None | Some("") | Some("from") => None,
+                    Some("iif") => Some(iface_var(var, "LAN", "eth2")?),
+                    Some(other) => bail!(
+                        "invalid HOST_RULE_MATCH: {:?} (expected from or iif)",
+                        other
+                    ),
+                },
+                fwmark: match var("HOST_RULE_FWMARK").as_deref().map(str::trim) {
+                    None | Some("") => None,
+                    Some(v) => Some(parse_fwmark(v).context("invalid HOST_RULE_FWMARK")?),
+                },
+            },
+            gateway_wan0: nexthop_var(var, "GATEWAY_WAN0")?,
+            gateway_wan1: nexthop_var(var, "GATEWAY_WAN1")?,
+            base_wan: match var("BASE_WAN").as_deref().map(str::trim) {
+                None | Some("") | Some("wan0") => "wan0",
+                Some("wan1") => "wan1",
+                Some(other) => bail!("invalid BASE_WAN: {:?} (expected wan0 or wan1)", other),
+            },
+            fallback_to_primary: flag_var(var, "FALLBACK_TO_PRIMARY"),
+            pin_primary: flag_var(var, "PIN_PRIMARY"),
+            switch_allow: prefix_list_var(var, "SWITCH_ALLOW")?,
+            switch_deny: prefix_list_var(var, "SWITCH_DENY")?,
+            lan_subnets: parse_prefix_list(
+                &var("LAN_SUBNETS").unwrap_or_else(|| DEFAULT_LAN_SUBNETS.to_string()),
+            )
+            .context("invalid LAN_SUBNETS")?
+            .iter()
+            .map(IpNet::trunc)
+            .collect(),
+            subnet_policy: match var("SWITCH_SUBNET_POLICY").as_deref().map(str::trim) {
+                None | Some("") | Some("strict") => SubnetPolicy::Strict,
+                Some("warn") => SubnetPolicy::Warn,
+                Some("any") => SubnetPolicy::Any,
+                Some(other) => bail!(
+                    "invalid SWITCH_SUBNET_POLICY: {:?} (expected strict, warn or any)",
+                    other
+                ),
+            },
+            overlap_policy: match var("MAPPING_OVERLAP_POLICY").as_deref().map(str::trim) {
+                None | Some("") | Some("replace") => mapping::OverlapPolicy::Replace,
+                Some("reject") => mapping::OverlapPolicy::Reject,
+                Some(other) => bail!(
+                    "invalid MAPPING_OVERLAP_POLICY: {:?} (expected replace or reject)",
+                    other
+                ),
+            },
+            min_prefix_len: match var("SWITCH_MIN_PREFIX_LEN").as_deref().map(str::trim) {
+                None | Some("") => None,
+                Some(v) => Some(v.parse().ok().filter(|len| *len <= 32).with_context(|| {
+                    format!("invalid SWITCH_MIN_PREFIX_LEN: {:?} (expected 0-32)", v)
+                })?),
+            },
+            ipv6: flag_var(var, "IPV6"),
+            strict_tables: flag_var(var, "STRICT_TABLES"),
+            strict_deletes: flag_var_or(var, "STRICT_DELETES", true),
+            preflight: flag_var(var, "PREFLIGHT"),
+            safe_mode: flag_var(var, "SAFE_MODE"),
+            management_host: match var("MANAGEMENT_HOST").as_deref().map(str::trim) {
+                None | Some("") => None,
+                Some(_) if !flag_var(var, "SAFE_MODE") => {
+                    bail!("MANAGEMENT_HOST requires SAFE_MODE")
+                }
+                Some(v) => Some(v.parse().with_context(|| {
+                    format!(
+                        "invalid MANAGEMENT_HOST: {:?} (expected an IPv4 address)",
+                        v
+                    )
+                })?),
+            },
+            iface_wait: Some(secs_var(var, "IFACE_WAIT", 0)?).filter(|d| !d.is_zero()),
+            src_wan0: src_var(var, "SRC_WAN0")?,
+            src_wan1: src_var(var, "SRC_WAN1")?,
+            route_mode_wan0: route_mode_var(var, "ROUTE_MODE_WAN0")?,
+            route_mode_wan1: route_mode_var(var, "ROUTE_MODE_WAN1")?,
+            extra_routes_wan0: extra_routes::parse(&var("EXTRA_ROUTES_WAN0").unwrap_or_default())
+                .context("invalid EXTRA_ROUTES_WAN0")?,
+            extra_routes_wan1: extra_routes::parse(&var("EXTRA_ROUTES_WAN1").unwrap_or_default())
+                .context("invalid EXTRA_ROUTES_WAN1")?,
+            listen_uds: var("LISTEN_UDS")
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| std::path::PathBuf::from(v.trim())),
+            listen_uds_mode: match var("LISTEN_UDS_MODE").as_deref().map(str::trim) {
+                None | Some("") => 0o600,
+                Some(v) => u32::from_str_radix(v.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .with_context(|| {
+                        format!(
+                            "invalid LISTEN_UDS_MODE: {:?} (expected octal, e.g. 0660)",
+                            v
+                        )
+                    })?,
+            },
+            asymmetry_check: flag_var_or(var, "ASYMMETRY_CHECK", true),
+            verify_dest: match var("VERIFY_DEST").as_deref().map(str::trim) {
+                None | Some("") => std::net::Ipv4Addr::new(1, 1, 1, 1),
+                Some(v) => v
+                    .parse()
+                    .with_context(|| format!("invalid VERIFY_DEST: {:?}", v))?,
+            },
+            schedule_utc_offset: match var("SCHEDULE_UTC_OFFSET") {
+                Some(v) if !v.trim().is_empty() => schedule::parse_utc_offset(&v)?,
+                _ => 0,
+            },
+            ui_enabled: flag_var(var, "UI_ENABLED"),
+            debug: flag_var(var, "DEBUG"),
+            dns_steering: flag_var(var, "DNS_STEERING"),
+            adopt_rules: flag_var_or(var, "ADOPT_RULES", true),
+            auto_balance: match var("AUTO_BALANCE") {
+                Some(v) if !v.trim().is_empty() => {
+                    Some(parse_weights(&v).context("invalid AUTO_BALANCE")?)
+                }
+                _ => None,
+            },
+            max_mappings: match var("MAX_MAPPINGS").as_deref().map(str::trim) {
+                None | Some("") => None,
+                Some(v) => Some(
+                    v.parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .with_context(|| format!("invalid MAX_MAPPINGS: {:?}", v))?,
+                ),
+            },
+            cleanup_on_exit: flag_var(var, "CLEANUP_ON_EXIT"),
+            state_file: var("STATE_FILE")
+                .filter(|v| !v.trim().is_empty())
+                .map(std::path::PathBuf::from),
+            checkpoint_interval: Some(secs_var(var, "CHECKPOINT_INTERVAL", 0)?)
+                .filter(|d| !d.is_zero()),
+            janitor_interval: secs_var(var, "JANITOR_INTERVAL", 60)?,
+            txn_ttl: secs_var(var, "TXN_TTL", 300)?,
+            max_commands: match var("MAX_CONCURRENT_COMMANDS").as_deref().map(str::trim) {
+                None | Some("") => DEFAULT_MAX_COMMANDS,
+                Some(v) => v
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .with_context(|| format!("invalid MAX_CONCURRENT_COMMANDS: {:?}", v))?,
+            },
+            read_only: flag_var(var, "READ_ONLY"),
+            read_only_interval: secs_var(var, "READ_ONLY_INTERVAL", 30)?,
+            overall_degraded_on: match var("OVERALL_DEGRADED_ON") {
+                Some(v) if !v.trim().is_empty() => {
+                    overall::parse_conditions(&v).context("invalid OVERALL_DEGRADED_ON")?
+                }
+                _ => overall::DEFAULT_DEGRADED_ON.to_vec(),
+            },
+            priorities: Priorities {
+                specific: prio_var(var, "PRIO_SPECIFIC", DEFAULT_PRIO_SPECIFIC)?,
+                lan_default: prio_var(var, "PRIO_LAN_DEFAULT", DEFAULT_PRIO_LAN_DEFAULT)?,
+            },
+            priority_band: match var("PRIO_BAND").as_deref().map(str::trim) {
+                None | Some("") => None,
+                Some(v) => Some(PriorityBand::parse(v).with_context(|| {
+                    format!("invalid PRIO_BAND: {:?} (expected <min>-<max>)", v)
+                })?),
+            },
+            switch_debounce: match var("SWITCH_DEBOUNCE_MS").as_deref().map(str::trim) {
+                None | Some("") | Some("0") => None,
+                Some(v) => Some(std::time::Duration::from_millis(
+                    v.parse()
+                        .with_context(|| format!("invalid SWITCH_DEBOUNCE_MS: {:?}", v))?,
+                )),
+            },
+            chaos: chaos_var(var)?,
+            post_switch_hook: var("POST_SWITCH_HOOK")
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            syslog: syslog::from_vars(var)?,
+            instance_name: instance_name_var(var)?,
+            nic_aliases: match var("NIC_ALIASES") {
+                Some(v) => parse_nic_aliases(&v).context("invalid NIC_ALIASES")?,
+                None => std::collections::BTreeMap::new(),
+            },
+            lock_token: var("LOCK_TOKEN")
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            diag_token: var("DIAG_TOKEN")
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            require_confirm: if flag_var(var, "REQUIRE_CONFIRM") {
+                Some(secs_var(var, "CONFIRM_WINDOW", 30)?).filter(|d| !d.is_zero())
+            } else {
+                None
+            },
+            leases: match var("LEASES_FILE") {
+                Some(path) if !path.trim().is_empty() => Some(leases::LeaseConfig {
+                    path: std::path::PathBuf::from(path.trim()),
+                    interval: secs_var(var, "LEASES_INTERVAL", 30)?,
+                }),
+                _ => None,
+            },
+            health: match var("HEALTH_TARGET") {
+                Some(target) if !target.trim().is_empty() => Some(health::HealthConfig {
+                    target: target.trim().to_string(),
+                    interval: secs_var(var, "HEALTH_INTERVAL", 5)?,
+                    window: match var("HEALTH_WINDOW").as_deref().map(str::trim) {
+                        None | Some("") => 10,
+                        Some(v) => v
+                            .parse()
+                            .ok()
+                            .filter(|n| *n > 0)
+                            .with_context(|| format!("invalid HEALTH_WINDOW: {:?}", v))?,
+                    },
+                    failover: flag_var(var, "FAILOVER"),
+                    hold_down: secs_var(var, "FAILOVER_HOLD_DOWN", 60)?,
+                    min_interval: secs_var(var, "FAILOVER_MIN_INTERVAL", 30)?,
+                    host_failover: host_failover_var(var)?,
+                    auto_hysteresis_ms: match var("AUTO_HYSTERESIS_MS").as_deref().map(str::trim) {
+                        None | Some("") => 20.0,
+                        Some(v) => v
+                            .parse()
+                            .ok()
+                            .filter(|ms: &f64| *ms >= 0.0)
+                            .with_context(|| format!("invalid AUTO_HYSTERESIS_MS: {:?}", v))?,
+                    },
+                    restore_max_age: secs_var(var, "HEALTH_RESTORE_MAX_AGE", 300)?,
+                    probe_table: flag_var(var, "HEALTH_PROBE_TABLE"),
+                }),
+                _ if !host_failover_var(var)?.is_empty() => {
+                    bail!("HOST_FAILOVER_WAN0/HOST_FAILOVER_WAN1 require HEALTH_TARGET")
+                }
+                _ if flag_var(var, "HEALTH_PROBE_TABLE") => {
+                    bail!("HEALTH_PROBE_TABLE requires HEALTH_TARGET")
+                }
+                _ => None,
+            },
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.lan_subnets.is_empty() {
+            bail!("LAN_SUBNETS must list at least one subnet");
+        }
+        if self.wan0 == self.wan1 {
+            bail!(
+                "WAN0 and WAN1 must be different interfaces (both are {})",
+                self.wan0
+            );
+        }
+        let prio = self.priorities;
+        // The exception and DNS rules need slots before the overrides, above
+        // the local table's 0
+        if prio.specific < 3 {
+            bail!("PRIO_SPECIFIC must be at least 3 (got {})", prio.specific);
+        }
+        // Overrides must win over the base rule, with room for the fallback between them
+        let min_lan_default = if self.fallback_to_primary {
+            prio.fallback() + 1
+        } else {
+            prio.specific + 1
+        };
+        if prio.lan_default < min_lan_default {
+            bail!(
+                "PRIO_LAN_DEFAULT ({}) must be greater than PRIO_SPECIFIC ({}){}",
+                prio.lan_default,
+                prio.specific,
+                if self.fallback_to_primary {
+                    " plus one for the fallback rule"
+                } else {
+                    ""
+                }
+            );
+        }
+        if prio.lan_default >= PRIO_MAIN {
+            bail!(
+                "PRIO_LAN_DEFAULT must be below the main table rule ({})",
+                PRIO_MAIN
+            );
+        }
+        if let Some(band) = self.priority_band {
+            let mut used = vec![
+                ("the exception rules (PRIO_SPECIFIC - 2)", prio.exception()),
+                ("the DNS rules (PRIO_SPECIFIC - 1)", prio.dns()),
+                ("PRIO_SPECIFIC", prio.specific),
+                ("PRIO_LAN_DEFAULT", prio.lan_default),
+            ];
+            if self.fallback_to_primary {
+                used.push(("the fallback rule (PRIO_SPECIFIC + 1)", prio.fallback()));
+            }
+            for (what, prio) in used {
+                if !band.contains(prio) {
+                    bail!("{} ({}) is outside PRIO_BAND ({})", what, prio, band);
+                }
+            }
+        }
+        if self.janitor_interval.is_zero() {
+            bail!("JANITOR_INTERVAL must be at least 1 second");
+        }
+        if self.txn_ttl.is_zero() {
+            bail!("TXN_TTL must be at least 1 second");
+        }
+        if self.read_only && self.read_only_interval.is_zero() {
+            bail!("READ_ONLY_INTERVAL must be at least 1 second");
+        }
+        if self.read_only && self.cleanup_on_exit {
+            bail!("CLEANUP_ON_EXIT cannot be combined with READ_ONLY");
+        }
+        if self.checkpoint_interval.is_some() && self.state_file.is_none() {
+            bail!("CHECKPOINT_INTERVAL requires STATE_FILE");
+        }
+        if self.listen_uds_mode != 0o600 && self.listen_uds.is_none() {
+            bail!("LISTEN_UDS_MODE requires LISTEN_UDS");
+        }
+        if self.lan == self.wan0 || self.lan == self.wan1 {
+            bail!(
+                "LAN ({}) must be a different interface from WAN0 ({}) and WAN1 ({})",
+                self.lan,
+                self.wan0,
+                self.wan1
+            );
+        }
+        Ok(())
+    }
+
+    /// Selectors of the LAN base rules: one per LAN subnet, or the LAN
+    /// interface alone with LAN_RULE_MODE=iif.
+    fn base_selectors(&self) -> Vec<BaseSelector> {
+        match self.lan_rule_mode {
+            LanRuleMode::Subnet => self
+                .lan_subnets
+                .iter()
+                .map(|n| BaseSelector::From(n.to_string()))
+                .collect(),
+            LanRuleMode::Iif => vec![BaseSelector::Iif(self.lan.to_string())],
+        }
+    }
+
+    /// Whether `rule` looks like a LAN base rule in either mode.
+    fn is_lan_rule(&self, rule: &IpRule) -> bool {
+        self.lan_subnets
+            .iter()
+            .any(|n| normalize_selector(&n.to_string()) == rule.from)
+            || (rule.from == "all" && rule.iif.as_deref() == Some(&*self.lan))
+    }
+
+    /// The WAN `nic` names: the one an alias stands for, else `nic` itself.
+    fn resolve_nic(&self, nic: &str) -> String {
+        self.nic_aliases
+            .get(nic)
+            .cloned()
+            .unwrap_or_else(|| nic.to_string())
+    }
+
+    /// Whether the WANs' own traffic gets rules into their tables
+    /// (HEALTH_PROBE_TABLE).
+    fn probe_table(&self) -> bool {
+        self.health.as_ref().is_some_and(|h| h.probe_table)
+    }
+
+    /// Table of the LAN base rule when no failover is in effect.
+    fn base_table(&self) -> &'static str {
+        nic_table(self.base_wan)
+    }
+
+    /// Table of the host rule a mapping to `nic` needs, if any: the base
+    /// WAN's hosts ride the base rule unless PIN_PRIMARY.
+    fn pin_table(&self, nic: &str) -> Option<&'static str> {
+        (nic != self.base_wan || self.pin_primary).then(|| nic_table(nic))
+    }
+
+    /// Why a rule at `prio` does not belong, if PRIO_BAND leaves it out.
+    fn outside_band(&self, prio: u32) -> Option<String> {
+        let band = self.priority_band?;
+        (!band.contains(prio)).then(|| format!("priority {} is outside PRIO_BAND ({})", prio, band))
+    }
+
+    /// The LAN subnet `prefix` is or contains, if any.
+    fn lan_subnet_within(&self, prefix: IpNet) -> Option<IpNet> {
+        self.lan_subnets
+            .iter()
+            .copied()
+            .find(|net| prefix.contains(net))
+    }
+
+    fn in_lan(&self, ip: IpAddr) -> bool {
+        self.lan_subnets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Whether `ip` may be mapped at all: inside a LAN subnet, or anywhere
+    /// unless SWITCH_SUBNET_POLICY=strict.
+    fn mappable(&self, ip: IpAddr) -> bool {
+        self.in_lan(ip) || self.subnet_policy != SubnetPolicy::Strict
+    }
+
+    /// Whether `/switch` may touch `ip`: never if denied, otherwise only if
+    /// allowed (or no allowlist is configured).
+    fn switch_permitted(&self, ip: IpAddr) -> bool {
+        if self.switch_deny.iter().any(|net| net.contains(&ip)) {
+            return false;
         }
+        self.switch_allow.is_empty() || self.switch_allow.iter().any(|net| net.contains(&ip))
     }
 }
 
-#[derive(Clone)]
-struct AppState {
-    mappings: Arc<Mutex<std::collections::HashMap<String, String>>>,
-    config: Config,
+fn flag_var(var: &dyn Fn(&str) -> Option<String>, key: &str) -> bool {
+    flag_var_or(var, key, false)
 }
 
-#[derive(Deserialize)]
-struct SwitchParams {
-    ip: String,
-    nic: String,
+fn flag_var_or(var: &dyn Fn(&str) -> Option<String>, key: &str, default: bool) -> bool {
+    match var(key).as_deref() {
+        None | Some("") => default,
+        Some(v) => matches!(v, "1" | "true" | "yes" | "on"),
+    }
 }
 
-#[derive(Serialize)]
-struct ApiResponse {
-    status: String,
-    message: String,
+/// The interface named by `key`, or `default` when unset.
+fn iface_var(var: &dyn Fn(&str) -> Option<String>, key: &str, default: &str) -> Result<Interface> {
+    Interface::new(var(key).as_deref().unwrap_or(default))
+        .with_context(|| format!("invalid {}", key))
 }
 
-fn run_cmd(cmd: &str, args: &[&str]) -> Result<String> {
-    let out = Command::new(cmd)
-        .args(args)
-        .output()
-        .with_context(|| format!("failed to run {} {:?}", cmd, args))?;
-    if !out.status.success() {
-        bail!(
-            "{} {:?} failed: {}",
-            cmd,
-            args,
-            String::from_utf8_lossy(&out.stderr)
-        );
+/// HOST_FAILOVER_WAN0=wan1 / HOST_FAILOVER_WAN1=wan0: which WANs move their
+/// pinned hosts to the other one while down.
+fn host_failover_var(
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Result<std::collections::BTreeMap<String, String>> {
+    let mut backups = std::collections::BTreeMap::new();
+    for (nic, key, other) in [
+        ("wan0", "HOST_FAILOVER_WAN0", "wan1"),
+        ("wan1", "HOST_FAILOVER_WAN1", "wan0"),
+    ] {
+        match var(key).as_deref().map(str::trim) {
+            None | Some("") => {}
+            Some(v) if v == other => {
+                backups.insert(nic.to_string(), v.to_string());
+            }
+            Some(v) => bail!("invalid {}: {:?} (expected {})", key, v, other),
+        }
     }
-    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+    Ok(backups)
 }
 
-// ---- Policy routing helpers ----
+/// Fault injection for exercising clients and the debounce path. Never on
+/// unless CHAOS_TESTING=true is set as well.
+#[derive(Clone, Debug, PartialEq)]
+struct ChaosConfig {
+    // Each /switch waits a random 0..=max_delay before doing anything
+    max_delay: std::time::Duration,
+    // Fraction (0.0-1.0) of /switch calls that fail outright
+    fail_rate: f64,
+}
 
-const TABLE_WAN0: &str = "100"; // routing table id for wan0
-const TABLE_WAN1: &str = "200"; // routing table id for wan1
-const PRIO_SPECIFIC: &str = "1000"; // higher priority (smaller number)
-const PRIO_LAN_DEFAULT: &str = "2000"; // default lan policy priority
+fn chaos_var(var: &dyn Fn(&str) -> Option<String>) -> Result<Option<ChaosConfig>> {
+    let delay = var("CHAOS_DELAY_MS").filter(|v| !v.trim().is_empty());
+    let rate = var("CHAOS_FAIL_RATE").filter(|v| !v.trim().is_empty());
+    if delay.is_none() && rate.is_none() {
+        return Ok(None);
+    }
+    if !flag_var(var, "CHAOS_TESTING") {
+        bail!("CHAOS_DELAY_MS/CHAOS_FAIL_RATE are for testing only and require CHAOS_TESTING=true");
+    }
+    let max_delay = match delay {
+        Some(v) => std::time::Duration::from_millis(
+            v.trim()
+                .parse()
+                .with_context(|| format!("invalid CHAOS_DELAY_MS: {:?}", v))?,
+        ),
+        None => std::time::Duration::ZERO,
+    };
+    let fail_rate = match rate {
+        Some(v) => v
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|r| (0.0..=1.0).contains(r))
+            .with_context(|| format!("invalid CHAOS_FAIL_RATE: {:?} (expected 0.0-1.0)", v))?,
+        None => 0.0,
+    };
+    Ok(Some(ChaosConfig {
+        max_delay,
+        fail_rate,
+    }))
+}
 
-fn get_default_gateway_for_iface(iface: &str) -> Result<String> {
-    // Try to read default route for specific iface
-    let out = run_cmd("ip", &["route", "show", "default", "dev", iface])?;
-    let re = Regex::new(r"via\s+(\d+\.\d+\.\d+\.\d+)").expect("regex compiles");
-    if let Some(cap) = re.captures(&out) {
-        return Ok(cap[1].to_string());
+fn prio_var(var: &dyn Fn(&str) -> Option<String>, key: &str, default: u32) -> Result<u32> {
+    match var(key).as_deref().map(str::trim) {
+        None | Some("") => Ok(default),
+        Some(v) => v
+            .parse()
+            .with_context(|| format!("invalid {}: expected a rule priority", key)),
     }
-    // Fallback: scan all defaults and pick the one matching iface
-    let all = run_cmd("ip", &["route", "show", "default"])?
-        .lines()
-        .map(|s| s.to_string())
-        .collect::<Vec<_>>()
-        .join("\n");
-    for line in all.lines() {
-        if line.contains(&format!(" dev {}", iface)) {
-            if let Some(cap) = re.captures(line) {
-                return Ok(cap[1].to_string());
-            }
+}
+
+/// INSTANCE_NAME, or the kernel's hostname. Events carry it as a
+/// `key=value` field, so it may not contain whitespace.
+fn instance_name_var(var: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let name = match var("INSTANCE_NAME").as_deref().map(str::trim) {
+        None | Some("") => std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .ok()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| "adaptiverouting".to_string()),
+        Some(v) => v.to_string(),
+    };
+    if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        bail!("invalid INSTANCE_NAME: {:?} (no whitespace allowed)", name);
+    }
+    Ok(name)
+}
+
+/// Parse "primary=wan0,lte-backup=wan1" into alias -> WAN.
+fn parse_nic_aliases(value: &str) -> Result<std::collections::BTreeMap<String, String>> {
+    let mut aliases = std::collections::BTreeMap::new();
+    for item in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (alias, nic) = item
+            .split_once('=')
+            .with_context(|| format!("expected alias=nic, got {:?}", item))?;
+        let (alias, nic) = (alias.trim(), nic.trim());
+        if nic != "wan0" && nic != "wan1" {
+            bail!(
+                "unknown nic {:?} for alias {:?} (expected wan0 or wan1)",
+                nic,
+                alias
+            );
+        }
+        if alias.is_empty() || alias.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            bail!("invalid alias {:?}", alias);
+        }
+        if matches!(alias, "wan0" | "wan1" | "auto") {
+            bail!("{:?} is a nic already and cannot be an alias", alias);
+        }
+        if aliases.insert(alias.to_string(), nic.to_string()).is_some() {
+            bail!("alias {:?} is given twice", alias);
         }
     }
-    bail!("Could not determine default gateway for iface {}", iface)
+    Ok(aliases)
 }
 
-fn ensure_table_default_route(iface: &str, table: &str, gw: &str) -> Result<()> {
-    // Create/replace default route for table
-    run_cmd(
-        "ip",
-        &[
-            "route", "replace", "default", "via", gw, "dev", iface, "table", table,
-        ],
-    )?;
-    Ok(())
+fn secs_var(
+    var: &dyn Fn(&str) -> Option<String>,
+    key: &str,
+    default: u64,
+) -> Result<std::time::Duration> {
+    let secs = match var(key).as_deref().map(str::trim) {
+        None | Some("") => default,
+        Some(v) => v
+            .parse()
+            .with_context(|| format!("invalid {}: expected whole seconds", key))?,
+    };
+    Ok(std::time::Duration::from_secs(secs))
 }
 
-fn ip_rule_list() -> Result<String> {
-    run_cmd("ip", &["rule", "show"])
+/// Parse "wan0:3,wan1:1" into per-WAN weights. At least one weight must be
+/// non-zero.
+fn parse_weights(value: &str) -> Result<Vec<(String, u32)>> {
+    let mut weights = Vec::new();
+    for item in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (nic, weight) = item
+            .split_once(':')
+            .with_context(|| format!("expected nic:weight, got {:?}", item))?;
+        let nic = nic.trim();
+        if nic != "wan0" && nic != "wan1" {
+            bail!("unknown nic {:?} (expected wan0 or wan1)", nic);
+        }
+        let weight: u32 = weight
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid weight for {}", nic))?;
+        weights.push((nic.to_string(), weight));
+    }
+    if weights.iter().all(|(_, w)| *w == 0) {
+        bail!("at least one weight must be greater than zero");
+    }
+    Ok(weights)
 }
 
-fn ip_rule_exists(from: &str, table: &str) -> Result<bool> {
-    let rules = ip_rule_list()?;
-    let needle = format!("from {} lookup {}", from, table);
-    Ok(rules.lines().any(|l| l.contains(&needle)))
+/// Comma-separated prefixes; a bare address is treated as a single host.
+fn parse_prefix_list(value: &str) -> Result<Vec<IpNet>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("'{}' is not an IP prefix", s))
+        })
+        .collect()
 }
 
-fn add_ip_rule(from: &str, table: &str, prio: &str) -> Result<()> {
-    if !ip_rule_exists(from, table)? {
-        run_cmd(
-            "ip",
-            &[
-                "rule", "add", "from", from, "lookup", table, "priority", prio,
-            ],
-        )?;
+fn prefix_list_var(var: &dyn Fn(&str) -> Option<String>, key: &str) -> Result<Vec<IpNet>> {
+    match var(key) {
+        Some(v) => parse_prefix_list(&v).with_context(|| format!("invalid {}", key)),
+        None => Ok(Vec::new()),
     }
-    Ok(())
 }
 
-fn del_ip_rule_quiet(from: &str, table: &str) {
-    // Best-effort delete; ignore errors
-    let _ = Command::new("ip")
-        .args(["rule", "del", "from", from, "lookup", table])
-        .output();
+/// Where the `src` of a WAN table's default route comes from.
+#[derive(Clone, Debug, PartialEq)]
+enum SrcAddr {
+    /// The interface's primary address, read at init
+    Auto,
+    Fixed(String),
 }
 
-fn mirror_link_routes_to_table(iface: &str, table: &str) -> Result<()> {
-    // Copy "scope link" routes of the interface into the given table
-    let out = run_cmd(
-        "ip",
-        &["-4", "route", "show", "dev", iface, "scope", "link"],
-    )?;
-    let re = Regex::new(r"^(\d+\.\d+\.\d+\.\d+(?:/\d+)?)\b").expect("regex compiles");
-    for line in out.lines() {
-        if let Some(cap) = re.captures(line) {
-            let prefix = &cap[1];
-            // Replace/ensure route exists in the custom table
-            let _ = run_cmd(
-                "ip",
-                &[
-                    "route", "replace", prefix, "dev", iface, "scope", "link", "table", table,
-                ],
-            );
+fn src_var(var: &dyn Fn(&str) -> Option<String>, key: &str) -> Result<Option<SrcAddr>> {
+    match var(key).as_deref() {
+        None | Some("") => Ok(None),
+        Some("auto") => Ok(Some(SrcAddr::Auto)),
+        Some(v) => {
+            let ip: IpAddr = v.parse().with_context(|| {
+                format!("invalid {}: '{}' is not an IP address or 'auto'", key, v)
+            })?;
+            Ok(Some(SrcAddr::Fixed(ip.to_string())))
         }
     }
-    Ok(())
 }
 
-async fn switch_handler(
-    Query(params): Query<SwitchParams>,
-    state: axum::extract::State<AppState>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    if params.nic != "wan0" && params.nic != "wan1" {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "nic must be 'wan0' or 'wan1'".to_string(),
-        ));
+/// How a WAN table's default route reaches the uplink.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RouteMode {
+    // `ip route replace`: the service owns the table's default route
+    Replace,
+    // `ip route add`: an existing default route is left alone
+    Add,
+}
+
+impl RouteMode {
+    fn verb(self) -> &'static str {
+        match self {
+            RouteMode::Replace => "replace",
+            RouteMode::Add => "add",
+        }
     }
+}
 
-    // Parse IP address - expecting format like "10.40.0.3/20"
-    let ip_re = Regex::new(r"^(\d+\.\d+\.\d+\.\d+)(/\d+)?$").unwrap();
-    let caps = ip_re.captures(&params.ip).ok_or((
-        StatusCode::BAD_REQUEST,
-        "Invalid IP format. Expected: IP or IP/subnet (e.g., 10.40.0.3 or 10.40.0.3/20)"
-            .to_string(),
-    ))?;
+fn route_mode_var(var: &dyn Fn(&str) -> Option<String>, key: &str) -> Result<RouteMode> {
+    match var(key).as_deref().map(str::trim) {
+        None | Some("") | Some("replace") => Ok(RouteMode::Replace),
+        Some("add") => Ok(RouteMode::Add),
+        Some(other) => bail!("invalid {}: {:?} (expected replace or add)", key, other),
+    }
+}
 
-    let base_ip = &caps[1];
+/// SWITCH_SUBNET_POLICY: switch targets outside every LAN subnet are
+/// rejected, allowed with a warning, or allowed silently (e.g. hosts behind a
+/// downstream router).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SubnetPolicy {
+    Strict,
+    Warn,
+    Any,
+}
 
-    // Ensure we use /32 (single host) for the actual IP command
-    let target_ip = format!("{}/32", base_ip);
+/// What the LAN base rule (and the fallback rule) select traffic by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LanRuleMode {
+    // `from <subnet>` for each LAN subnet
+    Subnet,
+    // `iif <lan>`: everything arriving on the LAN interface
+    Iif,
+}
 
-    // Policy routing approach:
-    // - Default: entire 10.40.0.0/20 goes to wan0 via routing table 100
-    // - Override: specific /32 can be forced to wan1 via table 200
+/// The match part of one LAN base rule.
+#[derive(Clone, Debug, PartialEq)]
+enum BaseSelector {
+    From(String),
+    Iif(String),
+}
 
-    // First, clear any existing per-IP rules for both tables
-    del_ip_rule_quiet(&target_ip, TABLE_WAN0);
-    del_ip_rule_quiet(&target_ip, TABLE_WAN1);
-
-    let message: String;
-    if params.nic == "wan1" {
-        // Add specific rule to wan1
-        if let Err(e) = add_ip_rule(&target_ip, TABLE_WAN1, PRIO_SPECIFIC) {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to add policy rule: {}", e),
-            ));
+impl BaseSelector {
+    fn args(&self) -> [&str; 2] {
+        match self {
+            BaseSelector::From(subnet) => ["from", subnet],
+            BaseSelector::Iif(iface) => ["iif", iface],
         }
-        message = format!(
-            "Routed {} to wan1 ({}) via policy",
-            target_ip, state.config.wan1
-        );
-    } else {
-        // For wan0, we rely on the default LAN rule; no per-IP rule needed
-        message = format!(
-            "Routed {} to wan0 ({}) via default policy",
-            target_ip, state.config.wan0
-        );
     }
 
-    let mut mappings = state.mappings.lock().await;
-    mappings.insert(base_ip.to_string(), params.nic.clone());
+    /// Whether `rule` selects exactly this, with no other match keys.
+    fn matches(&self, rule: &IpRule) -> bool {
+        let only = rule.to.is_none()
+            && rule.ipproto.is_none()
+            && rule.dport.is_none()
+            && rule.fwmark.is_none()
+            && rule.tos.is_none()
+            && rule.oif.is_none()
+            && !rule.extra_match;
+        match self {
+            BaseSelector::From(subnet) => {
+                rule.from == normalize_selector(subnet) && rule.iif.is_none() && only
+            }
+            BaseSelector::Iif(iface) => {
+                rule.from == "all" && rule.iif.as_deref() == Some(iface) && only
+            }
+        }
+    }
+}
 
-    let response = ApiResponse {
-        status: "success".to_string(),
-        message,
-    };
+impl std::fmt::Display for BaseSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BaseSelector::From(subnet) => f.write_str(subnet),
+            BaseSelector::Iif(iface) => write!(f, "iif {}", iface),
+        }
+    }
+}
 
-    Ok((StatusCode::OK, Json(response)))
+/// Match keys added to every host rule (and its DNS rules) after
+/// `from <ip>/32`, so overrides only catch the traffic they are meant for.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct HostMatch {
+    // `iif <lan>`: forwarded LAN traffic only, not the router's own
+    iif: Option<Interface>,
+    // `fwmark <mark>[/<mask>]`, rendered the way `ip rule show` prints it
+    fwmark: Option<String>,
 }
 
-async fn status_handler(state: axum::extract::State<AppState>) -> impl IntoResponse {
-    let mappings = state.mappings.lock().await;
-    Json(serde_json::json!({
-        "mappings": mappings.clone(),
-        "config": {
-            "wan0": state.config.wan0,
-            "wan1": state.config.wan1,
-            "lan": state.config.lan
+impl HostMatch {
+    fn args(&self) -> Vec<&str> {
+        let mut args = Vec::new();
+        if let Some(iif) = &self.iif {
+            args.extend(["iif", iif]);
         }
-    }))
+        if let Some(mark) = &self.fwmark {
+            args.extend(["fwmark", mark]);
+        }
+        args
+    }
+
+    /// Whether `rule` carries exactly these keys (ignoring `from`, `ipproto`
+    /// and `dport`).
+    fn keys_match(&self, rule: &IpRule) -> bool {
+        rule.iif.as_deref() == self.iif.as_deref()
+            && rule.fwmark == self.fwmark
+            && rule.to.is_none()
+            && rule.tos.is_none()
+            && rule.oif.is_none()
+            && !rule.extra_match
+    }
+
+    /// Whether `rule` has the shape of a host rule installed with this match.
+    fn matches(&self, rule: &IpRule) -> bool {
+        self.keys_match(rule) && rule.ipproto.is_none() && rule.dport.is_none()
+    }
 }
 
-async fn initialize_lan_to_wan0(config: &Config) -> Result<()> {
-    // Establish policy routing so that 10.40.0.0/20 goes out via wan0 by default
-    let lan_subnet = "10.40.0.0/20";
+/// Parse `<mark>[/<mask>]` (decimal or 0x-hex) into the kernel's rendering:
+/// hex, with the mask only when it is not all ones.
+fn parse_fwmark(value: &str) -> Result<String> {
+    let number = |s: &str| -> Result<u32> {
+        let s = s.trim();
+        match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        }
+        .with_context(|| format!("'{}' is not a 32-bit number", s))
+    };
+    let (mark, mask) = match value.split_once('/') {
+        Some((mark, mask)) => (number(mark)?, number(mask)?),
+        None => (number(value)?, u32::MAX),
+    };
+    if mask == 0 {
+        bail!("mask must not be zero");
+    }
+    if mark & !mask != 0 {
+        bail!("mark {:#x} has bits outside mask {:#x}", mark, mask);
+    }
+    Ok(if mask == u32::MAX {
+        format!("{:#x}", mark)
+    } else {
+        format!("{:#x}/{:#x}", mark, mask)
+    })
+}
 
-    println!(
-        "Initializing policy routing: {} -> wan0 ({})",
-        lan_subnet, config.wan0
-    );
+/// A network interface name, checked against the kernel's rules for device
+/// names so garbage never reaches an `ip ... dev` argument.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+struct Interface(String);
 
-    // Clean up any previous incorrect address assignments on WAN interfaces (best-effort)
-    let _ = Command::new("ip")
-        .args(["addr", "del", lan_subnet, "dev", &config.wan0])
-        .output();
-    let _ = Command::new("ip")
-        .args(["addr", "del", lan_subnet, "dev", &config.wan1])
-        .output();
+impl Interface {
+    // IFNAMSIZ minus the terminating NUL
+    const MAX_LEN: usize = 15;
 
-    // Discover gateways
-    let gw0 = get_default_gateway_for_iface(&config.wan0)
-        .with_context(|| format!("get gateway for {}", &config.wan0))?;
-    let gw1 = get_default_gateway_for_iface(&config.wan1)
-        .with_context(|| format!("get gateway for {}", &config.wan1))?;
+    fn new(name: &str) -> Result<Self> {
+        if name.is_empty() || name.len() > Self::MAX_LEN {
+            bail!(
+                "interface name '{}' must be 1-{} characters",
+                name,
+                Self::MAX_LEN
+            );
+        }
+        if name == "." || name == ".." {
+            bail!("'{}' is not a valid interface name", name);
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|c| *c == '/' || *c == ':' || c.is_whitespace() || c.is_control())
+        {
+            bail!("interface name '{}' may not contain {:?}", name, c);
+        }
+        Ok(Interface(name.to_string()))
+    }
+}
 
-    // Ensure routing tables have default routes
-    ensure_table_default_route(&config.wan0, TABLE_WAN0, &gw0)
-        .with_context(|| format!("set table {} default route", TABLE_WAN0))?;
-    ensure_table_default_route(&config.wan1, TABLE_WAN1, &gw1)
-        .with_context(|| format!("set table {} default route", TABLE_WAN1))?;
+impl std::ops::Deref for Interface {
+    type Target = str;
 
-    // Also mirror directly-connected link routes into each table (for ARP/gw resolution)
-    mirror_link_routes_to_table(&config.wan0, TABLE_WAN0).with_context(|| {
-        format!(
-            "mirror link routes for {} to table {}",
-            &config.wan0, TABLE_WAN0
-        )
-    })?;
-    mirror_link_routes_to_table(&config.wan1, TABLE_WAN1).with_context(|| {
-        format!(
-            "mirror link routes for {} to table {}",
-            &config.wan1, TABLE_WAN1
-        )
-    })?;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
 
-    // Ensure base rule for LAN subnet -> wan0 table
-    add_ip_rule(lan_subnet, TABLE_WAN0, PRIO_LAN_DEFAULT)
-        .with_context(|| "add base LAN policy rule".to_string())?;
+impl PartialEq<&str> for Interface {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
 
-    println!(
-        "Policy ready: {} uses table {}, specific hosts can be overridden to table {}",
-        lan_subnet, TABLE_WAN0, TABLE_WAN1
-    );
-    Ok(())
+impl std::fmt::Display for Interface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    let config = Config::from_env();
-    println!("Configuration:");
-    println!("  wan0: {}", config.wan0);
-    println!("  wan1: {}", config.wan1);
-    println!("  lan: {}", config.lan);
+impl TryFrom<String> for Interface {
+    type Error = anyhow::Error;
 
-    if let Err(e) = initialize_lan_to_wan0(&config).await {
-        eprintln!("Failed to initialize: {}", e);
-        std::process::exit(1);
+    fn try_from(name: String) -> Result<Self> {
+        Interface::new(&name)
     }
+}
 
-    let state = AppState {
-        mappings: Arc::new(Mutex::new(std::collections::HashMap::new())),
-        config,
-    };
+impl From<Interface> for String {
+    fn from(iface: Interface) -> String {
+        iface.0
+    }
+}
 
-    let app = Router::new()
-        .route("/switch", get(switch_handler))
-        .route("/status", get(status_handler))
-        .with_state(state);
+/// A gateway address; only ever built from a parsed IP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Gateway(IpAddr);
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:32599")
-        .await
-        .expect("Failed to bind to port 32599");
+impl std::str::FromStr for Gateway {
+    type Err = anyhow::Error;
 
-    println!(
-        "Server listening on http://127.0.0.1:32599 => {}",
-        version::VERSION
-    );
+    fn from_str(value: &str) -> Result<Self> {
+        value
+            .parse()
+            .map(Gateway)
+            .with_context(|| format!("'{}' is not an IP address", value))
+    }
+}
+
+impl std::fmt::Display for Gateway {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-    axum::serve(listener, app).await.expect("Server error");
+#[derive(Clone, Debug, PartialEq)]
+enum Nexthop {
+    /// Regular gateway: `default via <ip> dev <iface>`
+    Via(Gateway),
+    /// Point-to-point link (PPP, tunnels): `default dev <iface>`
+    DeviceOnly,
+    /// Several gateways on the same link, load-balanced with equal weights:
+    /// `default nexthop via <ip> dev <iface> nexthop via ...`
+    Multipath(Vec<Gateway>),
+}
+
+impl Nexthop {
+    /// The gateways routes through this nexthop use; empty for device-only.
+    fn gateways(&self) -> Vec<Gateway> {
+        match self {
+            Nexthop::Via(gw) => vec![*gw],
+            Nexthop::DeviceOnly => Vec::new(),
+            Nexthop::Multipath(gws) => gws.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Nexthop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Nexthop::Via(gw) => write!(f, "{}", gw),
+            Nexthop::DeviceOnly => write!(f, "dev"),
+            Nexthop::Multipath(gws) => {
+                let gws: Vec<String> = gws.iter().map(Gateway::to_string).collect();
+                write!(f, "{}", gws.join(","))
+            }
+        }
+    }
+}
+
+fn parse_nexthop(value: &str) -> Result<Nexthop> {
+    if value == "dev" {
+        return Ok(Nexthop::DeviceOnly);
+    }
+    if value.contains(',') {
+        let mut gws: Vec<Gateway> = Vec::new();
+        for part in value.split(',').map(str::trim) {
+            let gw: Gateway = part.parse()?;
+            if gws.contains(&gw) {
+                bail!("gateway {} is listed twice", gw);
+            }
+            if gws
+                .first()
+                .is_some_and(|first| first.0.is_ipv4() != gw.0.is_ipv4())
+            {
+                bail!("gateways must all be IPv4 or all be IPv6");
+            }
+            gws.push(gw);
+        }
+        return Ok(Nexthop::Multipath(gws));
+    }
+    let gw: Gateway = value
+        .parse()
+        .with_context(|| format!("'{}' is not an IP address or 'dev'", value))?;
+    Ok(Nexthop::Via(gw))
+}
+
+fn nexthop_var(var: &dyn Fn(&str) -> Option<String>, key: &str) -> Result<Option<Nexthop>> {
+    match var(key) {
+        Some(v) if !v.is_empty() => parse_nexthop(&v)
+            .map(Some)
+            .with_context(|| format!("invalid {}", key)),
+        _ => Ok(None),
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    mappings: Arc<Mutex<mapping::Mappings>>,
+    config: Config,
+    runner: Arc<dyn CommandRunner>,
+    // Preferred source address installed per WAN ("wan0"/"wan1") at init
+    sources: Arc<std::collections::HashMap<String, String>>,
+    // Hosts whose DNS is steered, with the table their DNS rules point at
+    dns_steered: Arc<Mutex<std::collections::HashMap<String, String>>>,
+    // Mappings made by auto-balance rather than /switch, with when each was
+    // last assigned or seen; manual ones are never touched
+    auto_assigned: Arc<Mutex<std::collections::HashMap<String, std::time::Instant>>>,
+    // Set by /pause: leave kernel state alone until /resume
+    paused: Arc<AtomicBool>,
+    // WAN probe results and which table currently carries the LAN base rule
+    health: Arc<Mutex<health::HealthState>>,
+    // Last successful gateway discovery per WAN, for /gateways
+    gateways: Arc<Mutex<std::collections::BTreeMap<String, (Nexthop, u64)>>>,
+    // What the most recent initialization set up, for /init/report
+    init_report: Arc<Mutex<Option<InitReport>>>,
+    // Kernel and iproute2 versions for /capabilities; main reads them at
+    // startup, otherwise the first request does
+    versions: Arc<std::sync::OnceLock<capabilities::Versions>>,
+    // Latest debounced /switch request per host; older ones give way to it
+    pending_switches: Arc<Mutex<std::collections::HashMap<String, u64>>>,
+    // Most recent rejected /switch requests, oldest first (DEBUG only)
+    rejections: Arc<Mutex<std::collections::VecDeque<Rejection>>>,
+    // Kernel rule changes of the most recent switches, oldest first (DEBUG only)
+    switch_diffs: Arc<Mutex<std::collections::VecDeque<SwitchDiff>>>,
+    // Hosts /switch may only change with the lock token. A plain mutex so
+    // persist::save can read it while holding the `mappings` lock.
+    locked: Arc<std::sync::Mutex<std::collections::BTreeSet<String>>>,
+    // Lease hostname -> address its pin currently sits on
+    lease_pins: Arc<std::sync::Mutex<std::collections::BTreeMap<String, String>>>,
+    // Outstanding REQUIRE_CONFIRM tokens
+    confirmations: Arc<std::sync::Mutex<confirm::Pendings>>,
+    // Open transactions and the steps staged in them, by id
+    transactions: Arc<std::sync::Mutex<txn::Transactions>>,
+    // Hosts pinned with nic=auto and what was chosen for them. A plain mutex
+    // so persist::save can read it while holding the `mappings` lock.
+    auto_pins: Arc<std::sync::Mutex<std::collections::BTreeMap<String, auto_select::AutoPin>>>,
+    // Time-of-day pins; a plain mutex for the same reason
+    schedules: Arc<std::sync::Mutex<schedule::Schedules>>,
+    // Destinations of pinned hosts that go out the other WAN; likewise
+    exceptions: Arc<std::sync::Mutex<exceptions::Exceptions>>,
+    // WAN per DSCP value, for /policy/dscp; likewise
+    dscp_policies: Arc<std::sync::Mutex<dscp::DscpPolicies>>,
+    // Global addresses per interface, read on first use and dropped by the
+    // janitor so address changes are picked up
+    iface_addrs: Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<IpNet>>>>,
+    // READ_ONLY: commands that would bring the kernel in line, as last checked
+    drift: Arc<std::sync::Mutex<Vec<String>>>,
+    // Health as last handed to STATE_FILE; a plain mutex so persist::save
+    // can read it while holding the `mappings` lock
+    saved_health: Arc<std::sync::Mutex<Option<health::SavedHealth>>>,
+    // When the host rules were last brought in line with (or, READ_ONLY,
+    // checked against) the kernel; 0 until they are
+    reconciled_at: Arc<AtomicU64>,
+    // When the process started, for /status; main sets it before init
+    started_at: u64,
+    started: std::time::Instant,
+}
+
+impl AppState {
+    fn new(
+        config: Config,
+        runner: Arc<dyn CommandRunner>,
+        sources: std::collections::HashMap<String, String>,
+    ) -> Self {
+        let base_wan = config.base_wan;
+        AppState {
+            mappings: Arc::new(Mutex::new(mapping::Mappings::new())),
+            config,
+            runner,
+            sources: Arc::new(sources),
+            dns_steered: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            auto_assigned: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            paused: Arc::new(AtomicBool::new(false)),
+            health: Arc::new(Mutex::new(health::HealthState::new(base_wan))),
+            gateways: Arc::new(Mutex::new(std::collections::BTreeMap::new())),
+            init_report: Arc::new(Mutex::new(None)),
+            versions: Arc::new(std::sync::OnceLock::new()),
+            pending_switches: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            rejections: Arc::new(Mutex::new(std::collections::VecDeque::new())),
+            switch_diffs: Arc::new(Mutex::new(std::collections::VecDeque::new())),
+            locked: Arc::new(std::sync::Mutex::new(std::collections::BTreeSet::new())),
+            lease_pins: Arc::new(std::sync::Mutex::new(std::collections::BTreeMap::new())),
+            confirmations: Arc::new(std::sync::Mutex::new(confirm::Pendings::new())),
+            transactions: Arc::new(std::sync::Mutex::new(txn::Transactions::new())),
+            auto_pins: Arc::new(std::sync::Mutex::new(std::collections::BTreeMap::new())),
+            schedules: Arc::new(std::sync::Mutex::new(schedule::Schedules::new())),
+            exceptions: Arc::new(std::sync::Mutex::new(exceptions::Exceptions::new())),
+            dscp_policies: Arc::new(std::sync::Mutex::new(dscp::DscpPolicies::new())),
+            iface_addrs: Arc::new(std::sync::Mutex::new(std::collections::HashMap::new())),
+            drift: Arc::new(std::sync::Mutex::new(Vec::new())),
+            saved_health: Arc::new(std::sync::Mutex::new(None)),
+            reconciled_at: Arc::new(AtomicU64::new(0)),
+            started_at: unix_now(),
+            started: std::time::Instant::now(),
+        }
+    }
+
+    /// `iface`'s global addresses, cached until the next janitor sweep.
+    fn iface_addrs(&self, iface: &Interface) -> Result<Vec<IpNet>> {
+        if let Some(addrs) = self.iface_addrs.lock().unwrap().get(&**iface) {
+            return Ok(addrs.clone());
+        }
+        let addrs = addrs::get_iface_addrs(self.runner.as_ref(), iface)?;
+        self.iface_addrs
+            .lock()
+            .unwrap()
+            .insert(iface.to_string(), addrs.clone());
+        Ok(addrs)
+    }
+}
+
+#[derive(Deserialize)]
+struct SwitchParams {
+    // Empty when the host is named by `lease`
+    #[serde(default)]
+    ip: String,
+    // DHCP lease hostname, resolved through LEASES_FILE
+    lease: Option<String>,
+    nic: String,
+    // Also steer the host's DNS; defaults to DNS_STEERING
+    dns: Option<bool>,
+    // Check with `ip route get` that the host's traffic now leaves the WAN
+    #[serde(default)]
+    verify: bool,
+    // Switch even though `ip` names a prefix covering a whole LAN subnet
+    #[serde(default)]
+    force: bool,
+    // Comma-separated prefixes that keep leaving through the other WAN
+    except: Option<String>,
+    // Comma-separated key:value tags replacing the host's; empty clears them
+    tags: Option<String>,
+    // Why nic=auto resolved to `nic`; set by the switch, never by the query
+    #[serde(skip)]
+    auto_reason: Option<String>,
+}
+
+/// `?format=json|yaml`, overriding the Accept header on read endpoints.
+#[derive(Deserialize)]
+struct FormatParams {
+    format: Option<String>,
+}
+
+/// `/status?detailed=true`: everything recorded about each mapping.
+#[derive(Deserialize)]
+struct StatusParams {
+    #[serde(default)]
+    detailed: bool,
+}
+
+#[derive(Deserialize)]
+struct GcParams {
+    #[serde(default)]
+    dry_run: bool,
+    // Token from the first call, with REQUIRE_CONFIRM
+    confirm: Option<String>,
+}
+
+/// Response body of `/status`; the single source of truth for its shape.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct StatusResponse {
+    // healthy, degraded or down, summarizing the fields below
+    overall: overall::Overall,
+    // INSTANCE_NAME
+    instance: String,
+    // Unix times: when this response was made and when the service started
+    generated_at: u64,
+    started_at: u64,
+    uptime_secs: u64,
+    // Last restore or READ_ONLY check of the host rules against the kernel
+    reconciled_at: Option<u64>,
+    // Last time the LAN base rule moved to the other WAN or back
+    last_failover_at: Option<u64>,
+    // One page of the hosts, by address (`?limit=&offset=`)
+    mappings: StatusMappings,
+    mappings_page: page::Page,
+    config: StatusConfig,
+    sources: std::collections::HashMap<String, String>,
+    dns_steered: Vec<String>,
+    auto_assigned: Vec<String>,
+    rule_priorities: std::collections::HashMap<String, u32>,
+    mapping_count: usize,
+    max_mappings: Option<usize>,
+    paused: bool,
+    // WAN carrying the LAN base rule ("wan1" while failed over)
+    base_wan: String,
+    health: std::collections::BTreeMap<String, health::WanHealth>,
+    // Hosts temporarily moved by host failover, with their original WAN
+    moved_hosts: std::collections::BTreeMap<String, String>,
+    // The same, per WAN being failed over from; empty unless one is
+    host_failovers: std::collections::BTreeMap<String, health::HostFailover>,
+    // WANs taken out of service with /wan/<name>/disable; `health` still
+    // shows what their probes say
+    disabled_wans: Vec<String>,
+    // Hosts whose mapping only changes with the lock token
+    locked: Vec<String>,
+    // Lease hostnames pinned via /switch?lease=, with their current address
+    lease_pins: std::collections::BTreeMap<String, String>,
+    // Gateways each WAN's default route uses, as set up at init
+    gateways: std::collections::BTreeMap<String, Vec<String>>,
+    // Hosts pinned with nic=auto: the WAN chosen and why
+    auto_pins: std::collections::BTreeMap<String, auto_select::AutoPin>,
+    // Time-of-day pins, with whether each is applied and when that changes
+    schedules: std::collections::BTreeMap<String, schedule::ScheduleStatus>,
+    // Destinations of pinned hosts that leave through the other WAN
+    exceptions: exceptions::Exceptions,
+    // WAN per DSCP value, whichever host sends the traffic
+    dscp_policies: dscp::DscpPolicies,
+    // READ_ONLY: nothing is changed, only watched
+    read_only: bool,
+    // READ_ONLY: commands that would bring the kernel in line
+    drift: Vec<String>,
+    commands: CommandStats,
+}
+
+/// Host -> WAN, or with `?detailed=true` the whole record of each host.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StatusMappings {
+    Nics(std::collections::HashMap<String, String>),
+    Detailed(std::collections::BTreeMap<String, mapping::Mapping>),
+}
+
+#[cfg(test)]
+impl StatusMappings {
+    fn nic(&self, host: &str) -> Option<&str> {
+        match self {
+            StatusMappings::Nics(nics) => nics.get(host).map(String::as_str),
+            StatusMappings::Detailed(mappings) => mappings.get(host).map(|m| m.nic.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct StatusConfig {
+    wan0: String,
+    wan1: String,
+    lan: String,
+    lan_subnets: Vec<IpNet>,
+    switch_allow: Vec<IpNet>,
+    switch_deny: Vec<IpNet>,
+    subnet_policy: SubnetPolicy,
+    overlap_policy: mapping::OverlapPolicy,
+    min_prefix_len: Option<u8>,
+    priorities: Priorities,
+    priority_band: Option<PriorityBand>,
+    // NIC_ALIASES, alias -> WAN
+    #[serde(default)]
+    nic_aliases: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CommandStats {
+    in_flight: usize,
+    queued: usize,
+    total: u64,
+}
+
+#[derive(Serialize)]
+struct ApiResponse {
+    status: String,
+    message: String,
+    // Priority of the rule a switch installed, as read back from the kernel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u32>,
+    // Things that may keep the switch from working, found after it was made
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    // Egress the kernel picks for the host, with /switch?verify=true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verification: Option<verify::Verification>,
+}
+
+/// Outcome of one item of a multi-item operation.
+#[derive(Debug, Serialize)]
+struct ItemResult {
+    // The host (or rule selector) the item is about
+    ip: String,
+    // "success" or "error"
+    status: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<IpRule>,
+}
+
+impl ItemResult {
+    fn from_outcome(ip: &str, outcome: Result<String>) -> Self {
+        let (status, message) = match outcome {
+            Ok(message) => ("success", message),
+            Err(e) => ("error", format!("{:#}", e)),
+        };
+        ItemResult {
+            ip: ip.to_string(),
+            status,
+            message,
+            rule: None,
+        }
+    }
+
+    fn with_rule(self, rule: IpRule) -> Self {
+        ItemResult {
+            rule: Some(rule),
+            ..self
+        }
+    }
+}
+
+/// Response body of multi-item endpoints: one entry per item plus counts.
+/// `status` is "success" when every item succeeded (or there were none),
+/// "error" when every item failed and "partial" otherwise.
+#[derive(Debug, Serialize)]
+struct MultiResult {
+    status: &'static str,
+    succeeded: usize,
+    failed: usize,
+    items: Vec<ItemResult>,
+}
+
+impl MultiResult {
+    fn new(items: Vec<ItemResult>) -> Self {
+        let failed = items.iter().filter(|i| i.status == "error").count();
+        let succeeded = items.len() - failed;
+        let status = match (succeeded, failed) {
+            (_, 0) => "success",
+            (0, _) => "error",
+            _ => "partial",
+        };
+        MultiResult {
+            status,
+            succeeded,
+            failed,
+            items,
+        }
+    }
+
+    /// 200 when everything succeeded, 207 Multi-Status on partial failure
+    /// and 500 when nothing did.
+    fn http_status(&self) -> StatusCode {
+        match self.status {
+            "success" => StatusCode::OK,
+            "partial" => StatusCode::MULTI_STATUS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Error body returned by the handlers. Failed external commands carry their
+/// (truncated) exit code and output under `command`.
+#[derive(Debug, Serialize)]
+struct ApiError {
+    #[serde(skip)]
+    http_status: StatusCode,
+    status: &'static str,
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<Box<CommandError>>,
+}
+
+impl ApiError {
+    fn new(http_status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            http_status,
+            status: "error",
+            code,
+            message: message.into(),
+            command: None,
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", message)
+    }
+
+    fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    fn locked(host: &str) -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "locked",
+            format!("{} is locked; switching it requires X-Lock-Token", host),
+        )
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    fn chaos() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "chaos",
+            "Failure injected by CHAOS_FAIL_RATE (test mode)",
+        )
+    }
+
+    fn read_only() -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "read_only",
+            "Service is read-only (READ_ONLY); changes are made by the active instance",
+        )
+    }
+
+    fn paused() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "paused",
+            "Service is paused; POST /resume to apply changes again",
+        )
+    }
+
+    /// A server-side failure; failed commands are reported as `command_failed`.
+    fn internal(context: &str, err: anyhow::Error) -> Self {
+        let message = format!("{}: {:#}", context, err);
+        match err.downcast_ref::<CommandError>() {
+            Some(cmd) => {
+                let cmd = cmd.truncated();
+                eprintln!(
+                    "{} (exit {:?}, stdout: {:?}, stderr: {:?})",
+                    message, cmd.exit_code, cmd.stdout, cmd.stderr
+                );
+                ApiError {
+                    command: Some(Box::new(cmd)),
+                    ..Self::new(StatusCode::INTERNAL_SERVER_ERROR, "command_failed", message)
+                }
+            }
+            None => {
+                eprintln!("{}", message);
+                Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+            }
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        (self.http_status, Json(&self)).into_response()
+    }
+}
+
+/// Render `body` as JSON, or as YAML when asked for via `?format=yaml` or an
+/// Accept header that lists a YAML type before JSON.
+fn negotiate<T: Serialize>(
+    headers: &HeaderMap,
+    params: &FormatParams,
+    body: &T,
+) -> Result<Response, ApiError> {
+    let yaml = match params.format.as_deref() {
+        Some("yaml") => true,
+        Some("json") => false,
+        Some(other) => {
+            return Err(ApiError::bad_request(format!(
+                "format must be 'json' or 'yaml', got '{}'",
+                other
+            )))
+        }
+        None => headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|accept| {
+                accept.split(',').find_map(|item| {
+                    match item.split(';').next().unwrap_or_default().trim() {
+                        "application/yaml" | "application/x-yaml" | "text/yaml" => Some(true),
+                        "application/json" => Some(false),
+                        _ => None,
+                    }
+                })
+            })
+            .unwrap_or(false),
+    };
+    if !yaml {
+        return Ok(Json(body).into_response());
+    }
+    let text = serde_yaml::to_string(body)
+        .map_err(|e| ApiError::internal("Failed to render YAML", e.into()))?;
+    Ok(([(header::CONTENT_TYPE, "application/yaml")], text).into_response())
+}
+
+// Process-wide counters of external command executions
+static CMD_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static CMD_TOTAL: AtomicU64 = AtomicU64::new(0);
+static MAPPINGS_EVICTED: AtomicU64 = AtomicU64::new(0);
+// Sequence numbers for debounced switches; never reused, unlike map entries
+static SWITCH_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// End-to-end /switch latency, keyed by (outcome, nic)
+static SWITCH_DURATION: std::sync::LazyLock<
+    std::sync::Mutex<std::collections::BTreeMap<(&'static str, String), Histogram>>,
+> = std::sync::LazyLock::new(Default::default);
+
+const SWITCH_DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Cumulative Prometheus-style histogram over `SWITCH_DURATION_BUCKETS`.
+#[derive(Default)]
+struct Histogram {
+    // counts[i] observations <= SWITCH_DURATION_BUCKETS[i]
+    counts: [u64; SWITCH_DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (i, bound) in SWITCH_DURATION_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        use std::fmt::Write;
+        for (bound, count) in SWITCH_DURATION_BUCKETS.iter().zip(self.counts) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+fn observe_switch_duration(outcome: &'static str, nic: &str, elapsed: std::time::Duration) {
+    // Keep label cardinality bounded when callers send garbage
+    let nic = match nic {
+        "wan0" | "wan1" | "auto" => nic,
+        _ => "invalid",
+    };
+    SWITCH_DURATION
+        .lock()
+        .unwrap()
+        .entry((outcome, nic.to_string()))
+        .or_default()
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Marks one command execution as in flight for as long as it is alive.
+struct InFlightGuard;
+
+impl InFlightGuard {
+    fn new() -> Self {
+        CMD_IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        CMD_TOTAL.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        CMD_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An external command that ran but exited unsuccessfully.
+#[derive(Clone, Debug, Serialize)]
+struct CommandError {
+    command: String,
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+// Cap on captured output carried into API errors and logs
+const MAX_COMMAND_OUTPUT: usize = 512;
+
+fn truncate_output(s: &str) -> String {
+    let s = s.trim_end();
+    match s.char_indices().nth(MAX_COMMAND_OUTPUT) {
+        Some((idx, _)) => format!("{}...", &s[..idx]),
+        None => s.to_string(),
+    }
+}
+
+impl CommandError {
+    fn truncated(&self) -> Self {
+        CommandError {
+            command: self.command.clone(),
+            exit_code: self.exit_code,
+            stdout: truncate_output(&self.stdout),
+            stderr: truncate_output(&self.stderr),
+        }
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.exit_code {
+            Some(code) => write!(f, "{} failed (exit {})", self.command, code)?,
+            None => write!(f, "{} failed (killed by signal)", self.command)?,
+        }
+        write!(f, ": {}", self.stderr.trim_end())
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+fn run_cmd(cmd: &str, args: &[&str]) -> Result<String> {
+    let _in_flight = InFlightGuard::new();
+    let out = Command::new(cmd)
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to run {} {:?}", cmd, args))?;
+    if !out.status.success() {
+        return Err(CommandError {
+            command: format!("{} {}", cmd, args.join(" ")),
+            exit_code: out.status.code(),
+            stdout: String::from_utf8_lossy(&out.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&out.stderr).to_string(),
+        }
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+/// Executes external commands. Production spawns real processes; tests swap
+/// in an in-memory model of the routing state.
+trait CommandRunner: Send + Sync {
+    fn run(&self, cmd: &str, args: &[&str]) -> Result<String>;
+
+    /// Whether `ip` prints JSON with `-j`.
+    fn ip_json(&self) -> bool {
+        false
+    }
+}
+
+const DEFAULT_MAX_COMMANDS: usize = 4;
+
+struct SystemRunner {
+    ip_json: bool,
+    limit: cmd_limit::CommandLimit,
+}
+
+impl SystemRunner {
+    /// Ask `ip -V` whether the installed iproute2 has JSON output.
+    fn detect(max_commands: usize) -> Self {
+        let ip_json = run_cmd("ip", &["-V"]).is_ok_and(|out| ipjson::version_supports_json(&out));
+        SystemRunner {
+            ip_json,
+            limit: cmd_limit::CommandLimit::new(max_commands),
+        }
+    }
+}
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, cmd: &str, args: &[&str]) -> Result<String> {
+        // Probes wait up to a second for a reply; holding a slot that long
+        // would queue switches behind health checks
+        let _slot = (cmd != "ping").then(|| self.limit.acquire());
+        run_cmd(cmd, args)
+    }
+
+    fn ip_json(&self) -> bool {
+        self.ip_json
+    }
+}
+
+// ---- Policy routing helpers ----
+
+const DEFAULT_LAN_SUBNETS: &str = "10.40.0.0/20";
+const TABLE_WAN0: &str = "100"; // routing table id for wan0
+const TABLE_WAN1: &str = "200"; // routing table id for wan1
+const DEFAULT_PRIO_SPECIFIC: u32 = 1000; // higher priority (smaller number)
+const DEFAULT_PRIO_LAN_DEFAULT: u32 = 2000; // default lan policy priority
+
+// The kernel's own "lookup main" rule; the LAN base rule must come first
+const PRIO_MAIN: u32 = 32766;
+
+/// The routing table of `nic`.
+fn nic_table(nic: &str) -> &'static str {
+    if nic == "wan1" {
+        TABLE_WAN1
+    } else {
+        TABLE_WAN0
+    }
+}
+
+/// Where the service's rules sit. The fallback and DNS rules are placed
+/// right after and right before the host overrides.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Priorities {
+    specific: u32,
+    lan_default: u32,
+}
+
+/// PRIO_BAND: the priorities the service may use, both ends included.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct PriorityBand {
+    min: u32,
+    max: u32,
+}
+
+impl PriorityBand {
+    fn parse(value: &str) -> Option<Self> {
+        let (min, max) = value.split_once('-')?;
+        let band = PriorityBand {
+            min: min.trim().parse().ok()?,
+            max: max.trim().parse().ok()?,
+        };
+        (band.min <= band.max).then_some(band)
+    }
+
+    fn contains(self, prio: u32) -> bool {
+        (self.min..=self.max).contains(&prio)
+    }
+}
+
+impl std::fmt::Display for PriorityBand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
+impl Priorities {
+    // Right after the overrides, before anything else
+    fn fallback(self) -> u32 {
+        self.specific + 1
+    }
+
+    // DNS steering must win over the host's own rule
+    fn dns(self) -> u32 {
+        self.specific - 1
+    }
+
+    // Destination exceptions must win over both
+    fn exception(self) -> u32 {
+        self.specific - 2
+    }
+
+    // DSCP policies win over everything tied to a host
+    fn dscp(self) -> u32 {
+        self.specific.saturating_sub(3)
+    }
+}
+
+/// A single `ip` invocation kept as data, so the same command can either be
+/// executed or rendered (e.g. for `/plan`).
+#[derive(Clone, Debug, PartialEq)]
+struct IpCommand {
+    args: Vec<String>,
+}
+
+impl IpCommand {
+    fn new(args: &[&str]) -> Self {
+        IpCommand {
+            args: args.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn run(&self, runner: &dyn CommandRunner) -> Result<String> {
+        let args: Vec<&str> = self.args.iter().map(String::as_str).collect();
+        runner.run("ip", &args)
+    }
+}
+
+impl std::fmt::Display for IpCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ip {}", self.args.join(" "))
+    }
+}
+
+fn table_default_route_cmd(
+    iface: &Interface,
+    table: &str,
+    nexthop: &Nexthop,
+    src: Option<&str>,
+    mode: RouteMode,
+    onlink: bool,
+) -> IpCommand {
+    let gws: Vec<String> = nexthop.gateways().iter().map(Gateway::to_string).collect();
+    let mut args = vec!["route", mode.verb(), "default"];
+    if let Nexthop::Multipath(_) = nexthop {
+        // Nexthop specs must come last
+        if let Some(src) = src {
+            args.extend(["src", src]);
+        }
+        args.extend(["table", table]);
+        for gw in &gws {
+            args.extend(["nexthop", "via", gw, "dev", iface]);
+        }
+        return IpCommand::new(&args);
+    }
+    if let Some(gw) = gws.first() {
+        args.extend(["via", gw]);
+    }
+    args.extend(["dev", iface]);
+    if onlink {
+        args.push("onlink");
+    }
+    if let Some(src) = src {
+        args.extend(["src", src]);
+    }
+    args.extend(["table", table]);
+    IpCommand::new(&args)
+}
+
+fn link_route_cmd(prefix: &str, iface: &Interface, table: &str) -> IpCommand {
+    IpCommand::new(&[
+        "route", "replace", prefix, "dev", iface, "scope", "link", "table", table,
+    ])
+}
+
+fn rule_add_cmd(from: &str, host_match: &HostMatch, table: &str, prio: u32) -> IpCommand {
+    let prio = prio.to_string();
+    let mut args = vec!["rule", "add", "from", from];
+    args.extend(host_match.args());
+    args.extend(["lookup", table, "priority", &prio]);
+    IpCommand::new(&args)
+}
+
+fn rule_del_cmd(from: &str, host_match: &HostMatch, table: &str) -> IpCommand {
+    let mut args = vec!["rule", "del", "from", from];
+    args.extend(host_match.args());
+    args.extend(["lookup", table]);
+    IpCommand::new(&args)
+}
+
+/// Rules steering a host's DNS (udp and tcp port 53) to `table`.
+fn dns_rule_cmds(
+    action: &str,
+    from: &str,
+    host_match: &HostMatch,
+    table: &str,
+    prio: u32,
+) -> Vec<IpCommand> {
+    let prio = prio.to_string();
+    ["udp", "tcp"]
+        .iter()
+        .map(|proto| {
+            let mut args = vec![
+                "rule", action, "from", from, "ipproto", proto, "dport", "53",
+            ];
+            args.extend(host_match.args());
+            args.extend(["lookup", table]);
+            if action == "add" {
+                args.extend(["priority", &prio]);
+            }
+            IpCommand::new(&args)
+        })
+        .collect()
+}
+
+fn rule_del_prio_cmd(from: &str, table: &str, prio: u32) -> IpCommand {
+    IpCommand::new(&[
+        "rule",
+        "del",
+        "priority",
+        &prio.to_string(),
+        "from",
+        from,
+        "lookup",
+        table,
+    ])
+}
+
+/// Delete exactly `rule`, including its destination if it has one.
+fn rule_del_exact_cmd(rule: &IpRule) -> IpCommand {
+    let mut cmd = rule_del_prio_cmd(&rule.from, &rule.table, rule.priority);
+    if let Some(to) = &rule.to {
+        cmd.args.extend(["to".to_string(), to.clone()]);
+    }
+    cmd
+}
+
+/// An IPv4 address as a client may write it. IPv4-mapped IPv6
+/// (`::ffff:10.40.0.3`) collapses to the IPv4 address, so one host is never
+/// keyed or matched under two spellings.
+fn parse_host_ip(value: &str) -> Option<std::net::Ipv4Addr> {
+    match value.parse::<IpAddr>().ok()?.to_canonical() {
+        IpAddr::V4(addr) => Some(addr),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// `net` with an IPv4-mapped network (`::ffff:10.40.0.0/116`) taken as the
+/// IPv4 prefix it stands for.
+fn canonical_net(net: IpNet) -> IpNet {
+    let IpNet::V6(v6) = net else {
+        return net;
+    };
+    match (v6.addr().to_ipv4_mapped(), v6.prefix_len().checked_sub(96)) {
+        (Some(addr), Some(len)) => ipnet::Ipv4Net::new(addr, len).map_or(net, IpNet::V4),
+        _ => net,
+    }
+}
+
+/// A host or an IPv4 prefix as a client may name a mapping: `10.40.0.3`,
+/// `10.40.1.0/24`, or their IPv4-mapped spellings.
+fn parse_host_net(value: &str) -> Option<IpNet> {
+    if let Some(addr) = parse_host_ip(value) {
+        return Some(IpNet::from(IpAddr::V4(addr)));
+    }
+    match canonical_net(value.parse::<IpNet>().ok()?) {
+        net @ IpNet::V4(_) => Some(net.trunc()),
+        IpNet::V6(_) => None,
+    }
+}
+
+/// The prefix a `/switch` target pins: a network address with a length
+/// under 32 (`10.40.1.0/24`). A host address with a length is the host.
+fn switch_prefix(value: &str) -> Option<IpNet> {
+    let net = canonical_net(value.parse::<IpNet>().ok()?);
+    (net.prefix_len() < 32 && net.addr() == net.network()).then_some(net)
+}
+
+/// The address of a `/switch` target: an IPv4 address, optionally followed
+/// by a `/len` prefix (0-32; see `switch_prefix`). The IPv4-mapped form is
+/// accepted too, with its length in IPv6 bits (96-128).
+fn parse_switch_ip(value: &str) -> Option<IpAddr> {
+    let (addr, prefix) = match value.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (value, None),
+    };
+    if let Some(prefix) = prefix {
+        let lens = if addr.contains(':') { 96..=128 } else { 0..=32 };
+        // u8 parsing alone would accept "+20"
+        if !prefix.bytes().all(|b| b.is_ascii_digit())
+            || !lens.contains(&prefix.parse::<u8>().ok()?)
+        {
+            return None;
+        }
+    }
+    parse_host_ip(addr).map(IpAddr::V4)
+}
+
+/// The gateway of a route line: the address following `via`, if any.
+fn parse_via(line: &str) -> Option<IpAddr> {
+    let mut tokens = line.split_whitespace();
+    tokens.find(|t| *t == "via")?;
+    tokens.next()?.parse().ok()
+}
+
+/// The IPv6 nexthop of `ip -6 route show default dev <iface>` output. RA
+/// routes usually point at a link-local `fe80::` address, which is only
+/// meaningful together with the device; multipath defaults list one
+/// `nexthop via ... dev ...` per line, and only `iface`'s counts.
+fn parse_ipv6_default_gateway(out: &str, iface: &str) -> Option<std::net::Ipv6Addr> {
+    out.lines()
+        .filter(|line| {
+            let mut tokens = line.split_whitespace();
+            match tokens.position(|t| t == "dev") {
+                Some(_) => tokens.next() == Some(iface),
+                None => true,
+            }
+        })
+        .find_map(|line| match parse_via(line)? {
+            IpAddr::V6(gw) => Some(gw),
+            IpAddr::V4(_) => None,
+        })
+}
+
+fn get_ipv6_gateway_for_iface(
+    runner: &dyn CommandRunner,
+    iface: &Interface,
+) -> Result<std::net::Ipv6Addr> {
+    let out = runner.run("ip", &["-6", "route", "show", "default", "dev", iface])?;
+    parse_ipv6_default_gateway(&out, iface).with_context(|| {
+        format!(
+            "Could not determine IPv6 default gateway for iface {}",
+            iface
+        )
+    })
+}
+
+/// IPv6 default route for a WAN table. The device is always given, which is
+/// what makes a link-local nexthop usable.
+fn table_default_route6_cmd(
+    iface: &Interface,
+    table: &str,
+    gateway: &std::net::Ipv6Addr,
+    mode: RouteMode,
+) -> IpCommand {
+    let gateway = gateway.to_string();
+    IpCommand::new(&[
+        "-6",
+        "route",
+        mode.verb(),
+        "default",
+        "via",
+        &gateway,
+        "dev",
+        iface,
+        "table",
+        table,
+    ])
+}
+
+fn get_default_gateway_for_iface(runner: &dyn CommandRunner, iface: &Interface) -> Result<Gateway> {
+    if let Some(routes) = ipjson::routes(runner, &["route", "show", "default"]) {
+        return match routes.iter().find_map(|route| route.gateway_on(iface)) {
+            Some(gw) => Ok(Gateway(gw)),
+            None => bail!("Could not determine default gateway for iface {}", iface),
+        };
+    }
+    // Try to read default route for specific iface
+    let out = runner.run("ip", &["route", "show", "default", "dev", iface])?;
+    if let Some(gw) = out.lines().find_map(parse_via) {
+        return Ok(Gateway(gw));
+    }
+    // Fallback: scan all defaults and pick the one matching iface
+    let all = runner
+        .run("ip", &["route", "show", "default"])?
+        .lines()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    for line in all.lines() {
+        if line.contains(&format!(" dev {}", iface)) {
+            if let Some(gw) = parse_via(line) {
+                return Ok(Gateway(gw));
+            }
+        }
+    }
+    bail!("Could not determine default gateway for iface {}", iface)
+}
+
+/// Use the configured nexthop if any, otherwise discover it. A default route
+/// without `via` (point-to-point uplink) yields a device-only nexthop.
+fn resolve_nexthop(
+    runner: &dyn CommandRunner,
+    iface: &Interface,
+    configured: Option<&Nexthop>,
+) -> Result<Nexthop> {
+    if let Some(nexthop) = configured {
+        return Ok(nexthop.clone());
+    }
+    match get_default_gateway_for_iface(runner, iface) {
+        Ok(gw) => Ok(Nexthop::Via(gw)),
+        Err(e) => {
+            let has_default = match ipjson::routes(runner, &["route", "show", "default"]) {
+                Some(routes) => routes.iter().any(|route| route.uses(iface)),
+                None => runner
+                    .run("ip", &["route", "show", "default", "dev", iface])?
+                    .lines()
+                    .any(|l| l.starts_with("default")),
+            };
+            if has_default {
+                Ok(Nexthop::DeviceOnly)
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+fn get_primary_addr_for_iface(runner: &dyn CommandRunner, iface: &Interface) -> Result<String> {
+    // First global IPv4 address
+    match addrs::get_iface_addrs(runner, iface)?
+        .into_iter()
+        .find(|net| net.addr().is_ipv4())
+    {
+        Some(net) => Ok(net.addr().to_string()),
+        None => bail!("No IPv4 address found on iface {}", iface),
+    }
+}
+
+fn resolve_src(
+    runner: &dyn CommandRunner,
+    iface: &Interface,
+    configured: Option<&SrcAddr>,
+) -> Result<Option<String>> {
+    match configured {
+        None => Ok(None),
+        Some(SrcAddr::Fixed(ip)) => Ok(Some(ip.clone())),
+        Some(SrcAddr::Auto) => get_primary_addr_for_iface(runner, iface).map(Some),
+    }
+}
+
+/// Routes in the WAN tables that the service would not have installed. Apart
+/// from the EXTRA_ROUTES_* it is given, the service only ever puts routes
+/// through a table's own WAN device there, so anything else (another device,
+/// or no device such as `blackhole` or `unreachable`) means another user of
+/// the same table id.
+fn foreign_table_routes(
+    runner: &dyn CommandRunner,
+    config: &Config,
+) -> Result<Vec<(&'static str, String)>> {
+    let mut foreign = Vec::new();
+    for (table, iface, extra) in [
+        (TABLE_WAN0, &config.wan0, &config.extra_routes_wan0),
+        (TABLE_WAN1, &config.wan1, &config.extra_routes_wan1),
+    ] {
+        let out = runner.run("ip", &["route", "show", "table", table])?;
+        for route in route_entries(&out) {
+            if extra.iter().any(|r| r.matches(&route)) {
+                continue;
+            }
+            let tokens: Vec<&str> = route.split_whitespace().collect();
+            let mut devs = tokens
+                .windows(2)
+                .filter(|pair| pair[0] == "dev")
+                .map(|pair| pair[1])
+                .peekable();
+            // Every nexthop of a multipath route must use the WAN device too
+            if devs.peek().is_none() || devs.any(|dev| dev != &**iface) {
+                foreign.push((table, route));
+            }
+        }
+    }
+    Ok(foreign)
+}
+
+/// Routes of an `ip route show` listing, one per entry: the indented
+/// `nexthop` lines of a multipath route are joined onto it.
+fn route_entries(out: &str) -> Vec<String> {
+    let mut routes: Vec<String> = Vec::new();
+    for line in out.lines().filter(|l| !l.trim().is_empty()) {
+        match routes.last_mut() {
+            Some(route) if line.starts_with(char::is_whitespace) => {
+                route.push(' ');
+                route.push_str(line.trim());
+            }
+            _ => routes.push(line.trim().to_string()),
+        }
+    }
+    routes
+}
+
+fn ensure_table_default_route(
+    runner: &dyn CommandRunner,
+    iface: &Interface,
+    table: &str,
+    nexthop: &Nexthop,
+    src: Option<&str>,
+    mode: RouteMode,
+) -> Result<()> {
+    if mode == RouteMode::Add {
+        let existing = runner.run("ip", &["route", "show", "table", table, "default"])?;
+        if let Some(route) = route_entries(&existing)
+            .into_iter()
+            .find(|r| r.starts_with("default"))
+        {
+            if !default_route_matches(&route, iface, nexthop) {
+                eprintln!(
+                    "Warning: table {} already has a different default route ({}); leaving it in place",
+                    table, route
+                );
+            }
+            return Ok(());
+        }
+    }
+    if let Nexthop::Multipath(gws) = nexthop {
+        // A multipath route is only as good as its worst nexthop, so every
+        // gateway must sit on the link rather than be retried with onlink
+        let offlink: Vec<String> = gws
+            .iter()
+            .filter(|gw| gateway_is_offlink(runner, iface, gw))
+            .map(Gateway::to_string)
+            .collect();
+        if !offlink.is_empty() {
+            bail!(
+                "gateways {} are not reachable on {}'s subnets",
+                offlink.join(", "),
+                iface
+            );
+        }
+    }
+    // Create (or, in replace mode, overwrite) the default route for table
+    let err = match table_default_route_cmd(iface, table, nexthop, src, mode, false).run(runner) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    // The kernel refuses a gateway outside the interface's subnets
+    // ("Nexthop has invalid gateway") unless the route says onlink
+    match nexthop {
+        Nexthop::Via(gw) if gateway_is_offlink(runner, iface, gw) => {
+            eprintln!(
+                "Gateway {} is outside {}'s subnets; retrying table {} default route with onlink",
+                gw, iface, table
+            );
+            table_default_route_cmd(iface, table, nexthop, src, mode, true).run(runner)?;
+            Ok(())
+        }
+        _ => Err(err),
+    }
+}
+
+/// Whether `gw` lies outside every IPv4 prefix on `iface`, so a route via it
+/// needs `onlink`. An interface without addresses (or a failed lookup) says
+/// nothing either way and counts as on-link.
+fn gateway_is_offlink(runner: &dyn CommandRunner, iface: &Interface, gw: &Gateway) -> bool {
+    let Ok(addrs) = addrs::get_iface_addrs(runner, iface) else {
+        return false;
+    };
+    let nets: Vec<IpNet> = addrs
+        .into_iter()
+        .filter(|net| net.addr().is_ipv4())
+        .collect();
+    !nets.is_empty() && !nets.iter().any(|net| net.contains(&gw.0))
+}
+
+/// Whether a `default ...` entry from `ip route show` (see `route_entries`)
+/// goes out `iface` via `nexthop`.
+fn default_route_matches(line: &str, iface: &str, nexthop: &Nexthop) -> bool {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let value_of = |key: &str| {
+        tokens
+            .iter()
+            .position(|t| *t == key)
+            .and_then(|i| tokens.get(i + 1))
+            .copied()
+    };
+    let via_matches = match nexthop {
+        Nexthop::Via(gw) => value_of("via").and_then(|v| v.parse().ok()) == Some(gw.0),
+        Nexthop::DeviceOnly => value_of("via").is_none(),
+        Nexthop::Multipath(gws) => return multipath_route_matches(&tokens, iface, gws),
+    };
+    !tokens.contains(&"nexthop") && via_matches && value_of("dev") == Some(iface)
+}
+
+/// Whether the nexthops of a multipath route are exactly `gws`, all on `iface`.
+fn multipath_route_matches(tokens: &[&str], iface: &str, gws: &[Gateway]) -> bool {
+    let hops: Vec<&[&str]> = tokens.split(|t| *t == "nexthop").skip(1).collect();
+    let value_in = |hop: &[&str], key: &str| {
+        hop.windows(2)
+            .find(|pair| pair[0] == key)
+            .map(|pair| pair[1].to_string())
+    };
+    let vias: std::collections::BTreeSet<Option<IpAddr>> = hops
+        .iter()
+        .map(|hop| value_in(hop, "via").and_then(|v| v.parse().ok()))
+        .collect();
+    let wanted: std::collections::BTreeSet<Option<IpAddr>> =
+        gws.iter().map(|gw| Some(gw.0)).collect();
+    hops.len() == gws.len()
+        && vias == wanted
+        && hops
+            .iter()
+            .all(|hop| value_in(hop, "dev").as_deref() == Some(iface))
+}
+
+fn ip_rule_list(runner: &dyn CommandRunner) -> Result<String> {
+    runner.run("ip", &["rule", "show"])
+}
+
+/// The current rules, from JSON output where `ip` has it.
+fn ip_rules(runner: &dyn CommandRunner) -> Result<Vec<IpRule>> {
+    match ipjson::rules(runner) {
+        Some(rules) => Ok(rules),
+        None => Ok(parse_ip_rules(&ip_rule_list(runner)?)),
+    }
+}
+
+fn ip_rule_exists(
+    runner: &dyn CommandRunner,
+    from: &str,
+    host_match: &HostMatch,
+    table: &str,
+) -> Result<bool> {
+    Ok(rule_priority(runner, from, host_match, table)?.is_some())
+}
+
+/// One line of `ip rule show`, reduced to the fields the service cares about.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct IpRule {
+    priority: u32,
+    from: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    table: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipproto: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dport: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iif: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fwmark: Option<String>,
+    // `tos`/`dsfield`, as printed: hex, or a name from rt_dsfield
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tos: Option<String>,
+    // Only set on rules for sockets bound to a device, e.g. the probe rules
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oif: Option<String>,
+    // Inverted or matched on anything else (sport, uidrange, ...)
+    #[serde(skip)]
+    extra_match: bool,
+}
+
+// Selector keywords beyond those IpRule keeps as fields
+const EXTRA_MATCH_KEYS: &[&str] = &["not", "sport", "uidrange", "l3mdev"];
+
+/// A `from` selector as the kernel prints it: single hosts without their
+/// `/32` (or `/128`), so `10.40.0.3` and `10.40.0.3/32` compare equal.
+fn normalize_selector(from: &str) -> &str {
+    from.strip_suffix("/32")
+        .filter(|host| !host.contains(':'))
+        .or_else(|| from.strip_suffix("/128"))
+        .unwrap_or(from)
+}
+
+/// Parse `ip rule show` output. Lines look like "1000:\tfrom 10.40.0.3 lookup 200",
+/// possibly with extra match keys ("to", "ipproto", "dport", "iif", ...) and
+/// flags ("not", "[detached]"). Host selectors are normalized (see
+/// `normalize_selector`) whichever way they were printed. Rules without a table (e.g. "unreachable",
+/// "goto") and unparseable lines are skipped.
+fn parse_ip_rules(out: &str) -> Vec<IpRule> {
+    out.lines()
+        .filter_map(|line| {
+            let (prio, rest) = line.split_once(':')?;
+            let tokens: Vec<&str> = rest.split_whitespace().collect();
+            let value_of = |key: &str| {
+                tokens
+                    .iter()
+                    .position(|t| *t == key)
+                    .and_then(|i| tokens.get(i + 1))
+                    .map(|v| v.to_string())
+            };
+            Some(IpRule {
+                priority: prio.trim().parse().ok()?,
+                from: normalize_selector(&value_of("from")?).to_string(),
+                to: value_of("to"),
+                table: value_of("lookup").or_else(|| value_of("table"))?,
+                ipproto: value_of("ipproto"),
+                dport: value_of("dport"),
+                iif: value_of("iif"),
+                fwmark: value_of("fwmark"),
+                tos: value_of("tos").or_else(|| value_of("dsfield")),
+                oif: value_of("oif"),
+                extra_match: tokens.iter().any(|t| EXTRA_MATCH_KEYS.contains(t)),
+            })
+        })
+        .collect()
+}
+
+fn base_rule_cmd(action: &str, selector: &BaseSelector, table: &str, prio: u32) -> IpCommand {
+    let prio = prio.to_string();
+    let mut args = vec!["rule", action];
+    args.extend(selector.args());
+    args.extend(["lookup", table, "priority", &prio]);
+    IpCommand::new(&args)
+}
+
+/// `ip rule <action> oif <iface> lookup <table>`: traffic the router sends
+/// out of a WAN interface, i.e. its probes, uses that WAN's table.
+fn probe_rule_cmd(action: &str, iface: &Interface, table: &str, prio: u32) -> IpCommand {
+    IpCommand::new(&[
+        "rule",
+        action,
+        "oif",
+        iface,
+        "lookup",
+        table,
+        "priority",
+        &prio.to_string(),
+    ])
+}
+
+fn add_base_rule(
+    runner: &dyn CommandRunner,
+    selector: &BaseSelector,
+    table: &str,
+    prio: u32,
+) -> Result<()> {
+    // Unlike add_ip_rule, the same selector/table may exist at another priority
+    let exists = ip_rules(runner)?
+        .iter()
+        .any(|r| selector.matches(r) && r.table == table && r.priority == prio);
+    if !exists {
+        base_rule_cmd("add", selector, table, prio).run(runner)?;
+    }
+    Ok(())
+}
+
+/// Ensure a `from` -> `table` rule exists and return the priority it sits at,
+/// which may differ from `prio` if the rule already existed.
+fn add_ip_rule(
+    runner: &dyn CommandRunner,
+    from: &str,
+    host_match: &HostMatch,
+    table: &str,
+    prio: u32,
+) -> Result<u32> {
+    if !ip_rule_exists(runner, from, host_match, table)? {
+        rule_add_cmd(from, host_match, table, prio).run(runner)?;
+        return Ok(prio);
+    }
+    // Report where the existing rule actually sits
+    Ok(rule_priority(runner, from, host_match, table)?.unwrap_or(prio))
+}
+
+/// Priority of the `from` -> `table` host rule with `host_match`'s keys.
+/// Selector and table are compared as parsed fields, never as substrings of
+/// the rule line.
+fn rule_priority(
+    runner: &dyn CommandRunner,
+    from: &str,
+    host_match: &HostMatch,
+    table: &str,
+) -> Result<Option<u32>> {
+    let host = normalize_selector(from);
+    Ok(ip_rules(runner)?
+        .iter()
+        .filter(|r| r.table == table && host_match.matches(r))
+        .find(|r| r.from == host)
+        .map(|r| r.priority))
+}
+
+/// Outcome of `reconcile_host_rules`.
+#[derive(Debug, Default)]
+struct Reconciled {
+    // Priority of each host's own rule, for hosts that have one
+    priorities: std::collections::HashMap<String, u32>,
+    added: usize,
+    removed: usize,
+    // Hosts whose rules could not be brought in line
+    failed: Vec<(String, anyhow::Error)>,
+}
+
+/// Bring the host rules of `mappings` in line with a single `ip rule show`:
+/// rules already in place are left alone, missing ones are added and ones
+/// pointing at the wrong table removed. Hosts not in `mappings` are not
+/// touched (that is `/gc`'s job).
+fn reconcile_host_rules(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    mappings: &std::collections::HashMap<String, String>,
+) -> Result<Reconciled> {
+    let rules = ip_rules(runner)?;
+    let mut existing: std::collections::HashMap<&str, Vec<&IpRule>> =
+        std::collections::HashMap::new();
+    for rule in &rules {
+        if config.host_match.matches(rule) && (rule.table == TABLE_WAN0 || rule.table == TABLE_WAN1)
+        {
+            existing.entry(rule.from.as_str()).or_default().push(rule);
+        }
+    }
+
+    let mut outcome = Reconciled::default();
+    for (host, nic) in mappings {
+        let want = config.pin_table(nic);
+        let target = mapping::selector(host);
+        let mut found = None;
+        let mut result = Ok(());
+        for rule in existing.get(host.as_str()).into_iter().flatten() {
+            if Some(rule.table.as_str()) == want && found.is_none() {
+                found = Some(rule.priority);
+                continue;
+            }
+            result = result.and(
+                rule_del_prio_cmd(&rule.from, &rule.table, rule.priority)
+                    .run(runner)
+                    .map(|_| outcome.removed += 1),
+            );
+        }
+        if let (Some(table), None) = (want, found) {
+            let prio = config.priorities.specific;
+            match rule_add_cmd(&target, &config.host_match, table, prio).run(runner) {
+                Ok(_) => {
+                    outcome.added += 1;
+                    found = Some(prio);
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        match result {
+            Ok(()) => {
+                if let Some(prio) = found {
+                    outcome.priorities.insert(host.clone(), prio);
+                }
+            }
+            Err(e) => outcome.failed.push((host.clone(), e)),
+        }
+    }
+    Ok(outcome)
+}
+
+/// Point `host`'s rule (and its DNS rules, if steered) at `nic`'s table.
+/// With `explicit`, a base WAN target gets its own rule even without
+/// PIN_PRIMARY. Returns the priority of the host's rule, if it has one.
+fn repin_host(
+    state: &AppState,
+    dns_steered: &mut std::collections::HashMap<String, String>,
+    host: &str,
+    nic: &str,
+    explicit: bool,
+) -> Result<Option<u32>> {
+    let runner = state.runner.as_ref();
+    let prio = state.config.priorities;
+    let host_match = &state.config.host_match;
+    let target = mapping::selector(host);
+    let table = nic_table(nic);
+    // Exception and DNS rules first: a plain `rule del` would otherwise match
+    // them as well
+    let except = exceptions::clear(state, host);
+    let dns = dns_steered.get(host).cloned();
+    if let Some(old) = &dns {
+        for cmd in dns_rule_cmds("del", &target, host_match, old, prio.dns()) {
+            let _ = cmd.run(runner);
+        }
+    }
+    del_ip_rule_checked(runner, &state.config, &target, TABLE_WAN0)?;
+    del_ip_rule_checked(runner, &state.config, &target, TABLE_WAN1)?;
+    let priority = if explicit || state.config.pin_table(nic).is_some() {
+        Some(add_ip_rule(
+            runner,
+            &target,
+            host_match,
+            table,
+            prio.specific,
+        )?)
+    } else {
+        None
+    };
+    if dns.is_some() {
+        for cmd in dns_rule_cmds("add", &target, host_match, table, prio.dns()) {
+            cmd.run(runner)?;
+        }
+        dns_steered.insert(host.to_string(), table.to_string());
+    }
+    if !except.is_empty() {
+        exceptions::reinstall(state, host, nic, &except, priority)?;
+    }
+    Ok(priority)
+}
+
+fn del_ip_rule_quiet(runner: &dyn CommandRunner, from: &str, host_match: &HostMatch, table: &str) {
+    // Best-effort delete; ignore errors
+    let _ = rule_del_cmd(from, host_match, table).run(runner);
+}
+
+// Copies of one rule a strict delete takes out before giving up
+const MAX_DUPLICATE_RULES: usize = 4;
+
+/// Delete `from`'s rule to `table` before a new one is added. With
+/// STRICT_DELETES the rule is looked up again afterwards, and one that
+/// stays (a failed delete, a duplicate, a kernel quirk) is an error rather
+/// than a silent contradiction of the new rule.
+fn del_ip_rule_checked(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    from: &str,
+    table: &str,
+) -> Result<()> {
+    let host_match = &config.host_match;
+    if !config.strict_deletes {
+        del_ip_rule_quiet(runner, from, host_match, table);
+        return Ok(());
+    }
+    for _ in 0..MAX_DUPLICATE_RULES {
+        if !ip_rule_exists(runner, from, host_match, table)? {
+            return Ok(());
+        }
+        rule_del_cmd(from, host_match, table)
+            .run(runner)
+            .with_context(|| format!("delete rule from {} to table {}", from, table))?;
+    }
+    if ip_rule_exists(runner, from, host_match, table)? {
+        bail!(
+            "rule from {} to table {} is still present after deleting it",
+            from,
+            table
+        );
+    }
+    Ok(())
+}
+
+fn link_route_cmds(
+    runner: &dyn CommandRunner,
+    iface: &Interface,
+    table: &str,
+) -> Result<Vec<IpCommand>> {
+    // Build "scope link" routes of the interface for the given table
+    let ipv4 = |dst: &str| {
+        dst.parse::<ipnet::Ipv4Net>().is_ok() || dst.parse::<std::net::Ipv4Addr>().is_ok()
+    };
+    let json = ipjson::routes(
+        runner,
+        &["-4", "route", "show", "dev", iface, "scope", "link"],
+    );
+    if let Some(routes) = json {
+        return Ok(routes
+            .iter()
+            .filter(|route| ipv4(&route.dst))
+            .map(|route| link_route_cmd(&route.dst, iface, table))
+            .collect());
+    }
+    let out = runner.run(
+        "ip",
+        &["-4", "route", "show", "dev", iface, "scope", "link"],
+    )?;
+    Ok(out
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|dst| ipv4(dst))
+        .map(|dst| link_route_cmd(dst, iface, table))
+        .collect())
+}
+
+/// Copy the interface's "scope link" routes into `table`. Returns how many
+/// were installed.
+fn mirror_link_routes_to_table(
+    runner: &dyn CommandRunner,
+    iface: &Interface,
+    table: &str,
+) -> Result<usize> {
+    let mut mirrored = 0;
+    for cmd in link_route_cmds(runner, iface, table)? {
+        // Replace/ensure route exists in the custom table
+        if cmd.run(runner).is_ok() {
+            mirrored += 1;
+        }
+    }
+    Ok(mirrored)
+}
+
+/// The WAN table routes and LAN base rules of a fresh initialization, with
+/// the base rules pointing at `base_table`.
+fn init_plan(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    base_table: &str,
+) -> Result<Vec<IpCommand>> {
+    let gw0 = resolve_nexthop(runner, &config.wan0, config.gateway_wan0.as_ref())
+        .with_context(|| format!("get gateway for {}", &config.wan0))?;
+    let gw1 = resolve_nexthop(runner, &config.wan1, config.gateway_wan1.as_ref())
+        .with_context(|| format!("get gateway for {}", &config.wan1))?;
+
+    let src0 = resolve_src(runner, &config.wan0, config.src_wan0.as_ref())?;
+    let src1 = resolve_src(runner, &config.wan1, config.src_wan1.as_ref())?;
+
+    // Initialization only falls back to onlink when the plain route fails,
+    // which for an off-link gateway it always does
+    let onlink = |iface, nexthop: &Nexthop| matches!(nexthop, Nexthop::Via(gw) if gateway_is_offlink(runner, iface, gw));
+    let mut cmds = vec![
+        table_default_route_cmd(
+            &config.wan0,
+            TABLE_WAN0,
+            &gw0,
+            src0.as_deref(),
+            config.route_mode_wan0,
+            onlink(&config.wan0, &gw0),
+        ),
+        table_default_route_cmd(
+            &config.wan1,
+            TABLE_WAN1,
+            &gw1,
+            src1.as_deref(),
+            config.route_mode_wan1,
+            onlink(&config.wan1, &gw1),
+        ),
+    ];
+    if config.ipv6 {
+        for (iface, table, mode) in [
+            (&config.wan0, TABLE_WAN0, config.route_mode_wan0),
+            (&config.wan1, TABLE_WAN1, config.route_mode_wan1),
+        ] {
+            if let Ok(gw) = get_ipv6_gateway_for_iface(runner, iface) {
+                cmds.push(table_default_route6_cmd(iface, table, &gw, mode));
+            }
+        }
+    }
+    cmds.extend(link_route_cmds(runner, &config.wan0, TABLE_WAN0)?);
+    cmds.extend(link_route_cmds(runner, &config.wan1, TABLE_WAN1)?);
+    cmds.extend(config.extra_routes_wan0.iter().map(|r| r.cmd(TABLE_WAN0)));
+    cmds.extend(config.extra_routes_wan1.iter().map(|r| r.cmd(TABLE_WAN1)));
+    for selector in config.base_selectors() {
+        cmds.push(base_rule_cmd(
+            "add",
+            &selector,
+            base_table,
+            config.priorities.lan_default,
+        ));
+        if config.fallback_to_primary {
+            cmds.push(base_rule_cmd(
+                "add",
+                &selector,
+                base_table,
+                config.priorities.fallback(),
+            ));
+        }
+    }
+    if config.probe_table() {
+        for (iface, table) in [(&config.wan0, TABLE_WAN0), (&config.wan1, TABLE_WAN1)] {
+            cmds.push(probe_rule_cmd(
+                "add",
+                iface,
+                table,
+                config.priorities.lan_default,
+            ));
+        }
+    }
+    Ok(cmds)
+}
+
+/// Commands a fresh initialization would run, followed by the overrides
+/// needed to reproduce `mappings`. Nothing is executed except read-only
+/// discovery of gateways and link routes.
+fn build_plan(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    mappings: &std::collections::HashMap<String, String>,
+    dns_steered: &std::collections::HashMap<String, String>,
+) -> Result<Vec<IpCommand>> {
+    let mut cmds = init_plan(runner, config, config.base_table())?;
+
+    // From scratch there is nothing to delete; base WAN hosts ride the base
+    // rule unless PIN_PRIMARY gives them their own
+    let mut ips: Vec<_> = mappings
+        .iter()
+        .filter_map(|(ip, nic)| Some((ip, config.pin_table(nic)?)))
+        .collect();
+    ips.sort();
+    for (ip, table) in ips {
+        cmds.push(rule_add_cmd(
+            &mapping::selector(ip),
+            &config.host_match,
+            table,
+            config.priorities.specific,
+        ));
+    }
+    let mut steered: Vec<_> = dns_steered.iter().collect();
+    steered.sort();
+    for (ip, table) in steered {
+        cmds.extend(dns_rule_cmds(
+            "add",
+            &mapping::selector(ip),
+            &config.host_match,
+            table,
+            config.priorities.dns(),
+        ));
+    }
+    Ok(cmds)
+}
+
+async fn switch_handler(
+    Query(mut params): Query<SwitchParams>,
+    client: Option<ConnectInfo<std::net::SocketAddr>>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Time the whole request, including lock waits
+    let started = std::time::Instant::now();
+    // Debouncing, metrics and rejections all see the WAN, not the alias
+    params.nic = state.config.resolve_nic(&params.nic);
+    let nic = params.nic.clone();
+    let client = client.map(|ConnectInfo(addr)| addr.ip());
+    let lease = params.lease.clone();
+    if let Some(name) = &lease {
+        match resolve_lease(&state.config, &params.ip, name) {
+            Ok(ip) => params.ip = ip.to_string(),
+            Err(e) => {
+                observe_switch_duration("error", &nic, started.elapsed());
+                let requested = format!("lease:{}", name);
+                record_rejection(&state, client, &requested, &nic, &e).await;
+                return Err(e);
+            }
+        }
+    }
+    if superseded_switch(&params, &state).await {
+        observe_switch_duration("debounced", &nic, started.elapsed());
+        return Ok((
+            StatusCode::OK,
+            Json(ApiResponse {
+                status: "debounced".to_string(),
+                message: format!("Superseded by a later switch for {}", params.ip),
+                priority: None,
+                warnings: Vec::new(),
+                verification: None,
+            }),
+        ));
+    }
+    let ip = params.ip.clone();
+    let result = match inject_chaos(&state.config).await {
+        Ok(()) => {
+            let unlocked = has_lock_token(&headers, &state.config);
+            apply_switch(params, &state, unlocked).await
+        }
+        Err(e) => Err(e),
+    };
+    let outcome = if result.is_ok() { "success" } else { "error" };
+    observe_switch_duration(outcome, &nic, started.elapsed());
+    if let Err(e) = &result {
+        // Refusals are the caller's business; failures are the router's
+        let severity = if e.http_status.is_client_error() {
+            syslog::Severity::Warning
+        } else {
+            syslog::Severity::Err
+        };
+        syslog::send(
+            &state.config,
+            severity,
+            "switch_error",
+            &[
+                ("ip", &ip),
+                ("to", &nic),
+                ("code", e.code),
+                ("error", &e.message),
+            ],
+        );
+    }
+    match (&result, lease) {
+        (Ok(_), Some(name)) => {
+            let key = name.to_ascii_lowercase();
+            state.lease_pins.lock().unwrap().insert(key, ip);
+        }
+        (Err(e), _) if e.http_status.is_client_error() => {
+            record_rejection(&state, client, &ip, &nic, e).await;
+        }
+        _ => {}
+    }
+    result
+}
+
+/// The address `/switch?lease=<name>` refers to, read fresh from LEASES_FILE.
+fn resolve_lease(config: &Config, ip: &str, name: &str) -> Result<IpAddr, ApiError> {
+    let Some(leases) = &config.leases else {
+        return Err(ApiError::bad_request("lease requires LEASES_FILE"));
+    };
+    if !ip.is_empty() {
+        return Err(ApiError::bad_request("give either ip or lease, not both"));
+    }
+    let current = leases::read(&leases.path)
+        .map_err(|e| ApiError::internal("Failed to read leases file", e))?;
+    match leases::lookup(&current, name) {
+        leases::Lookup::Found(ip) => Ok(ip),
+        leases::Lookup::Missing => Err(ApiError::not_found(format!(
+            "no current lease for hostname '{}'",
+            name
+        ))),
+        leases::Lookup::Ambiguous(ips) => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "ambiguous_lease",
+            format!("hostname '{}' is leased to several hosts: {:?}", name, ips),
+        )),
+    }
+}
+
+/// A `/switch` request refused for what it asked, as listed by
+/// `/debug/rejections`.
+#[derive(Clone, Debug, Serialize)]
+struct Rejection {
+    at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<IpAddr>,
+    ip: String,
+    nic: String,
+    code: &'static str,
+    message: String,
+}
+
+const MAX_REJECTIONS: usize = 100;
+
+/// Log a rejected switch and, with DEBUG, remember the last `MAX_REJECTIONS`.
+async fn record_rejection(
+    state: &AppState,
+    client: Option<IpAddr>,
+    ip: &str,
+    nic: &str,
+    err: &ApiError,
+) {
+    let from = client.map_or_else(|| "unknown client".to_string(), |c| c.to_string());
+    eprintln!(
+        "Rejected /switch from {} (ip={:?} nic={:?}): {}: {}",
+        from, ip, nic, err.code, err.message
+    );
+    if !state.config.debug {
+        return;
+    }
+    let mut rejections = state.rejections.lock().await;
+    if rejections.len() == MAX_REJECTIONS {
+        rejections.pop_front();
+    }
+    rejections.push_back(Rejection {
+        at: unix_now(),
+        client,
+        ip: ip.to_string(),
+        nic: nic.to_string(),
+        code: err.code,
+        message: err.message.clone(),
+    });
+}
+
+/// Managed rules (those pointing at a WAN table) before and after one
+/// switch, as listed by `/debug/switches`.
+#[derive(Clone, Debug, Serialize)]
+struct SwitchDiff {
+    at: u64,
+    ip: String,
+    nic: String,
+    // Whether the switch itself succeeded; a failed one may still have changed rules
+    succeeded: bool,
+    removed: Vec<String>,
+    added: Vec<String>,
+}
+
+const MAX_SWITCH_DIFFS: usize = 100;
+
+/// `ip rule show` lines for rules in the WAN tables, whitespace-normalized.
+/// A failed listing yields nothing; the diff is only a debugging aid.
+fn managed_rule_lines(runner: &dyn CommandRunner) -> Vec<String> {
+    let out = ip_rule_list(runner).unwrap_or_default();
+    out.lines()
+        .filter(|line| {
+            parse_ip_rules(line)
+                .iter()
+                .any(|r| r.table == TABLE_WAN0 || r.table == TABLE_WAN1)
+        })
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect()
+}
+
+/// Log the rules a switch removed and added, and remember the last
+/// `MAX_SWITCH_DIFFS` (DEBUG only).
+async fn record_switch_diff(
+    state: &AppState,
+    ip: &str,
+    nic: &str,
+    before: Vec<String>,
+    succeeded: bool,
+) {
+    let after = managed_rule_lines(state.runner.as_ref());
+    let diff = SwitchDiff {
+        at: unix_now(),
+        ip: ip.to_string(),
+        nic: nic.to_string(),
+        succeeded,
+        removed: before
+            .iter()
+            .filter(|l| !after.contains(l))
+            .cloned()
+            .collect(),
+        added: after
+            .iter()
+            .filter(|l| !before.contains(l))
+            .cloned()
+            .collect(),
+    };
+    println!(
+        "Switch {} -> {} changed rules: removed {:?}, added {:?}",
+        ip, nic, diff.removed, diff.added
+    );
+    let mut diffs = state.switch_diffs.lock().await;
+    if diffs.len() == MAX_SWITCH_DIFFS {
+        diffs.pop_front();
+    }
+    diffs.push_back(diff);
+}
+
+async fn switch_diffs_handler(state: axum::extract::State<AppState>) -> impl IntoResponse {
+    let diffs = state.switch_diffs.lock().await;
+    Json(serde_json::json!({ "switches": *diffs }))
+}
+
+async fn rejections_handler(state: axum::extract::State<AppState>) -> impl IntoResponse {
+    let rejections = state.rejections.lock().await;
+    Json(serde_json::json!({ "rejections": *rejections }))
+}
+
+#[derive(Deserialize)]
+struct DebugGatewayParams {
+    iface: String,
+}
+
+/// Run gateway discovery against any interface, configured as a WAN or not.
+/// A failure is reported with the default routes discovery had to work with.
+async fn debug_gateway_handler(
+    Query(params): Query<DebugGatewayParams>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let iface = Interface::new(params.iface.trim())
+        .map_err(|e| ApiError::bad_request(format!("Invalid iface: {}", e)))?;
+    let runner = state.runner.as_ref();
+    if let Err(e) = runner.run("ip", &["link", "show", "dev", &iface]) {
+        return Err(ApiError::not_found(format!(
+            "No interface {}: {:#}",
+            iface, e
+        )));
+    }
+    Ok(Json(match get_default_gateway_for_iface(runner, &iface) {
+        Ok(gateway) => serde_json::json!({
+            "interface": iface.to_string(),
+            "gateway": gateway.to_string(),
+        }),
+        Err(e) => serde_json::json!({
+            "interface": iface.to_string(),
+            "error": format!("{:#}", e),
+            "default_routes": runner
+                .run("ip", &["route", "show", "default"])
+                .map(|out| route_entries(&out))
+                .unwrap_or_default(),
+        }),
+    }))
+}
+
+#[derive(Deserialize)]
+struct RouteGetParams {
+    dest: String,
+    from: String,
+}
+
+/// What the kernel decides for traffic from a LAN host to `dest`, with every
+/// rule and route taken into account. Needs X-Diag-Token.
+async fn route_get_handler(
+    headers: HeaderMap,
+    Query(params): Query<RouteGetParams>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<verify::RouteGet>, ApiError> {
+    let token = state.config.diag_token.as_deref().unwrap_or_default();
+    if headers.get("x-diag-token").map(|v| v.as_bytes()) != Some(token.as_bytes()) {
+        return Err(ApiError::forbidden("X-Diag-Token is missing or wrong"));
+    }
+    let addr = |name: &str, value: &str| {
+        parse_host_ip(value.trim())
+            .ok_or_else(|| ApiError::bad_request(format!("Invalid {}: {}", name, value)))
+    };
+    let dest = addr("dest", &params.dest)?;
+    let from = addr("from", &params.from)?;
+    verify::route_get(state.runner.as_ref(), &state.config, dest, from)
+        .map(Json)
+        .map_err(|e| {
+            ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "no_route",
+                format!("No route to {} from {}: {:#}", dest, from, e),
+            )
+        })
+}
+
+/// In chaos test mode, delay and possibly fail a switch before it touches the
+/// kernel.
+async fn inject_chaos(config: &Config) -> Result<(), ApiError> {
+    use rand::Rng;
+
+    let Some(chaos) = &config.chaos else {
+        return Ok(());
+    };
+    let (delay, fail) = {
+        let mut rng = rand::thread_rng();
+        (
+            rng.gen_range(std::time::Duration::ZERO..=chaos.max_delay),
+            rng.gen_bool(chaos.fail_rate),
+        )
+    };
+    tokio::time::sleep(delay).await;
+    if fail {
+        eprintln!("CHAOS: failing /switch after {:?}", delay);
+        return Err(ApiError::chaos());
+    }
+    Ok(())
+}
+
+/// With SWITCH_DEBOUNCE_MS set, wait out the window and report whether a
+/// later request for the same host arrived meanwhile; only the last one of a
+/// burst is applied. Unparseable addresses are left to `apply_switch`.
+async fn superseded_switch(params: &SwitchParams, state: &AppState) -> bool {
+    let (Some(window), Some(addr)) = (state.config.switch_debounce, parse_switch_ip(&params.ip))
+    else {
+        return false;
+    };
+    let host = addr.to_string();
+    let seq = SWITCH_SEQ.fetch_add(1, Ordering::SeqCst);
+    state
+        .pending_switches
+        .lock()
+        .await
+        .insert(host.clone(), seq);
+    tokio::time::sleep(window).await;
+    let mut pending = state.pending_switches.lock().await;
+    if pending.get(&host) != Some(&seq) {
+        return true;
+    }
+    pending.remove(&host);
+    false
+}
+
+/// Apply a switch; with `unlocked`, also for hosts that are locked.
+async fn apply_switch(
+    mut params: SwitchParams,
+    state: &AppState,
+    unlocked: bool,
+) -> Result<(StatusCode, Json<ApiResponse>), ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    if state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::paused());
+    }
+    params.nic = state.config.resolve_nic(&params.nic);
+    if params.nic == "auto" {
+        if state.config.health.is_none() {
+            return Err(ApiError::bad_request(
+                "nic=auto needs health probing; set HEALTH_TARGET",
+            ));
+        }
+        let health = state.health.lock().await;
+        let (nic, reason) = auto_select::pick(&health.effective_wans(), health.failover.active);
+        params.nic = nic;
+        params.auto_reason = Some(reason);
+    }
+    if params.nic != "wan0" && params.nic != "wan1" {
+        return Err(ApiError::bad_request(
+            "nic must be 'wan0', 'wan1' or 'auto'",
+        ));
+    }
+    if state.health.lock().await.disabled.contains(&params.nic) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "wan_disabled",
+            format!("{} is disabled for maintenance", params.nic),
+        ));
+    }
+    let except = exceptions::parse(params.except.as_deref().unwrap_or_default())?;
+    let tags = match params.tags.as_deref().map(mapping::parse_tags) {
+        Some(Ok(tags)) => Some(tags),
+        Some(Err(e)) => return Err(ApiError::bad_request(format!("Invalid tags: {:#}", e))),
+        None => None,
+    };
+
+    // Parse IP address - expecting format like "10.40.0.3/20"
+    let addr = parse_switch_ip(&params.ip).ok_or_else(|| {
+        ApiError::bad_request(
+            "Invalid IP format. Expected: IP or IP/subnet (e.g., 10.40.0.3 or 10.40.0.3/20)",
+        )
+    })?;
+    // Any length broader than SWITCH_MIN_PREFIX_LEN is refused, whatever
+    // address it is written with. A network address with a length is
+    // pinned as that prefix; on a host address the `/len` is ignored. A
+    // prefix that takes in a whole LAN subnet (10.40.0.0/20, 10.0.0.0/8) was
+    // most likely not meant to move everyone
+    let given = params.ip.parse::<IpNet>().ok().map(canonical_net);
+    if let (Some(net), Some(min)) = (given, state.config.min_prefix_len) {
+        if net.prefix_len() < min {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "prefix_too_broad",
+                format!("{} is broader than /{} (SWITCH_MIN_PREFIX_LEN)", net, min),
+            ));
+        }
+    }
+    let prefix = switch_prefix(&params.ip);
+    let base_ip = &prefix.map_or_else(|| addr.to_string(), |net| mapping::host(&net));
+    let whole_lan = prefix.and_then(|net| Some((net, state.config.lan_subnet_within(net)?)));
+    let whole_lan = match whole_lan {
+        // Its rule would be the LAN base rule itself, which the switch
+        // would delete and replace
+        Some((net, subnet)) if net == subnet => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "whole_lan",
+                format!(
+                    "{} is the LAN subnet itself; it follows the base rule, not a pin",
+                    net
+                ),
+            ));
+        }
+        Some((net, subnet)) if !params.force => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "whole_lan",
+                format!(
+                    "{} covers the whole LAN subnet {}; add force=true to pin it anyway",
+                    net, subnet
+                ),
+            ));
+        }
+        Some((net, subnet)) => Some(format!(
+            "{} covers the whole LAN subnet {}; pinned anyway (force=true)",
+            net, subnet
+        )),
+        None => None,
+    };
+    let outside_lan = match state.config.subnet_policy {
+        _ if state.config.in_lan(addr) => None,
+        SubnetPolicy::Strict => {
+            return Err(ApiError::bad_request(format!(
+                "{} is not inside any LAN subnet",
+                base_ip
+            )))
+        }
+        SubnetPolicy::Warn => Some(format!(
+            "{} is not inside any LAN subnet; switched anyway (SWITCH_SUBNET_POLICY=warn)",
+            base_ip
+        )),
+        SubnetPolicy::Any => None,
+    };
+    if !state.config.switch_permitted(addr) {
+        return Err(ApiError::forbidden(format!(
+            "{} is not permitted by SWITCH_ALLOW/SWITCH_DENY",
+            base_ip
+        )));
+    }
+    if !unlocked && state.locked.lock().unwrap().contains(base_ip) {
+        return Err(ApiError::locked(base_ip));
+    }
+
+    // Policy routing approach:
+    // - Default: entire 10.40.0.0/20 goes to wan0 via routing table 100
+    // - Override: specific /32 can be forced to wan1 via table 200
+    let mut dns_steered = state.dns_steered.lock().await;
+    // With DEBUG, record what the switch actually changed in the kernel
+    let before = state
+        .config
+        .debug
+        .then(|| managed_rule_lines(state.runner.as_ref()));
+    let mut result =
+        switch_host_rules(state, &params, base_ip, &except, tags, &mut dns_steered).await;
+    if let Some(before) = before {
+        record_switch_diff(state, base_ip, &params.nic, before, result.is_ok()).await;
+    }
+    if let Ok((_, Json(response))) = &mut result {
+        for warning in outside_lan.into_iter().chain(whole_lan) {
+            eprintln!(
+                "Warning: switch of {} to {}: {}",
+                base_ip, params.nic, warning
+            );
+            response.warnings.push(warning);
+        }
+    }
+    result
+}
+
+/// The kernel side of a validated switch: replace the host's rules and
+/// update the mappings.
+async fn switch_host_rules(
+    state: &AppState,
+    params: &SwitchParams,
+    base_ip: &str,
+    except: &[ipnet::Ipv4Net],
+    tags: Option<mapping::Tags>,
+    dns_steered: &mut std::collections::HashMap<String, String>,
+) -> Result<(StatusCode, Json<ApiResponse>), ApiError> {
+    // The host's /32, or the pinned prefix itself
+    let target_ip = mapping::selector(base_ip);
+
+    // First, clear any existing per-IP rules for both tables. Exception and
+    // DNS rules go first: a plain `rule del` would otherwise match them as well.
+    let runner = state.runner.as_ref();
+    let host_match = &state.config.host_match;
+    // A mapping of another prefix covering the host, or covered by it, is
+    // settled by MAPPING_OVERLAP_POLICY before anything changes. Under
+    // `replace` it is only dropped once the host's own rules are in, so a
+    // failed switch leaves it as it was.
+    let prefix = mapping::parse_prefix(base_ip);
+    if let Some(prefix) = prefix {
+        mapping::check_claim(
+            &*state.mappings.lock().await,
+            prefix,
+            state.config.overlap_policy,
+        )
+        .map_err(|e| ApiError::new(StatusCode::CONFLICT, "prefix_overlap", format!("{:#}", e)))?;
+    }
+    exceptions::clear(state, base_ip);
+    if let Some(table) = dns_steered.remove(base_ip) {
+        let prio = state.config.priorities.dns();
+        for cmd in dns_rule_cmds("del", &target_ip, host_match, &table, prio) {
+            let _ = cmd.run(runner);
+        }
+    }
+    for table in [TABLE_WAN0, TABLE_WAN1] {
+        del_ip_rule_checked(runner, &state.config, &target_ip, table)
+            .map_err(|e| ApiError::internal("Failed to delete policy rule", e))?;
+    }
+
+    // The non-base WAN always gets a specific rule; the base WAN relies on
+    // the default LAN rule unless PIN_PRIMARY asks for one
+    let pin_table = state.config.pin_table(&params.nic);
+    let priority = match pin_table {
+        Some(table) => {
+            let prio = state.config.priorities.specific;
+            match add_ip_rule(runner, &target_ip, host_match, table, prio) {
+                Ok(priority) => Some(priority),
+                Err(e) => return Err(ApiError::internal("Failed to add policy rule", e)),
+            }
+        }
+        None => None,
+    };
+    let iface = if params.nic == "wan1" {
+        &state.config.wan1
+    } else {
+        &state.config.wan0
+    };
+    let mut message = match priority {
+        Some(priority) => format!(
+            "Routed {} to {} ({}) via policy at priority {}",
+            target_ip, params.nic, iface, priority
+        ),
+        None => format!(
+            "Routed {} to {} ({}) via default policy",
+            target_ip, params.nic, iface
+        ),
+    };
+
+    if params.dns.unwrap_or(state.config.dns_steering) {
+        let table = nic_table(&params.nic);
+        let prio = state.config.priorities.dns();
+        for cmd in dns_rule_cmds("add", &target_ip, host_match, table, prio) {
+            if let Err(e) = cmd.run(runner) {
+                return Err(ApiError::internal("Failed to add DNS steering rule", e));
+            }
+        }
+        dns_steered.insert(base_ip.to_string(), table.to_string());
+        message.push_str(", DNS steered");
+    }
+
+    if !except.is_empty() {
+        if let Err(e) = exceptions::check_order(state.config.priorities, priority) {
+            return Err(ApiError::new(StatusCode::CONFLICT, "exception_order", e));
+        }
+        if let Err(e) = exceptions::install(state, base_ip, &params.nic, except) {
+            return Err(ApiError::internal("Failed to add destination exception", e));
+        }
+        let other = if params.nic == "wan1" { "wan0" } else { "wan1" };
+        message.push_str(&format!(
+            ", {} destination exception(s) via {}",
+            except.len(),
+            other
+        ));
+    }
+
+    let mut mappings = state.mappings.lock().await;
+    // A manual switch takes the host out of auto-balance's hands
+    let mut auto_assigned = state.auto_assigned.lock().await;
+    auto_assigned.remove(base_ip);
+    // Checked above; switches are serialized by the dns_steered lock, so
+    // under `reject` nothing overlapping has appeared since
+    let overlapped = prefix
+        .and_then(|prefix| mapping::claim(&mut mappings, prefix, state.config.overlap_policy).ok())
+        .unwrap_or_default();
+    for net in &overlapped {
+        let from = net.to_string();
+        del_ip_rule_quiet(runner, &from, host_match, TABLE_WAN0);
+        del_ip_rule_quiet(runner, &from, host_match, TABLE_WAN1);
+        auto_assigned.remove(&mapping::host(net));
+    }
+    if !overlapped.is_empty() {
+        let list: Vec<String> = overlapped.iter().map(|net| net.to_string()).collect();
+        message.push_str(&format!(", replacing the mapping of {}", list.join(", ")));
+    }
+    // Manual pins are never refused; they only push out auto ones
+    make_room(state, &mut mappings, &mut auto_assigned, base_ip);
+    let old_nic = mapping::pin(
+        &mut mappings,
+        base_ip,
+        &params.nic,
+        priority,
+        match params.auto_reason {
+            Some(_) => mapping::Origin::AutoSelect,
+            None => mapping::Origin::Switch,
+        },
+    );
+    if let (Some(tags), Some(net)) = (tags, prefix) {
+        if let Some(mapping) = mappings.get_mut(&net) {
+            mapping.tags = tags;
+        }
+    }
+    match &params.auto_reason {
+        Some(reason) => {
+            message.push_str(&format!(" (auto: {})", reason));
+            let pin = auto_select::AutoPin {
+                nic: params.nic.clone(),
+                reason: reason.clone(),
+                chosen_at: unix_now(),
+            };
+            state
+                .auto_pins
+                .lock()
+                .unwrap()
+                .insert(base_ip.to_string(), pin);
+        }
+        None => {
+            state.auto_pins.lock().unwrap().remove(base_ip);
+        }
+    }
+    persist::save(state, &mappings, &auto_assigned);
+    let mut health = state.health.lock().await;
+    // A manual pin replaces any temporary host failover move
+    health.moved_hosts.remove(base_ip);
+    // Unmapped hosts were riding the base rule
+    let old_nic = old_nic.unwrap_or_else(|| health.failover.active.to_string());
+    spawn_post_switch_hook(state, base_ip, &old_nic, &params.nic);
+    syslog::send(
+        &state.config,
+        syslog::Severity::Notice,
+        "switch",
+        &[("ip", base_ip), ("from", &old_nic), ("to", &params.nic)],
+    );
+
+    // A prefix is probed through its network address
+    let probe = prefix.map_or_else(|| base_ip.to_string(), |net| net.addr().to_string());
+    let mut warnings = if state.config.asymmetry_check {
+        asymmetry::check(runner, &state.config, &probe, iface)
+    } else {
+        Vec::new()
+    };
+    // A rule that was already in place keeps its priority
+    warnings.extend(priority.and_then(|prio| state.config.outside_band(prio)));
+    let verification = params.verify.then(|| {
+        let (verification, warning) = verify::egress(runner, &state.config, &probe, iface);
+        warnings.extend(warning);
+        verification
+    });
+    for warning in &warnings {
+        eprintln!(
+            "Warning: switch of {} to {}: {}",
+            base_ip, params.nic, warning
+        );
+    }
+    let response = ApiResponse {
+        status: "success".to_string(),
+        message,
+        priority,
+        warnings,
+        verification,
+    };
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+/// Run POST_SWITCH_HOOK in the background as `<hook> <ip> <old nic> <new nic>`.
+/// A failing hook is logged and otherwise ignored.
+fn spawn_post_switch_hook(state: &AppState, ip: &str, old_nic: &str, new_nic: &str) {
+    let Some(hook) = state.config.post_switch_hook.clone() else {
+        return;
+    };
+    let runner = state.runner.clone();
+    let args = [ip.to_string(), old_nic.to_string(), new_nic.to_string()];
+    tokio::task::spawn_blocking(move || {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        if let Err(e) = runner.run(&hook, &args) {
+            eprintln!("POST_SWITCH_HOOK {} {:?} failed: {:#}", hook, args, e);
+        }
+    });
+}
+
+async fn status_handler(
+    Query(format): Query<FormatParams>,
+    Query(params): Query<StatusParams>,
+    Query(page_params): Query<page::PageParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    // Same lock order as switch_handler: dns_steered, mappings, auto_assigned
+    let mut dns_steered: Vec<String> = state.dns_steered.lock().await.keys().cloned().collect();
+    dns_steered.sort();
+    let mappings = state.mappings.lock().await;
+    let mut listed: Vec<(IpNet, mapping::Mapping)> =
+        mappings.iter().map(|(ip, m)| (*ip, m.clone())).collect();
+    listed.sort_by_key(|(ip, _)| *ip);
+    let (listed, mappings_page) = page::paginate(listed, &page_params)?;
+    let listed: mapping::Mappings = listed.into_iter().collect();
+    let mut auto_assigned: Vec<String> = state.auto_assigned.lock().await.keys().cloned().collect();
+    auto_assigned.sort();
+    let (base_wan, last_failover_at, wan_health, moved_hosts, host_failovers, disabled_wans) = {
+        let health = state.health.lock().await;
+        (
+            health.failover.active,
+            health.failover.changed_at,
+            health.wans.clone(),
+            health.moved_hosts.clone(),
+            health.host_failovers(&mappings),
+            health.disabled.iter().cloned().collect(),
+        )
+    };
+    let init_report = state.init_report.lock().await;
+    let gateways = match &*init_report {
+        Some(report) => report
+            .wans
+            .iter()
+            .map(|(nic, wan)| (nic.clone(), wan.nexthops.clone()))
+            .collect(),
+        None => Default::default(),
+    };
+    let drift = state.drift.lock().unwrap().clone();
+    let paused = state.paused.load(Ordering::SeqCst);
+    let overall = overall::Signals {
+        initialized: init_report.is_some(),
+        health: &wan_health,
+        drift: !drift.is_empty(),
+        failed_over: base_wan != state.config.base_wan,
+        paused,
+    }
+    .overall(&state.config.overall_degraded_on);
+    let status = StatusResponse {
+        overall,
+        instance: state.config.instance_name.clone(),
+        generated_at: unix_now(),
+        started_at: state.started_at,
+        uptime_secs: state.started.elapsed().as_secs(),
+        reconciled_at: Some(state.reconciled_at.load(Ordering::SeqCst)).filter(|&at| at > 0),
+        last_failover_at,
+        mappings: if params.detailed {
+            StatusMappings::Detailed(mapping::detailed(&listed))
+        } else {
+            StatusMappings::Nics(mapping::nics(&listed))
+        },
+        mappings_page,
+        config: StatusConfig {
+            wan0: state.config.wan0.to_string(),
+            wan1: state.config.wan1.to_string(),
+            lan: state.config.lan.to_string(),
+            lan_subnets: state.config.lan_subnets.clone(),
+            switch_allow: state.config.switch_allow.clone(),
+            switch_deny: state.config.switch_deny.clone(),
+            subnet_policy: state.config.subnet_policy,
+            overlap_policy: state.config.overlap_policy,
+            min_prefix_len: state.config.min_prefix_len,
+            priorities: state.config.priorities,
+            priority_band: state.config.priority_band,
+            nic_aliases: state.config.nic_aliases.clone(),
+        },
+        sources: (*state.sources).clone(),
+        dns_steered,
+        auto_assigned,
+        rule_priorities: mapping::priorities(&listed),
+        mapping_count: mappings.len(),
+        max_mappings: state.config.max_mappings,
+        paused,
+        base_wan: base_wan.to_string(),
+        health: wan_health,
+        moved_hosts,
+        host_failovers,
+        disabled_wans,
+        locked: state.locked.lock().unwrap().iter().cloned().collect(),
+        lease_pins: state.lease_pins.lock().unwrap().clone(),
+        gateways,
+        auto_pins: state.auto_pins.lock().unwrap().clone(),
+        schedules: {
+            let now = unix_now();
+            let offset = state.config.schedule_utc_offset;
+            state
+                .schedules
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, s)| (name.clone(), s.status(now, offset)))
+                .collect()
+        },
+        exceptions: state.exceptions.lock().unwrap().clone(),
+        dscp_policies: state.dscp_policies.lock().unwrap().clone(),
+        read_only: state.config.read_only,
+        drift,
+        commands: CommandStats {
+            in_flight: CMD_IN_FLIGHT.load(Ordering::SeqCst),
+            queued: cmd_limit::QUEUED.load(Ordering::SeqCst),
+            total: CMD_TOTAL.load(Ordering::SeqCst),
+        },
+    };
+    negotiate(&headers, &format, &status)
+}
+
+async fn version_handler(state: axum::extract::State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "version": version::VERSION,
+        "instance": state.config.instance_name,
+    }))
+}
+
+async fn capabilities_handler(
+    Query(format): Query<FormatParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    let versions = state
+        .versions
+        .get_or_init(|| capabilities::Versions::probe(state.runner.as_ref()));
+    negotiate(
+        &headers,
+        &format,
+        &capabilities::describe(&state.config, versions),
+    )
+}
+
+async fn metrics_handler(state: axum::extract::State<AppState>) -> impl IntoResponse {
+    let mut body = format!(
+        "# HELP adaptive_commands_in_flight External commands currently executing.\n\
+         # TYPE adaptive_commands_in_flight gauge\n\
+         adaptive_commands_in_flight {}\n\
+         # HELP adaptive_commands_queued External commands waiting for MAX_CONCURRENT_COMMANDS.\n\
+         # TYPE adaptive_commands_queued gauge\n\
+         adaptive_commands_queued {}\n\
+         # HELP adaptive_commands_total External commands executed since start.\n\
+         # TYPE adaptive_commands_total counter\n\
+         adaptive_commands_total {}\n",
+        CMD_IN_FLIGHT.load(Ordering::SeqCst),
+        cmd_limit::QUEUED.load(Ordering::SeqCst),
+        CMD_TOTAL.load(Ordering::SeqCst)
+    );
+    body.push_str(
+        "# HELP adaptive_switch_duration_seconds Time from /switch request receipt to response.\n\
+         # TYPE adaptive_switch_duration_seconds histogram\n",
+    );
+    for ((outcome, nic), histogram) in SWITCH_DURATION.lock().unwrap().iter() {
+        let labels = format!("outcome=\"{}\",nic=\"{}\"", outcome, nic);
+        histogram.render("adaptive_switch_duration_seconds", &labels, &mut body);
+    }
+    body.push_str(&format!(
+        "# HELP adaptive_mappings Hosts currently mapped to a WAN.\n\
+         # TYPE adaptive_mappings gauge\n\
+         adaptive_mappings {}\n\
+         # HELP adaptive_mappings_evicted_total Auto mappings evicted to stay under MAX_MAPPINGS.\n\
+         # TYPE adaptive_mappings_evicted_total counter\n\
+         adaptive_mappings_evicted_total {}\n",
+        state.mappings.lock().await.len(),
+        MAPPINGS_EVICTED.load(Ordering::SeqCst)
+    ));
+    let wans = state.health.lock().await.wans.clone();
+    if !wans.is_empty() {
+        body.push_str(
+            "# HELP adaptive_wan_loss_ratio Share of recent health probes that went unanswered.\n\
+             # TYPE adaptive_wan_loss_ratio gauge\n",
+        );
+        for (nic, wan) in &wans {
+            body.push_str(&format!(
+                "adaptive_wan_loss_ratio{{nic=\"{}\"}} {}\n",
+                nic, wan.loss_ratio
+            ));
+        }
+        body.push_str(
+            "# HELP adaptive_wan_rtt_ms Mean round-trip time of recent answered health probes.\n\
+             # TYPE adaptive_wan_rtt_ms gauge\n",
+        );
+        // A WAN with no answered probe in the window has no RTT to report
+        for (nic, wan) in &wans {
+            if let Some(rtt) = wan.rtt_ms {
+                body.push_str(&format!("adaptive_wan_rtt_ms{{nic=\"{}\"}} {}\n", nic, rtt));
+            }
+        }
+    }
+    if let Some(max) = state.config.max_mappings {
+        body.push_str(&format!(
+            "# HELP adaptive_mappings_max Configured MAX_MAPPINGS.\n\
+             # TYPE adaptive_mappings_max gauge\n\
+             adaptive_mappings_max {}\n",
+            max
+        ));
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+const AUTO_BALANCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Addresses in `ip neigh show` output that currently resolve to a host.
+fn parse_neighbors(out: &str) -> Vec<IpAddr> {
+    out.lines()
+        .filter(|line| !line.contains("FAILED") && !line.contains("INCOMPLETE"))
+        .filter_map(|line| line.split_whitespace().next()?.parse().ok())
+        .collect()
+}
+
+/// Map `roll` (uniform in `0..total weight`) onto a WAN.
+fn pick_weighted(weights: &[(String, u32)], mut roll: u32) -> &str {
+    for (nic, weight) in weights {
+        if roll < *weight {
+            return nic;
+        }
+        roll -= weight;
+    }
+    // Only reachable with an out-of-range roll
+    &weights[weights.len() - 1].0
+}
+
+/// Assign every neighbor on the LAN that has no mapping yet to a WAN chosen
+/// by weight. Returns the assignments made.
+async fn auto_balance_once(
+    state: &AppState,
+    weights: &[(String, u32)],
+) -> Result<Vec<(String, String)>> {
+    use rand::Rng;
+
+    let runner = state.runner.as_ref();
+    let out = runner.run("ip", &["-4", "neigh", "show", "dev", &state.config.lan])?;
+    let total: u32 = weights.iter().map(|(_, w)| w).sum();
+
+    let mut mappings = state.mappings.lock().await;
+    let mut auto_assigned = state.auto_assigned.lock().await;
+    let mut assigned = Vec::new();
+    let count_before = mappings.len();
+    for ip in parse_neighbors(&out) {
+        let host = ip.to_string();
+        // Still around: keep it off the eviction list
+        if let Some(touched) = auto_assigned.get_mut(&host) {
+            *touched = std::time::Instant::now();
+        }
+        // A host inside a pinned prefix already follows it
+        if mappings.keys().any(|net| net.contains(&ip))
+            || !state.config.in_lan(ip)
+            || !state.config.switch_permitted(ip)
+            || state.locked.lock().unwrap().contains(&host)
+        {
+            continue;
+        }
+        if !make_room(state, &mut mappings, &mut auto_assigned, &host) {
+            println!(
+                "Not auto-balancing {}: MAX_MAPPINGS reached with only manual mappings",
+                host
+            );
+            continue;
+        }
+        let nic = pick_weighted(weights, rand::thread_rng().gen_range(0..total));
+        // Pin the same way a manual switch would
+        let priority = match state.config.pin_table(nic) {
+            Some(table) => Some(
+                add_ip_rule(
+                    runner,
+                    &mapping::selector(&host),
+                    &state.config.host_match,
+                    table,
+                    state.config.priorities.specific,
+                )
+                .with_context(|| format!("pin {} to {}", host, nic))?,
+            ),
+            None => None,
+        };
+        println!("Auto-balanced {} -> {}", host, nic);
+        mapping::pin(
+            &mut mappings,
+            &host,
+            nic,
+            priority,
+            mapping::Origin::AutoBalance,
+        );
+        auto_assigned.insert(host.clone(), std::time::Instant::now());
+        assigned.push((host, nic.to_string()));
+    }
+    // Evictions alone can change the table too
+    if !assigned.is_empty() || mappings.len() != count_before {
+        persist::save(state, &mappings, &auto_assigned);
+    }
+    Ok(assigned)
+}
+
+/// With MAX_MAPPINGS set, evict least-recently-touched auto mappings (and
+/// their rules) until `incoming` fits. Returns false if only manual mappings
+/// are left to evict.
+fn make_room(
+    state: &AppState,
+    mappings: &mut mapping::Mappings,
+    auto_assigned: &mut std::collections::HashMap<String, std::time::Instant>,
+    incoming: &str,
+) -> bool {
+    let Some(max) = state.config.max_mappings else {
+        return true;
+    };
+    let runner = state.runner.as_ref();
+    while mappings.len() >= max && !mapping::contains(mappings, incoming) {
+        let Some(oldest) = auto_assigned
+            .iter()
+            .min_by_key(|(_, touched)| **touched)
+            .map(|(host, _)| host.clone())
+        else {
+            return false;
+        };
+        let target = mapping::selector(&oldest);
+        exceptions::clear(state, &oldest);
+        del_ip_rule_quiet(runner, &target, &state.config.host_match, TABLE_WAN0);
+        del_ip_rule_quiet(runner, &target, &state.config.host_match, TABLE_WAN1);
+        auto_assigned.remove(&oldest);
+        mapping::remove(mappings, &oldest);
+        MAPPINGS_EVICTED.fetch_add(1, Ordering::SeqCst);
+        println!("Evicted auto mapping {} (MAX_MAPPINGS={})", oldest, max);
+    }
+    true
+}
+
+async fn auto_balance_loop(state: AppState, weights: Vec<(String, u32)>) {
+    let mut interval = tokio::time::interval(AUTO_BALANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if state.paused.load(Ordering::SeqCst) {
+            continue;
+        }
+        if let Err(e) = auto_balance_once(&state, &weights).await {
+            eprintln!("Auto-balance failed: {:#}", e);
+        }
+    }
+}
+
+/// State recovered from rules already present in the kernel.
+#[derive(Debug, Default)]
+struct AdoptedRules {
+    mappings: mapping::Mappings,
+    dns_steered: std::collections::HashMap<String, String>,
+    dscp_policies: dscp::DscpPolicies,
+}
+
+/// Mappings (DNS steering, DSCP policies) implied by host rules already present in the
+/// managed tables, e.g. left behind by a previous run.
+fn adopt_existing_rules(runner: &dyn CommandRunner, config: &Config) -> Result<AdoptedRules> {
+    let adopted = adopt_rules(config, &ip_rules(runner)?);
+    let mut hosts: Vec<_> = adopted.mappings.iter().collect();
+    hosts.sort_by_key(|(ip, _)| **ip);
+    for (net, mapping) in hosts {
+        let host = mapping::host(net);
+        let prio = mapping.priority.unwrap_or_default();
+        println!(
+            "Adopted existing rule: {} -> {} (priority {})",
+            host, mapping.nic, prio
+        );
+        if let Some(warning) = config.outside_band(prio) {
+            eprintln!("Warning: adopted rule for {}: {}", host, warning);
+        }
+    }
+    Ok(adopted)
+}
+
+/// What `rules` imply for the service's state, as `adopt_existing_rules`.
+fn adopt_rules(config: &Config, rules: &[IpRule]) -> AdoptedRules {
+    let mut adopted = AdoptedRules {
+        dscp_policies: dscp::adopt(config, rules),
+        ..Default::default()
+    };
+    for rule in rules {
+        let nic = match rule.table.as_str() {
+            TABLE_WAN0 => "wan0",
+            TABLE_WAN1 => "wan1",
+            _ => continue,
+        };
+        let host = rule.from.as_str();
+        // Pinned prefixes are taken back too, but not the LAN base rules
+        match mapping::parse_prefix(host) {
+            Some(net) if config.mappable(net.addr()) && !config.is_lan_rule(rule) => {}
+            _ => continue,
+        }
+        if rule.dport.as_deref() == Some("53") {
+            adopted
+                .dns_steered
+                .insert(host.to_string(), rule.table.clone());
+        } else if config.host_match.matches(rule) {
+            mapping::pin(
+                &mut adopted.mappings,
+                host,
+                nic,
+                Some(rule.priority),
+                mapping::Origin::Adopted,
+            );
+        }
+    }
+    adopted
+}
+
+/// Rules in the service's tables that the current state does not account for:
+/// host rules with no matching mapping, LAN rules at unexpected priorities,
+/// plus repeated copies of a rule.
+fn stale_rules(
+    config: &Config,
+    rules: &[IpRule],
+    mappings: &std::collections::HashMap<String, String>,
+    base_table: &str,
+) -> Vec<IpRule> {
+    let selectors = config.base_selectors();
+    // The base rule follows failover; the fallback always points at the base WAN
+    let mut expected = vec![(base_table, config.priorities.lan_default)];
+    if config.fallback_to_primary {
+        expected.push((config.base_table(), config.priorities.fallback()));
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut stale = Vec::new();
+    for rule in rules {
+        let nic = match rule.table.as_str() {
+            TABLE_WAN0 => "wan0",
+            TABLE_WAN1 => "wan1",
+            _ => continue,
+        };
+        let host = rule.from.as_str();
+        // DSCP policies are only removed through /policy/dscp
+        if dscp::policy_of(config, rule).is_some() {
+            continue;
+        }
+        if config.is_lan_rule(rule) {
+            // Rules of the other LAN_RULE_MODE are leftovers too
+            let prio = rule.priority;
+            let is_expected = selectors.iter().any(|s| s.matches(rule))
+                && expected.contains(&(rule.table.as_str(), prio));
+            let key = format!("{:?} {:?}", rule.iif, prio);
+            if !is_expected || !seen.insert((rule.from.clone(), rule.table.clone(), key)) {
+                stale.push(rule.clone());
+            }
+            continue;
+        }
+        if mapping::parse_prefix(host).is_none() {
+            continue;
+        }
+        // As are host rules of the other HOST_RULE_MATCH/HOST_RULE_FWMARK
+        let match_keys = format!("{:?} {:?}", rule.ipproto, rule.dport);
+        if !config.host_match.keys_match(rule)
+            || !seen.insert((host.to_string(), rule.table.clone(), match_keys))
+            || mappings.get(host).map(String::as_str) != Some(nic)
+        {
+            stale.push(rule.clone());
+        }
+    }
+    stale
+}
+
+/// A rule in one of the service's tables, as reported by `/rules`.
+#[derive(Debug, Serialize)]
+struct ManagedRule {
+    #[serde(flatten)]
+    rule: IpRule,
+    nic: &'static str,
+    // "lan" (subnet base/fallback), "host" (pin), "dns" (DNS steering),
+    // "exception" (destination exception), "dscp" (DSCP policy) or "probe"
+    // (a WAN's own traffic, HEALTH_PROBE_TABLE)
+    kind: &'static str,
+    // Whether the rule matches current state: a configured LAN subnet, a
+    // host mapped to this WAN, a host whose DNS is steered to this table, a
+    // recorded exception or a recorded DSCP policy
+    known: bool,
+}
+
+/// `/rules`: one page of the rules, by priority.
+#[derive(Debug, Serialize)]
+struct RulesResponse {
+    rules: Vec<ManagedRule>,
+    page: page::Page,
+}
+
+fn managed_rules(
+    config: &Config,
+    rules: Vec<IpRule>,
+    mappings: &std::collections::HashMap<String, String>,
+    dns_steered: &std::collections::HashMap<String, String>,
+    exceptions: &exceptions::Exceptions,
+    dscp_policies: &dscp::DscpPolicies,
+) -> Vec<ManagedRule> {
+    rules
+        .into_iter()
+        .filter_map(|rule| {
+            let nic = match rule.table.as_str() {
+                TABLE_WAN0 => "wan0",
+                TABLE_WAN1 => "wan1",
+                _ => return None,
+            };
+            let host = rule.from.as_str();
+            let (kind, known) = if let Some((dscp, nic)) = dscp::policy_of(config, &rule) {
+                (
+                    "dscp",
+                    dscp_policies.get(&dscp).map(String::as_str) == Some(nic),
+                )
+            } else if config.is_lan_rule(&rule) {
+                ("lan", true)
+            } else if let Some(oif) = &rule.oif {
+                let iface = if nic == "wan1" {
+                    &config.wan1
+                } else {
+                    &config.wan0
+                };
+                ("probe", config.probe_table() && oif.as_str() == &**iface)
+            } else if rule.to.is_some() {
+                (
+                    "exception",
+                    exceptions::is_known(config, exceptions, mappings, &rule),
+                )
+            } else if rule.dport.as_deref() == Some("53") {
+                ("dns", dns_steered.get(host) == Some(&rule.table))
+            } else {
+                ("host", mappings.get(host).map(String::as_str) == Some(nic))
+            };
+            Some(ManagedRule {
+                rule,
+                nic,
+                kind,
+                known,
+            })
+        })
+        .collect()
+}
+
+async fn rules_handler(
+    Query(format): Query<FormatParams>,
+    Query(page_params): Query<page::PageParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    // Same lock order as switch_handler
+    let dns_steered = state.dns_steered.lock().await.clone();
+    let mappings = mapping::nics(&*state.mappings.lock().await);
+    let exceptions = state.exceptions.lock().unwrap().clone();
+    let dscp_policies = state.dscp_policies.lock().unwrap().clone();
+    let mut rules = ip_rules(state.runner.as_ref())
+        .map_err(|e| ApiError::internal("Failed to list rules", e))?;
+    // `ip rule show` order already is; rules at one priority keep theirs
+    rules.sort_by_key(|rule| rule.priority);
+    let rules = managed_rules(
+        &state.config,
+        rules,
+        &mappings,
+        &dns_steered,
+        &exceptions,
+        &dscp_policies,
+    );
+    let (rules, page) = page::paginate(rules, &page_params)?;
+    let body = RulesResponse { rules, page };
+    negotiate(&headers, &format, &body)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ProbeResponse {
+    wan: String,
+    iface: String,
+    target: String,
+    #[serde(flatten)]
+    result: health::ProbeResult,
+}
+
+/// Run the health probe for one WAN right away. Only reports; the periodic
+/// checker's state is left alone.
+async fn probe_handler(
+    Path(name): Path<String>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ProbeResponse>, ApiError> {
+    let iface = match name.as_str() {
+        "wan0" => &state.config.wan0,
+        "wan1" => &state.config.wan1,
+        _ => return Err(ApiError::not_found(format!("Unknown WAN: {}", name))),
+    };
+    let Some(health) = &state.config.health else {
+        return Err(ApiError::bad_request(
+            "No probe target; set HEALTH_TARGET to enable probing",
+        ));
+    };
+    let result = health::probe_timed(state.runner.as_ref(), iface, &health.target);
+    Ok(Json(ProbeResponse {
+        wan: name,
+        iface: iface.to_string(),
+        target: health.target.clone(),
+        result,
+    }))
+}
+
+async fn gc_handler(
+    Query(params): Query<GcParams>,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    if !params.dry_run && state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    if !params.dry_run && state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::paused());
+    }
+    // Hold the lock so a concurrent switch can't race the comparison
+    let mappings = state.mappings.lock().await;
+    let runner = state.runner.as_ref();
+    let rules = ip_rules(runner).map_err(|e| ApiError::internal("Failed to list rules", e))?;
+
+    let base_table = state.health.lock().await.failover.active_table();
+    // Destination exceptions are checked against their own bookkeeping
+    let exceptions = state.exceptions.lock().unwrap().clone();
+    let nics = mapping::nics(&mappings);
+    let stale: Vec<IpRule> = stale_rules(&state.config, &rules, &nics, base_table)
+        .into_iter()
+        .filter(|rule| !exceptions::is_known(&state.config, &exceptions, &nics, rule))
+        .collect();
+    if let (Some(window), false) = (state.config.require_confirm, params.dry_run) {
+        let changes = stale
+            .iter()
+            .map(|rule| rule_del_exact_cmd(rule).to_string())
+            .collect();
+        let required = confirm::check(
+            &mut state.confirmations.lock().unwrap(),
+            window,
+            "gc",
+            changes,
+            params.confirm.as_deref(),
+            std::time::Instant::now(),
+        )?;
+        if let Some(required) = required {
+            return Ok(required.into_response());
+        }
+    }
+    let items = stale
+        .into_iter()
+        .map(|rule| {
+            let what = format!("priority {} lookup {}", rule.priority, rule.table);
+            let outcome = if params.dry_run {
+                Ok(format!("Would remove {}", what))
+            } else {
+                rule_del_exact_cmd(&rule)
+                    .run(runner)
+                    .map(|_| format!("Removed {}", what))
+            };
+            ItemResult::from_outcome(&rule.from, outcome).with_rule(rule)
+        })
+        .collect();
+
+    Ok(GcResponse {
+        dry_run: params.dry_run,
+        result: MultiResult::new(items),
+    }
+    .into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct GcResponse {
+    dry_run: bool,
+    #[serde(flatten)]
+    result: MultiResult,
+}
+
+impl IntoResponse for GcResponse {
+    fn into_response(self) -> Response {
+        (self.result.http_status(), Json(&self)).into_response()
+    }
+}
+
+/// Apply the assignments in the body (see `import`) as switches, one item
+/// per line. Lines are independent: a bad line doesn't stop the rest.
+async fn import_csv_handler(
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+    body: String,
+) -> impl IntoResponse {
+    let unlocked = has_lock_token(&headers, &state.config);
+    let mut items = Vec::new();
+    for parsed in import::parse(&body) {
+        let item = match parsed {
+            Ok(entry) => {
+                let params = SwitchParams {
+                    ip: entry.ip.clone(),
+                    lease: None,
+                    nic: entry.nic,
+                    dns: None,
+                    verify: false,
+                    force: false,
+                    except: None,
+                    tags: None,
+                    auto_reason: None,
+                };
+                let outcome = match apply_switch(params, &state, unlocked).await {
+                    Ok((_, Json(response))) => {
+                        let mut message = format!("line {}: {}", entry.line, response.message);
+                        for warning in response.warnings {
+                            message.push_str(&format!("; warning: {}", warning));
+                        }
+                        Ok(message)
+                    }
+                    Err(e) => Err(anyhow::anyhow!("line {}: {}", entry.line, e.message)),
+                };
+                ItemResult::from_outcome(&entry.ip, outcome)
+            }
+            Err(e) => ItemResult::from_outcome(
+                &e.text,
+                Err(anyhow::anyhow!("line {}: {}", e.line, e.message)),
+            ),
+        };
+        items.push(item);
+    }
+    let result = MultiResult::new(items);
+    (result.http_status(), Json(result))
+}
+
+#[derive(Deserialize)]
+struct TagParams {
+    // `key:value`, or a bare `key` for any value
+    tag: Option<String>,
+}
+
+/// Full records of the mapped hosts, only those carrying `?tag=` if given.
+async fn mappings_handler(
+    Query(format): Query<FormatParams>,
+    Query(params): Query<TagParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    let mut mappings = mapping::detailed(&*state.mappings.lock().await);
+    if let Some(tag) = &params.tag {
+        mappings.retain(|_, m| mapping::has_tag(m, tag));
+    }
+    negotiate(&headers, &format, &mappings)
+}
+
+/// One host's desired rules against the kernel's, with the commands that
+/// would reconcile them.
+async fn mapping_diff_handler(
+    Path(ip): Path<String>,
+    Query(format): Query<FormatParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    let net = parse_host_net(&ip)
+        .ok_or_else(|| ApiError::bad_request(format!("Invalid IP address: {}", ip)))?;
+    let diff = host_diff::diff(&state, net)
+        .await
+        .map_err(|e| ApiError::internal("Failed to list rules", e))?;
+    negotiate(&headers, &format, &diff)
+}
+
+#[derive(Deserialize)]
+struct SwitchByTagParams {
+    tag: String,
+    nic: String,
+}
+
+/// Switch every host carrying `tag`, one item per host. Hosts are
+/// independent: one failing doesn't stop the rest.
+async fn switch_by_tag_handler(
+    Query(params): Query<SwitchByTagParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    let hosts = mapping::tagged(&*state.mappings.lock().await, &params.tag);
+    if hosts.is_empty() {
+        return Err(ApiError::not_found(format!(
+            "No mapping is tagged {}",
+            params.tag
+        )));
+    }
+    let unlocked = has_lock_token(&headers, &state.config);
+    let mut items = Vec::new();
+    for host in hosts {
+        let host = host.to_string();
+        let switch = SwitchParams {
+            ip: host.clone(),
+            lease: None,
+            nic: params.nic.clone(),
+            dns: None,
+            verify: false,
+            force: false,
+            except: None,
+            tags: None,
+            auto_reason: None,
+        };
+        let outcome = match apply_switch(switch, &state, unlocked).await {
+            Ok((_, Json(response))) => {
+                let mut message = response.message;
+                for warning in response.warnings {
+                    message.push_str(&format!("; warning: {}", warning));
+                }
+                Ok(message)
+            }
+            Err(e) => Err(anyhow::anyhow!(e.message)),
+        };
+        items.push(ItemResult::from_outcome(&host, outcome));
+    }
+    let result = MultiResult::new(items);
+    Ok((result.http_status(), Json(result)).into_response())
+}
+
+/// Drop `host`'s mapping and rules so it rides the base rule again.
+async fn revert_host(state: &AppState, host: &str, unlocked: bool) -> Result<String, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    if state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::paused());
+    }
+    if !unlocked && state.locked.lock().unwrap().contains(host) {
+        return Err(ApiError::locked(host));
+    }
+    let runner = state.runner.as_ref();
+    let host_match = &state.config.host_match;
+    let target = mapping::selector(host);
+    // Same lock order as switch_handler
+    let mut dns_steered = state.dns_steered.lock().await;
+    let mut mappings = state.mappings.lock().await;
+    let mut auto_assigned = state.auto_assigned.lock().await;
+    if !mapping::contains(&mappings, host) {
+        return Err(ApiError::not_found(format!("{} is not mapped", host)));
+    }
+    exceptions::clear(state, host);
+    if let Some(table) = dns_steered.remove(host) {
+        let prio = state.config.priorities.dns();
+        for cmd in dns_rule_cmds("del", &target, host_match, &table, prio) {
+            let _ = cmd.run(runner);
+        }
+    }
+    del_ip_rule_quiet(runner, &target, host_match, TABLE_WAN0);
+    del_ip_rule_quiet(runner, &target, host_match, TABLE_WAN1);
+    mapping::remove(&mut mappings, host);
+    auto_assigned.remove(host);
+    state.auto_pins.lock().unwrap().remove(host);
+    persist::save(state, &mappings, &auto_assigned);
+    state.health.lock().await.moved_hosts.remove(host);
+    Ok(format!("Reverted {} to the base rule", host))
+}
+
+#[derive(Serialize)]
+struct TxnResponse {
+    id: String,
+    // Seconds left before an uncommitted transaction is dropped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in: Option<u64>,
+    steps: Vec<txn::Step>,
+}
+
+fn txn_not_found(id: &str) -> ApiError {
+    ApiError::not_found(format!("Unknown or expired transaction {}", id))
+}
+
+async fn txn_open_handler(
+    state: axum::extract::State<AppState>,
+) -> Result<Json<TxnResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    let now = std::time::Instant::now();
+    let id = txn::open(
+        &mut state.transactions.lock().unwrap(),
+        state.config.txn_ttl,
+        now,
+    );
+    Ok(Json(TxnResponse {
+        id,
+        expires_in: Some(state.config.txn_ttl.as_secs()),
+        steps: Vec::new(),
+    }))
+}
+
+async fn txn_show_handler(
+    Path(id): Path<String>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<TxnResponse>, ApiError> {
+    let mut txns = state.transactions.lock().unwrap();
+    let txn =
+        txn::get(&mut txns, &id, std::time::Instant::now()).ok_or_else(|| txn_not_found(&id))?;
+    Ok(Json(TxnResponse {
+        steps: txn.steps.clone(),
+        id,
+        expires_in: None,
+    }))
+}
+
+async fn txn_abort_handler(
+    Path(id): Path<String>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    state
+        .transactions
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or_else(|| txn_not_found(&id))?;
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Abandoned transaction {}", id),
+        priority: None,
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+/// The host a staged step is about, checked as /switch would check it.
+fn txn_target(config: &Config, ip: &str) -> Result<String, ApiError> {
+    let addr = parse_switch_ip(ip)
+        .filter(|addr| config.mappable(*addr))
+        .ok_or_else(|| ApiError::bad_request(format!("{} is not a LAN host", ip)))?;
+    if !config.switch_permitted(addr) {
+        return Err(ApiError::forbidden(format!(
+            "{} is not permitted by SWITCH_ALLOW/SWITCH_DENY",
+            addr
+        )));
+    }
+    Ok(switch_prefix(ip).map_or_else(|| addr.to_string(), |net| mapping::host(&net)))
+}
+
+/// Add `step` to transaction `id` and show what is staged so far.
+fn stage(state: &AppState, id: String, step: txn::Step) -> Result<Json<TxnResponse>, ApiError> {
+    let mut txns = state.transactions.lock().unwrap();
+    let txn =
+        txn::get(&mut txns, &id, std::time::Instant::now()).ok_or_else(|| txn_not_found(&id))?;
+    txn.steps.push(step);
+    Ok(Json(TxnResponse {
+        steps: txn.steps.clone(),
+        id,
+        expires_in: None,
+    }))
+}
+
+#[derive(Deserialize)]
+struct TxnSwitchParams {
+    ip: String,
+    nic: String,
+}
+
+async fn txn_switch_handler(
+    Path(id): Path<String>,
+    Query(params): Query<TxnSwitchParams>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<TxnResponse>, ApiError> {
+    let nic = state.config.resolve_nic(&params.nic);
+    if nic != "wan0" && nic != "wan1" {
+        return Err(ApiError::bad_request("nic must be 'wan0' or 'wan1'"));
+    }
+    let ip = txn_target(&state.config, &params.ip)?;
+    stage(&state, id, txn::Step::Switch { ip, nic })
+}
+
+#[derive(Deserialize)]
+struct TxnRevertParams {
+    ip: String,
+}
+
+async fn txn_revert_handler(
+    Path(id): Path<String>,
+    Query(params): Query<TxnRevertParams>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<TxnResponse>, ApiError> {
+    let ip = txn_target(&state.config, &params.ip)?;
+    stage(&state, id, txn::Step::Revert { ip })
+}
+
+/// Apply one step; the message on success, the API error's otherwise.
+async fn apply_step(
+    state: &AppState,
+    step: &txn::Step,
+    unlocked: bool,
+) -> Result<String, ApiError> {
+    match step {
+        txn::Step::Switch { ip, nic } => {
+            let params = SwitchParams {
+                ip: ip.clone(),
+                lease: None,
+                nic: nic.clone(),
+                dns: None,
+                verify: false,
+                force: false,
+                except: None,
+                tags: None,
+                auto_reason: None,
+            };
+            let (_, Json(response)) = apply_switch(params, state, unlocked).await?;
+            Ok(response.message)
+        }
+        txn::Step::Revert { ip } => revert_host(state, ip, unlocked).await,
+    }
+}
+
+#[derive(Serialize)]
+struct TxnCommitResponse {
+    // "committed", or "rolled_back" when a step failed
+    status: &'static str,
+    id: String,
+    // The steps tried, up to and including the one that failed
+    items: Vec<ItemResult>,
+}
+
+/// Apply the staged steps in order. When one fails, every host changed so
+/// far is put back on its previous WAN (or unmapped again), newest first.
+async fn txn_commit_handler(
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    let steps = {
+        let mut txns = state.transactions.lock().unwrap();
+        txn::get(&mut txns, &id, std::time::Instant::now()).ok_or_else(|| txn_not_found(&id))?;
+        txns.remove(&id).map(|txn| txn.steps).unwrap_or_default()
+    };
+    let unlocked = has_lock_token(&headers, &state.config);
+    let mut items = Vec::new();
+    // (host, WAN it was on before its step; None if it was unmapped)
+    let mut applied: Vec<(String, Option<String>)> = Vec::new();
+    let mut failed = false;
+    for step in &steps {
+        let host = step.ip();
+        let previous = mapping::nic(&*state.mappings.lock().await, host).map(str::to_string);
+        match apply_step(&state, step, unlocked).await {
+            Ok(message) => {
+                items.push(ItemResult::from_outcome(host, Ok(message)));
+                applied.push((host.to_string(), previous));
+            }
+            Err(e) => {
+                items.push(ItemResult::from_outcome(
+                    host,
+                    Err(anyhow::anyhow!(e.message)),
+                ));
+                failed = true;
+                break;
+            }
+        }
+    }
+    if !failed {
+        println!("Committed transaction {} ({} steps)", id, steps.len());
+        let body = TxnCommitResponse {
+            status: "committed",
+            id,
+            items,
+        };
+        return Ok(Json(body).into_response());
+    }
+    for (host, previous) in applied.iter().rev() {
+        // The transaction's own changes are undone even on locked hosts
+        let undo = match previous {
+            Some(nic) => txn::Step::Switch {
+                ip: host.clone(),
+                nic: nic.clone(),
+            },
+            None => txn::Step::Revert { ip: host.clone() },
+        };
+        if let Err(e) = apply_step(&state, &undo, true).await {
+            eprintln!(
+                "Failed to roll back {} in transaction {}: {}",
+                host, id, e.message
+            );
+        }
+    }
+    println!("Rolled back transaction {}", id);
+    let body = TxnCommitResponse {
+        status: "rolled_back",
+        id,
+        items,
+    };
+    Ok((StatusCode::CONFLICT, Json(body)).into_response())
+}
+
+/// Whether the request carries LOCK_TOKEN in X-Lock-Token.
+fn has_lock_token(headers: &HeaderMap, config: &Config) -> bool {
+    let Some(token) = &config.lock_token else {
+        return false;
+    };
+    headers
+        .get("x-lock-token")
+        .is_some_and(|v| v.as_bytes() == token.as_bytes())
+}
+
+/// Validate the `:ip` of a lock request and the caller's token.
+fn lock_target(headers: &HeaderMap, config: &Config, ip: &str) -> Result<String, ApiError> {
+    if !has_lock_token(headers, config) {
+        return Err(ApiError::forbidden("X-Lock-Token is missing or wrong"));
+    }
+    let addr = parse_switch_ip(ip)
+        .filter(|addr| config.mappable(*addr))
+        .ok_or_else(|| ApiError::bad_request(format!("{} is not a LAN host", ip)))?;
+    Ok(addr.to_string())
+}
+
+async fn lock_handler(
+    Path(ip): Path<String>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    let host = lock_target(&headers, &state.config, &ip)?;
+    let mappings = state.mappings.lock().await;
+    // A locked mapping must not be evicted as an auto one either
+    let mut auto_assigned = state.auto_assigned.lock().await;
+    auto_assigned.remove(&host);
+    state.locked.lock().unwrap().insert(host.clone());
+    persist::save(&state, &mappings, &auto_assigned);
+    let nic = mapping::nic(&mappings, &host).unwrap_or("the base rule");
+    println!("Locked {} ({})", host, nic);
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Locked {} on {}", host, nic),
+        priority: None,
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+async fn unlock_handler(
+    Path(ip): Path<String>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    let host = lock_target(&headers, &state.config, &ip)?;
+    let mappings = state.mappings.lock().await;
+    let auto_assigned = state.auto_assigned.lock().await;
+    let removed = state.locked.lock().unwrap().remove(&host);
+    persist::save(&state, &mappings, &auto_assigned);
+    if removed {
+        println!("Unlocked {}", host);
+    }
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: if removed {
+            format!("Unlocked {}", host)
+        } else {
+            format!("{} was not locked", host)
+        },
+        priority: None,
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+#[derive(Deserialize)]
+struct ScheduleParams {
+    // Comma-separated LAN hosts
+    hosts: String,
+    nic: String,
+    // Daily `HH:MM-HH:MM`, in SCHEDULE_UTC_OFFSET
+    window: String,
+}
+
+/// Add or replace the schedule `:name`. It takes effect right away if the
+/// window is open.
+async fn schedule_handler(
+    Path(name): Path<String>,
+    Query(params): Query<ScheduleParams>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<schedule::ScheduleStatus>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    if state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::paused());
+    }
+    let nic = state.config.resolve_nic(&params.nic);
+    if nic != "wan0" && nic != "wan1" {
+        return Err(ApiError::bad_request("nic must be 'wan0' or 'wan1'"));
+    }
+    let window: schedule::Window = params
+        .window
+        .parse()
+        .map_err(|e: anyhow::Error| ApiError::bad_request(format!("{:#}", e)))?;
+    let mut hosts = Vec::new();
+    for host in params.hosts.split(',').map(str::trim) {
+        let addr = parse_switch_ip(host)
+            .filter(|addr| state.config.mappable(*addr))
+            .ok_or_else(|| ApiError::bad_request(format!("{} is not a LAN host", host)))?;
+        if !state.config.switch_permitted(addr) {
+            return Err(ApiError::forbidden(format!(
+                "{} is not permitted by SWITCH_ALLOW/SWITCH_DENY",
+                addr
+            )));
+        }
+        if !hosts.contains(&addr.to_string()) {
+            hosts.push(addr.to_string());
+        }
+    }
+    let new = schedule::Schedule {
+        hosts,
+        nic,
+        window,
+        applied: None,
+    };
+    schedule::set(&state, &name, Some(new)).await?;
+    let now = unix_now();
+    schedule::tick(&state, now).await;
+    let schedules = state.schedules.lock().unwrap();
+    // Gone only if deleted in the meantime
+    let added = schedules
+        .get(&name)
+        .ok_or_else(|| ApiError::not_found(format!("No schedule named {}", name)))?;
+    Ok(Json(added.status(now, state.config.schedule_utc_offset)))
+}
+
+/// Remove the schedule `:name`, putting its hosts back if it is applied.
+async fn unschedule_handler(
+    Path(name): Path<String>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    if state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::paused());
+    }
+    if !schedule::set(&state, &name, None).await? {
+        return Err(ApiError::not_found(format!("No schedule named {}", name)));
+    }
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Removed schedule {}", name),
+        priority: None,
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+#[derive(Deserialize)]
+struct DscpParams {
+    // 1-63 or a class name (ef, csN, afXY)
+    dscp: String,
+    nic: String,
+}
+
+/// Send traffic marked with a DSCP value to a WAN, whichever host sends it.
+/// Replaces the value's policy if it has one.
+async fn dscp_policy_handler(
+    Query(params): Query<DscpParams>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    if state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::paused());
+    }
+    let value = dscp::parse(&params.dscp)?;
+    let nic = state.config.resolve_nic(&params.nic);
+    if nic != "wan0" && nic != "wan1" {
+        return Err(ApiError::bad_request("nic must be 'wan0' or 'wan1'"));
+    }
+    let prio = state.config.priorities.dscp();
+    let misplaced = if prio == 0 {
+        Some("PRIO_SPECIFIC must be at least 4 to leave room for DSCP policies".to_string())
+    } else {
+        state.config.outside_band(prio)
+    };
+    if let Some(reason) = misplaced {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "priority_unavailable",
+            format!("No room for DSCP policies: {}", reason),
+        ));
+    }
+    // Held so persist::save sees a consistent state
+    let mappings = state.mappings.lock().await;
+    let auto_assigned = state.auto_assigned.lock().await;
+    dscp::install(&state, value, &nic)
+        .map_err(|e| ApiError::internal("Failed to add DSCP policy rule", e))?;
+    persist::save(&state, &mappings, &auto_assigned);
+    println!("DSCP policy: {} -> {}", value, nic);
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Traffic marked DSCP {} now uses {}", value, nic),
+        priority: Some(prio),
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+async fn remove_dscp_policy_handler(
+    Path(value): Path<String>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    if state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::paused());
+    }
+    let value = dscp::parse(&value)?;
+    let mappings = state.mappings.lock().await;
+    let auto_assigned = state.auto_assigned.lock().await;
+    let removed = dscp::remove(&state, value)
+        .map_err(|_| ApiError::not_found(format!("No DSCP policy for {}", value)))?;
+    persist::save(&state, &mappings, &auto_assigned);
+    println!("Removed DSCP policy for {}", value);
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: match removed {
+            Some(nic) => format!("Removed DSCP policy {} -> {}", value, nic),
+            None => format!("Removed leftover DSCP policy rule for {}", value),
+        },
+        priority: None,
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+async fn pause_handler(
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    state.paused.store(true, Ordering::SeqCst);
+    println!("Paused: rule changes are suspended");
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: "Paused".to_string(),
+        priority: None,
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+/// Take a WAN out of service, or put it back: while disabled it takes no new
+/// pins and counts as down for failover.
+async fn set_wan_disabled(
+    state: &AppState,
+    name: &str,
+    disabled: bool,
+) -> Result<Json<ApiResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    if name != "wan0" && name != "wan1" {
+        return Err(ApiError::not_found(format!("Unknown WAN: {}", name)));
+    }
+    let changed = {
+        let mut health = state.health.lock().await;
+        if disabled {
+            health.disabled.insert(name.to_string())
+        } else {
+            health.disabled.remove(name)
+        }
+    };
+    let message = match (disabled, changed) {
+        (true, true) => format!("Disabled {}", name),
+        (false, true) => format!("Enabled {}", name),
+        (true, false) => format!("{} was already disabled", name),
+        (false, false) => format!("{} was not disabled", name),
+    };
+    if changed {
+        syslog::log(
+            &state.config,
+            syslog::Severity::Warning,
+            &message,
+            "wan_admin",
+            &[
+                ("wan", name),
+                ("disabled", if disabled { "true" } else { "false" }),
+            ],
+        );
+        // Fail over (or back) now rather than at the next probe
+        if let Some(health) = &state.config.health {
+            if !state.paused.load(Ordering::SeqCst) {
+                health::react(state, health, std::time::Instant::now()).await;
+            }
+        }
+    }
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message,
+        priority: None,
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+async fn disable_wan_handler(
+    Path(name): Path<String>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    set_wan_disabled(&state, &name, true).await
+}
+
+async fn enable_wan_handler(
+    Path(name): Path<String>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    set_wan_disabled(&state, &name, false).await
+}
+
+async fn resume_handler(
+    state: axum::extract::State<AppState>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    if state.config.read_only {
+        return Err(ApiError::read_only());
+    }
+    state.paused.store(false, Ordering::SeqCst);
+    println!("Resumed");
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: "Resumed".to_string(),
+        priority: None,
+        warnings: Vec::new(),
+        verification: None,
+    }))
+}
+
+async fn init_report_handler(
+    Query(format): Query<FormatParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    match state.init_report.lock().await.as_ref() {
+        Some(report) => negotiate(&headers, &format, report),
+        None => Err(ApiError::not_found("No initialization has completed")),
+    }
+}
+
+/// One WAN's entry in `/gateways`.
+#[derive(Debug, Serialize)]
+struct GatewayInfo {
+    interface: String,
+    // "192.0.2.1", "dev" for a point-to-point default without a nexthop, or
+    // comma-separated gateways for a multipath default
+    gateway: Option<String>,
+    // "configured" (GATEWAY_WANx) or "discovered"
+    source: &'static str,
+    // Unix time of the last successful lookup
+    refreshed_at: Option<u64>,
+    // The interface's global addresses, to tell whether the gateway is on-link
+    addresses: Vec<IpNet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+async fn gateways_handler(
+    Query(format): Query<FormatParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    let runner = state.runner.as_ref();
+    let mut cache = state.gateways.lock().await;
+    let mut body = std::collections::BTreeMap::new();
+    for (nic, iface, configured) in [
+        (
+            "wan0",
+            &state.config.wan0,
+            state.config.gateway_wan0.as_ref(),
+        ),
+        (
+            "wan1",
+            &state.config.wan1,
+            state.config.gateway_wan1.as_ref(),
+        ),
+    ] {
+        // A failed lookup keeps reporting the last good gateway and its age
+        let error = match resolve_nexthop(runner, iface, configured) {
+            Ok(nexthop) => {
+                cache.insert(nic.to_string(), (nexthop, unix_now()));
+                None
+            }
+            Err(e) => Some(format!("{:#}", e)),
+        };
+        let last = cache.get(nic);
+        body.insert(
+            nic,
+            GatewayInfo {
+                interface: iface.to_string(),
+                gateway: last.map(|(nexthop, _)| nexthop.to_string()),
+                source: if configured.is_some() {
+                    "configured"
+                } else {
+                    "discovered"
+                },
+                refreshed_at: last.map(|(_, at)| *at),
+                addresses: state.iface_addrs(iface).unwrap_or_default(),
+                error,
+            },
+        );
+    }
+    negotiate(&headers, &format, &body)
+}
+
+async fn plan_handler(
+    Query(format): Query<FormatParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    let mappings = mapping::nics(&*state.mappings.lock().await);
+    let dns_steered = state.dns_steered.lock().await.clone();
+    let cmds = build_plan(
+        state.runner.as_ref(),
+        &state.config,
+        &mappings,
+        &dns_steered,
+    )
+    .map_err(|e| ApiError::internal("Failed to build plan", e))?;
+    let body = serde_json::json!({
+        "commands": cmds.iter().map(|c| c.to_string()).collect::<Vec<_>>()
+    });
+    negotiate(&headers, &format, &body)
+}
+
+/// The current routing state as a shell script of `ip` commands.
+async fn export_script_handler(
+    state: axum::extract::State<AppState>,
+) -> Result<Response, ApiError> {
+    let script = script::export(&state)
+        .await
+        .map_err(|e| ApiError::internal("Failed to build script", e))?;
+    Ok(([(header::CONTENT_TYPE, "text/x-shellscript")], script).into_response())
+}
+
+#[derive(Deserialize)]
+struct ConfigParams {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// `KEY=VALUE` lines as in an environment file; blank lines and `#` comments
+/// are skipped and quotes around a value are dropped.
+fn parse_env_file(body: &str) -> Result<std::collections::HashMap<String, String>, ApiError> {
+    let mut vars = std::collections::HashMap::new();
+    for (n, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(ApiError::bad_request(format!(
+                "line {}: expected KEY=VALUE",
+                n + 1
+            )));
+        };
+        let value = value.trim();
+        let value = ['"', '\''].iter().fold(value, |v, q| {
+            v.strip_prefix(*q)
+                .and_then(|v| v.strip_suffix(*q))
+                .unwrap_or(v)
+        });
+        vars.insert(key.trim().to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+/// Preview what starting with other settings would do to the kernel. The
+/// body overrides the environment with `KEY=VALUE` lines; the answer lists
+/// the plan commands the new settings add and those of the current plan they
+/// no longer include. Settings only take effect on restart, so only dry runs
+/// are accepted.
+async fn config_handler(
+    Query(params): Query<ConfigParams>,
+    Query(format): Query<FormatParams>,
+    headers: HeaderMap,
+    state: axum::extract::State<AppState>,
+    body: String,
+) -> Result<Response, ApiError> {
+    if !params.dry_run {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "restart_required",
+            "Settings are applied at startup; use ?dry_run=true to preview them",
+        ));
+    }
+    let vars = parse_env_file(&body)?;
+    let config = Config::from_vars(&|key| vars.get(key).cloned().or_else(|| env::var(key).ok()))
+        .map_err(|e| ApiError::bad_request(format!("Invalid configuration: {:#}", e)))?;
+    let mappings = mapping::nics(&*state.mappings.lock().await);
+    let dns_steered = state.dns_steered.lock().await.clone();
+    let runner = state.runner.as_ref();
+    let plan = |config: &Config| -> Result<Vec<String>, ApiError> {
+        Ok(build_plan(runner, config, &mappings, &dns_steered)
+            .map_err(|e| ApiError::internal("Failed to build plan", e))?
+            .iter()
+            .map(IpCommand::to_string)
+            .collect())
+    };
+    let current = plan(&state.config)?;
+    let proposed = plan(&config)?;
+    let body = serde_json::json!({
+        "dry_run": true,
+        "commands": proposed.iter().filter(|c| !current.contains(c)).collect::<Vec<_>>(),
+        "dropped": current.iter().filter(|c| !proposed.contains(c)).collect::<Vec<_>>(),
+        "unchanged": proposed.iter().filter(|c| current.contains(c)).count(),
+    });
+    negotiate(&headers, &format, &body)
+}
+
+/// What one run of `initialize_lan_to_wan0` set up, for `/init/report`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct InitReport {
+    // Unix time initialization finished
+    completed_at: u64,
+    wans: std::collections::BTreeMap<String, WanInit>,
+    // The LAN base (and fallback) rules ensured, as `ip rule` commands
+    base_rules: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct WanInit {
+    interface: String,
+    table: String,
+    // Nexthop of the table's default route ("dev" for point-to-point,
+    // comma-separated gateways for multipath)
+    gateway: String,
+    // Every gateway the default route balances across; one unless multipath
+    #[serde(default)]
+    nexthops: Vec<String>,
+    // Preferred source address, if one was configured
+    source: Option<String>,
+    link_routes: usize,
+    // EXTRA_ROUTES_* installed
+    #[serde(default)]
+    extra_routes: usize,
+    // RA-learned IPv6 nexthop, with IPV6=true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_gateway: Option<String>,
+}
+
+impl InitReport {
+    /// The preferred source address chosen for each WAN that has one.
+    fn sources(&self) -> std::collections::HashMap<String, String> {
+        self.wans
+            .iter()
+            .filter_map(|(nic, wan)| Some((nic.clone(), wan.source.clone()?)))
+            .collect()
+    }
+}
+
+/// Initialize policy routing and report what was set up.
+async fn initialize_lan_to_wan0(runner: &dyn CommandRunner, config: &Config) -> Result<InitReport> {
+    // Establish policy routing so that the LAN subnets go out via wan0 by default
+    let lan_subnets: Vec<String> = config.lan_subnets.iter().map(|n| n.to_string()).collect();
+    let lan_list = lan_subnets.join(", ");
+
+    println!(
+        "Initializing policy routing: {} -> {} ({})",
+        lan_list,
+        config.base_wan,
+        if config.base_wan == "wan1" {
+            &config.wan1
+        } else {
+            &config.wan0
+        }
+    );
+
+    // Clean up any previous incorrect address assignments on WAN interfaces (best-effort)
+    for lan_subnet in &lan_subnets {
+        let _ = runner.run("ip", &["addr", "del", lan_subnet, "dev", &config.wan0]);
+        let _ = runner.run("ip", &["addr", "del", lan_subnet, "dev", &config.wan1]);
+    }
+
+    // Discover gateways
+    let gw0 = resolve_nexthop(runner, &config.wan0, config.gateway_wan0.as_ref())
+        .with_context(|| format!("get gateway for {}", &config.wan0))?;
+    let gw1 = resolve_nexthop(runner, &config.wan1, config.gateway_wan1.as_ref())
+        .with_context(|| format!("get gateway for {}", &config.wan1))?;
+
+    // Pick preferred source addresses, if configured
+    let src0 = resolve_src(runner, &config.wan0, config.src_wan0.as_ref())
+        .with_context(|| format!("get source address for {}", &config.wan0))?;
+    let src1 = resolve_src(runner, &config.wan1, config.src_wan1.as_ref())
+        .with_context(|| format!("get source address for {}", &config.wan1))?;
+
+    // Ensure routing tables have default routes
+    ensure_table_default_route(
+        runner,
+        &config.wan0,
+        TABLE_WAN0,
+        &gw0,
+        src0.as_deref(),
+        config.route_mode_wan0,
+    )
+    .with_context(|| format!("set table {} default route", TABLE_WAN0))?;
+    ensure_table_default_route(
+        runner,
+        &config.wan1,
+        TABLE_WAN1,
+        &gw1,
+        src1.as_deref(),
+        config.route_mode_wan1,
+    )
+    .with_context(|| format!("set table {} default route", TABLE_WAN1))?;
+
+    // Also mirror directly-connected link routes into each table (for ARP/gw resolution)
+    let links0 =
+        mirror_link_routes_to_table(runner, &config.wan0, TABLE_WAN0).with_context(|| {
+            format!(
+                "mirror link routes for {} to table {}",
+                &config.wan0, TABLE_WAN0
+            )
+        })?;
+    let links1 =
+        mirror_link_routes_to_table(runner, &config.wan1, TABLE_WAN1).with_context(|| {
+            format!(
+                "mirror link routes for {} to table {}",
+                &config.wan1, TABLE_WAN1
+            )
+        })?;
+
+    // Then the configured static routes, whose gateways may need the link routes
+    let extra0 = extra_routes::install(runner, &config.extra_routes_wan0, TABLE_WAN0)?;
+    let extra1 = extra_routes::install(runner, &config.extra_routes_wan1, TABLE_WAN1)?;
+
+    let mut report = InitReport::default();
+    for (nic, iface, table, gw, src, links, extra) in [
+        ("wan0", &config.wan0, TABLE_WAN0, gw0, src0, links0, extra0),
+        ("wan1", &config.wan1, TABLE_WAN1, gw1, src1, links1, extra1),
+    ] {
+        report.wans.insert(
+            nic.to_string(),
+            WanInit {
+                interface: iface.to_string(),
+                table: table.to_string(),
+                gateway: gw.to_string(),
+                nexthops: gw.gateways().iter().map(Gateway::to_string).collect(),
+                source: src,
+                link_routes: links,
+                extra_routes: extra,
+                ipv6_gateway: None,
+            },
+        );
+    }
+
+    // IPv6 defaults are best-effort: a WAN without an RA default route keeps
+    // working for IPv4
+    if config.ipv6 {
+        for (nic, iface, table, mode) in [
+            ("wan0", &config.wan0, TABLE_WAN0, config.route_mode_wan0),
+            ("wan1", &config.wan1, TABLE_WAN1, config.route_mode_wan1),
+        ] {
+            let result = get_ipv6_gateway_for_iface(runner, iface).and_then(|gw| {
+                table_default_route6_cmd(iface, table, &gw, mode).run(runner)?;
+                Ok(gw)
+            });
+            match result {
+                Ok(gw) => {
+                    if let Some(wan) = report.wans.get_mut(nic) {
+                        wan.ipv6_gateway = Some(gw.to_string());
+                    }
+                }
+                Err(e) => eprintln!(
+                    "Warning: no IPv6 default route for table {}: {:#}",
+                    table, e
+                ),
+            }
+        }
+    }
+
+    let (base_table, other_table) = match config.base_wan {
+        "wan1" => (TABLE_WAN1, TABLE_WAN0),
+        _ => (TABLE_WAN0, TABLE_WAN1),
+    };
+    for selector in config.base_selectors() {
+        // Ensure base rule for the LAN -> base WAN table, dropping one left
+        // in the other table by a failover or an earlier BASE_WAN
+        let prio = config.priorities.lan_default;
+        add_base_rule(runner, &selector, base_table, prio)
+            .with_context(|| format!("add base LAN policy rule for {}", selector))?;
+        let _ = base_rule_cmd("del", &selector, other_table, prio).run(runner);
+        report
+            .base_rules
+            .push(base_rule_cmd("add", &selector, base_table, prio).to_string());
+
+        // Optional fallback: if a pinned host's table has no usable route (e.g. during
+        // a gateway change) the lookup falls through to this rule instead of any
+        // unrelated rules between the overrides and the base rule
+        let fallback = config.priorities.fallback();
+        if config.fallback_to_primary {
+            add_base_rule(runner, &selector, base_table, fallback)
+                .with_context(|| format!("add fallback policy rule for {}", selector))?;
+            let _ = base_rule_cmd("del", &selector, other_table, fallback).run(runner);
+            report
+                .base_rules
+                .push(base_rule_cmd("add", &selector, base_table, fallback).to_string());
+        } else {
+            for table in [TABLE_WAN0, TABLE_WAN1] {
+                let _ = base_rule_cmd("del", &selector, table, fallback).run(runner);
+            }
+        }
+    }
+
+    // Health probes bind to their WAN's interface, but without a rule the
+    // route lookup for them still goes through the main table
+    let prio = config.priorities.lan_default;
+    for (iface, table) in [(&config.wan0, TABLE_WAN0), (&config.wan1, TABLE_WAN1)] {
+        if !config.probe_table() {
+            let _ = probe_rule_cmd("del", iface, table, prio).run(runner);
+            continue;
+        }
+        let exists = ip_rules(runner)?
+            .iter()
+            .any(|r| r.oif.as_deref() == Some(&**iface) && r.table == table && r.priority == prio);
+        if !exists {
+            probe_rule_cmd("add", iface, table, prio)
+                .run(runner)
+                .with_context(|| format!("add probe policy rule for {}", iface))?;
+        }
+        report
+            .base_rules
+            .push(probe_rule_cmd("add", iface, table, prio).to_string());
+    }
+
+    println!(
+        "Policy ready: {} uses table {}, specific hosts can be overridden to table {}",
+        lan_list, base_table, other_table
+    );
+
+    report.completed_at = unix_now();
+    Ok(report)
+}
+
+async fn ui_handler() -> impl IntoResponse {
+    Html(include_str!("ui.html"))
+}
+
+fn build_router(state: AppState) -> Router {
+    let mut router = Router::new()
+        .route("/switch", get(switch_handler))
+        .route("/switch/by-tag", post(switch_by_tag_handler))
+        .route("/mappings", get(mappings_handler))
+        .route("/mapping/:ip/diff", get(mapping_diff_handler))
+        .route("/status", get(status_handler))
+        .route("/version", get(version_handler))
+        .route("/capabilities", get(capabilities_handler))
+        .route("/plan", get(plan_handler))
+        .route("/export/script", get(export_script_handler))
+        .route("/config", put(config_handler))
+        .route("/rules", get(rules_handler))
+        .route("/gateways", get(gateways_handler))
+        .route("/init/report", get(init_report_handler))
+        .route("/wan/:name/probe", post(probe_handler))
+        .route("/wan/:name/disable", post(disable_wan_handler))
+        .route("/wan/:name/enable", post(enable_wan_handler))
+        .route("/gc", post(gc_handler))
+        .route("/policy/dscp", post(dscp_policy_handler))
+        .route("/policy/dscp/:dscp", delete(remove_dscp_policy_handler))
+        .route("/import/csv", post(import_csv_handler))
+        .route("/txn", post(txn_open_handler))
+        .route("/txn/:id", get(txn_show_handler).delete(txn_abort_handler))
+        .route("/txn/:id/switch", post(txn_switch_handler))
+        .route("/txn/:id/revert", post(txn_revert_handler))
+        .route("/txn/:id/commit", post(txn_commit_handler))
+        .route(
+            "/schedules/:name",
+            post(schedule_handler).delete(unschedule_handler),
+        )
+        .route("/pause", post(pause_handler))
+        .route("/resume", post(resume_handler))
+        .route("/metrics", get(metrics_handler));
+    if state.config.ui_enabled {
+        router = router.route("/", get(ui_handler));
+    }
+    if state.config.debug {
+        router = router
+            .route("/debug/rejections", get(rejections_handler))
+            .route("/debug/switches", get(switch_diffs_handler))
+            .route("/debug/gateway", get(debug_gateway_handler));
+    }
+    if state.config.lock_token.is_some() {
+        router = router.route("/hosts/:ip/lock", post(lock_handler).delete(unlock_handler));
+    }
+    if state.config.diag_token.is_some() {
+        router = router.route("/route-get", get(route_get_handler));
+    }
+    router
+        .layer(
+            tower_http::compression::CompressionLayer::new()
+                .compress_when(tower_http::compression::predicate::SizeAbove::new(1024)),
+        )
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() {
+    let started_at = unix_now();
+    let started = std::time::Instant::now();
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Configuration:");
+    println!("  wan0: {}", config.wan0);
+    println!("  wan1: {}", config.wan1);
+    println!(
+        "  lan: {} (rule mode: {:?})",
+        config.lan, config.lan_rule_mode
+    );
+    if config.host_match != HostMatch::default() {
+        println!(
+            "  host rule match: from <ip> {}",
+            config.host_match.args().join(" ")
+        );
+    }
+    println!("  base wan: {}", config.base_wan);
+    println!("  fallback to primary: {}", config.fallback_to_primary);
+    println!("  pin primary: {}", config.pin_primary);
+    println!("  cleanup on exit: {}", config.cleanup_on_exit);
+    if let Some(path) = &config.state_file {
+        println!(
+            "  state file: {} (checkpoint: {:?})",
+            path.display(),
+            config.checkpoint_interval
+        );
+    }
+    println!(
+        "  default route mode: wan0={:?} wan1={:?}",
+        config.route_mode_wan0, config.route_mode_wan1
+    );
+    for (table, extra) in [
+        (TABLE_WAN0, &config.extra_routes_wan0),
+        (TABLE_WAN1, &config.extra_routes_wan1),
+    ] {
+        for route in extra {
+            println!("  extra route in table {}: {}", table, route);
+        }
+    }
+    println!("  ui enabled: {}", config.ui_enabled);
+    if config.debug {
+        println!(
+            "  debug: rejected switches kept at /debug/rejections, rule diffs at /debug/switches"
+        );
+    }
+    if let Some(hook) = &config.post_switch_hook {
+        println!("  post-switch hook: {}", hook);
+    }
+    println!("  host locking: {}", config.lock_token.is_some());
+    if let Some(window) = config.require_confirm {
+        println!("  /gc requires confirmation within {:?}", window);
+    }
+    if let Some(leases) = &config.leases {
+        println!(
+            "  leases file: {} (checked every {:?})",
+            leases.path.display(),
+            leases.interval
+        );
+    }
+    if let Some(weights) = &config.auto_balance {
+        println!("  auto-balance weights: {:?}", weights);
+    }
+    if let Some(health) = &config.health {
+        println!(
+            "  health target: {} every {:?} (failover: {})",
+            health.target, health.interval, health.failover
+        );
+    }
+
+    if config.read_only {
+        eprintln!(
+            "READ-ONLY MODE: watching routes and rules every {:?}; nothing is changed and mutating endpoints return 403",
+            config.read_only_interval
+        );
+    }
+    if let Some(chaos) = &config.chaos {
+        eprintln!(
+            "WARNING: CHAOS TEST MODE: /switch is delayed up to {:?} and fails {:.0}% of the time",
+            chaos.max_delay,
+            chaos.fail_rate * 100.0
+        );
+    }
+
+    let runner: Arc<dyn CommandRunner> = Arc::new(SystemRunner::detect(config.max_commands));
+    println!("  ip JSON output: {}", runner.ip_json());
+    if config.preflight {
+        let path = env::var_os("PATH").unwrap_or_default();
+        let missing = preflight::probe(runner.as_ref(), &config, &path);
+        if !missing.is_empty() {
+            eprintln!("Refusing to start (PREFLIGHT); missing:");
+            for m in &missing {
+                eprintln!("  {}", m);
+            }
+            std::process::exit(1);
+        }
+        println!("Preflight: policy routing and required tools are available");
+    }
+    if let Some(timeout) = config.iface_wait {
+        let wans = [&config.wan0, &config.wan1];
+        let down =
+            link_wait::wait_for_links(&wans, timeout, std::path::Path::new("/sys/class/net")).await;
+        if !down.is_empty() {
+            eprintln!(
+                "Warning: {} still not up after {:?}; initializing anyway",
+                down.join(", "),
+                timeout
+            );
+        }
+    }
+    match foreign_table_routes(runner.as_ref(), &config) {
+        Ok(foreign) if !foreign.is_empty() => {
+            for (table, route) in &foreign {
+                eprintln!(
+                    "Table {} has a route the service does not own: {}",
+                    table, route
+                );
+            }
+            if config.strict_tables {
+                eprintln!("Refusing to start (STRICT_TABLES); see the README on table conflicts");
+                std::process::exit(1);
+            }
+            eprintln!("Warning: another process may be using the WAN tables");
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to inspect the WAN tables: {:#}", e),
+    }
+    if config.safe_mode && !config.read_only {
+        let problems = safe_mode::check(runner.as_ref(), &config);
+        if !problems.is_empty() {
+            eprintln!("Refusing to start (SAFE_MODE):");
+            for problem in &problems {
+                eprintln!("  {}", problem);
+            }
+            std::process::exit(1);
+        }
+        println!("Safe mode: initialization leaves the default and management routes alone");
+    }
+    let snapshot = if config.cleanup_on_exit {
+        match cleanup::StartupSnapshot::take(runner.as_ref(), &config) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                eprintln!("Failed to snapshot kernel state: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let init_report = if config.read_only {
+        readonly::discover(runner.as_ref(), &config)
+    } else {
+        initialize_lan_to_wan0(runner.as_ref(), &config).await
+    };
+    let init_report = match init_report {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Failed to initialize: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // READ_ONLY reads the host rules itself below
+    let adopted = if config.adopt_rules && !config.read_only {
+        match adopt_existing_rules(runner.as_ref(), &config) {
+            Ok(adopted) => Some(adopted),
+            Err(e) => {
+                eprintln!("Failed to adopt existing rules: {:#}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Restoring reconciles rules, so READ_ONLY leaves the state file alone
+    let saved = match config.state_file.as_ref().filter(|_| !config.read_only) {
+        Some(path) => match persist::read(path) {
+            Ok(saved) => saved,
+            Err(e) => {
+                eprintln!("Failed to load saved state: {:#}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut state = AppState::new(config, runner, init_report.sources());
+    state.started_at = started_at;
+    state.started = started;
+    *state.init_report.lock().await = Some(init_report);
+    let versions = state
+        .versions
+        .get_or_init(|| capabilities::Versions::probe(state.runner.as_ref()));
+    println!(
+        "  kernel {}, iproute2 {}",
+        versions.kernel.as_deref().unwrap_or("unknown"),
+        versions.iproute2.as_deref().unwrap_or("unknown")
+    );
+    if let Some(mut saved) = saved {
+        let health = saved.health.take();
+        persist::restore(&state, saved).await;
+        if let Some(health) = health {
+            health::restore(&state, health, unix_now()).await;
+        }
+    }
+    // The kernel has the final say over anything saved
+    if let Some(adopted) = adopted {
+        state.dns_steered.lock().await.extend(adopted.dns_steered);
+        mapping::adopt(&mut *state.mappings.lock().await, adopted.mappings);
+        state
+            .dscp_policies
+            .lock()
+            .unwrap()
+            .extend(adopted.dscp_policies);
+    }
+
+    if state.config.read_only {
+        if let Err(e) = readonly::refresh(&state).await {
+            eprintln!("Failed to read routing state: {:#}", e);
+        }
+        let drift = state.drift.lock().unwrap().clone();
+        if drift.is_empty() {
+            println!("Routing state matches the configuration");
+        }
+        for cmd in &drift {
+            println!("Drift: {}", cmd);
+        }
+        tokio::spawn(readonly::watch_loop(
+            state.clone(),
+            state.config.read_only_interval,
+        ));
+    } else {
+        if let Some(weights) = state.config.auto_balance.clone() {
+            tokio::spawn(auto_balance_loop(state.clone(), weights));
+        }
+
+        {
+            let mappings = state.mappings.lock().await;
+            let auto_assigned = state.auto_assigned.lock().await;
+            persist::save(&state, &mappings, &auto_assigned);
+        }
+        if let Some(interval) = state.config.checkpoint_interval {
+            tokio::spawn(persist::checkpoint_loop(state.clone(), interval));
+        }
+
+        if let Some(lease_config) = state.config.leases.clone() {
+            tokio::spawn(leases::follow_loop(state.clone(), lease_config));
+        }
+    }
+
+    // READ_ONLY probes too, but only reports
+    if let Some(health_config) = state.config.health.clone() {
+        tokio::spawn(health::health_loop(state.clone(), health_config));
+    }
+
+    tokio::spawn(schedule::scheduler_loop(state.clone()));
+    tokio::spawn(janitor::janitor_loop(
+        state.clone(),
+        state.config.janitor_interval,
+    ));
+
+    let uds = state
+        .config
+        .listen_uds
+        .clone()
+        .map(|path| (path, state.config.listen_uds_mode));
+    let instance_name = state.config.instance_name.clone();
+    let app = build_router(state);
+
+    if let Some((path, mode)) = uds {
+        let listener = match bind_uds(&path, mode) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Failed to bind {}: {:#}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        println!(
+            "Server listening on unix:{} (mode {:o}) => {} ({})",
+            path.display(),
+            mode,
+            version::VERSION,
+            instance_name
+        );
+        serve_uds(listener, app, shutdown_signal()).await;
+        let _ = std::fs::remove_file(&path);
+    } else {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:32599")
+            .await
+            .expect("Failed to bind to port 32599");
+
+        println!(
+            "Server listening on http://127.0.0.1:32599 => {} ({})",
+            version::VERSION,
+            instance_name
+        );
+
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .expect("Server error");
+    }
+
+    if let Some(snapshot) = snapshot {
+        println!("Shutting down: restoring kernel state found at startup");
+        // Cleanup runs its commands one after another
+        cleanup::restore(&SystemRunner::detect(1), &snapshot);
+    }
+}
+
+/// Bind LISTEN_UDS, replacing a socket left behind by an earlier run (but
+/// never any other kind of file), and restrict it to `mode`.
+fn bind_uds(path: &std::path::Path, mode: u32) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path).context("remove stale socket")?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .context("set socket permissions")?;
+    Ok(listener)
+}
+
+/// Serve `app` over HTTP/1 on a Unix socket until `shutdown` resolves;
+/// `axum::serve` only takes TCP listeners. Handlers see no peer address.
+async fn serve_uds(
+    listener: tokio::net::UnixListener,
+    app: Router,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept on the control socket: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => return,
+        };
+        let service = hyper_util::service::TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let io = hyper_util::rt::TokioIo::new(stream);
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(io, service)
+                .await
+            {
+                eprintln!("Control socket connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory_runner::MemoryRunner;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn config_from(pairs: &[(&str, &str)]) -> Result<Config> {
+        let vars: HashMap<String, String> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::from_vars(&|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn config_defaults_are_valid() {
+        let config = config_from(&[]).unwrap();
+        assert_eq!(config.wan0, "eth0");
+        assert_eq!(config.wan1, "eth1");
+        assert_eq!(config.lan, "eth2");
+    }
+
+    #[test]
+    fn config_rejects_invalid_interfaces_and_gateways() {
+        for (key, value) in [
+            ("WAN0", ""),
+            ("WAN1", "eth1 extra"),
+            ("LAN", "br0/1"),
+            ("WAN0", "a-very-long-ifname"),
+            ("WAN1", ".."),
+            ("GATEWAY_WAN0", "192.0.2.300"),
+        ] {
+            let err = config_from(&[(key, value)]).unwrap_err();
+            assert!(err.to_string().contains(key), "{}: {}", key, err);
+        }
+        assert!(Interface::new("wg-home.10").is_ok());
+        assert!("fe80::1".parse::<Gateway>().is_ok());
+    }
+
+    #[test]
+    fn config_rejects_same_wan_interfaces() {
+        let err = config_from(&[("WAN0", "eth0"), ("WAN1", "eth0")]).unwrap_err();
+        assert!(err.to_string().contains("WAN0 and WAN1"), "{}", err);
+    }
+
+    #[test]
+    fn config_validates_priorities() {
+        let config = config_from(&[("PRIO_SPECIFIC", "500"), ("PRIO_LAN_DEFAULT", "600")]).unwrap();
+        assert_eq!(config.priorities.dns(), 499);
+        assert_eq!(config.priorities.fallback(), 501);
+
+        for pairs in [
+            &[("PRIO_SPECIFIC", "2000")][..],
+            &[("PRIO_SPECIFIC", "1"), ("PRIO_LAN_DEFAULT", "100")],
+            &[("PRIO_LAN_DEFAULT", "32766")],
+            &[
+                ("PRIO_LAN_DEFAULT", "1001"),
+                ("FALLBACK_TO_PRIMARY", "true"),
+            ],
+            &[("PRIO_SPECIFIC", "high")],
+        ] {
+            assert!(config_from(pairs).is_err(), "{:?}", pairs);
+        }
+    }
+
+    #[test]
+    fn priority_band_must_hold_every_rule() {
+        let config = config_from(&[("PRIO_BAND", "998-2000")]).unwrap();
+        assert_eq!(config.outside_band(2000), None);
+        assert_eq!(
+            config.outside_band(32000).unwrap(),
+            "priority 32000 is outside PRIO_BAND (998-2000)"
+        );
+
+        // The exception rules sit two below PRIO_SPECIFIC
+        let err = config_from(&[("PRIO_BAND", "999-2000")]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("exception rules (PRIO_SPECIFIC - 2)"),
+            "{}",
+            err
+        );
+        let err = config_from(&[("PRIO_BAND", "998-1999")]).unwrap_err();
+        assert!(
+            err.to_string().contains("PRIO_LAN_DEFAULT (2000)"),
+            "{}",
+            err
+        );
+        for band in ["2000-998", "998", "low-high"] {
+            assert!(config_from(&[("PRIO_BAND", band)]).is_err(), "{}", band);
+        }
+    }
+
+    #[test]
+    fn switch_permitted_honours_allow_and_deny() {
+        let config = config_from(&[
+            ("SWITCH_ALLOW", "10.40.0.0/24"),
+            ("SWITCH_DENY", "10.40.0.1"),
+        ])
+        .unwrap();
+        assert!(config.switch_permitted("10.40.0.3".parse().unwrap()));
+        assert!(!config.switch_permitted("10.40.0.1".parse().unwrap()));
+        assert!(!config.switch_permitted("10.40.1.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn config_parses_multiple_lan_subnets() {
+        let config = config_from(&[("LAN_SUBNETS", "10.40.0.0/20, 192.168.5.1/24")]).unwrap();
+        assert_eq!(
+            config.lan_subnets,
+            vec![
+                "10.40.0.0/20".parse::<IpNet>().unwrap(),
+                "192.168.5.0/24".parse::<IpNet>().unwrap()
+            ]
+        );
+        assert!(config.in_lan("192.168.5.20".parse().unwrap()));
+        assert!(!config.in_lan("192.168.6.20".parse().unwrap()));
+    }
+
+    #[test]
+    fn run_cmd_error_carries_exit_code_and_output() {
+        let err = run_cmd("sh", &["-c", "echo partial; echo boom >&2; exit 3"]).unwrap_err();
+        let cmd = err.downcast_ref::<CommandError>().unwrap();
+        assert_eq!(cmd.exit_code, Some(3));
+        assert_eq!(cmd.stdout, "partial\n");
+        assert_eq!(cmd.stderr, "boom\n");
+
+        let api = ApiError::internal("Failed to add policy rule", err);
+        assert_eq!(api.code, "command_failed");
+        let body = serde_json::to_value(&api).unwrap();
+        assert_eq!(body["command"]["exit_code"], 3);
+    }
+
+    #[test]
+    fn status_response_round_trips() {
+        let status = StatusResponse {
+            overall: overall::Overall::Degraded,
+            instance: "site-a".to_string(),
+            generated_at: 1_700_000_600,
+            started_at: 1_700_000_000,
+            uptime_secs: 600,
+            reconciled_at: Some(1_700_000_001),
+            last_failover_at: None,
+            mappings: StatusMappings::Nics(HashMap::from([(
+                "10.40.0.3".to_string(),
+                "wan1".to_string(),
+            )])),
+            config: StatusConfig {
+                wan0: "eth0".to_string(),
+                wan1: "eth1".to_string(),
+                lan: "eth2".to_string(),
+                lan_subnets: vec!["10.40.0.0/20".parse().unwrap()],
+                switch_allow: vec!["10.40.0.0/24".parse().unwrap()],
+                switch_deny: Vec::new(),
+                subnet_policy: SubnetPolicy::Strict,
+                overlap_policy: mapping::OverlapPolicy::Replace,
+                min_prefix_len: Some(28),
+                priorities: Priorities {
+                    specific: 1000,
+                    lan_default: 2000,
+                },
+                priority_band: Some(PriorityBand {
+                    min: 900,
+                    max: 2100,
+                }),
+                nic_aliases: std::collections::BTreeMap::new(),
+            },
+            sources: HashMap::from([("wan1".to_string(), "198.51.100.7".to_string())]),
+            dns_steered: vec!["10.40.0.3".to_string()],
+            auto_assigned: Vec::new(),
+            mappings_page: page::Page {
+                offset: 0,
+                limit: page::DEFAULT_LIMIT,
+                total: 1,
+                next_offset: None,
+            },
+            rule_priorities: HashMap::from([("10.40.0.3".to_string(), 1000)]),
+            mapping_count: 1,
+            max_mappings: Some(256),
+            paused: false,
+            base_wan: "wan0".to_string(),
+            health: Default::default(),
+            moved_hosts: Default::default(),
+            host_failovers: Default::default(),
+            disabled_wans: Vec::new(),
+            locked: vec!["10.40.0.3".to_string()],
+            lease_pins: [("laptop".to_string(), "10.40.0.3".to_string())].into(),
+            auto_pins: Default::default(),
+            schedules: Default::default(),
+            exceptions: Default::default(),
+            dscp_policies: Default::default(),
+            gateways: [(
+                "wan1".to_string(),
+                vec!["198.51.100.1".to_string(), "198.51.100.2".to_string()],
+            )]
+            .into(),
+            read_only: false,
+            drift: Vec::new(),
+            commands: CommandStats {
+                in_flight: 0,
+                queued: 0,
+                total: 7,
+            },
+        };
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["mappings"]["10.40.0.3"], "wan1");
+        assert_eq!(json["overall"], "degraded");
+        assert_eq!(json["config"]["lan"], "eth2");
+        let back: StatusResponse = serde_json::from_value(json).unwrap();
+        assert_eq!(back, status);
+    }
+
+    #[test]
+    fn config_rejects_lan_shared_with_wan() {
+        let err = config_from(&[("LAN", "eth1")]).unwrap_err();
+        assert!(err.to_string().contains("LAN (eth1)"), "{}", err);
+    }
+
+    /// Initialize against `runner` and serve the full router on an ephemeral port.
+    async fn spawn_app(runner: Arc<MemoryRunner>) -> std::net::SocketAddr {
+        spawn_app_with(runner, config_from(&[]).unwrap()).await
+    }
+
+    async fn spawn_app_with(runner: Arc<MemoryRunner>, config: Config) -> std::net::SocketAddr {
+        spawn_state(runner, config).await.1
+    }
+
+    /// `spawn_app_with`, keeping the state for tests that look inside it.
+    async fn spawn_state(
+        runner: Arc<MemoryRunner>,
+        config: Config,
+    ) -> (AppState, std::net::SocketAddr) {
+        let report = initialize_lan_to_wan0(runner.as_ref(), &config)
+            .await
+            .unwrap();
+        let state = AppState::new(config, runner, report.sources());
+        *state.init_report.lock().await = Some(report);
+        let addr = serve(state.clone()).await;
+        (state, addr)
+    }
+
+    async fn serve(state: AppState) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = build_router(state).into_make_service_with_connect_info::<std::net::SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    async fn http(addr: std::net::SocketAddr, method: &str, path: &str) -> (u16, String) {
+        http_with(addr, method, path, &[]).await
+    }
+
+    async fn http_with(
+        addr: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> (u16, String) {
+        http_body(addr, method, path, headers, "").await
+    }
+
+    async fn http_body(
+        addr: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> (u16, String) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let extra: String = headers
+            .iter()
+            .map(|(k, v)| format!("{}: {}\r\n", k, v))
+            .collect();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            extra,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).await.unwrap();
+        let raw = String::from_utf8(raw).unwrap();
+        let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn api_is_served_on_a_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("wan-switcher-{}.sock", std::process::id()));
+        // A leftover socket from an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let config = config_from(&[
+            ("LISTEN_UDS", path.to_str().unwrap()),
+            ("LISTEN_UDS_MODE", "0660"),
+        ])
+        .unwrap();
+        let listener = bind_uds(&path, config.listen_uds_mode).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let runner = Arc::new(MemoryRunner::new());
+        let state = AppState::new(config, runner, HashMap::new());
+        tokio::spawn(serve_uds(
+            listener,
+            build_router(state),
+            std::future::pending(),
+        ));
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /switch?ip=10.40.0.3&nic=wan1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).await.unwrap();
+        assert!(raw.starts_with("HTTP/1.1 200"), "{}", raw);
+        std::fs::remove_file(&path).unwrap();
+
+        // Never replace something that is not a socket
+        let file =
+            std::env::temp_dir().join(format!("wan-switcher-{}.notsock", std::process::id()));
+        std::fs::write(&file, "keep").unwrap();
+        assert!(bind_uds(&file, 0o600).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+        std::fs::remove_file(&file).unwrap();
+
+        assert!(config_from(&[("LISTEN_UDS_MODE", "0660")]).is_err());
+        assert!(
+            config_from(&[("LISTEN_UDS", "/tmp/x.sock"), ("LISTEN_UDS_MODE", "0999")]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn init_populates_tables_and_base_rule() {
+        let runner = Arc::new(MemoryRunner::new());
+        spawn_app(runner.clone()).await;
+
+        assert!(runner
+            .routes(TABLE_WAN0)
+            .contains(&"default via 192.0.2.1 dev eth0".to_string()));
+        assert!(runner
+            .routes(TABLE_WAN1)
+            .contains(&"default via 198.51.100.1 dev eth1".to_string()));
+        assert!(runner.has_rule("10.40.0.0/20", TABLE_WAN0));
+    }
+
+    #[tokio::test]
+    async fn iif_mode_scopes_base_rule_by_lan_interface() {
+        let runner = MemoryRunner::new();
+        // A previous subnet-mode run left its base rule behind
+        initialize_lan_to_wan0(&runner, &config_from(&[]).unwrap())
+            .await
+            .unwrap();
+        let config =
+            config_from(&[("LAN_RULE_MODE", "iif"), ("FALLBACK_TO_PRIMARY", "true")]).unwrap();
+        initialize_lan_to_wan0(&runner, &config).await.unwrap();
+
+        let rules = parse_ip_rules(&ip_rule_list(&runner).unwrap());
+        let iif_rules: Vec<u32> = rules
+            .iter()
+            .filter(|r| r.iif.as_deref() == Some("eth2") && r.table == TABLE_WAN0)
+            .map(|r| r.priority)
+            .collect();
+        assert_eq!(iif_rules, vec![1001, 2000]);
+        let stale = stale_rules(&config, &rules, &HashMap::new(), TABLE_WAN0);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].from, "10.40.0.0/20");
+
+        assert!(config_from(&[("LAN_RULE_MODE", "oif")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn host_rules_carry_configured_match_keys() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config =
+            config_from(&[("HOST_RULE_MATCH", "iif"), ("HOST_RULE_FWMARK", "16/0xf0")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config.clone()).await;
+
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1&dns=true").await;
+        assert_eq!(status, 200);
+        let host_rules: Vec<String> = runner
+            .rules()
+            .into_iter()
+            .filter(|r| r.from == "10.40.0.3")
+            .map(|r| format!("{} {}", r.selector, r.table))
+            .collect();
+        assert_eq!(
+            host_rules,
+            vec![
+                "iif eth2 fwmark 0x10/0xf0 200",
+                "ipproto udp dport 53 iif eth2 fwmark 0x10/0xf0 200",
+                "ipproto tcp dport 53 iif eth2 fwmark 0x10/0xf0 200",
+            ]
+        );
+        let rules = parse_ip_rules(&ip_rule_list(runner.as_ref()).unwrap());
+        let mappings = HashMap::from([("10.40.0.3".to_string(), "wan1".to_string())]);
+        assert!(stale_rules(&config, &rules, &mappings, TABLE_WAN0).is_empty());
+        // The same host rule without the keys is a leftover of plain `from` mode
+        let plain = config_from(&[]).unwrap();
+        assert_eq!(
+            stale_rules(&plain, &rules, &mappings, TABLE_WAN0)
+                .iter()
+                .filter(|r| r.dport.is_none())
+                .count(),
+            1
+        );
+
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert!(runner.rules().iter().all(|r| r.from != "10.40.0.3"));
+    }
+
+    #[test]
+    fn host_rule_match_config_is_validated() {
+        assert_eq!(parse_fwmark("0x1").unwrap(), "0x1");
+        assert_eq!(parse_fwmark("256/0xff00").unwrap(), "0x100/0xff00");
+        assert_eq!(parse_fwmark("0x10/0xffffffff").unwrap(), "0x10");
+        assert!(parse_fwmark("0x11/0xf0").is_err());
+        assert!(parse_fwmark("1/0").is_err());
+        assert!(parse_fwmark("mark").is_err());
+        assert!(config_from(&[("HOST_RULE_MATCH", "to")]).is_err());
+        assert!(config_from(&[("HOST_RULE_FWMARK", "0x1/")]).is_err());
+        assert_eq!(config_from(&[]).unwrap().host_match, HostMatch::default());
+    }
+
+    #[tokio::test]
+    async fn rejected_switches_are_listed_with_debug() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        let (status, _) = http(addr, "GET", "/debug/rejections").await;
+        assert_eq!(status, 404);
+
+        let config = config_from(&[("DEBUG", "true"), ("SWITCH_DENY", "10.40.0.9/32")]).unwrap();
+        let addr = spawn_app_with(runner, config).await;
+        http(addr, "GET", "/switch?ip=192.168.1.5&nic=wan1").await;
+        http(addr, "GET", "/switch?ip=10.40.0.9&nic=wan1").await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan2").await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+
+        let (status, body) = http(addr, "GET", "/debug/rejections").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let rejections = body["rejections"].as_array().unwrap();
+        let seen: Vec<(&str, &str)> = rejections
+            .iter()
+            .map(|r| (r["ip"].as_str().unwrap(), r["code"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            seen,
+            vec![
+                ("192.168.1.5", "invalid_request"),
+                ("10.40.0.9", "forbidden"),
+                ("10.40.0.3", "invalid_request"),
+            ]
+        );
+        assert_eq!(rejections[0]["client"], "127.0.0.1");
+        assert!(rejections[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("not inside any LAN subnet"));
+    }
+
+    #[tokio::test]
+    async fn transactions_commit_whole_or_roll_back() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        let open = || async {
+            let (status, body) = http(addr, "POST", "/txn").await;
+            assert_eq!(status, 200, "{}", body);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["expires_in"], 300);
+            body["id"].as_str().unwrap().to_string()
+        };
+        http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan1").await;
+
+        let id = open().await;
+        for step in [
+            "switch?ip=10.40.0.3&nic=wan1",
+            "revert?ip=10.40.0.4",
+            "switch?ip=10.40.0.5&nic=wan1",
+        ] {
+            let (status, body) = http(addr, "POST", &format!("/txn/{}/{}", id, step)).await;
+            assert_eq!(status, 200, "{}", body);
+        }
+        let (status, _) = http(
+            addr,
+            "POST",
+            &format!("/txn/{}/switch?ip=192.168.7.1&nic=wan1", id),
+        )
+        .await;
+        assert_eq!(status, 400);
+        // Staging leaves the kernel alone
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        let (_, body) = http(addr, "GET", &format!("/txn/{}", id)).await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body["steps"][1],
+            serde_json::json!({"op": "revert", "ip": "10.40.0.4"})
+        );
+
+        let (status, body) = http(addr, "POST", &format!("/txn/{}/commit", id)).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("\"committed\""), "{}", body);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.4/32", TABLE_WAN1));
+        assert!(runner.has_rule("10.40.0.5/32", TABLE_WAN1));
+        let (status, _) = http(addr, "POST", &format!("/txn/{}/commit", id)).await;
+        assert_eq!(status, 404);
+
+        // 10.40.0.9 is not mapped, so its revert fails and the rest is undone
+        let id = open().await;
+        for step in [
+            "switch?ip=10.40.0.7&nic=wan1",
+            "switch?ip=10.40.0.3&nic=wan0",
+            "revert?ip=10.40.0.5",
+            "revert?ip=10.40.0.9",
+        ] {
+            http(addr, "POST", &format!("/txn/{}/{}", id, step)).await;
+        }
+        let (status, body) = http(addr, "POST", &format!("/txn/{}/commit", id)).await;
+        assert_eq!(status, 409, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], "rolled_back");
+        assert_eq!(body["items"].as_array().unwrap().len(), 4);
+        assert_eq!(body["items"][3]["status"], "error");
+        assert!(!runner.has_rule("10.40.0.7/32", TABLE_WAN1));
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        assert!(runner.has_rule("10.40.0.5/32", TABLE_WAN1));
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status.mappings.nic("10.40.0.7"), None);
+        assert_eq!(status.mappings.nic("10.40.0.3"), Some("wan1"));
+
+        let id = open().await;
+        let (status, _) = http(addr, "DELETE", &format!("/txn/{}", id)).await;
+        assert_eq!(status, 200);
+        let (status, _) = http(addr, "GET", &format!("/txn/{}", id)).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn tagged_hosts_switch_together() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        for path in [
+            "/switch?ip=10.40.0.3&nic=wan0&tags=role:voip",
+            "/switch?ip=10.40.0.4&nic=wan0&tags=role:voip,floor:2",
+            "/switch?ip=10.40.0.5&nic=wan0&tags=role:nas",
+        ] {
+            let (status, body) = http(addr, "GET", path).await;
+            assert_eq!(status, 200, "{}", body);
+        }
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.6&nic=wan1&tags=voip").await;
+        assert_eq!(status, 400);
+
+        let (status, body) = http(addr, "POST", "/switch/by-tag?tag=role:voip&nic=wan1").await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["succeeded"], 2);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        assert!(runner.has_rule("10.40.0.4/32", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.5/32", TABLE_WAN1));
+
+        // A switch without `tags` keeps them
+        let (_, body) = http(addr, "GET", "/mappings?tag=floor").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let hosts = body.as_object().unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts["10.40.0.4"]["nic"], "wan1");
+        assert_eq!(hosts["10.40.0.4"]["tags"]["role"], "voip");
+        let (_, body) = http(addr, "GET", "/status?detailed=true").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["mappings"]["10.40.0.5"]["tags"]["role"], "nas");
+
+        let (status, _) = http(addr, "POST", "/switch/by-tag?tag=role:printer&nic=wan1").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn route_get_shows_what_the_kernel_picks_for_a_flow() {
+        let addr = spawn_app(Arc::new(MemoryRunner::new())).await;
+        let (status, _) = http(addr, "GET", "/route-get?dest=8.8.8.8&from=10.40.0.3").await;
+        assert_eq!(status, 404);
+
+        let config = config_from(&[("LOCK_TOKEN", "s3cret")]).unwrap();
+        let addr = spawn_app_with(Arc::new(MemoryRunner::new()), config).await;
+        let (status, _) = http(addr, "GET", "/route-get?dest=8.8.8.8&from=10.40.0.3").await;
+        assert_eq!(status, 404);
+
+        let config = config_from(&[("DIAG_TOKEN", "look"), ("LOCK_TOKEN", "s3cret")]).unwrap();
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app_with(runner.clone(), config).await;
+        let token = [("X-Diag-Token", "look")];
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        let (status, _) = http(addr, "GET", "/route-get?dest=8.8.8.8&from=10.40.0.3").await;
+        assert_eq!(status, 403);
+        // The lock token grants switching rights, not diagnostics
+        let (status, _) = http_with(
+            addr,
+            "GET",
+            "/route-get?dest=8.8.8.8&from=10.40.0.3",
+            &[("X-Diag-Token", "s3cret")],
+        )
+        .await;
+        assert_eq!(status, 403);
+
+        let route = |from: &'static str| async move {
+            let path = format!("/route-get?dest=8.8.8.8&from={}", from);
+            let (status, body) = http_with(addr, "GET", &path, &token).await;
+            assert_eq!(status, 200, "{}", body);
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+        assert_eq!(
+            route("10.40.0.3").await,
+            serde_json::json!({
+                "dest": "8.8.8.8",
+                "from": "10.40.0.3",
+                "dev": "eth1",
+                "gateway": "198.51.100.1",
+                "table": "200",
+                "wan": "wan1",
+            })
+        );
+        let other = route("10.40.0.4").await;
+        assert_eq!(
+            (&other["wan"], &other["table"]),
+            (&"wan0".into(), &"100".into())
+        );
+
+        let (status, _) =
+            http_with(addr, "GET", "/route-get?dest=dns&from=10.40.0.3", &token).await;
+        assert_eq!(status, 400);
+        runner.fail_commands("ip -4 route get 192.0.2.200");
+        let (status, body) = http_with(
+            addr,
+            "GET",
+            "/route-get?dest=192.0.2.200&from=10.40.0.3",
+            &token,
+        )
+        .await;
+        assert_eq!(status, 422, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn overlapping_prefixes_follow_mapping_overlap_policy() {
+        for policy in ["reject", "replace"] {
+            let runner = Arc::new(MemoryRunner::new());
+            let config = config_from(&[("MAPPING_OVERLAP_POLICY", policy)]).unwrap();
+            let (state, addr) = spawn_state(runner.clone(), config).await;
+            let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.0/24&nic=wan1").await;
+            assert_eq!(status, 200, "{}", body);
+            assert!(runner.has_rule("10.40.0.0/24", TABLE_WAN1));
+
+            let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+            let mappings = state.mappings.lock().await.clone();
+            if policy == "reject" {
+                assert_eq!(status, 409, "{}", body);
+                assert!(body.contains("\"prefix_overlap\""), "{}", body);
+                assert!(runner.has_rule("10.40.0.0/24", TABLE_WAN1));
+                assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+                assert_eq!(mapping::nic(&mappings, "10.40.0.0/24"), Some("wan1"));
+            } else {
+                assert_eq!(status, 200, "{}", body);
+                assert!(
+                    body.contains("replacing the mapping of 10.40.0.0/24"),
+                    "{}",
+                    body
+                );
+                assert!(!runner.has_rule("10.40.0.0/24", TABLE_WAN1));
+                assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+                assert_eq!(mapping::nic(&mappings, "10.40.0.0/24"), None);
+            }
+        }
+        assert!(config_from(&[("MAPPING_OVERLAP_POLICY", "merge")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_switched_host_then_its_subnet_follow_mapping_overlap_policy() {
+        for policy in ["reject", "replace"] {
+            let runner = Arc::new(MemoryRunner::new());
+            let config = config_from(&[("MAPPING_OVERLAP_POLICY", policy)]).unwrap();
+            let (state, addr) = spawn_state(runner.clone(), config).await;
+
+            let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+            assert_eq!(status, 200, "{}", body);
+
+            let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.0/24&nic=wan1").await;
+            let mappings = state.mappings.lock().await.clone();
+            if policy == "reject" {
+                assert_eq!(status, 409, "{}", body);
+                assert!(body.contains("10.40.0.3/32"), "{}", body);
+                assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+                assert!(!runner.has_rule("10.40.0.0/24", TABLE_WAN1));
+                assert_eq!(mapping::nic(&mappings, "10.40.0.3"), Some("wan1"));
+            } else {
+                assert_eq!(status, 200, "{}", body);
+                assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+                assert!(runner.has_rule("10.40.0.0/24", TABLE_WAN1));
+                assert_eq!(mapping::nic(&mappings, "10.40.0.3"), None);
+                assert_eq!(mapping::nic(&mappings, "10.40.0.0/24"), Some("wan1"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_switch_keeps_the_mapping_it_would_replace() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("MAPPING_OVERLAP_POLICY", "replace")]).unwrap();
+        let (state, addr) = spawn_state(runner.clone(), config).await;
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.0/24&nic=wan1").await;
+        assert_eq!(status, 200, "{}", body);
+
+        runner.fail_commands("ip rule add from 10.40.0.3");
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 500, "{}", body);
+        assert!(runner.has_rule("10.40.0.0/24", TABLE_WAN1));
+        let mappings = state.mappings.lock().await;
+        assert_eq!(mapping::nic(&mappings, "10.40.0.0/24"), Some("wan1"));
+    }
+
+    #[tokio::test]
+    async fn prefixes_covering_the_whole_lan_need_force() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+
+        // Equal to LAN_SUBNET, which not even force=true pins, and a
+        // supernet of it
+        for (ip, message) in [
+            ("10.40.0.0/20", "10.40.0.0/20 is the LAN subnet itself"),
+            (
+                "10.40.0.0/20&force=true",
+                "10.40.0.0/20 is the LAN subnet itself",
+            ),
+            ("10.0.0.0/8", "covers the whole LAN subnet 10.40.0.0/20"),
+        ] {
+            let (status, body) = http(addr, "GET", &format!("/switch?ip={}&nic=wan1", ip)).await;
+            assert_eq!(status, 400, "{}", body);
+            assert!(body.contains("\"whole_lan\""), "{}", body);
+            assert!(body.contains(message), "{}", body);
+        }
+        assert!(!runner.has_rule("10.40.0.0/20", TABLE_WAN1));
+        assert!(!runner.has_rule("10.0.0.0/8", TABLE_WAN1));
+
+        // A proper subset is pinned as that prefix, a host written with its
+        // subnet's length as the host
+        for (ip, rule) in [
+            ("10.40.1.0/24", "10.40.1.0/24"),
+            ("10.40.0.3/20", "10.40.0.3"),
+        ] {
+            let (status, body) = http(addr, "GET", &format!("/switch?ip={}&nic=wan1", ip)).await;
+            assert_eq!(status, 200, "{}", body);
+            assert!(runner.has_rule(rule, TABLE_WAN1));
+        }
+
+        let config = config_from(&[("SWITCH_SUBNET_POLICY", "any")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+        let (status, body) = http(addr, "GET", "/switch?ip=10.0.0.0/8&nic=wan1&force=true").await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("pinned anyway (force=true)"), "{}", body);
+        assert!(runner.has_rule("10.0.0.0/8", TABLE_WAN1));
+        assert!(runner.has_rule("10.40.0.0/20", TABLE_WAN0));
+    }
+
+    #[tokio::test]
+    async fn prefixes_broader_than_the_minimum_length_are_refused() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("SWITCH_MIN_PREFIX_LEN", "28")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        // force=true lifts the whole-LAN guard, not this one, and a host
+        // address with the length is no way around it either
+        for ip in ["10.40.1.0/24", "10.40.0.0/20&force=true", "10.40.0.3/8"] {
+            let (status, body) = http(addr, "GET", &format!("/switch?ip={}&nic=wan1", ip)).await;
+            assert_eq!(status, 400, "{}", body);
+            assert!(body.contains("\"prefix_too_broad\""), "{}", body);
+            assert!(body.contains("broader than /28"), "{}", body);
+        }
+        for (ip, rule) in [
+            ("10.40.1.16/28", "10.40.1.16/28"),
+            ("10.40.0.3/30", "10.40.0.3"),
+        ] {
+            let (status, body) = http(addr, "GET", &format!("/switch?ip={}&nic=wan1", ip)).await;
+            assert_eq!(status, 200, "{}", body);
+            assert!(runner.has_rule(rule, TABLE_WAN1));
+        }
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["config"]["min_prefix_len"], 28);
+        for bad in ["33", "/28", "-1"] {
+            assert!(
+                config_from(&[("SWITCH_MIN_PREFIX_LEN", bad)]).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn subnet_policy_decides_on_hosts_outside_the_lan() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("SWITCH_SUBNET_POLICY", "warn")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+        let (status, body) = http(addr, "GET", "/switch?ip=192.168.7.20&nic=wan1").await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["warnings"][0]
+            .as_str()
+            .unwrap()
+            .contains("192.168.7.20 is not inside any LAN subnet"));
+        assert!(runner.has_rule("192.168.7.20/32", TABLE_WAN1));
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status.config.subnet_policy, SubnetPolicy::Warn);
+        assert_eq!(status.mappings.nic("192.168.7.20"), Some("wan1"));
+
+        let config = config_from(&[("SWITCH_SUBNET_POLICY", "any")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+        let (status, body) = http(addr, "GET", "/switch?ip=192.168.7.21&nic=wan1").await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(!body.contains("warnings"), "{}", body);
+
+        // Strict is the default
+        let addr = spawn_app(runner).await;
+        let (status, _) = http(addr, "GET", "/switch?ip=192.168.7.22&nic=wan1").await;
+        assert_eq!(status, 400);
+        assert!(config_from(&[("SWITCH_SUBNET_POLICY", "loose")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn debug_gateway_runs_discovery_on_any_interface() {
+        let runner = Arc::new(
+            MemoryRunner::new()
+                .with_uplink(
+                    "eth3",
+                    Some("203.0.113.1"),
+                    "203.0.113.9/24",
+                    "203.0.113.0/24",
+                )
+                .with_uplink("ppp0", None, "100.64.0.9/32", "100.64.0.1/32"),
+        );
+        let addr = spawn_app(runner.clone()).await;
+        let (status, _) = http(addr, "GET", "/debug/gateway?iface=eth3").await;
+        assert_eq!(status, 404);
+
+        let addr = spawn_app_with(runner, config_from(&[("DEBUG", "true")]).unwrap()).await;
+        let (status, body) = http(addr, "GET", "/debug/gateway?iface=eth3").await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["gateway"], "203.0.113.1");
+
+        // No `via` to read: the failure comes with what was read
+        let (status, body) = http(addr, "GET", "/debug/gateway?iface=ppp0").await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("Could not determine default gateway for iface ppp0"));
+        assert!(body["default_routes"]
+            .as_array()
+            .unwrap()
+            .contains(&"default dev ppp0 scope link".into()));
+
+        let (status, body) = http(addr, "GET", "/debug/gateway?iface=eth9").await;
+        assert_eq!(status, 404, "{}", body);
+        assert!(body.contains("does not exist"), "{}", body);
+        let (status, _) = http(addr, "GET", "/debug/gateway?iface=eth0:1").await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn switches_record_their_rule_diff_with_debug() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        let (status, _) = http(addr, "GET", "/debug/switches").await;
+        assert_eq!(status, 404);
+
+        let config = config_from(&[("DEBUG", "true")]).unwrap();
+        let addr = spawn_app_with(runner, config).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        // Rejected before touching the kernel: no diff
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan2").await;
+
+        let (status, body) = http(addr, "GET", "/debug/switches").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let switches = body["switches"].as_array().unwrap();
+        assert_eq!(switches.len(), 2);
+        assert_eq!(switches[0]["nic"], "wan1");
+        assert_eq!(switches[0]["succeeded"], true);
+        assert_eq!(
+            switches[0]["added"],
+            serde_json::json!(["1000: from 10.40.0.3 lookup 200"])
+        );
+        assert_eq!(switches[0]["removed"], serde_json::json!([]));
+        assert_eq!(switches[1]["added"], serde_json::json!([]));
+        assert_eq!(
+            switches[1]["removed"],
+            serde_json::json!(["1000: from 10.40.0.3 lookup 200"])
+        );
+    }
+
+    #[tokio::test]
+    async fn rejection_list_is_bounded() {
+        let config = config_from(&[("DEBUG", "true")]).unwrap();
+        let state = AppState::new(config, Arc::new(MemoryRunner::new()), HashMap::new());
+        let err = ApiError::bad_request("nic must be 'wan0' or 'wan1'");
+        for i in 0..MAX_REJECTIONS + 5 {
+            record_rejection(&state, None, &format!("10.40.0.{}", i), "wan9", &err).await;
+        }
+        let rejections = state.rejections.lock().await;
+        assert_eq!(rejections.len(), MAX_REJECTIONS);
+        assert_eq!(rejections[0].ip, "10.40.0.5");
+    }
+
+    #[tokio::test]
+    async fn switch_warns_about_missing_nat_and_return_route() {
+        let runner = Arc::new(
+            MemoryRunner::new()
+                .with_nft_ruleset("oifname \"eth0\" masquerade\n")
+                .with_host_route("10.40.0.3", "eth2")
+                .with_host_route("10.40.0.4", "eth1"),
+        );
+        let addr = spawn_app(runner.clone()).await;
+
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body.get("warnings").is_none(), "{}", body);
+
+        // Not blocked, but both problems are reported
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan1").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let warnings = body["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0]
+            .as_str()
+            .unwrap()
+            .contains("No masquerade/SNAT rule found for eth1"));
+        assert!(warnings[1]
+            .as_str()
+            .unwrap()
+            .contains("routed out eth1 rather than the LAN (eth2)"));
+        assert!(runner.has_rule("10.40.0.4", TABLE_WAN1));
+
+        let config = config_from(&[("ASYMMETRY_CHECK", "false")]).unwrap();
+        let addr = spawn_app_with(runner, config).await;
+        let (_, body) = http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan1").await;
+        assert!(!body.contains("warnings"));
+    }
+
+    #[tokio::test]
+    async fn switch_verifies_the_egress_on_request() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1&verify=true").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body["verification"],
+            serde_json::json!({"dest": "1.1.1.1", "dev": "eth1", "expected": "eth1", "ok": true})
+        );
+        assert!(runner
+            .calls()
+            .contains(&"ip -4 route get 1.1.1.1 from 10.40.0.3 iif eth2".to_string()));
+
+        // A rule ahead of the host's catches its traffic first
+        runner
+            .run(
+                "ip",
+                &[
+                    "rule",
+                    "add",
+                    "from",
+                    "10.40.0.0/24",
+                    "lookup",
+                    "100",
+                    "priority",
+                    "500",
+                ],
+            )
+            .unwrap();
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1&verify=true").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["verification"]["ok"], false);
+        assert_eq!(body["verification"]["dev"], "eth0");
+        assert_eq!(
+            body["warnings"][0],
+            "Verification: traffic from 10.40.0.3 to 1.1.1.1 leaves via eth0, not eth1"
+        );
+
+        let (_, body) = http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan1").await;
+        assert!(!body.contains("verification"));
+    }
+
+    #[tokio::test]
+    async fn destination_exceptions_follow_the_pin() {
+        let runner = Arc::new(MemoryRunner::new());
+        let state = AppState::new(config_from(&[]).unwrap(), runner.clone(), HashMap::new());
+        let addr = serve(state.clone()).await;
+        let exception_rules = || -> Vec<(u32, String, String)> {
+            runner
+                .rules()
+                .into_iter()
+                .filter(|r| r.from == "10.40.0.3" && r.selector.starts_with("to "))
+                .map(|r| (r.priority, r.selector, r.table))
+                .collect()
+        };
+
+        let (status, body) = http(
+            addr,
+            "GET",
+            "/switch?ip=10.40.0.3&nic=wan1&dns=true&except=198.51.100.7,203.0.113.0/24",
+        )
+        .await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(
+            body.contains("2 destination exception(s) via wan0"),
+            "{}",
+            body
+        );
+        assert_eq!(
+            exception_rules(),
+            [
+                (
+                    998,
+                    "to 198.51.100.7/32".to_string(),
+                    TABLE_WAN0.to_string()
+                ),
+                (998, "to 203.0.113.0/24".to_string(), TABLE_WAN0.to_string()),
+            ]
+        );
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body["exceptions"]["10.40.0.3"],
+            serde_json::json!(["198.51.100.7/32", "203.0.113.0/24"])
+        );
+        // Recorded exceptions are not garbage
+        let (_, body) = http(addr, "POST", "/gc?dry_run=true").await;
+        assert!(!body.contains("198.51.100"), "{}", body);
+
+        // A move keeps them, pointing at the other WAN's table
+        let mut dns_steered = state.dns_steered.lock().await;
+        repin_host(&state, &mut dns_steered, "10.40.0.3", "wan0", true).unwrap();
+        drop(dns_steered);
+        assert_eq!(
+            exception_rules(),
+            [
+                (
+                    998,
+                    "to 198.51.100.7/32".to_string(),
+                    TABLE_WAN1.to_string()
+                ),
+                (998, "to 203.0.113.0/24".to_string(), TABLE_WAN1.to_string()),
+            ]
+        );
+
+        // A switch without `except` drops them, leaving the host's own rules
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        assert!(exception_rules().is_empty());
+        assert!(runner
+            .rules()
+            .iter()
+            .any(|r| r.from == "10.40.0.3" && r.table == TABLE_WAN1 && r.selector.is_empty()));
+
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1&except=vpn").await;
+        assert_eq!(status, 400);
+        assert!(body.contains("'vpn' is not an IPv4 prefix"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn dscp_policies_steer_marked_traffic() {
+        let runner = Arc::new(MemoryRunner::new());
+        let path = temp_state_file("dscp");
+        let config = config_from(&[("STATE_FILE", path.to_str().unwrap())]).unwrap();
+        let state = AppState::new(config, runner.clone(), HashMap::new());
+        let addr = serve(state.clone()).await;
+        let dscp_rules = || -> Vec<(u32, String, String)> {
+            let mut rules: Vec<_> = runner
+                .rules()
+                .into_iter()
+                .filter(|r| r.selector.contains("tos "))
+                .map(|r| (r.priority, r.selector, r.table))
+                .collect();
+            rules.sort();
+            rules
+        };
+
+        let (status, body) = http(addr, "POST", "/policy/dscp?dscp=ef&nic=wan1").await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("DSCP 46 now uses wan1"), "{}", body);
+        assert_eq!(
+            dscp_rules(),
+            [(997, "iif eth2 tos 0xb8".to_string(), TABLE_WAN1.to_string())]
+        );
+        // A second policy for the same value replaces the first
+        http(addr, "POST", "/policy/dscp?dscp=46&nic=wan0").await;
+        http(addr, "POST", "/policy/dscp?dscp=af41&nic=wan1").await;
+        assert_eq!(
+            dscp_rules(),
+            [
+                (997, "iif eth2 tos 0x88".to_string(), TABLE_WAN1.to_string()),
+                (997, "iif eth2 tos 0xb8".to_string(), TABLE_WAN0.to_string()),
+            ]
+        );
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body["dscp_policies"],
+            serde_json::json!({"34": "wan1", "46": "wan0"})
+        );
+        let (_, body) = http(addr, "GET", "/rules").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let kinds: Vec<(&str, bool)> = body["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|r| r["tos"].is_string())
+            .map(|r| (r["kind"].as_str().unwrap(), r["known"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(kinds, [("dscp", true), ("dscp", true)]);
+        // Policies are not garbage, and are saved
+        let (_, body) = http(addr, "POST", "/gc?dry_run=true").await;
+        assert!(!body.contains("tos"), "{}", body);
+        let saved = persist::read(&path).unwrap().unwrap();
+        assert_eq!(saved.dscp_policies.len(), 2);
+
+        let (status, _) = http(addr, "DELETE", "/policy/dscp/EF").await;
+        assert_eq!(status, 200);
+        assert_eq!(dscp_rules().len(), 1);
+        let (status, _) = http(addr, "DELETE", "/policy/dscp/ef").await;
+        assert_eq!(status, 404);
+        for bad in ["dscp=0&nic=wan1", "dscp=voice&nic=wan1", "dscp=ef&nic=wan9"] {
+            let (status, _) = http(addr, "POST", &format!("/policy/dscp?{}", bad)).await;
+            assert_eq!(status, 400, "{}", bad);
+        }
+
+        // Restoring the saved state puts the remaining policy back
+        let runner = Arc::new(MemoryRunner::new());
+        let state = AppState::new(config_from(&[]).unwrap(), runner.clone(), HashMap::new());
+        persist::restore(&state, persist::read(&path).unwrap().unwrap()).await;
+        assert!(runner
+            .rules()
+            .iter()
+            .any(|r| r.selector == "iif eth2 tos 0x88" && r.table == TABLE_WAN1));
+        assert_eq!(state.dscp_policies.lock().unwrap()[&34], "wan1");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn mapping_diff_shows_what_the_kernel_lacks() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        let diff = |path: &'static str| async move {
+            let (status, body) = http(addr, "GET", path).await;
+            assert_eq!(status, 200, "{}", body);
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+
+        http(
+            addr,
+            "GET",
+            "/switch?ip=10.40.0.3&nic=wan1&dns=true&except=198.51.100.7",
+        )
+        .await;
+        let body = diff("/mapping/10.40.0.3/diff").await;
+        assert_eq!(body["in_sync"], true);
+        assert_eq!(body["desired"]["nic"], "wan1");
+        assert_eq!(body["desired"]["rules"].as_array().unwrap().len(), 4);
+        assert_eq!(body["actual"]["rules"].as_array().unwrap().len(), 4);
+        assert_eq!(body["commands"], serde_json::json!([]));
+
+        // The host rule goes missing and a stray one shows up
+        runner
+            .run(
+                "ip",
+                &[
+                    "rule",
+                    "del",
+                    "priority",
+                    "1000",
+                    "from",
+                    "10.40.0.3/32",
+                    "lookup",
+                    TABLE_WAN1,
+                ],
+            )
+            .unwrap();
+        runner
+            .run(
+                "ip",
+                &[
+                    "rule",
+                    "add",
+                    "from",
+                    "10.40.0.3/32",
+                    "lookup",
+                    TABLE_WAN0,
+                    "priority",
+                    "1500",
+                ],
+            )
+            .unwrap();
+        let body = diff("/mapping/10.40.0.3/diff").await;
+        assert_eq!(body["in_sync"], false);
+        assert_eq!(
+            body["missing"],
+            serde_json::json!([format!(
+                "ip rule add from 10.40.0.3/32 lookup {} priority 1000",
+                TABLE_WAN1
+            )])
+        );
+        assert_eq!(body["unexpected"][0]["priority"], 1500);
+        let commands: Vec<String> = serde_json::from_value(body["commands"].clone()).unwrap();
+        assert_eq!(
+            commands[0],
+            format!(
+                "ip rule del priority 1500 from 10.40.0.3 lookup {}",
+                TABLE_WAN0
+            )
+        );
+        // Running the commands brings the kernel back in line
+        for command in &commands {
+            let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+            runner.run("ip", &args).unwrap();
+        }
+        let body = diff("/mapping/10.40.0.3/diff").await;
+        assert_eq!(body["commands"], serde_json::json!([]));
+        assert_eq!(body["in_sync"], true);
+
+        // Unmapped hosts should have no rules of their own
+        let body = diff("/mapping/10.40.0.9/diff").await;
+        assert_eq!(body["desired"]["mapped"], false);
+        assert_eq!(body["desired"]["nic"], "wan0");
+        assert_eq!(body["desired"]["rules"], serde_json::json!([]));
+        let (status, _) = http(addr, "GET", "/mapping/10.40.0/diff").await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn successful_switch_runs_post_switch_hook() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("POST_SWITCH_HOOK", "/usr/local/bin/notify-fw")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        // The memory runner fails the hook; the switch still succeeds
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan9").await;
+
+        let hooks = || -> Vec<String> {
+            runner
+                .calls()
+                .into_iter()
+                .filter(|c| c.starts_with("/usr/local/bin/notify-fw"))
+                .collect()
+        };
+        for _ in 0..50 {
+            if hooks().len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Hooks run in the background, so they may finish in either order
+        let mut ran = hooks();
+        ran.sort();
+        assert_eq!(
+            ran,
+            vec![
+                "/usr/local/bin/notify-fw 10.40.0.3 wan0 wan1",
+                "/usr/local/bin/notify-fw 10.40.0.3 wan1 wan0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn init_report_describes_initialization() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("SRC_WAN1", "auto")]).unwrap();
+        let addr = spawn_app_with(runner, config).await;
+
+        let (status, body) = http(addr, "GET", "/init/report").await;
+        assert_eq!(status, 200);
+        let report: InitReport = serde_json::from_str(&body).unwrap();
+        let wan0 = &report.wans["wan0"];
+        assert_eq!(
+            (wan0.interface.as_str(), wan0.table.as_str()),
+            ("eth0", "100")
+        );
+        assert_eq!(wan0.gateway, "192.0.2.1");
+        assert_eq!(wan0.source, None);
+        assert_eq!(wan0.link_routes, 1);
+        assert_eq!(report.wans["wan1"].source.as_deref(), Some("198.51.100.7"));
+        assert_eq!(
+            report.base_rules,
+            vec!["ip rule add from 10.40.0.0/20 lookup 100 priority 2000"]
+        );
+        assert!(report.completed_at > 0);
+    }
+
+    #[tokio::test]
+    async fn switch_installs_and_removes_host_rule() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["priority"], 1000);
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status.mappings.nic("10.40.0.3"), Some("wan1"));
+        assert_eq!(status.rule_priorities["10.40.0.3"], 1000);
+
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert_eq!(status, 200);
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN0));
+    }
+
+    #[tokio::test]
+    async fn chaos_mode_fails_switches_before_touching_the_kernel() {
+        assert!(config_from(&[("CHAOS_FAIL_RATE", "1")]).is_err());
+        assert!(config_from(&[("CHAOS_TESTING", "true"), ("CHAOS_FAIL_RATE", "1.5")]).is_err());
+
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[
+            ("CHAOS_TESTING", "true"),
+            ("CHAOS_DELAY_MS", "20"),
+            ("CHAOS_FAIL_RATE", "1"),
+        ])
+        .unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+        let before = runner.calls().len();
+
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 503);
+        assert!(body.contains("\"chaos\""), "{}", body);
+        assert_eq!(runner.calls().len(), before);
+    }
+
+    #[tokio::test]
+    async fn switch_debounce_applies_only_the_last_request() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("SWITCH_DEBOUNCE_MS", "200")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        let first = tokio::spawn(http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0"));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let last = http(addr, "GET", "/switch?ip=10.40.0.3/20&nic=wan1").await;
+
+        let (status, body) = first.await.unwrap();
+        assert_eq!(status, 200);
+        assert!(body.contains("\"debounced\""), "{}", body);
+        assert_eq!(last.0, 200);
+        assert!(last.1.contains("\"success\""), "{}", last.1);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status.mappings.nic("10.40.0.3"), Some("wan1"));
+    }
+
+    #[test]
+    fn add_ip_rule_reports_existing_priority() {
+        let runner = MemoryRunner::new();
+        runner
+            .run(
+                "ip",
+                &[
+                    "rule",
+                    "add",
+                    "from",
+                    "10.40.0.3/32",
+                    "lookup",
+                    "200",
+                    "priority",
+                    "1500",
+                ],
+            )
+            .unwrap();
+        let priority = add_ip_rule(
+            &runner,
+            "10.40.0.3/32",
+            &HostMatch::default(),
+            TABLE_WAN1,
+            DEFAULT_PRIO_SPECIFIC,
+        )
+        .unwrap();
+        assert_eq!(priority, 1500);
+    }
+
+    #[tokio::test]
+    async fn switch_with_dns_steers_and_reverts() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1&dns=true").await;
+        assert_eq!(status, 200);
+        let dns_rules = |table: &str| {
+            runner
+                .rules()
+                .iter()
+                .filter(|r| {
+                    r.from == "10.40.0.3" && r.table == table && r.selector.contains("dport 53")
+                })
+                .count()
+        };
+        assert_eq!(dns_rules(TABLE_WAN1), 2);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert_eq!(status, 200);
+        assert_eq!(dns_rules(TABLE_WAN1), 0);
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+    }
+
+    #[test]
+    fn adopt_existing_rules_imports_host_rules() {
+        let runner = MemoryRunner::new();
+        for args in [
+            "rule add from 10.40.0.3/32 lookup 200 priority 1000",
+            "rule add from 10.40.0.4 lookup 100 priority 1000",
+            "rule add from 10.40.0.3/32 ipproto udp dport 53 lookup 200 priority 999",
+            "rule add from 192.168.9.9/32 lookup 200 priority 1000",
+            "rule add from 10.40.0.0/20 lookup 100 priority 2000",
+            "rule add from 10.40.1.0/24 lookup 200 priority 1000",
+        ] {
+            let args: Vec<&str> = args.split(' ').collect();
+            runner.run("ip", &args).unwrap();
+        }
+
+        let config = config_from(&[]).unwrap();
+        let adopted = adopt_existing_rules(&runner, &config).unwrap();
+        assert_eq!(
+            mapping::nics(&adopted.mappings),
+            HashMap::from([
+                ("10.40.0.3".to_string(), "wan1".to_string()),
+                ("10.40.0.4".to_string(), "wan0".to_string()),
+                ("10.40.1.0/24".to_string(), "wan1".to_string()),
+            ])
+        );
+        assert_eq!(
+            adopted.dns_steered,
+            HashMap::from([("10.40.0.3".to_string(), TABLE_WAN1.to_string())])
+        );
+        let host: IpNet = "10.40.0.4/32".parse().unwrap();
+        assert_eq!(adopted.mappings[&host].priority, Some(1000));
+        assert_eq!(adopted.mappings[&host].origin, mapping::Origin::Adopted);
+    }
+
+    #[test]
+    fn auto_balance_weights_parse_and_pick() {
+        let weights = parse_weights("wan0:3, wan1:1").unwrap();
+        assert_eq!(
+            weights,
+            vec![("wan0".to_string(), 3), ("wan1".to_string(), 1)]
+        );
+        let picks: Vec<&str> = (0..4).map(|roll| pick_weighted(&weights, roll)).collect();
+        assert_eq!(picks, ["wan0", "wan0", "wan0", "wan1"]);
+
+        assert!(parse_weights("wan0:0,wan1:0").is_err());
+        assert!(parse_weights("wan2:1").is_err());
+        assert!(config_from(&[("AUTO_BALANCE", "wan1")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn auto_balance_assigns_new_hosts_and_keeps_manual_pins() {
+        let runner = Arc::new(
+            MemoryRunner::new()
+                .with_neighbor("eth2", "10.40.0.5")
+                .with_neighbor("eth2", "10.40.0.6")
+                .with_neighbor("eth2", "192.168.9.9"),
+        );
+        let config = config_from(&[("AUTO_BALANCE", "wan0:0,wan1:1")]).unwrap();
+        initialize_lan_to_wan0(runner.as_ref(), &config)
+            .await
+            .unwrap();
+        let weights = config.auto_balance.clone().unwrap();
+        let state = AppState::new(config, runner.clone(), HashMap::new());
+        mapping::pin(
+            &mut *state.mappings.lock().await,
+            "10.40.0.6",
+            "wan0",
+            None,
+            mapping::Origin::Switch,
+        );
+
+        let assigned = auto_balance_once(&state, &weights).await.unwrap();
+        assert_eq!(
+            assigned,
+            vec![("10.40.0.5".to_string(), "wan1".to_string())]
+        );
+        assert!(runner.has_rule("10.40.0.5/32", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.6/32", TABLE_WAN1));
+        assert!(state.auto_assigned.lock().await.contains_key("10.40.0.5"));
+
+        // Already-mapped hosts are left alone on the next pass
+        assert!(auto_balance_once(&state, &weights)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn pause_blocks_switch_until_resume() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+
+        let (status, _) = http(addr, "POST", "/pause").await;
+        assert_eq!(status, 200);
+        let (_, body) = http(addr, "GET", "/status").await;
+        assert!(
+            serde_json::from_str::<StatusResponse>(&body)
+                .unwrap()
+                .paused
+        );
+
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 503);
+        assert!(body.contains("\"paused\""));
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+
+        let (status, _) = http(addr, "POST", "/resume").await;
+        assert_eq!(status, 200);
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+    }
+
+    #[tokio::test]
+    async fn read_only_watches_without_changing_anything() {
+        // The active instance has set things up and pinned a host
+        let runner = Arc::new(MemoryRunner::new());
+        let active = spawn_app(runner.clone()).await;
+        let (status, _) = http(active, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+
+        let config = config_from(&[("READ_ONLY", "true")]).unwrap();
+        let report = readonly::discover(runner.as_ref(), &config).unwrap();
+        let state = AppState::new(config, runner.clone(), report.sources());
+        *state.init_report.lock().await = Some(report);
+        readonly::refresh(&state).await.unwrap();
+        assert!(state.reconciled_at.load(Ordering::SeqCst) > 0);
+        let addr = serve(state.clone()).await;
+        let changes = || {
+            runner
+                .calls()
+                .iter()
+                .filter(|c| c.contains(" add ") || c.contains(" del ") || c.contains(" replace "))
+                .count()
+        };
+        let before = changes();
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert!(status.read_only);
+        assert!(status.drift.is_empty(), "{:?}", status.drift);
+        assert_eq!(status.overall, overall::Overall::Healthy);
+        assert_eq!(status.mappings.nic("10.40.0.3"), Some("wan1"));
+
+        for (method, path) in [
+            ("GET", "/switch?ip=10.40.0.3&nic=wan0"),
+            ("POST", "/gc"),
+            ("POST", "/pause"),
+            ("DELETE", "/schedules/night"),
+        ] {
+            let (status, body) = http(addr, method, path).await;
+            assert_eq!(status, 403, "{} {}", method, path);
+            assert!(body.contains("\"read_only\""), "{}", body);
+        }
+        let (status, _) = http(addr, "POST", "/gc?dry_run=true").await;
+        assert_eq!(status, 200);
+        assert_eq!(changes(), before);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+
+        // Someone else removing the base rule is drift
+        let del = "rule del from 10.40.0.0/20 lookup 100 priority 2000";
+        runner
+            .run("ip", &del.split(' ').collect::<Vec<_>>())
+            .unwrap();
+        readonly::refresh(&state).await.unwrap();
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status.drift.len(), 1, "{:?}", status.drift);
+        assert_eq!(status.overall, overall::Overall::Degraded);
+    }
+
+    #[test]
+    fn add_route_mode_keeps_existing_default() {
+        let runner = MemoryRunner::new();
+        runner
+            .run(
+                "ip",
+                &[
+                    "route",
+                    "replace",
+                    "default",
+                    "via",
+                    "192.0.2.254",
+                    "dev",
+                    "eth0",
+                    "table",
+                    "100",
+                ],
+            )
+            .unwrap();
+        let config =
+            config_from(&[("ROUTE_MODE_WAN0", "add"), ("ROUTE_MODE_WAN1", "add")]).unwrap();
+        let gw0 = Nexthop::Via("192.0.2.1".parse().unwrap());
+        let gw1 = Nexthop::Via("198.51.100.1".parse().unwrap());
+
+        ensure_table_default_route(
+            &runner,
+            &config.wan0,
+            TABLE_WAN0,
+            &gw0,
+            None,
+            config.route_mode_wan0,
+        )
+        .unwrap();
+        ensure_table_default_route(
+            &runner,
+            &config.wan1,
+            TABLE_WAN1,
+            &gw1,
+            None,
+            config.route_mode_wan1,
+        )
+        .unwrap();
+        assert_eq!(
+            runner.routes(TABLE_WAN0),
+            vec!["default via 192.0.2.254 dev eth0".to_string()]
+        );
+        assert_eq!(
+            runner.routes(TABLE_WAN1),
+            vec!["default via 198.51.100.1 dev eth1".to_string()]
+        );
+        assert!(runner
+            .calls()
+            .iter()
+            .any(|c| c.starts_with("ip route add default")));
+
+        assert!(config_from(&[("ROUTE_MODE_WAN0", "merge")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn wan1_base_pins_wan0_hosts_as_exceptions() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("BASE_WAN", "wan1"), ("FALLBACK_TO_PRIMARY", "true")]).unwrap();
+        // A base rule left in wan0's table by an earlier run is dropped
+        runner
+            .run(
+                "ip",
+                &[
+                    "rule",
+                    "add",
+                    "from",
+                    "10.40.0.0/20",
+                    "lookup",
+                    "100",
+                    "priority",
+                    "2000",
+                ],
+            )
+            .unwrap();
+        initialize_lan_to_wan0(runner.as_ref(), &config)
+            .await
+            .unwrap();
+        let lan_rules: Vec<(u32, String)> = runner
+            .rules()
+            .into_iter()
+            .filter(|r| r.from == "10.40.0.0/20")
+            .map(|r| (r.priority, r.table))
+            .collect();
+        assert_eq!(
+            lan_rules,
+            vec![(2000, "200".to_string()), (1001, "200".to_string())]
+        );
+
+        let state = AppState::new(config.clone(), runner.clone(), HashMap::new());
+        assert_eq!(state.health.lock().await.failover.active, "wan1");
+        let addr = serve(state.clone()).await;
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert_eq!(status, 200);
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan1").await;
+        assert_eq!(status, 200);
+        // The exception gets its own rule; wan1 hosts ride the base rule
+        assert!(runner.has_rule("10.40.0.3", TABLE_WAN0));
+        assert!(!runner.has_rule("10.40.0.4", TABLE_WAN1));
+        assert!(runner
+            .rules()
+            .iter()
+            .all(|r| r.from != "10.40.0.3" || r.priority < 2000));
+
+        let rules = parse_ip_rules(&ip_rule_list(runner.as_ref()).unwrap());
+        let mappings = mapping::nics(&*state.mappings.lock().await);
+        assert!(stale_rules(&config, &rules, &mappings, TABLE_WAN1).is_empty());
+        let plan = build_plan(runner.as_ref(), &config, &mappings, &HashMap::new()).unwrap();
+        let plan: Vec<String> = plan.iter().map(|c| c.to_string()).collect();
+        assert!(
+            plan.contains(&"ip rule add from 10.40.0.0/20 lookup 200 priority 2000".to_string())
+        );
+        assert!(
+            plan.contains(&"ip rule add from 10.40.0.3/32 lookup 100 priority 1000".to_string())
+        );
+        assert!(!plan.iter().any(|c| c.contains("10.40.0.4")));
+
+        assert!(config_from(&[("BASE_WAN", "wan2")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn offlink_gateway_is_retried_with_onlink() {
+        let runner = MemoryRunner::new();
+        // eth1 sits in 198.51.100.0/24; the ISP's gateway does not
+        let config = config_from(&[("GATEWAY_WAN1", "203.0.113.1")]).unwrap();
+        initialize_lan_to_wan0(&runner, &config).await.unwrap();
+
+        let attempts: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .filter(|c| c.starts_with("ip route replace default via 203.0.113.1"))
+            .collect();
+        assert_eq!(
+            attempts,
+            vec![
+                "ip route replace default via 203.0.113.1 dev eth1 table 200",
+                "ip route replace default via 203.0.113.1 dev eth1 onlink table 200",
+            ]
+        );
+        assert!(runner
+            .routes(TABLE_WAN1)
+            .contains(&"default via 203.0.113.1 dev eth1 onlink".to_string()));
+        // An on-link gateway is never marked
+        assert!(runner
+            .routes(TABLE_WAN0)
+            .contains(&"default via 192.0.2.1 dev eth0".to_string()));
+
+        let plan = build_plan(&runner, &config, &HashMap::new(), &HashMap::new()).unwrap();
+        assert_eq!(
+            plan[1].to_string(),
+            "ip route replace default via 203.0.113.1 dev eth1 onlink table 200"
+        );
+    }
+
+    #[tokio::test]
+    async fn multipath_gateways_share_the_table_default() {
+        let runner = MemoryRunner::new();
+        let config = config_from(&[("GATEWAY_WAN1", "198.51.100.1, 198.51.100.2")]).unwrap();
+        let report = initialize_lan_to_wan0(&runner, &config).await.unwrap();
+        assert!(runner.calls().contains(
+            &"ip route replace default table 200 nexthop via 198.51.100.1 dev eth1 \
+              nexthop via 198.51.100.2 dev eth1"
+                .to_string()
+        ));
+        assert_eq!(
+            report.wans["wan1"].nexthops,
+            vec!["198.51.100.1", "198.51.100.2"]
+        );
+        assert_eq!(report.wans["wan1"].gateway, "198.51.100.1,198.51.100.2");
+        // The kernel prints each nexthop on its own line; they are one route
+        assert!(foreign_table_routes(&runner, &config).unwrap().is_empty());
+        let shown = runner
+            .run("ip", &["route", "show", "table", "200", "default"])
+            .unwrap();
+        let routes = route_entries(&shown);
+        assert_eq!(routes.len(), 1);
+        assert!(default_route_matches(
+            &routes[0],
+            "eth1",
+            config.gateway_wan1.as_ref().unwrap()
+        ));
+        assert!(!default_route_matches(
+            &routes[0],
+            "eth1",
+            &Nexthop::Via("198.51.100.1".parse().unwrap())
+        ));
+
+        // Every gateway must be on the link
+        let config = config_from(&[("GATEWAY_WAN1", "198.51.100.1,203.0.113.1")]).unwrap();
+        let err = initialize_lan_to_wan0(&MemoryRunner::new(), &config)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("203.0.113.1 are not reachable on eth1"));
+
+        for bad in [
+            "192.0.2.1,192.0.2.1",
+            "192.0.2.1,2001:db8::1",
+            "192.0.2.1,dev",
+        ] {
+            assert!(config_from(&[("GATEWAY_WAN0", bad)]).is_err(), "{}", bad);
+        }
+    }
+
+    /// Answers every command with the same canned output.
+    struct FixedRunner(&'static str);
+
+    impl CommandRunner for FixedRunner {
+        fn run(&self, _cmd: &str, _args: &[&str]) -> Result<String> {
+            Ok(self.0.to_string())
+        }
+    }
+
+    #[test]
+    fn ip_rule_exists_matches_whole_rule_not_substring() {
+        let tricky = FixedRunner(
+            "0:\tfrom all lookup local\n\
+             999:\tfrom 10.40.0.3 ipproto udp dport 53 lookup 200\n\
+             1000:\tfrom 10.40.0.30 lookup 200\n\
+             1001:\tfrom 10.40.0.3 to 203.0.113.0/24 lookup 200\n\
+             1002:\tnot from 10.40.0.3 lookup 200\n\
+             1003:\tfrom 10.40.0.3 iif eth2 lookup 200\n\
+             1004:\tfrom 10.40.0.3 lookup 2000\n\
+             1005:\tfrom 10.40.0.4 lookup 100\n\
+             32766:\tfrom all lookup main\n",
+        );
+        // Every line above contains "from 10.40.0.3" and/or "lookup 200" text,
+        // but none is the plain host rule
+        assert!(
+            !ip_rule_exists(&tricky, "10.40.0.3/32", &HostMatch::default(), TABLE_WAN1).unwrap()
+        );
+        assert!(!ip_rule_exists(&tricky, "10.40.0.3", &HostMatch::default(), TABLE_WAN1).unwrap());
+        assert!(
+            !ip_rule_exists(&tricky, "10.40.0.4/32", &HostMatch::default(), TABLE_WAN1).unwrap()
+        );
+        assert!(
+            ip_rule_exists(&tricky, "10.40.0.30/32", &HostMatch::default(), TABLE_WAN1).unwrap()
+        );
+        assert!(
+            ip_rule_exists(&tricky, "10.40.0.4/32", &HostMatch::default(), TABLE_WAN0).unwrap()
+        );
+
+        // Kernel-style output drops /32; both spellings find the rule
+        let plain = FixedRunner("1000:\tfrom 10.40.0.3 lookup 200\n");
+        assert!(ip_rule_exists(&plain, "10.40.0.3/32", &HostMatch::default(), TABLE_WAN1).unwrap());
+        assert!(ip_rule_exists(&plain, "10.40.0.3", &HostMatch::default(), TABLE_WAN1).unwrap());
+    }
+
+    #[test]
+    fn masked_and_bare_host_selectors_are_one_rule() {
+        let rules = parse_ip_rules(
+            "1000:\tfrom 10.40.0.3/32 lookup 200\n\
+             1001:\tfrom 10.40.0.3 lookup 200\n\
+             1002:\tfrom 2001:db8::5/128 lookup 100\n\
+             1003:\tfrom 2001:db8::/32 lookup 100\n",
+        );
+        let froms: Vec<&str> = rules.iter().map(|r| r.from.as_str()).collect();
+        assert_eq!(
+            froms,
+            ["10.40.0.3", "10.40.0.3", "2001:db8::5", "2001:db8::/32"]
+        );
+
+        // A rule added by hand with its mask is found under either spelling
+        let masked = FixedRunner("500:\tfrom 10.40.0.3/32 lookup 200\n");
+        for from in ["10.40.0.3", "10.40.0.3/32"] {
+            assert_eq!(
+                rule_priority(&masked, from, &HostMatch::default(), TABLE_WAN1).unwrap(),
+                Some(500)
+            );
+        }
+
+        // The second spelling of the same rule is a duplicate for /gc
+        let config = config_from(&[]).unwrap();
+        let mappings = HashMap::from([("10.40.0.3".to_string(), "wan1".to_string())]);
+        let stale = stale_rules(&config, &rules[..2], &mappings, TABLE_WAN0);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].priority, 1001);
+    }
+
+    #[test]
+    fn parse_ip_rules_handles_varied_lines() {
+        let out = "0:\tfrom all lookup local\n\
+                   999:\tfrom 10.40.0.3 ipproto udp dport 53 lookup 200\n\
+                   1000:\tfrom 10.40.0.3 lookup 200 proto static\n\
+                   1500:\tfrom all to 203.0.113.0/24 lookup 100\n\
+                   1600:\tnot from 10.40.0.9 lookup 100 [detached]\n\
+                   1700:\tfrom all iif eth2 table 200\n\
+                   1800:\tfrom all unreachable\n\
+                   1900:\tfrom all goto 2000\n\
+                   garbage line\n\
+                   32766:\tfrom all lookup main\n";
+        let rules = parse_ip_rules(out);
+        let summary: Vec<(u32, &str, Option<&str>, &str)> = rules
+            .iter()
+            .map(|r| {
+                (
+                    r.priority,
+                    r.from.as_str(),
+                    r.to.as_deref(),
+                    r.table.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, "all", None, "local"),
+                (999, "10.40.0.3", None, "200"),
+                (1000, "10.40.0.3", None, "200"),
+                (1500, "all", Some("203.0.113.0/24"), "100"),
+                (1600, "10.40.0.9", None, "100"),
+                (1700, "all", None, "200"),
+                (32766, "all", None, "main"),
+            ]
+        );
+        assert_eq!(rules[1].ipproto.as_deref(), Some("udp"));
+        assert_eq!(rules[1].dport.as_deref(), Some("53"));
+        assert_eq!(rules[2].dport, None);
+    }
+
+    #[tokio::test]
+    async fn rules_endpoint_reports_managed_rules() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1&dns=true").await;
+        runner
+            .run("ip", &["rule", "add", "from", "10.40.0.8", "lookup", "200"])
+            .unwrap();
+
+        let (status, body) = http(addr, "GET", "/rules").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let rules: Vec<(String, String, bool)> = body["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| {
+                (
+                    r["from"].as_str().unwrap().to_string(),
+                    r["kind"].as_str().unwrap().to_string(),
+                    r["known"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert!(rules.contains(&("10.40.0.0/20".into(), "lan".into(), true)));
+        assert!(rules.contains(&("10.40.0.3".into(), "host".into(), true)));
+        assert!(rules.contains(&("10.40.0.3".into(), "dns".into(), true)));
+        assert!(rules.contains(&("10.40.0.8".into(), "host".into(), false)));
+        assert!(!rules.iter().any(|(from, _, _)| from == "all"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        histogram.observe(30.0);
+        let mut out = String::new();
+        histogram.render("h", "nic=\"wan1\"", &mut out);
+        assert!(out.contains("h_bucket{nic=\"wan1\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("h_bucket{nic=\"wan1\",le=\"0.25\"} 2\n"));
+        assert!(out.contains("h_bucket{nic=\"wan1\",le=\"10\"} 2\n"));
+        assert!(out.contains("h_bucket{nic=\"wan1\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_count{nic=\"wan1\"} 3\n"));
+    }
+
+    #[tokio::test]
+    async fn metrics_include_switch_duration() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=bogus").await;
+
+        let (_, body) = http(addr, "GET", "/metrics").await;
+        assert!(body.contains("# TYPE adaptive_switch_duration_seconds histogram"));
+        assert!(body
+            .contains("adaptive_switch_duration_seconds_count{outcome=\"success\",nic=\"wan1\"}"));
+        assert!(body
+            .contains("adaptive_switch_duration_seconds_count{outcome=\"error\",nic=\"invalid\"}"));
+    }
+
+    #[tokio::test]
+    async fn pin_primary_installs_wan0_host_rule() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("PIN_PRIMARY", "true")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert_eq!(status, 200);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN0));
+
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN0));
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+    }
+
+    #[test]
+    fn switch_ip_parsing_edge_cases() {
+        let ok = |s: &str| parse_switch_ip(s).map(|ip| ip.to_string());
+        assert_eq!(ok("10.40.0.3"), Some("10.40.0.3".to_string()));
+        assert_eq!(ok("10.40.0.3/20"), Some("10.40.0.3".to_string()));
+        for mapped in [
+            "::ffff:10.40.0.3",
+            "::FFFF:10.40.0.3",
+            "0:0:0:0:0:ffff:a28:3",
+            "::ffff:a28:3/116",
+            "::ffff:10.40.0.3/128",
+        ] {
+            assert_eq!(ok(mapped), Some("10.40.0.3".to_string()), "{}", mapped);
+        }
+        for bad in [
+            "999.1.1.1",
+            "10.40.0.03",
+            "10.40.0",
+            "10.40.0.3.4",
+            "10.40.0.3/33",
+            "10.40.0.3/+1",
+            "10.40.0.3/",
+            " 10.40.0.3",
+            "10.40.0.3 extra",
+            "::1",
+            "2001:db8::a28:3",
+            "::ffff:10.40.0.3/20",
+            "::ffff:10.40.0.3/129",
+        ] {
+            assert_eq!(parse_switch_ip(bad), None, "{:?}", bad);
+        }
+        assert_eq!(
+            parse_via("default via 192.0.2.1 dev eth0 proto dhcp metric 100"),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(parse_via("default dev ppp0 scope link"), None);
+        assert_eq!(parse_via("default via 300.1.1.1 dev eth0"), None);
+    }
+
+    #[test]
+    fn parses_ipv6_ra_default_gateway() {
+        let single =
+            "default via fe80::1 proto ra metric 1024 expires 1790sec hoplimit 64 pref medium\n";
+        assert_eq!(
+            parse_ipv6_default_gateway(single, "eth0"),
+            Some("fe80::1".parse().unwrap())
+        );
+        let multipath = "default proto ra metric 1024 expires 1790sec pref medium\n\
+            \tnexthop via fe80::aaaa dev eth1 weight 1\n\
+            \tnexthop via fe80::bbbb dev eth0 weight 1\n";
+        assert_eq!(
+            parse_ipv6_default_gateway(multipath, "eth0"),
+            Some("fe80::bbbb".parse().unwrap())
+        );
+        assert_eq!(parse_ipv6_default_gateway("", "eth0"), None);
+        assert_eq!(
+            parse_ipv6_default_gateway("default dev ppp0 proto kernel metric 256\n", "ppp0"),
+            None
+        );
+
+        let gw = "fe80::1".parse().unwrap();
+        assert_eq!(
+            table_default_route6_cmd(
+                &Interface::new("eth0").unwrap(),
+                TABLE_WAN0,
+                &gw,
+                RouteMode::Replace
+            )
+            .to_string(),
+            "ip -6 route replace default via fe80::1 dev eth0 table 100"
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn switch_ip_accepts_any_ipv4(addr: std::net::Ipv4Addr, prefix in proptest::option::of(0u8..=32)) {
+            let input = match prefix {
+                Some(len) => format!("{}/{}", addr, len),
+                None => addr.to_string(),
+            };
+            proptest::prop_assert_eq!(parse_switch_ip(&input), Some(IpAddr::V4(addr)));
+        }
+
+        #[test]
+        fn switch_ip_rejects_out_of_range_octets(
+            octets in proptest::array::uniform4(0u32..1000),
+        ) {
+            let input = octets.map(|o| o.to_string()).join(".");
+            let valid = octets.iter().all(|o| *o <= 255);
+            proptest::prop_assert_eq!(parse_switch_ip(&input).is_some(), valid);
+        }
+
+        #[test]
+        fn switch_ip_never_panics_and_round_trips(input in "[0-9./a-z: +-]{0,24}") {
+            if let Some(ip) = parse_switch_ip(&input) {
+                let host = input.split('/').next().unwrap();
+                // IPv4-mapped spellings come back as plain IPv4
+                let canonical = host.parse::<IpAddr>().unwrap().to_canonical();
+                proptest::prop_assert_eq!(ip, canonical);
+            }
+        }
+
+        #[test]
+        fn gateway_parsing_finds_via(gw: std::net::Ipv4Addr, tail in "( [a-z0-9]{1,8}){0,4}") {
+            let line = format!("default via {} dev eth0{}", gw, tail);
+            proptest::prop_assert_eq!(parse_via(&line), Some(IpAddr::V4(gw)));
+        }
+
+        #[test]
+        fn gateway_parsing_never_panics(line in "\\PC{0,64}") {
+            let _ = parse_via(&line);
+        }
+    }
+
+    #[tokio::test]
+    async fn failover_moves_base_rule_and_gc_keeps_it() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("HEALTH_TARGET", "192.0.2.53"), ("FAILOVER", "true")]).unwrap();
+        initialize_lan_to_wan0(runner.as_ref(), &config)
+            .await
+            .unwrap();
+        let health_config = config.health.clone().unwrap();
+        let state = AppState::new(config, runner.clone(), HashMap::new());
+
+        let now = std::time::Instant::now();
+        health::check_once(&state, &health_config, now).await;
+        runner.set_reachable("eth0", false);
+        for _ in 0..3 {
+            health::check_once(&state, &health_config, now).await;
+        }
+        assert!(runner.has_rule("10.40.0.0/20", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.0/20", TABLE_WAN0));
+        assert_eq!(state.health.lock().await.failover.active, "wan1");
+        assert!(state.health.lock().await.failover.changed_at.is_some());
+
+        let rules = parse_ip_rules(&ip_rule_list(runner.as_ref()).unwrap());
+        assert!(stale_rules(&state.config, &rules, &HashMap::new(), TABLE_WAN1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_deletes_refuse_to_leave_a_contradicting_rule() {
+        let pinned = |runner: &MemoryRunner, table: &str| {
+            runner
+                .rules()
+                .iter()
+                .filter(|r| r.from == "10.40.0.3" && r.table == table)
+                .count()
+        };
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("PIN_PRIMARY", "true")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        runner.fail_commands("ip rule del from 10.40.0.3/32");
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert_eq!(status, 500, "{}", body);
+        assert!(body.contains("delete rule from 10.40.0.3/32"), "{}", body);
+        assert_eq!(pinned(&runner, TABLE_WAN1), 1);
+        assert_eq!(pinned(&runner, TABLE_WAN0), 0);
+
+        // Best-effort deletes go on and leave both rules in place
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("PIN_PRIMARY", "true"), ("STRICT_DELETES", "false")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        runner.fail_commands("ip rule del from 10.40.0.3/32");
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert_eq!(status, 200);
+        assert_eq!(pinned(&runner, TABLE_WAN1), 1);
+        assert_eq!(pinned(&runner, TABLE_WAN0), 1);
+    }
+
+    #[tokio::test]
+    async fn exported_script_recreates_the_rules() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        for path in [
+            "/switch?ip=10.40.0.3&nic=wan1&dns=true&except=198.51.100.7",
+            "/switch?ip=10.40.0.4&nic=wan0",
+        ] {
+            let (status, body) = http(addr, "GET", path).await;
+            assert_eq!(status, 200, "{}", body);
+        }
+        http(addr, "POST", "/policy/dscp?dscp=ef&nic=wan1").await;
+
+        let (status, script) = http(addr, "GET", "/export/script").await;
+        assert_eq!(status, 200);
+        assert!(script.starts_with("#!/bin/sh\n"), "{}", script);
+        for section in [
+            "# --- init: WAN table routes and LAN base rules ---",
+            "# --- hosts (2) ---",
+            "# 10.40.0.3 -> wan1\n",
+            "# 10.40.0.4 -> wan0 (base rule)",
+            "# --- DSCP policies ---",
+        ] {
+            assert!(script.contains(section), "{}\n{}", section, script);
+        }
+
+        // Replayed on a clean box, it yields the same rules
+        let clean = MemoryRunner::new();
+        for line in script.lines().filter(|l| l.starts_with("ip ")) {
+            let args: Vec<&str> = line.split_whitespace().skip(1).collect();
+            clean.run("ip", &args).unwrap();
+        }
+        let rules = |runner: &MemoryRunner| {
+            let mut rules: Vec<_> = runner
+                .rules()
+                .into_iter()
+                .map(|r| (r.priority, r.from, r.selector, r.table))
+                .collect();
+            rules.sort();
+            rules
+        };
+        assert_eq!(rules(&clean), rules(&runner));
+        assert_eq!(clean.routes(TABLE_WAN1), runner.routes(TABLE_WAN1));
+    }
+
+    #[tokio::test]
+    async fn status_and_rules_are_paged() {
+        let addr = spawn_app(Arc::new(MemoryRunner::new())).await;
+        for host in ["10.40.0.10", "10.40.0.9", "10.40.0.3"] {
+            let (status, _) = http(addr, "GET", &format!("/switch?ip={}&nic=wan1", host)).await;
+            assert_eq!(status, 200);
+        }
+        let status = |path: String| async move {
+            let (code, body) = http(addr, "GET", &path).await;
+            assert_eq!(code, 200, "{}", body);
+            serde_json::from_str::<StatusResponse>(&body).unwrap()
+        };
+
+        // Hosts come in address order, not as strings
+        let first = status("/status?limit=2".to_string()).await;
+        assert!(first.mappings.nic("10.40.0.3").is_some());
+        assert!(first.mappings.nic("10.40.0.9").is_some());
+        assert_eq!(first.rule_priorities.len(), 2);
+        assert_eq!(first.mapping_count, 3);
+        let next = first.mappings_page.next_offset.unwrap();
+        let second = status(format!("/status?limit=2&offset={}", next)).await;
+        assert!(second.mappings.nic("10.40.0.10").is_some());
+        assert_eq!(second.mappings_page.next_offset, None);
+        let all = status("/status".to_string()).await;
+        assert_eq!(all.mappings_page.limit, page::DEFAULT_LIMIT);
+        assert_eq!(all.mappings_page.total, 3);
+
+        let (_, body) = http(addr, "GET", "/rules?limit=1&offset=1").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["rules"].as_array().unwrap().len(), 1);
+        assert_eq!(body["page"]["total"], 4);
+        assert_eq!(body["page"]["next_offset"], 2);
+        assert_eq!(body["rules"][0]["priority"], 1000);
+        let (_, body) = http(addr, "GET", "/rules?limit=2&offset=2").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["rules"][1]["kind"], "lan");
+        assert_eq!(body["page"]["next_offset"], serde_json::Value::Null);
+
+        for path in ["/status?limit=0", "/rules?limit=5001"] {
+            let (code, _) = http(addr, "GET", path).await;
+            assert_eq!(code, 400, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv4_mapped_spellings_are_one_host() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+
+        for ip in ["::ffff:10.40.0.3", "10.40.0.3", "0:0:0:0:0:FFFF:a28:3/128"] {
+            let (status, body) = http(addr, "GET", &format!("/switch?ip={}&nic=wan1", ip)).await;
+            assert_eq!(status, 200, "{}: {}", ip, body);
+        }
+        let host_rules: Vec<_> = runner
+            .rules()
+            .into_iter()
+            .filter(|r| r.from == "10.40.0.3")
+            .collect();
+        assert_eq!(host_rules.len(), 1, "{:?}", host_rules);
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["mappings"], serde_json::json!({"10.40.0.3": "wan1"}));
+
+        // A mapped network address still reads as a prefix
+        let (status, _) = http(addr, "GET", "/switch?ip=::ffff:10.40.0.0/116&nic=wan1").await;
+        assert_eq!(status, 400);
+        let (status, _) = http(addr, "GET", "/mapping/::ffff:10.40.0.3/diff").await;
+        assert_eq!(status, 200);
+        assert!(exceptions::parse("::ffff:203.0.113.9").is_ok());
+    }
+
+    #[tokio::test]
+    async fn nic_aliases_resolve_to_their_wan() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("NIC_ALIASES", "primary=wan0, lte-backup=wan1")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=lte-backup").await;
+        assert_eq!(status, 200);
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.4&nic=eth1").await;
+        assert_eq!(status, 400);
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["mappings"]["10.40.0.3"], "wan1");
+        assert_eq!(body["config"]["nic_aliases"]["lte-backup"], "wan1");
+        assert_eq!(body["config"]["nic_aliases"]["primary"], "wan0");
+
+        for aliases in [
+            "backup=wan2",
+            "wan1=wan0",
+            "a=wan0,a=wan1",
+            "lte backup=wan1",
+            "wan1",
+        ] {
+            assert!(
+                config_from(&[("NIC_ALIASES", aliases)]).is_err(),
+                "{}",
+                aliases
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn capabilities_follow_the_config() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[
+            ("HEALTH_TARGET", "192.0.2.53"),
+            ("FAILOVER", "true"),
+            ("HOST_RULE_FWMARK", "0x10"),
+            ("ROUTE_MODE_WAN1", "add"),
+            ("NIC_ALIASES", "lte-backup=wan1"),
+        ])
+        .unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        let (status, body) = http(addr, "GET", "/capabilities").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["wan_count"], 2);
+        assert_eq!(body["wans"]["wan1"], "eth1");
+        assert_eq!(body["nic_aliases"]["lte-backup"], "wan1");
+        assert_eq!(body["modes"]["lan_rule"], "subnet");
+        assert_eq!(body["modes"]["host_match"], serde_json::json!(["fwmark"]));
+        assert_eq!(body["modes"]["route"]["wan1"], "add");
+        assert_eq!(body["features"]["failover"], true);
+        assert_eq!(body["features"]["ipv6"], false);
+        assert_eq!(body["system"]["iproute2"], "6.1.0");
+        assert_eq!(body["system"]["ip_json"], false);
+
+        // Probed once, not per request
+        http(addr, "GET", "/capabilities").await;
+        let probes = runner.calls().iter().filter(|c| *c == "ip -V").count();
+        assert_eq!(probes, 1);
+    }
+
+    #[tokio::test]
+    async fn instance_name_is_reported() {
+        let config = config_from(&[("INSTANCE_NAME", " site-a ")]).unwrap();
+        let addr = spawn_app_with(Arc::new(MemoryRunner::new()), config).await;
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["instance"], "site-a");
+        let (status, body) = http(addr, "GET", "/version").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["instance"], "site-a");
+        assert_eq!(body["version"], version::VERSION);
+
+        // Unset, it falls back to the hostname
+        assert!(!config_from(&[]).unwrap().instance_name.is_empty());
+        assert!(config_from(&[("INSTANCE_NAME", "site a")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn status_reports_when_it_was_made_and_uptime() {
+        let runner = Arc::new(MemoryRunner::new());
+        let mut state = AppState::new(config_from(&[]).unwrap(), runner, HashMap::new());
+        state.started_at = unix_now() - 90;
+        state.started = std::time::Instant::now() - std::time::Duration::from_secs(90);
+        let addr = serve(state.clone()).await;
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let generated_at = body["generated_at"].as_u64().unwrap();
+        assert_eq!(body["started_at"].as_u64().unwrap(), state.started_at);
+        assert!(generated_at >= state.started_at + 90);
+        assert!(body["uptime_secs"].as_u64().unwrap() >= 90);
+        // Nothing restored or failed over yet
+        assert!(body["reconciled_at"].is_null());
+        assert!(body["last_failover_at"].is_null());
+
+        state.reconciled_at.store(generated_at, Ordering::SeqCst);
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["reconciled_at"].as_u64(), Some(generated_at));
+    }
+
+    #[tokio::test]
+    async fn link_quality_is_reported_in_status_and_metrics() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config =
+            config_from(&[("HEALTH_TARGET", "192.0.2.53"), ("HEALTH_WINDOW", "4")]).unwrap();
+        let health_config = config.health.clone().unwrap();
+        let state = AppState::new(config, runner.clone(), HashMap::new());
+        let now = std::time::Instant::now();
+        for _ in 0..3 {
+            health::check_once(&state, &health_config, now).await;
+        }
+        runner.set_reachable("eth1", false);
+        for _ in 0..2 {
+            health::check_once(&state, &health_config, now).await;
+        }
+        let addr = serve(state).await;
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["health"]["wan0"]["rtt_ms"], 1.5);
+        assert_eq!(body["health"]["wan0"]["loss_ratio"], 0.0);
+        // Two of the last four probes went unanswered; wan1 is still up
+        assert_eq!(body["health"]["wan1"]["loss_ratio"], 0.5);
+        assert_eq!(body["health"]["wan1"]["healthy"], true);
+
+        let (_, body) = http(addr, "GET", "/metrics").await;
+        assert!(body.contains("adaptive_wan_rtt_ms{nic=\"wan0\"} 1.5\n"));
+        assert!(body.contains("adaptive_wan_loss_ratio{nic=\"wan1\"} 0.5\n"));
+        assert!(config_from(&[("HEALTH_TARGET", "192.0.2.53"), ("HEALTH_WINDOW", "0")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn auto_pins_follow_the_faster_wan_with_hysteresis() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config =
+            config_from(&[("HEALTH_TARGET", "192.0.2.53"), ("HEALTH_WINDOW", "1")]).unwrap();
+        let health_config = config.health.clone().unwrap();
+        let state = AppState::new(config, runner.clone(), HashMap::new());
+        runner.set_rtt("eth0", 40.0);
+        runner.set_rtt("eth1", 15.0);
+        let now = std::time::Instant::now();
+        health::check_once(&state, &health_config, now).await;
+        let addr = serve(state.clone()).await;
+
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=auto").await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("(auto: lowest RTT: 15.0 ms vs wan0 40.0 ms)"));
+        assert!(runner.has_rule("10.40.0.3", TABLE_WAN1));
+
+        // 10 ms better is within the 20 ms hysteresis
+        runner.set_rtt("eth0", 5.0);
+        health::check_once(&state, &health_config, now).await;
+        assert!(runner.has_rule("10.40.0.3", TABLE_WAN1));
+        runner.set_rtt("eth1", 30.0);
+        health::check_once(&state, &health_config, now).await;
+        assert!(!runner.has_rule("10.40.0.3", TABLE_WAN1));
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["mappings"]["10.40.0.3"], "wan0");
+        assert_eq!(body["auto_pins"]["10.40.0.3"]["nic"], "wan0");
+        assert_eq!(
+            body["auto_pins"]["10.40.0.3"]["reason"],
+            "lower RTT: 5.0 ms vs wan1 30.0 ms"
+        );
+
+        // A manual switch ends the auto pin
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert!(state.auto_pins.lock().unwrap().is_empty());
+        runner.set_rtt("eth1", 90.0);
+        health::check_once(&state, &health_config, now).await;
+        assert!(runner.has_rule("10.40.0.3", TABLE_WAN1));
+
+        let addr = spawn_app(Arc::new(MemoryRunner::new())).await;
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=auto").await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn disabled_wans_take_no_pins_and_fail_their_hosts_over() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[
+            ("HEALTH_TARGET", "192.0.2.53"),
+            ("HOST_FAILOVER_WAN1", "wan0"),
+        ])
+        .unwrap();
+        let health_config = config.health.clone().unwrap();
+        let (state, addr) = spawn_state(runner.clone(), config).await;
+        health::check_once(&state, &health_config, std::time::Instant::now()).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+
+        let (status, body) = http(addr, "POST", "/wan/wan1/disable").await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN0));
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan1").await;
+        assert_eq!(status, 409);
+        assert!(body.contains("\"wan_disabled\""), "{}", body);
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status.disabled_wans, ["wan1"]);
+        // Health keeps reporting the probes
+        assert!(status.health["wan1"].healthy);
+        assert_eq!(status.moved_hosts["10.40.0.3"], "wan1");
+
+        // A probe round does not bring the hosts back while disabled
+        health::check_once(&state, &health_config, std::time::Instant::now()).await;
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN0));
+
+        let (status, _) = http(addr, "POST", "/wan/wan1/enable").await;
+        assert_eq!(status, 200);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan1").await;
+        assert_eq!(status, 200);
+        let (status, _) = http(addr, "POST", "/wan/wan9/disable").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn health_survives_a_restart_during_an_outage() {
+        let path = temp_state_file("health");
+        let vars = [
+            ("STATE_FILE", path.to_str().unwrap()),
+            ("HEALTH_TARGET", "192.0.2.53"),
+            ("FAILOVER", "true"),
+            ("HOST_FAILOVER_WAN0", "wan1"),
+        ];
+        let start = |runner: Arc<MemoryRunner>| async move {
+            let config = config_from(&vars).unwrap();
+            initialize_lan_to_wan0(runner.as_ref(), &config)
+                .await
+                .unwrap();
+            AppState::new(config, runner, HashMap::new())
+        };
+        let runner = Arc::new(MemoryRunner::new());
+        let state = start(runner.clone()).await;
+        let health_config = state.config.health.clone().unwrap();
+        let addr = serve(state.clone()).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+
+        let now = std::time::Instant::now();
+        health::check_once(&state, &health_config, now).await;
+        runner.set_reachable("eth0", false);
+        for _ in 0..3 {
+            health::check_once(&state, &health_config, now).await;
+        }
+        assert!(runner.has_rule("10.40.0.0/20", TABLE_WAN1));
+        let saved = persist::read(&path).unwrap().unwrap();
+        let health = saved.health.clone().unwrap();
+        assert_eq!(health.base_wan, "wan1");
+        assert!(!health.wans["wan0"].healthy);
+        assert_eq!(health.moved_hosts["10.40.0.3"], "wan0");
+
+        // A restart while wan0 is still down starts out failed over
+        let runner = Arc::new(MemoryRunner::new());
+        let state = start(runner.clone()).await;
+        let mut restored = persist::read(&path).unwrap().unwrap();
+        let health = restored.health.take().unwrap();
+        persist::restore(&state, restored).await;
+        health::restore(&state, health.clone(), health.checked_at + 10).await;
+        assert!(runner.has_rule("10.40.0.0/20", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.0/20", TABLE_WAN0));
+        {
+            let restored = state.health.lock().await;
+            assert_eq!(restored.failover.active, "wan1");
+            assert!(restored.wans["wan0"].is_down());
+            assert_eq!(restored.moved_hosts["10.40.0.3"], "wan0");
+        }
+        // The first probes correct it once wan0 answers again
+        for _ in 0..3 {
+            health::check_once(&state, &health_config, now).await;
+        }
+        assert!(state.health.lock().await.moved_hosts.is_empty());
+        assert_eq!(
+            mapping::nic(&*state.mappings.lock().await, "10.40.0.3"),
+            Some("wan0")
+        );
+
+        // Health saved too long ago is left out
+        let runner = Arc::new(MemoryRunner::new());
+        let state = start(runner.clone()).await;
+        health::restore(&state, health.clone(), health.checked_at + 301).await;
+        assert!(runner.has_rule("10.40.0.0/20", TABLE_WAN0));
+        assert_eq!(state.health.lock().await.failover.active, "wan0");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn host_failover_moves_pinned_hosts_and_restores_them() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[
+            ("HEALTH_TARGET", "192.0.2.53"),
+            ("HOST_FAILOVER_WAN1", "wan0"),
+        ])
+        .unwrap();
+        let health_config = config.health.clone().unwrap();
+        let (state, addr) = spawn_state(runner.clone(), config).await;
+        let params = SwitchParams {
+            ip: "10.40.0.3".to_string(),
+            lease: None,
+            nic: "wan1".to_string(),
+            dns: Some(true),
+            verify: false,
+            force: false,
+            except: None,
+            tags: None,
+            auto_reason: None,
+        };
+        let (status, _) = apply_switch(params, &state, false).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        let now = std::time::Instant::now();
+        health::check_once(&state, &health_config, now).await;
+        runner.set_reachable("eth1", false);
+        for _ in 0..3 {
+            health::check_once(&state, &health_config, now).await;
+        }
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN0));
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        let rules = parse_ip_rules(&ip_rule_list(runner.as_ref()).unwrap());
+        assert!(rules
+            .iter()
+            .any(|r| r.dport.as_deref() == Some("53") && r.table == TABLE_WAN0));
+        assert_eq!(
+            mapping::nic(&*state.mappings.lock().await, "10.40.0.3").unwrap(),
+            "wan0"
+        );
+        assert_eq!(state.health.lock().await.moved_hosts["10.40.0.3"], "wan1");
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status.host_failovers["wan1"].to["10.40.0.3"], "wan0");
+
+        runner.set_reachable("eth1", true);
+        health::check_once(&state, &health_config, now).await;
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN0));
+        assert_eq!(
+            mapping::nic(&*state.mappings.lock().await, "10.40.0.3").unwrap(),
+            "wan1"
+        );
+        assert!(state.health.lock().await.moved_hosts.is_empty());
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert!(status.host_failovers.is_empty());
+
+        assert!(config_from(&[("HOST_FAILOVER_WAN1", "wan0")]).is_err());
+        assert!(config_from(&[
+            ("HEALTH_TARGET", "192.0.2.53"),
+            ("HOST_FAILOVER_WAN1", "wan1")
+        ])
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn extra_routes_are_installed_planned_and_not_foreign() {
+        let runner = MemoryRunner::new();
+        let config = config_from(&[
+            (
+                "EXTRA_ROUTES_WAN1",
+                "10.99.0.0/24 via 198.51.100.7 dev eth1",
+            ),
+            ("EXTRA_ROUTES_WAN0", "203.0.113.9 dev eth2"),
+        ])
+        .unwrap();
+        let report = initialize_lan_to_wan0(&runner, &config).await.unwrap();
+        assert_eq!(report.wans["wan1"].extra_routes, 1);
+        assert!(runner
+            .routes(TABLE_WAN1)
+            .contains(&"10.99.0.0/24 via 198.51.100.7 dev eth1".to_string()));
+        assert!(runner
+            .routes(TABLE_WAN0)
+            .contains(&"203.0.113.9 dev eth2".to_string()));
+        // A route through the LAN device is foreign unless configured
+        assert!(foreign_table_routes(&runner, &config).unwrap().is_empty());
+        assert_eq!(
+            foreign_table_routes(&runner, &config_from(&[]).unwrap())
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let plan = build_plan(&runner, &config, &HashMap::new(), &HashMap::new()).unwrap();
+        assert!(plan.iter().any(|c| c.to_string()
+            == "ip route replace 10.99.0.0/24 via 198.51.100.7 dev eth1 table 200"));
+
+        let err =
+            config_from(&[("EXTRA_ROUTES_WAN0", "10.99.0.0/24 through 10.40.0.1")]).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("EXTRA_ROUTES_WAN0"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn foreign_table_routes_ignores_the_services_own() {
+        let runner = MemoryRunner::new();
+        let config = config_from(&[]).unwrap();
+        initialize_lan_to_wan0(&runner, &config).await.unwrap();
+        assert!(foreign_table_routes(&runner, &config).unwrap().is_empty());
+
+        runner
+            .run(
+                "ip",
+                &[
+                    "route",
+                    "add",
+                    "192.168.77.0/24",
+                    "dev",
+                    "wg0",
+                    "table",
+                    "100",
+                ],
+            )
+            .unwrap();
+        runner
+            .run(
+                "ip",
+                &["route", "add", "blackhole", "10.9.0.0/16", "table", "200"],
+            )
+            .unwrap();
+        assert_eq!(
+            foreign_table_routes(&runner, &config).unwrap(),
+            vec![
+                (TABLE_WAN0, "192.168.77.0/24 dev wg0".to_string()),
+                (TABLE_WAN1, "blackhole 10.9.0.0/16".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_endpoint_reports_each_wan() {
+        let runner = Arc::new(MemoryRunner::new());
+        runner.set_reachable("eth1", false);
+        let config = config_from(&[("HEALTH_TARGET", "192.0.2.53")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        let (status, body) = http(addr, "POST", "/wan/wan0/probe").await;
+        assert_eq!(status, 200);
+        let probe: ProbeResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (probe.iface.as_str(), probe.result.reachable),
+            ("eth0", true)
+        );
+        assert!(probe.result.latency_ms.is_some());
+
+        let (_, body) = http(addr, "POST", "/wan/wan1/probe").await;
+        let probe: ProbeResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(probe.result.latency_ms, None);
+        assert!(!probe.result.reachable);
+
+        let (status, _) = http(addr, "POST", "/wan/wan2/probe").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn probes_are_bound_to_and_routed_through_their_wan() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[
+            ("HEALTH_TARGET", "192.0.2.53"),
+            ("HEALTH_PROBE_TABLE", "true"),
+        ])
+        .unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        http(addr, "POST", "/wan/wan1/probe").await;
+        assert!(runner
+            .calls()
+            .contains(&"ping -c 1 -W 1 -I eth1 192.0.2.53".to_string()));
+        let oif: Vec<(Option<String>, String)> = ip_rules(runner.as_ref())
+            .unwrap()
+            .into_iter()
+            .filter(|r| r.oif.is_some())
+            .map(|r| (r.oif, r.table))
+            .collect();
+        assert_eq!(
+            oif,
+            [
+                (Some("eth0".to_string()), TABLE_WAN0.to_string()),
+                (Some("eth1".to_string()), TABLE_WAN1.to_string()),
+            ]
+        );
+
+        let (_, body) = http(addr, "GET", "/rules").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let probes: Vec<&serde_json::Value> = body["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|r| r["kind"] == "probe")
+            .collect();
+        assert_eq!(probes.len(), 2);
+        assert!(probes.iter().all(|r| r["known"] == true));
+        // Nothing for gc to take
+        let (_, body) = http(addr, "POST", "/gc?dry_run=true").await;
+        assert!(!body.contains("eth0"), "{}", body);
+
+        assert!(config_from(&[("HEALTH_PROBE_TABLE", "true")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn max_mappings_evicts_oldest_auto_mapping_only() {
+        let runner = Arc::new(
+            MemoryRunner::new()
+                .with_neighbor("eth2", "10.40.0.5")
+                .with_neighbor("eth2", "10.40.0.6"),
+        );
+        let config = config_from(&[("AUTO_BALANCE", "wan1:1"), ("MAX_MAPPINGS", "2")]).unwrap();
+        let weights = config.auto_balance.clone().unwrap();
+        let (state, addr) = spawn_state(runner.clone(), config).await;
+        auto_balance_once(&state, &weights).await.unwrap();
+        // 10.40.0.6 becomes the most recently touched
+        state
+            .auto_assigned
+            .lock()
+            .await
+            .insert("10.40.0.6".to_string(), std::time::Instant::now());
+
+        // A manual pin over the cap pushes out the oldest auto mapping
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.7&nic=wan1").await;
+        assert_eq!(status, 200);
+        let mappings = state.mappings.lock().await.clone();
+        assert_eq!(mappings.len(), 2);
+        assert!(!mapping::contains(&mappings, "10.40.0.5"));
+        assert!(!runner.has_rule("10.40.0.5/32", TABLE_WAN1));
+
+        // With only one auto mapping left, the next manual pin evicts it, then
+        // nothing else can be auto-assigned
+        http(addr, "GET", "/switch?ip=10.40.0.8&nic=wan0").await;
+        let mappings = state.mappings.lock().await.clone();
+        assert_eq!(
+            mappings
+                .keys()
+                .map(mapping::host)
+                .collect::<std::collections::BTreeSet<_>>(),
+            ["10.40.0.7".to_string(), "10.40.0.8".to_string()].into()
+        );
+        assert!(auto_balance_once(&state, &weights)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let (_, body) = http(addr, "GET", "/metrics").await;
+        assert!(body.contains("adaptive_mappings 2\n"));
+        assert!(body.contains("adaptive_mappings_max 2\n"));
+    }
+
+    #[tokio::test]
+    async fn cleanup_restores_startup_state() {
+        let runner = Arc::new(MemoryRunner::new());
+        runner
+            .run(
+                "ip",
+                &[
+                    "route",
+                    "replace",
+                    "default",
+                    "via",
+                    "192.0.2.254",
+                    "dev",
+                    "eth0",
+                    "table",
+                    "100",
+                ],
+            )
+            .unwrap();
+        // An operator's own rule into a WAN table survives cleanup
+        runner
+            .run(
+                "ip",
+                &[
+                    "rule",
+                    "add",
+                    "from",
+                    "10.99.0.0/16",
+                    "lookup",
+                    "200",
+                    "priority",
+                    "1500",
+                ],
+            )
+            .unwrap();
+        let rules_before = runner.rules();
+        let config = config_from(&[("CLEANUP_ON_EXIT", "true"), ("DNS_STEERING", "true")]).unwrap();
+        let snapshot = cleanup::StartupSnapshot::take(runner.as_ref(), &config).unwrap();
+
+        let addr = spawn_app_with(runner.clone(), config).await;
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        assert_ne!(runner.rules(), rules_before);
+
+        cleanup::restore(runner.as_ref(), &snapshot);
+        assert_eq!(runner.rules(), rules_before);
+        assert_eq!(
+            runner.routes(TABLE_WAN0),
+            vec!["default via 192.0.2.254 dev eth0".to_string()]
+        );
+        assert!(runner.routes(TABLE_WAN1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn status_negotiates_yaml() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+
+        let (status, body) = http_with(
+            addr,
+            "GET",
+            "/status",
+            &[("Accept", "application/yaml, application/json;q=0.5")],
+        )
+        .await;
+        assert_eq!(status, 200);
+        let yaml: StatusResponse = serde_yaml::from_str(&body).unwrap();
+        assert_eq!(yaml.mappings.nic("10.40.0.3"), Some("wan1"));
+
+        // The query parameter wins over Accept
+        let (_, body) = http_with(
+            addr,
+            "GET",
+            "/status?format=json",
+            &[("Accept", "application/yaml")],
+        )
+        .await;
+        assert!(serde_json::from_str::<StatusResponse>(&body).is_ok());
+
+        let (_, body) = http(addr, "GET", "/rules?format=yaml").await;
+        assert!(body.starts_with("rules:"));
+        let (status, _) = http(addr, "GET", "/plan?format=xml").await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_compressed_on_request() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[]).unwrap();
+        let (state, addr) = spawn_state(runner, config).await;
+        let mut mappings = state.mappings.lock().await;
+        for i in 0..200 {
+            let host = format!("10.40.{}.{}", i / 250, i % 250);
+            mapping::pin(&mut mappings, &host, "wan1", None, mapping::Origin::Switch);
+        }
+        drop(mappings);
+
+        let get = |path: &'static str, accept: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nAccept-Encoding: {}\r\n\r\n",
+                path, accept
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut raw = Vec::new();
+            stream.read_to_end(&mut raw).await.unwrap();
+            let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            let head = String::from_utf8(raw[..split].to_vec())
+                .unwrap()
+                .to_lowercase();
+            (head, raw[split + 4..].to_vec())
+        };
+
+        let (head, _) = get("/status", "gzip, deflate").await;
+        assert!(head.contains("content-encoding: gzip"), "{}", head);
+        assert!(head.contains("vary: accept-encoding"), "{}", head);
+
+        let (head, body) = get("/status", "identity").await;
+        assert!(!head.contains("content-encoding"));
+        assert!(serde_json::from_slice::<StatusResponse>(&body).is_ok());
+
+        // Below the threshold the body is left alone
+        let (head, body) = get("/gateways", "gzip").await;
+        assert!(body.len() < 1024);
+        assert!(!head.contains("content-encoding"));
+    }
+
+    fn temp_state_file(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("adaptive-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn config_dry_run_diffs_the_plan() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        let before = runner.calls().len();
+        let preview = |body: &'static str| async move {
+            let (status, body) = http_body(addr, "PUT", "/config?dry_run=true", &[], body).await;
+            let json = serde_json::from_str::<serde_json::Value>(&body).unwrap_or_default();
+            (status, json, body)
+        };
+
+        let (status, body, raw) = preview("# same as now\n\n").await;
+        assert_eq!(status, 200, "{}", raw);
+        assert_eq!(body["commands"], serde_json::json!([]));
+        assert_eq!(body["dropped"], serde_json::json!([]));
+
+        let (status, body, raw) =
+            preview("BASE_WAN=wan1\nLAN_SUBNETS=\"10.40.0.0/20,10.50.0.0/24\"\n").await;
+        assert_eq!(status, 200, "{}", raw);
+        assert_eq!(
+            body["commands"],
+            serde_json::json!([
+                "ip rule add from 10.40.0.0/20 lookup 200 priority 2000",
+                "ip rule add from 10.50.0.0/24 lookup 200 priority 2000",
+            ])
+        );
+        assert_eq!(
+            body["dropped"],
+            serde_json::json!(["ip rule add from 10.40.0.0/20 lookup 100 priority 2000"])
+        );
+        // Previewing leaves the kernel alone
+        assert!(runner.calls()[before..]
+            .iter()
+            .all(|c| !c.contains(" add ") && !c.contains(" replace ")));
+
+        let (status, _, raw) = preview("BASE_WAN=wan2").await;
+        assert_eq!(status, 400, "{}", raw);
+        let (status, _, raw) = preview("BASE_WAN").await;
+        assert_eq!(status, 400, "{}", raw);
+        let (status, body) = http_body(addr, "PUT", "/config", &[], "BASE_WAN=wan1").await;
+        assert_eq!(status, 409);
+        assert!(body.contains("\"restart_required\""), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn switches_are_sent_to_syslog() {
+        let path = temp_state_file("syslog");
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let vars = [
+            ("SYSLOG_FACILITY", "daemon"),
+            ("SYSLOG_SOCKET", path.to_str().unwrap()),
+            ("INSTANCE_NAME", "site-a"),
+        ];
+        let addr = spawn_app_with(Arc::new(MemoryRunner::new()), config_from(&vars).unwrap()).await;
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        let mut buf = [0; 512];
+        let n = socket.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..n]).unwrap();
+        // daemon (3) * 8 + notice (5)
+        assert!(message.starts_with("<29>"), "{}", message);
+        assert!(
+            message.ends_with(&format!(
+                " adaptiverouting[{}]: switch instance=site-a ip=10.40.0.3 from=wan0 to=wan1",
+                std::process::id()
+            )),
+            "{}",
+            message
+        );
+
+        // Switches are less severe than warning
+        let vars = [
+            ("SYSLOG_FACILITY", "daemon"),
+            ("SYSLOG_SEVERITY", "warning"),
+            ("SYSLOG_SOCKET", path.to_str().unwrap()),
+        ];
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app_with(runner.clone(), config_from(&vars).unwrap()).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        socket.set_nonblocking(true).unwrap();
+        assert!(socket.recv(&mut buf).is_err());
+
+        // Failures are errors, and say why
+        runner.fail_commands("ip rule add from 10.40.0.4");
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan1").await;
+        assert_eq!(status, 500);
+        socket.set_nonblocking(false).unwrap();
+        let n = socket.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..n]).unwrap();
+        // daemon (3) * 8 + err (3)
+        assert!(message.starts_with("<27>"), "{}", message);
+        assert!(
+            message.contains(" ip=10.40.0.4 to=wan1 code=internal error=\"Failed to add"),
+            "{}",
+            message
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn lease_pins_resolve_and_follow_the_hostname() {
+        let path = temp_state_file("leases");
+        std::fs::write(
+            &path,
+            "0 02:00:00:00:00:01 10.40.0.3 laptop *\n\
+             0 02:00:00:00:00:02 10.40.0.4 android *\n\
+             0 02:00:00:00:00:03 10.40.0.5 android *\n",
+        )
+        .unwrap();
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("LEASES_FILE", path.to_str().unwrap())]).unwrap();
+        let (state, addr) = spawn_state(runner.clone(), config.clone()).await;
+
+        let (status, _) = http(addr, "GET", "/switch?lease=Laptop&nic=wan1&dns=true").await;
+        assert_eq!(status, 200);
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        let (status, _) = http(addr, "GET", "/switch?lease=tablet&nic=wan1").await;
+        assert_eq!(status, 404);
+        let (status, body) = http(addr, "GET", "/switch?lease=android&nic=wan1").await;
+        assert_eq!(status, 409);
+        assert!(body.contains("ambiguous_lease"));
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&lease=laptop&nic=wan1").await;
+        assert_eq!(status, 400);
+
+        // The laptop's lease moves; the pin and its DNS steering follow
+        std::fs::write(&path, "0 02:00:00:00:00:01 10.40.0.9 laptop *\n").unwrap();
+        leases::follow_once(&state, config.leases.as_ref().unwrap())
+            .await
+            .unwrap();
+        assert!(!runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        assert!(runner.has_rule("10.40.0.9/32", TABLE_WAN1));
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status.lease_pins["laptop"], "10.40.0.9");
+        assert_eq!(status.mappings.nic("10.40.0.3"), None);
+        assert_eq!(status.mappings.nic("10.40.0.9"), Some("wan1"));
+        assert_eq!(status.dns_steered, vec!["10.40.0.9"]);
+        assert_eq!(
+            runner
+                .rules()
+                .iter()
+                .filter(|r| r.from == "10.40.0.9" && r.selector.contains("dport 53"))
+                .count(),
+            2
+        );
+
+        // A vanished lease leaves the pin where it is
+        std::fs::write(&path, "").unwrap();
+        leases::follow_once(&state, config.leases.as_ref().unwrap())
+            .await
+            .unwrap();
+        assert!(runner.has_rule("10.40.0.9/32", TABLE_WAN1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn state_file_saves_on_switch_and_restores() {
+        let path = temp_state_file("switch");
+        let vars = [("STATE_FILE", path.to_str().unwrap())];
+        let addr = spawn_app_with(Arc::new(MemoryRunner::new()), config_from(&vars).unwrap()).await;
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        let (_, body) = http(addr, "GET", "/status?detailed=true").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let record = &body["mappings"]["10.40.0.3"];
+        assert_eq!(record["nic"], "wan1");
+        assert_eq!(record["origin"], "switch");
+        assert_eq!(record["prefix_len"], 32);
+        assert!(record["created"].as_u64().unwrap() > 0);
+
+        let saved = persist::read(&path).unwrap().unwrap();
+        assert_eq!(saved.mappings["10.40.0.3"].nic(), "wan1");
+        assert!(!persist::tmp_path(&path).exists());
+
+        // A fresh kernel gets the pin back from the file
+        let runner = Arc::new(MemoryRunner::new());
+        let state = AppState::new(config_from(&vars).unwrap(), runner.clone(), HashMap::new());
+        persist::restore(&state, saved).await;
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        let host: IpNet = "10.40.0.3/32".parse().unwrap();
+        let mappings = state.mappings.lock().await.clone();
+        assert_eq!(mappings[&host].nic, "wan1");
+        assert_eq!(mappings[&host].origin, mapping::Origin::Switch);
+
+        // Files written before mappings were records still load
+        std::fs::write(&path, r#"{"mappings":{"10.40.0.4":"wan1"}}"#).unwrap();
+        let saved = persist::read(&path).unwrap().unwrap();
+        persist::restore(&state, saved).await;
+        let host: IpNet = "10.40.0.4/32".parse().unwrap();
+        assert_eq!(
+            state.mappings.lock().await[&host].origin,
+            mapping::Origin::Restored
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_pinned_prefix_keeps_its_own_rule_through_restore_and_revert() {
+        let path = temp_state_file("prefix");
+        let vars = [("STATE_FILE", path.to_str().unwrap())];
+        let runner = Arc::new(MemoryRunner::new());
+        let (state, addr) = spawn_state(runner.clone(), config_from(&vars).unwrap()).await;
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.1.0/24&nic=wan1").await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(runner.has_rule("10.40.1.0/24", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.1.0/32", TABLE_WAN1));
+
+        // Planned and kept by /gc as the prefix it is
+        let (_, body) = http(addr, "GET", "/plan").await;
+        assert!(
+            body.contains("rule add from 10.40.1.0/24 lookup 200"),
+            "{}",
+            body
+        );
+        let (_, body) = http(addr, "POST", "/gc?dry_run=true").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["succeeded"], 0, "{}", body);
+        let (status, body) = http(addr, "GET", "/mapping/10.40.1.0%2F24/diff").await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["host"], "10.40.1.0/24");
+        assert_eq!(body["missing"], serde_json::json!([]), "{}", body);
+
+        let saved = persist::read(&path).unwrap().unwrap();
+        assert_eq!(saved.mappings["10.40.1.0/24"].nic(), "wan1");
+        let fresh = Arc::new(MemoryRunner::new());
+        let restored = AppState::new(config_from(&vars).unwrap(), fresh.clone(), HashMap::new());
+        persist::restore(&restored, saved).await;
+        assert!(fresh.has_rule("10.40.1.0/24", TABLE_WAN1));
+        assert!(!fresh.has_rule("10.40.1.0/32", TABLE_WAN1));
+
+        let (_, body) = http(addr, "POST", "/txn").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let id = body["id"].as_str().unwrap();
+        let (status, body) =
+            http(addr, "POST", &format!("/txn/{}/revert?ip=10.40.1.0/24", id)).await;
+        assert_eq!(status, 200, "{}", body);
+        let (status, body) = http(addr, "POST", &format!("/txn/{}/commit", id)).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(!runner.has_rule("10.40.1.0/24", TABLE_WAN1));
+        assert!(state.mappings.lock().await.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_state_file_ending_in_tmp_is_written_beside_its_temporary() {
+        let path = temp_state_file("suffix").with_extension("tmp");
+        let vars = [("STATE_FILE", path.to_str().unwrap())];
+        let addr = spawn_app_with(Arc::new(MemoryRunner::new()), config_from(&vars).unwrap()).await;
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        let saved = persist::read(&path).unwrap().unwrap();
+        assert_eq!(saved.mappings["10.40.0.3"].nic(), "wan1");
+        assert!(!persist::tmp_path(&path).exists());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn restore_collapses_ipv4_mapped_hosts() {
+        let runner = Arc::new(MemoryRunner::new());
+        let state = AppState::new(config_from(&[]).unwrap(), runner.clone(), HashMap::new());
+        let saved: persist::PersistedState = serde_json::from_str(
+            r#"{"mappings": {"::ffff:10.40.0.3": "wan1"},
+                "locked": ["::ffff:10.40.0.3"],
+                "exceptions": {"::ffff:10.40.0.3": ["203.0.113.0/24"]}}"#,
+        )
+        .unwrap();
+        persist::restore(&state, saved).await;
+
+        assert!(runner.has_rule("10.40.0.3/32", TABLE_WAN1));
+        assert!(runner.rules().iter().all(|r| !r.from.contains(':')));
+        let host: IpNet = "10.40.0.3/32".parse().unwrap();
+        assert_eq!(state.mappings.lock().await[&host].nic, "wan1");
+        assert!(state.locked.lock().unwrap().contains("10.40.0.3"));
+        assert!(state.exceptions.lock().unwrap().contains_key("10.40.0.3"));
+        assert!(runner
+            .rules()
+            .iter()
+            .any(|r| r.from == "10.40.0.3" && r.selector.contains("203.0.113.0/24")));
+    }
+
+    #[tokio::test]
+    async fn locked_hosts_need_the_token_to_switch() {
+        let (status, _) = http(
+            spawn_app(Arc::new(MemoryRunner::new())).await,
+            "POST",
+            "/hosts/10.40.0.3/lock",
+        )
+        .await;
+        assert_eq!(status, 404);
+
+        let path = temp_state_file("lock");
+        let vars = [
+            ("STATE_FILE", path.to_str().unwrap()),
+            ("LOCK_TOKEN", "s3cret"),
+        ];
+        let addr = spawn_app_with(Arc::new(MemoryRunner::new()), config_from(&vars).unwrap()).await;
+        let token = [("X-Lock-Token", "s3cret")];
+        http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+
+        let (status, _) = http(addr, "POST", "/hosts/10.40.0.3/lock").await;
+        assert_eq!(status, 403);
+        let (status, _) = http_with(
+            addr,
+            "POST",
+            "/hosts/10.40.0.3/lock",
+            &[("X-Lock-Token", "guess")],
+        )
+        .await;
+        assert_eq!(status, 403);
+        let (status, _) = http_with(addr, "POST", "/hosts/192.168.1.5/lock", &token).await;
+        assert_eq!(status, 400);
+        let (status, _) = http_with(addr, "POST", "/hosts/10.40.0.3/lock", &token).await;
+        assert_eq!(status, 200);
+
+        let (status, body) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0").await;
+        assert_eq!(status, 409);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["code"], "locked");
+        let (_, body) = http(addr, "GET", "/status").await;
+        let status_body: StatusResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(status_body.locked, vec!["10.40.0.3"]);
+        assert_eq!(status_body.mappings.nic("10.40.0.3"), Some("wan1"));
+        assert_eq!(
+            persist::read(&path).unwrap().unwrap().locked,
+            vec!["10.40.0.3"]
+        );
+
+        // The token overrides the lock, which stays in place
+        let (status, _) = http_with(addr, "GET", "/switch?ip=10.40.0.3&nic=wan0", &token).await;
+        assert_eq!(status, 200);
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 409);
+
+        let (status, _) = http_with(addr, "DELETE", "/hosts/10.40.0.3/lock", &token).await;
+        assert_eq!(status, 200);
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan1").await;
+        assert_eq!(status, 200);
+        assert!(persist::read(&path).unwrap().unwrap().locked.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gc_reports_each_rule_and_partial_failure() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        for host in ["10.40.0.7/32", "10.40.0.8/32"] {
+            runner
+                .run(
+                    "ip",
+                    &[
+                        "rule", "add", "from", host, "lookup", "200", "priority", "1000",
+                    ],
+                )
+                .unwrap();
+        }
+
+        let (status, body) = http(addr, "POST", "/gc?dry_run=true").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (body["dry_run"].as_bool(), body["status"].as_str()),
+            (Some(true), Some("success"))
+        );
+        assert_eq!(body["succeeded"], 2);
+        assert_eq!(body["items"][0]["ip"], "10.40.0.7");
+        assert_eq!(
+            body["items"][0]["message"],
+            "Would remove priority 1000 lookup 200"
+        );
+        assert_eq!(body["items"][0]["rule"]["table"], "200");
+
+        runner.fail_commands("ip rule del priority 1000 from 10.40.0.8");
+        let (status, body) = http(addr, "POST", "/gc").await;
+        assert_eq!(status, 207);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], "partial");
+        assert_eq!(
+            (body["succeeded"].as_u64(), body["failed"].as_u64()),
+            (Some(1), Some(1))
+        );
+        let items: Vec<(&str, &str)> = body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| (i["ip"].as_str().unwrap(), i["status"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            items,
+            vec![("10.40.0.7", "success"), ("10.40.0.8", "error")]
+        );
+        assert!(body["items"][1]["message"]
+            .as_str()
+            .unwrap()
+            .contains("Operation not permitted"));
+
+        let (status, body) = http(addr, "POST", "/gc").await;
+        assert_eq!(status, 500);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn csv_import_applies_each_line_and_reports_failures() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+        let body = "ip,nic\n\
+                    10.40.0.3,wan1\n\
+                    # moved back later\n\
+                    10.40.0.4=wan0\n\
+                    192.168.1.5,wan1\n\
+                    10.40.0.6,wan2\n\
+                    10.40.0.3/20,wan0\n";
+        let (status, body) = http_body(addr, "POST", "/import/csv", &[], body).await;
+        assert_eq!(status, 207);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (body["succeeded"].as_u64(), body["failed"].as_u64()),
+            (Some(2), Some(3))
+        );
+        let items: Vec<(&str, &str)> = body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| (i["ip"].as_str().unwrap(), i["message"].as_str().unwrap()))
+            .collect();
+        assert!(items[0]
+            .1
+            .starts_with("line 2: Routed 10.40.0.3/32 to wan1"));
+        assert_eq!(items[1].0, "10.40.0.4");
+        assert!(items[2].1.starts_with("line 5: 192.168.1.5 is not inside"));
+        assert_eq!(items[3].1, "line 6: nic must be 'wan0', 'wan1' or 'auto'");
+        assert_eq!(
+            items[4],
+            (
+                "10.40.0.3/20,wan0",
+                "line 7: 10.40.0.3 is already assigned on line 2"
+            )
+        );
+        assert!(runner.has_rule("10.40.0.3", TABLE_WAN1));
+
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["mappings"]["10.40.0.3"], "wan1");
+        assert_eq!(body["mappings"]["10.40.0.4"], "wan0");
+    }
+
+    #[tokio::test]
+    async fn schedules_pin_hosts_during_their_window_only() {
+        let runner = Arc::new(MemoryRunner::new());
+        let state = AppState::new(config_from(&[]).unwrap(), runner.clone(), HashMap::new());
+        let addr = serve(state.clone()).await;
+        http(addr, "GET", "/switch?ip=10.40.0.4&nic=wan0").await;
+
+        // A window opening an hour from now
+        let now = unix_now();
+        let minute = now % 86400 / 60;
+        let (start, end) = ((minute + 60) % 1440, (minute + 120) % 1440);
+        let window = format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            start / 60,
+            start % 60,
+            end / 60,
+            end % 60
+        );
+        let path = format!(
+            "/schedules/backup?hosts=10.40.0.3,10.40.0.4&nic=wan1&window={}",
+            window
+        );
+        let (status, body) = http(addr, "POST", &path).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["active"], false);
+        assert_eq!(body["next_transition"], now - now % 60 + 3600);
+        assert!(!runner.has_rule("10.40.0.3", TABLE_WAN1));
+
+        schedule::tick(&state, now + 3600).await;
+        assert!(runner.has_rule("10.40.0.3", TABLE_WAN1));
+        assert!(runner.has_rule("10.40.0.4", TABLE_WAN1));
+        let (_, body) = http(addr, "GET", "/status").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["schedules"]["backup"]["active"], true);
+        assert_eq!(body["mappings"]["10.40.0.3"], "wan1");
+
+        schedule::tick(&state, now + 3 * 3600).await;
+        assert!(!runner.has_rule("10.40.0.3", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.4", TABLE_WAN1));
+        let mappings = state.mappings.lock().await.clone();
+        assert_eq!(mapping::nic(&mappings, "10.40.0.3"), None);
+        assert_eq!(mapping::nic(&mappings, "10.40.0.4"), Some("wan0"));
+
+        let (status, body) = http(
+            addr,
+            "POST",
+            "/schedules/other?hosts=10.40.0.3&nic=wan0&window=01:00-02:00",
+        )
+        .await;
+        assert_eq!(status, 409);
+        assert!(body.contains("10.40.0.3 is already in schedule backup"));
+        let (status, _) = http(
+            addr,
+            "POST",
+            "/schedules/other?hosts=10.40.0.5&nic=wan0&window=01:00-01:00",
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        let (status, _) = http(addr, "DELETE", "/schedules/backup").await;
+        assert_eq!(status, 200);
+        let (status, _) = http(addr, "DELETE", "/schedules/backup").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn gc_waits_for_a_confirmation_token() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("REQUIRE_CONFIRM", "true")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+        let add_stale = |host: &str| {
+            runner
+                .run(
+                    "ip",
+                    &[
+                        "rule", "add", "from", host, "lookup", "200", "priority", "1000",
+                    ],
+                )
+                .unwrap();
+        };
+        add_stale("10.40.0.7/32");
+
+        // A dry run needs no token
+        let (status, _) = http(addr, "POST", "/gc?dry_run=true").await;
+        assert_eq!(status, 200);
+
+        let (status, body) = http(addr, "POST", "/gc").await;
+        assert_eq!(status, 202);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], "confirm_required");
+        assert_eq!(body["expires_in"], 30);
+        assert_eq!(
+            body["changes"],
+            serde_json::json!(["ip rule del priority 1000 from 10.40.0.7 lookup 200"])
+        );
+        assert!(runner.has_rule("10.40.0.7", TABLE_WAN1));
+
+        // Another stale rule appeared since: the token no longer describes the run
+        let token = body["token"].as_str().unwrap().to_string();
+        add_stale("10.40.0.8/32");
+        let (status, body) = http(addr, "POST", &format!("/gc?confirm={}", token)).await;
+        assert_eq!(status, 409, "{}", body);
+        assert!(body.contains("confirm_stale"));
+
+        let (_, body) = http(addr, "POST", "/gc").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let path = format!("/gc?confirm={}", body["token"].as_str().unwrap());
+        let (status, _) = http(addr, "POST", &path).await;
+        assert_eq!(status, 200);
+        assert!(!runner.has_rule("10.40.0.7", TABLE_WAN1));
+        assert!(!runner.has_rule("10.40.0.8", TABLE_WAN1));
+
+        // Tokens are single-use
+        let (status, body) = http(addr, "POST", &path).await;
+        assert_eq!(status, 409);
+        assert!(body.contains("confirm_invalid"));
+    }
+
+    #[tokio::test]
+    async fn restore_reconciles_with_one_rule_listing() {
+        let runner = Arc::new(MemoryRunner::new());
+        let state = AppState::new(config_from(&[]).unwrap(), runner.clone(), HashMap::new());
+        // One host already pinned correctly, one pinned to the wrong table
+        runner
+            .run(
+                "ip",
+                &["rule", "add", "from", "10.40.1.0/32", "lookup", "200"],
+            )
+            .unwrap();
+        runner
+            .run(
+                "ip",
+                &["rule", "add", "from", "10.40.1.1/32", "lookup", "100"],
+            )
+            .unwrap();
+        let saved = persist::PersistedState {
+            mappings: (0..=255)
+                .map(|i| {
+                    let nic = persist::SavedMapping::Nic("wan1".to_string());
+                    (format!("10.40.1.{}", i), nic)
+                })
+                .collect(),
+            auto_assigned: Vec::new(),
+            locked: Vec::new(),
+            lease_pins: Default::default(),
+            auto_pins: Default::default(),
+            schedules: Default::default(),
+            exceptions: Default::default(),
+            dscp_policies: Default::default(),
+            health: None,
+        };
+        let before = runner.calls().len();
+        persist::restore(&state, saved).await;
+        let calls = &runner.calls()[before..];
+
+        // One listing, 255 adds and one delete, instead of a listing or two per host
+        assert_eq!(
+            calls
+                .iter()
+                .filter(|c| c.starts_with("ip rule show"))
+                .count(),
+            1
+        );
+        assert_eq!(calls.len(), 1 + 255 + 1, "{:?}", calls);
+        assert!(!runner.has_rule("10.40.1.1/32", TABLE_WAN0));
+        assert_eq!(state.mappings.lock().await.len(), 256);
+
+        // Nothing left to do the second time
+        let mappings = state.mappings.lock().await.clone();
+        let before = runner.calls().len();
+        let mappings = mapping::nics(&mappings);
+        let outcome = reconcile_host_rules(runner.as_ref(), &state.config, &mappings).unwrap();
+        assert_eq!((outcome.added, outcome.removed), (0, 0));
+        assert_eq!(outcome.priorities.len(), 256);
+        assert_eq!(runner.calls().len() - before, 1);
+    }
+
+    #[tokio::test]
+    async fn checkpoint_writes_changes_made_outside_the_api() {
+        let path = temp_state_file("checkpoint");
+        let config = config_from(&[
+            ("STATE_FILE", path.to_str().unwrap()),
+            ("CHECKPOINT_INTERVAL", "30"),
+        ])
+        .unwrap();
+        let state = AppState::new(config, Arc::new(MemoryRunner::new()), HashMap::new());
+        mapping::pin(
+            &mut *state.mappings.lock().await,
+            "10.40.0.9",
+            "wan0",
+            None,
+            mapping::Origin::Switch,
+        );
+
+        let task = tokio::spawn(persist::checkpoint_loop(
+            state.clone(),
+            std::time::Duration::from_millis(10),
+        ));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        task.abort();
+        let saved = persist::read(&path).unwrap().unwrap();
+        assert_eq!(saved.mappings["10.40.0.9"].nic(), "wan0");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(config_from(&[("CHECKPOINT_INTERVAL", "30")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn gateways_reports_discovery_and_errors() {
+        let runner = Arc::new(MemoryRunner::new());
+        let config = config_from(&[("GATEWAY_WAN1", "198.51.100.254")]).unwrap();
+        let addr = spawn_app_with(runner.clone(), config).await;
+
+        let (status, body) = http(addr, "GET", "/gateways").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["wan0"]["interface"], "eth0");
+        assert_eq!(body["wan0"]["gateway"], "192.0.2.1");
+        assert_eq!(body["wan0"]["source"], "discovered");
+        assert!(body["wan0"]["refreshed_at"].as_u64().unwrap() > 0);
+        assert!(body["wan0"].get("error").is_none());
+        assert_eq!(body["wan1"]["gateway"], "198.51.100.254");
+        assert_eq!(body["wan1"]["source"], "configured");
+        assert_eq!(
+            body["wan0"]["addresses"],
+            serde_json::json!(["192.0.2.10/24"])
+        );
+
+        // Addresses are read once and then served from the cache
+        let lookups = || {
+            runner
+                .calls()
+                .iter()
+                .filter(|c| c.contains("addr show dev eth0"))
+                .count()
+        };
+        let before = lookups();
+        http(addr, "GET", "/gateways").await;
+        assert_eq!(lookups(), before);
+    }
+
+    #[tokio::test]
+    async fn gateways_reports_error_per_wan() {
+        // eth9 has no default route to discover
+        let config = config_from(&[("WAN1", "eth9")]).unwrap();
+        let state = AppState::new(config, Arc::new(MemoryRunner::new()), HashMap::new());
+        let addr = serve(state).await;
+
+        let (_, body) = http(addr, "GET", "/gateways").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["wan0"]["gateway"], "192.0.2.1");
+        assert!(body["wan1"]["gateway"].is_null());
+        assert!(body["wan1"]["error"].as_str().unwrap().contains("eth9"));
+    }
+
+    #[tokio::test]
+    async fn switch_rejects_unknown_nic() {
+        let runner = Arc::new(MemoryRunner::new());
+        let addr = spawn_app(runner.clone()).await;
+
+        let (status, _) = http(addr, "GET", "/switch?ip=10.40.0.3&nic=wan9").await;
+        assert_eq!(status, 400);
+        assert!(!runner.calls().iter().any(|c| c.contains("10.40.0.3")));
+    }
 }
@@ -0,0 +1,430 @@
+//! What the service records about each mapped host.
+//!
+//! `/status` keeps showing only the WAN of each host unless `?detailed=true`
+//! asks for the whole record.
+//!
+//! Each mapping is keyed by the prefix it covers, so `10.40.0.8` and
+//! `10.40.0.8/29` are different mappings. Outside this module a mapping is
+//! named by `host`: the bare address for a single host, as the kernel prints
+//! it, else `addr/len`; its rule matches `selector`. Two recorded prefixes
+//! never overlap; `claim` decides, following MAPPING_OVERLAP_POLICY, whether
+//! a new one pushes out the mappings it overlaps or is refused.
+//!
+//! Hosts can carry `key:value` tags (set with `/switch?tags=`), which
+//! `/mappings?tag=` and `/switch/by-tag` select hosts by.
+
+use crate::unix_now;
+use anyhow::{bail, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+/// What made the latest change to a mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    // /switch, or /import/csv
+    Switch,
+    // Spread by AUTO_BALANCE
+    AutoBalance,
+    // nic=auto, moved as the probes changed
+    AutoSelect,
+    // Moved by HOST_FAILOVER
+    Failover,
+    // Followed its lease to a new address
+    Lease,
+    // Pinned or put back by a schedule
+    Schedule,
+    // Read from a rule already in the kernel
+    Adopted,
+    // Loaded from STATE_FILE
+    Restored,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mapping {
+    pub nic: String,
+    // Length of the source prefix the host rule matches; always that of the key
+    pub prefix_len: u8,
+    // Priority of the host's own rule; None while it rides the base rule
+    pub priority: Option<u32>,
+    // Unix times the host was first mapped and last changed
+    pub created: u64,
+    pub modified: u64,
+    // Seconds after `modified` the mapping is meant to last; None is forever
+    pub ttl: Option<u64>,
+    pub note: Option<String>,
+    // Free-form grouping, e.g. role -> voip
+    #[serde(default)]
+    pub tags: Tags,
+    pub origin: Origin,
+}
+
+pub type Tags = BTreeMap<String, String>;
+
+impl Mapping {
+    pub fn new(nic: &str, priority: Option<u32>, origin: Origin) -> Self {
+        let now = unix_now();
+        Mapping {
+            nic: nic.to_string(),
+            prefix_len: 32,
+            priority,
+            created: now,
+            modified: now,
+            ttl: None,
+            note: None,
+            tags: Tags::new(),
+            origin,
+        }
+    }
+}
+
+pub type Mappings = HashMap<IpNet, Mapping>;
+
+/// What happens to recorded mappings a new prefix overlaps with a
+/// different mask, e.g. `10.40.0.3` under a new `10.40.0.0/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    // The new prefix takes over; the overlapped mappings are dropped
+    Replace,
+    // The new prefix is refused while they exist
+    Reject,
+}
+
+/// A host (`/32`) or `addr/len`, truncated to its network.
+pub fn parse_prefix(host: &str) -> Option<IpNet> {
+    host.parse::<IpNet>()
+        .or_else(|_| host.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+        .map(|net| net.trunc())
+}
+
+/// `net` as the rest of the service names it: `10.40.0.3` for a single
+/// host, `10.40.0.0/24` otherwise.
+pub fn host(net: &IpNet) -> String {
+    if net.prefix_len() == net.max_prefix_len() {
+        net.addr().to_string()
+    } else {
+        net.to_string()
+    }
+}
+
+/// The `from` selector of the rule for `host`: `10.40.0.3/32` for a single
+/// host, the prefix itself otherwise.
+pub fn selector(host: &str) -> String {
+    match parse_prefix(host) {
+        Some(net) => net.to_string(),
+        None => format!("{}/32", host),
+    }
+}
+
+/// Recorded prefixes that overlap `prefix` without being it.
+pub fn overlapping(mappings: &Mappings, prefix: IpNet) -> Vec<IpNet> {
+    let mut found: Vec<IpNet> = mappings
+        .keys()
+        .copied()
+        .filter(|net| {
+            *net != prefix && (net.contains(&prefix.network()) || prefix.contains(&net.network()))
+        })
+        .collect();
+    found.sort();
+    found
+}
+
+/// What `claim` would remove, or its error, leaving `mappings` alone.
+pub fn check_claim(
+    mappings: &Mappings,
+    prefix: IpNet,
+    policy: OverlapPolicy,
+) -> Result<Vec<IpNet>> {
+    let overlapped = overlapping(mappings, prefix);
+    if !overlapped.is_empty() && policy == OverlapPolicy::Reject {
+        let list: Vec<String> = overlapped.iter().map(IpNet::to_string).collect();
+        bail!("{} overlaps the mapping of {}", prefix, list.join(", "));
+    }
+    Ok(overlapped)
+}
+
+/// Make room for a mapping of `prefix`. Under `Replace` the overlapping
+/// mappings are removed and returned, so their rules can be taken down too;
+/// under `Reject` they are left alone and an error names them.
+pub fn claim(mappings: &mut Mappings, prefix: IpNet, policy: OverlapPolicy) -> Result<Vec<IpNet>> {
+    let overlapped = check_claim(mappings, prefix, policy)?;
+    for net in &overlapped {
+        mappings.remove(net);
+    }
+    Ok(overlapped)
+}
+
+/// Record `host` (or `addr/len`) on `nic` with its rule's priority. An existing mapping
+/// keeps its creation time, TTL, note and tags. Returns the previous WAN.
+pub fn pin(
+    mappings: &mut Mappings,
+    host: &str,
+    nic: &str,
+    priority: Option<u32>,
+    origin: Origin,
+) -> Option<String> {
+    let Some(prefix) = parse_prefix(host) else {
+        eprintln!("Not recording a mapping for {}: not an address", host);
+        return None;
+    };
+    match mappings.get_mut(&prefix) {
+        Some(mapping) => {
+            let previous = std::mem::replace(&mut mapping.nic, nic.to_string());
+            mapping.priority = priority;
+            mapping.modified = unix_now();
+            mapping.origin = origin;
+            Some(previous)
+        }
+        None => {
+            let mut mapping = Mapping::new(nic, priority, origin);
+            mapping.prefix_len = prefix.prefix_len();
+            mappings.insert(prefix, mapping);
+            None
+        }
+    }
+}
+
+/// The WAN `host` is mapped to. A prefix is only found by its own
+/// `addr/len`, not by an address inside it.
+pub fn nic<'a>(mappings: &'a Mappings, host: &str) -> Option<&'a str> {
+    mappings.get(&parse_prefix(host)?).map(|m| m.nic.as_str())
+}
+
+pub fn contains(mappings: &Mappings, host: &str) -> bool {
+    nic(mappings, host).is_some()
+}
+
+pub fn remove(mappings: &mut Mappings, host: &str) -> Option<Mapping> {
+    mappings.remove(&parse_prefix(host)?)
+}
+
+/// Take in mappings read from the kernel's rules. A record the kernel
+/// agrees with keeps its history; only its priority is updated.
+pub fn adopt(mappings: &mut Mappings, adopted: Mappings) {
+    for (net, adopted) in adopted {
+        match mappings.get_mut(&net) {
+            Some(known) if known.nic == adopted.nic => known.priority = adopted.priority,
+            _ => {
+                mappings.insert(net, adopted);
+            }
+        }
+    }
+}
+
+/// `key:value,key:value`; an empty list clears a host's tags.
+pub fn parse_tags(list: &str) -> Result<Tags> {
+    let mut tags = Tags::new();
+    for tag in list.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let Some((key, value)) = tag.split_once(':') else {
+            bail!("tag {:?} must be key:value", tag);
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() || value.is_empty() {
+            bail!("tag {:?} must be key:value", tag);
+        }
+        tags.insert(key.to_string(), value.to_string());
+    }
+    Ok(tags)
+}
+
+/// Whether `mapping` carries `tag`: `key:value`, or a bare `key` with any
+/// value.
+pub fn has_tag(mapping: &Mapping, tag: &str) -> bool {
+    match tag.split_once(':') {
+        Some((key, value)) => {
+            mapping.tags.get(key.trim()).map(String::as_str) == Some(value.trim())
+        }
+        None => mapping.tags.contains_key(tag.trim()),
+    }
+}
+
+/// Hosts carrying `tag`, in address order.
+pub fn tagged(mappings: &Mappings, tag: &str) -> Vec<String> {
+    let mut nets: Vec<IpNet> = mappings
+        .iter()
+        .filter(|(_, m)| has_tag(m, tag))
+        .map(|(net, _)| *net)
+        .collect();
+    nets.sort();
+    nets.iter().map(host).collect()
+}
+
+/// Host -> WAN, the shape `/status` has always had.
+pub fn nics(mappings: &Mappings) -> HashMap<String, String> {
+    mappings
+        .iter()
+        .map(|(net, m)| (host(net), m.nic.clone()))
+        .collect()
+}
+
+/// Host -> priority of its own rule, for hosts that have one.
+pub fn priorities(mappings: &Mappings) -> HashMap<String, u32> {
+    mappings
+        .iter()
+        .filter_map(|(net, m)| Some((host(net), m.priority?)))
+        .collect()
+}
+
+/// Host -> full record, for `?detailed=true`.
+pub fn detailed(mappings: &Mappings) -> BTreeMap<String, Mapping> {
+    mappings
+        .iter()
+        .map(|(net, m)| (host(net), m.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repinning_keeps_when_and_why_the_host_was_first_mapped() {
+        let mut mappings = Mappings::new();
+        assert_eq!(
+            pin(
+                &mut mappings,
+                "10.40.0.3",
+                "wan1",
+                Some(1000),
+                Origin::Switch
+            ),
+            None
+        );
+        let ip = parse_prefix("10.40.0.3").unwrap();
+        mappings.get_mut(&ip).unwrap().created = 1;
+        mappings.get_mut(&ip).unwrap().note = Some("printer".to_string());
+
+        assert_eq!(
+            pin(&mut mappings, "10.40.0.3", "wan0", None, Origin::Failover),
+            Some("wan1".to_string())
+        );
+        let mapping = &mappings[&ip];
+        assert_eq!(mapping.nic, "wan0");
+        assert_eq!(mapping.priority, None);
+        assert_eq!(mapping.created, 1);
+        assert!(mapping.modified > 1);
+        assert_eq!(mapping.note.as_deref(), Some("printer"));
+        assert_eq!(mapping.origin, Origin::Failover);
+
+        assert_eq!(nic(&mappings, "10.40.0.3"), Some("wan0"));
+        assert_eq!(nic(&mappings, "not-an-ip"), None);
+        assert!(priorities(&mappings).is_empty());
+        assert_eq!(nics(&mappings)["10.40.0.3"], "wan0");
+        assert!(pin(&mut mappings, "laptop", "wan1", None, Origin::Switch).is_none());
+        assert_eq!(mappings.len(), 1);
+    }
+
+    #[test]
+    fn a_host_then_its_subnet_follows_the_overlap_policy() {
+        let net = |s: &str| parse_prefix(s).unwrap();
+        let mut mappings = Mappings::new();
+        pin(
+            &mut mappings,
+            "10.40.0.3",
+            "wan1",
+            Some(1000),
+            Origin::Switch,
+        );
+        pin(
+            &mut mappings,
+            "10.40.1.9",
+            "wan1",
+            Some(1000),
+            Origin::Switch,
+        );
+
+        let err = claim(&mut mappings, net("10.40.0.3/24"), OverlapPolicy::Reject).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "10.40.0.0/24 overlaps the mapping of 10.40.0.3/32"
+        );
+        assert_eq!(mappings.len(), 2);
+
+        assert_eq!(
+            claim(&mut mappings, net("10.40.0.3/24"), OverlapPolicy::Replace).unwrap(),
+            [net("10.40.0.3")]
+        );
+        pin(&mut mappings, "10.40.0.3/24", "wan0", None, Origin::Switch);
+        assert_eq!(mappings[&net("10.40.0.0/24")].prefix_len, 24);
+        assert_eq!(nic(&mappings, "10.40.0.0/24"), Some("wan0"));
+        assert_eq!(nics(&mappings)["10.40.0.0/24"], "wan0");
+        // Neither the host that was replaced nor the network address alone
+        assert_eq!(nic(&mappings, "10.40.0.3"), None);
+        assert_eq!(nic(&mappings, "10.40.0.0"), None);
+        // Re-pinning the same prefix is not an overlap
+        assert!(overlapping(&mappings, net("10.40.0.0/24")).is_empty());
+    }
+
+    #[test]
+    fn a_subnet_then_a_host_inside_it_follows_the_overlap_policy() {
+        let net = |s: &str| parse_prefix(s).unwrap();
+        let mut mappings = Mappings::new();
+        pin(
+            &mut mappings,
+            "10.40.0.0/24",
+            "wan1",
+            Some(1000),
+            Origin::Switch,
+        );
+
+        assert!(claim(&mut mappings, net("10.40.0.3"), OverlapPolicy::Reject).is_err());
+        assert!(
+            claim(&mut mappings, net("10.40.1.3"), OverlapPolicy::Reject)
+                .unwrap()
+                .is_empty()
+        );
+        // The network address alone is a host of its own, overlapping the subnet
+        assert_eq!(
+            claim(&mut mappings, net("10.40.0.0/32"), OverlapPolicy::Replace).unwrap(),
+            [net("10.40.0.0/24")]
+        );
+        pin(&mut mappings, "10.40.0.0", "wan0", None, Origin::Switch);
+        assert_eq!(nic(&mappings, "10.40.0.0"), Some("wan0"));
+        assert_eq!(nic(&mappings, "10.40.0.0/24"), None);
+        assert_eq!(mappings.values().next().unwrap().prefix_len, 32);
+    }
+
+    #[test]
+    fn hosts_and_prefixes_are_named_and_selected_by_their_own_length() {
+        let net = |s: &str| parse_prefix(s).unwrap();
+        assert_eq!(host(&net("10.40.0.3")), "10.40.0.3");
+        assert_eq!(host(&net("10.40.0.8/29")), "10.40.0.8/29");
+        assert_eq!(selector("10.40.0.3"), "10.40.0.3/32");
+        assert_eq!(selector("10.40.0.8/29"), "10.40.0.8/29");
+
+        let mut mappings = Mappings::new();
+        pin(&mut mappings, "10.40.0.8/29", "wan1", None, Origin::Switch);
+        pin(&mut mappings, "10.40.1.8", "wan0", None, Origin::Switch);
+        assert_eq!(nic(&mappings, "10.40.0.8"), None);
+        assert!(remove(&mut mappings, "10.40.0.8").is_none());
+        assert_eq!(remove(&mut mappings, "10.40.0.8/29").unwrap().nic, "wan1");
+    }
+
+    #[test]
+    fn hosts_are_selected_by_tag() {
+        let mut mappings = Mappings::new();
+        for (host, tags) in [
+            ("10.40.0.9", "role:voip, floor:2"),
+            ("10.40.0.3", "role:voip"),
+            ("10.40.0.4", "role:nas"),
+        ] {
+            pin(&mut mappings, host, "wan1", None, Origin::Switch);
+            let net = parse_prefix(host).unwrap();
+            mappings.get_mut(&net).unwrap().tags = parse_tags(tags).unwrap();
+        }
+        let hosts = |tag| tagged(&mappings, tag);
+        assert_eq!(hosts("role:voip"), ["10.40.0.3", "10.40.0.9"]);
+        assert_eq!(hosts("floor"), ["10.40.0.9"]);
+        assert_eq!(hosts("role"), ["10.40.0.3", "10.40.0.4", "10.40.0.9"]);
+        assert!(hosts("role:printer").is_empty());
+
+        assert!(parse_tags("").unwrap().is_empty());
+        for bad in ["voip", "role:", ":voip"] {
+            assert!(parse_tags(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! In-memory stand-in for the `ip` command. It models uplinks, routing tables
+//! and policy rules in plain data structures so the handlers can be exercised
+//! end to end without touching the kernel.
+
+use crate::CommandRunner;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// A policy rule as the kernel stores it (host selectors without `/32`).
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rule {
+    pub priority: u32,
+    pub from: String,
+    // Any further match keys, e.g. "ipproto udp dport 53"
+    pub selector: String,
+    pub table: String,
+}
+
+struct Uplink {
+    iface: String,
+    gateway: Option<String>,
+    // Address with prefix length, e.g. "192.0.2.10/24"
+    addr: String,
+    // Connected prefix, e.g. "192.0.2.0/24"
+    link: String,
+}
+
+#[derive(Default)]
+struct State {
+    uplinks: Vec<Uplink>,
+    // table id -> routes, rendered the way `ip route show table <id>` prints them
+    tables: BTreeMap<String, Vec<String>>,
+    rules: Vec<Rule>,
+    // (iface, address) pairs reported by `ip neigh show`
+    neighbors: Vec<(String, String)>,
+    // Interfaces whose pings fail
+    unreachable: Vec<String>,
+    // Round-trip time pings through an interface report; 1.5 ms if unset
+    rtts: BTreeMap<String, f64>,
+    // What `nft list ruleset` prints; None means nft is not installed
+    nft_ruleset: Option<String>,
+    // Device (and source, if any) `ip route get <addr>` answers with, per address
+    host_routes: BTreeMap<String, (String, Option<String>)>,
+    // Commands (rendered as `ip ...`) starting with one of these fail
+    failing: Vec<String>,
+    calls: Vec<String>,
+}
+
+pub struct MemoryRunner {
+    state: Mutex<State>,
+}
+
+impl MemoryRunner {
+    /// Two uplinks matching the default config: eth0 via 192.0.2.1 and eth1
+    /// via 198.51.100.1, plus the kernel's built-in rules.
+    pub fn new() -> Self {
+        let runner = MemoryRunner {
+            state: Mutex::new(State {
+                rules: vec![
+                    Rule::new(0, "all", "local"),
+                    Rule::new(32766, "all", "main"),
+                    Rule::new(32767, "all", "default"),
+                ],
+                ..State::default()
+            }),
+        };
+        runner
+            .with_uplink("eth0", Some("192.0.2.1"), "192.0.2.10/24", "192.0.2.0/24")
+            .with_uplink(
+                "eth1",
+                Some("198.51.100.1"),
+                "198.51.100.7/24",
+                "198.51.100.0/24",
+            )
+    }
+
+    pub fn with_uplink(self, iface: &str, gateway: Option<&str>, addr: &str, link: &str) -> Self {
+        self.state.lock().unwrap().uplinks.push(Uplink {
+            iface: iface.to_string(),
+            gateway: gateway.map(str::to_string),
+            addr: addr.to_string(),
+            link: link.to_string(),
+        });
+        self
+    }
+
+    pub fn with_nft_ruleset(self, ruleset: &str) -> Self {
+        self.state.lock().unwrap().nft_ruleset = Some(ruleset.to_string());
+        self
+    }
+
+    pub fn with_host_route(self, addr: &str, dev: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .host_routes
+            .insert(addr.to_string(), (dev.to_string(), None));
+        self
+    }
+
+    /// Like `with_host_route`, with the source address the kernel picks.
+    pub fn with_host_route_from(self, addr: &str, dev: &str, src: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .host_routes
+            .insert(addr.to_string(), (dev.to_string(), Some(src.to_string())));
+        self
+    }
+
+    pub fn with_neighbor(self, iface: &str, addr: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .neighbors
+            .push((iface.to_string(), addr.to_string()));
+        self
+    }
+
+    /// Make pings through `iface` fail (or succeed again).
+    pub fn set_reachable(&self, iface: &str, reachable: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.unreachable.retain(|i| i != iface);
+        if !reachable {
+            state.unreachable.push(iface.to_string());
+        }
+    }
+
+    /// Make pings through `iface` report `ms` as their round-trip time.
+    pub fn set_rtt(&self, iface: &str, ms: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .rtts
+            .insert(iface.to_string(), ms);
+    }
+
+    /// Make every command that starts with `prefix` fail.
+    pub fn fail_commands(&self, prefix: &str) {
+        self.state.lock().unwrap().failing.push(prefix.to_string());
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.state.lock().unwrap().rules.clone()
+    }
+
+    pub fn has_rule(&self, from: &str, table: &str) -> bool {
+        let from = kernel_selector(from);
+        self.rules()
+            .iter()
+            .any(|r| r.from == from && r.table == table)
+    }
+
+    pub fn routes(&self, table: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.tables.get(table).cloned().unwrap_or_default()
+    }
+
+    /// Every command seen so far, rendered as `ip ...`.
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.clone()
+    }
+}
+
+impl Rule {
+    fn new(priority: u32, from: &str, table: &str) -> Self {
+        Rule {
+            priority,
+            from: from.to_string(),
+            selector: String::new(),
+            table: table.to_string(),
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut line = format!("{}:\tfrom {}", self.priority, self.from);
+        if !self.selector.is_empty() {
+            line.push(' ');
+            line.push_str(&self.selector);
+        }
+        format!("{} lookup {}\n", line, self.table)
+    }
+}
+
+/// Key/value pairs of a rule command other than from/lookup/priority.
+fn extra_selector(args: &[&str]) -> String {
+    args.chunks(2)
+        .filter(|kv| !matches!(kv[0], "from" | "lookup" | "table" | "priority"))
+        .map(|kv| kv.join(" "))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The kernel prints host selectors without their `/32`.
+fn kernel_selector(from: &str) -> String {
+    match from {
+        "0.0.0.0/0" => "all".to_string(),
+        _ => from.strip_suffix("/32").unwrap_or(from).to_string(),
+    }
+}
+
+/// Like the kernel, refuse an IPv4 gateway outside the device's connected
+/// prefix unless the route (or that nexthop of a multipath route) is marked
+/// `onlink`.
+fn check_gateway(state: &State, args: &[&str]) -> Result<()> {
+    args.split(|a| *a == "nexthop")
+        .try_for_each(|hop| check_nexthop(state, hop))
+}
+
+fn check_nexthop(state: &State, args: &[&str]) -> Result<()> {
+    let (Some(gw), Some(dev)) = (value_after(args, "via"), value_after(args, "dev")) else {
+        return Ok(());
+    };
+    let Ok(gw) = gw.parse::<std::net::Ipv4Addr>() else {
+        return Ok(());
+    };
+    let off_link = state
+        .uplinks
+        .iter()
+        .filter(|u| u.iface == dev)
+        .filter_map(|u| u.link.parse::<ipnet::Ipv4Net>().ok())
+        .any(|link| !link.contains(&gw));
+    if off_link && !args.contains(&"onlink") {
+        bail!("Error: Nexthop has invalid gateway.");
+    }
+    Ok(())
+}
+
+/// A route as stored: its destination and attributes without the table.
+fn route_spec(dest: &str, rest: &[&str]) -> String {
+    let mut route = vec![dest];
+    let mut args = rest.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "table" => {
+                args.next();
+            }
+            _ => route.push(arg),
+        }
+    }
+    route.join(" ")
+}
+
+/// Like `ip route get <dest> from <src> iif <iif>`: walk the rules in
+/// priority order and answer from the first table with a default route.
+fn route_lookup(
+    state: &State,
+    dest: &str,
+    src: &str,
+    iif: &str,
+    mark: Option<&str>,
+) -> Result<String> {
+    let mut rules = state.rules.clone();
+    rules.sort();
+    for rule in rules.iter().filter(|r| rule_selects(r, src, iif, mark)) {
+        let hop = match rule.table.as_str() {
+            "local" => None,
+            "main" => state
+                .uplinks
+                .first()
+                .map(|u| (u.gateway.clone(), u.iface.clone())),
+            table => state
+                .tables
+                .get(table)
+                .and_then(|routes| routes.iter().find(|r| r.starts_with("default ")))
+                .and_then(|route| {
+                    let tokens: Vec<&str> = route.split_whitespace().collect();
+                    let dev = value_after(&tokens, "dev")?.to_string();
+                    Some((value_after(&tokens, "via").map(str::to_string), dev))
+                }),
+        };
+        let Some((gateway, dev)) = hop else {
+            continue;
+        };
+        let via = gateway.map(|gw| format!("via {} ", gw)).unwrap_or_default();
+        return Ok(format!(
+            "{} from {} {}dev {} table {} \n    cache iif {}\n",
+            dest, src, via, dev, rule.table, iif
+        ));
+    }
+    bail!("RTNETLINK answers: Network is unreachable")
+}
+
+/// Whether `rule` applies to a packet from `src` arriving on `iif`.
+fn rule_selects(rule: &Rule, src: &str, iif: &str, mark: Option<&str>) -> bool {
+    let from_matches = rule.from == "all"
+        || rule.from == src
+        || matches!(
+            (rule.from.parse::<ipnet::Ipv4Net>(), src.parse::<std::net::Ipv4Addr>()),
+            (Ok(net), Ok(addr)) if net.contains(&addr)
+        );
+    let tokens: Vec<&str> = rule.selector.split_whitespace().collect();
+    from_matches
+        && tokens.chunks(2).all(|kv| match kv {
+            ["iif", dev] => *dev == iif,
+            ["fwmark", value] => mark == value.split('/').next(),
+            _ => false,
+        })
+}
+
+/// Like the kernel, print each nexthop of a multipath route on its own
+/// indented line.
+fn render_route(route: &str) -> String {
+    format!("{}\n", route.replace(" nexthop ", "\n\tnexthop "))
+}
+
+fn value_after<'a>(args: &[&'a str], key: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| *a == key)
+        .and_then(|i| args.get(i + 1))
+        .copied()
+}
+
+impl CommandRunner for MemoryRunner {
+    fn run(&self, cmd: &str, args: &[&str]) -> Result<String> {
+        let mut state = self.state.lock().unwrap();
+        let call = format!("{} {}", cmd, args.join(" "));
+        if state.failing.iter().any(|prefix| call.starts_with(prefix)) {
+            state.calls.push(call);
+            bail!("RTNETLINK answers: Operation not permitted");
+        }
+        state.calls.push(call);
+        if cmd == "ping" {
+            let iface = value_after(args, "-I").unwrap_or_default();
+            if state.unreachable.iter().any(|i| i == iface) {
+                bail!("1 packets transmitted, 0 received, 100% packet loss");
+            }
+            let rtt = state.rtts.get(iface).copied().unwrap_or(1.5);
+            return Ok(format!(
+                "64 bytes from {}: icmp_seq=1 ttl=64 time={} ms\n\
+                 1 packets transmitted, 1 received, 0% packet loss\n",
+                args.last().unwrap_or(&""),
+                rtt
+            ));
+        }
+        if cmd == "nft" && args == ["list", "ruleset"] {
+            if let Some(ruleset) = &state.nft_ruleset {
+                return Ok(ruleset.clone());
+            }
+        }
+        if cmd != "ip" {
+            bail!("memory runner: unsupported command {}", cmd);
+        }
+
+        // Drop family/one-line flags; the model is IPv4 only
+        let args: Vec<&str> = args
+            .iter()
+            .copied()
+            .filter(|a| *a != "-4" && *a != "-o")
+            .collect();
+
+        match args.as_slice() {
+            ["-V"] => Ok("ip utility, iproute2-6.1.0, libbpf 1.1.0\n".to_string()),
+            ["rule", "show"] => {
+                let mut rules = state.rules.clone();
+                rules.sort();
+                Ok(rules.iter().map(Rule::render).collect())
+            }
+            ["rule", "add", rest @ ..] => {
+                let Some(table) = value_after(rest, "lookup") else {
+                    bail!("memory runner: unsupported rule add {:?}", rest);
+                };
+                // Like the kernel, a rule without a source matches any
+                let from = value_after(rest, "from").unwrap_or("all");
+                let priority = match value_after(rest, "priority") {
+                    Some(p) => p.parse()?,
+                    None => {
+                        let lowest = state
+                            .rules
+                            .iter()
+                            .map(|r| r.priority)
+                            .filter(|p| *p > 0)
+                            .min();
+                        lowest.unwrap_or(32766) - 1
+                    }
+                };
+                let rule = Rule {
+                    selector: extra_selector(rest),
+                    ..Rule::new(priority, &kernel_selector(from), table)
+                };
+                if state.rules.contains(&rule) {
+                    bail!("RTNETLINK answers: File exists");
+                }
+                state.rules.push(rule);
+                Ok(String::new())
+            }
+            ["rule", "del", rest @ ..] => {
+                let from = value_after(rest, "from").map(kernel_selector);
+                let table = value_after(rest, "lookup");
+                let priority: Option<u32> =
+                    value_after(rest, "priority").map(str::parse).transpose()?;
+                // Like the kernel, unspecified keys act as wildcards
+                let selector = extra_selector(rest);
+                let found = state.rules.iter().position(|r| {
+                    from.as_ref().is_none_or(|f| *f == r.from)
+                        && table.is_none_or(|t| t == r.table)
+                        && priority.is_none_or(|p| p == r.priority)
+                        && (selector.is_empty() || selector == r.selector)
+                });
+                match found {
+                    Some(i) => {
+                        state.rules.remove(i);
+                        Ok(String::new())
+                    }
+                    None => bail!("RTNETLINK answers: No such file or directory"),
+                }
+            }
+            ["route", "replace", dest, rest @ ..] => {
+                check_gateway(&state, rest)?;
+                let table = value_after(rest, "table").unwrap_or("main").to_string();
+                let route = route_spec(dest, rest);
+                let routes = state.tables.entry(table).or_default();
+                routes.retain(|r| r.split_whitespace().next() != Some(dest));
+                routes.push(route);
+                Ok(String::new())
+            }
+            ["route", "add", dest, rest @ ..] => {
+                check_gateway(&state, rest)?;
+                let table = value_after(rest, "table").unwrap_or("main").to_string();
+                let route = route_spec(dest, rest);
+                let routes = state.tables.entry(table).or_default();
+                if routes
+                    .iter()
+                    .any(|r| r.split_whitespace().next() == Some(dest))
+                {
+                    bail!("RTNETLINK answers: File exists");
+                }
+                routes.push(route);
+                Ok(String::new())
+            }
+            ["route", "show", "table", table, dest] => Ok(state
+                .tables
+                .get(*table)
+                .map(|routes| {
+                    routes
+                        .iter()
+                        .filter(|r| r.split_whitespace().next() == Some(dest))
+                        .map(|r| render_route(r))
+                        .collect()
+                })
+                .unwrap_or_default()),
+            ["route", "flush", "table", table] => {
+                state.tables.remove(*table);
+                Ok(String::new())
+            }
+            ["route", "show", "table", table] => Ok(state
+                .tables
+                .get(*table)
+                .map(|routes| routes.iter().map(|r| render_route(r)).collect())
+                .unwrap_or_default()),
+            ["route", "show", "default", "dev", iface] => Ok(state
+                .uplinks
+                .iter()
+                .filter(|u| u.iface == *iface)
+                .map(|u| match &u.gateway {
+                    Some(gw) => format!("default via {} proto dhcp metric 100\n", gw),
+                    None => "default scope link\n".to_string(),
+                })
+                .collect()),
+            ["route", "get", dest, "from", src, "iif", iif, rest @ ..] => {
+                route_lookup(&state, dest, src, iif, value_after(rest, "mark"))
+            }
+            ["route", "get", addr] => match state.host_routes.get(*addr) {
+                Some((dev, None)) => Ok(format!("{} dev {} uid 0\n    cache\n", addr, dev)),
+                Some((dev, Some(src))) => Ok(format!(
+                    "{} dev {} src {} uid 0\n    cache\n",
+                    addr, dev, src
+                )),
+                None => bail!("memory runner: no route for {}", addr),
+            },
+            ["route", "show", "default"] => Ok(state
+                .uplinks
+                .iter()
+                .map(|u| match &u.gateway {
+                    Some(gw) => {
+                        format!("default via {} dev {} proto dhcp metric 100\n", gw, u.iface)
+                    }
+                    None => format!("default dev {} scope link\n", u.iface),
+                })
+                .collect()),
+            ["route", "show", "dev", iface, "scope", "link"] => Ok(state
+                .uplinks
+                .iter()
+                .filter(|u| u.iface == *iface)
+                .map(|u| {
+                    let src = u.addr.split('/').next().unwrap_or_default();
+                    format!("{} proto kernel scope link src {}\n", u.link, src)
+                })
+                .collect()),
+            ["-j", "addr", "show", "dev", iface, "scope", "global"] => {
+                let addr_info: Vec<serde_json::Value> = state
+                    .uplinks
+                    .iter()
+                    .filter(|u| u.iface == *iface)
+                    .filter_map(|u| u.addr.split_once('/'))
+                    .map(|(local, len)| {
+                        serde_json::json!({
+                            "family": "inet",
+                            "local": local,
+                            "prefixlen": len.parse::<u8>().unwrap_or(32),
+                            "scope": "global",
+                        })
+                    })
+                    .collect();
+                Ok(serde_json::json!([{ "ifname": iface, "addr_info": addr_info }]).to_string())
+            }
+            ["addr", "show", "dev", iface, "scope", "global"] => Ok(state
+                .uplinks
+                .iter()
+                .filter(|u| u.iface == *iface)
+                .map(|u| {
+                    format!(
+                        "2: {}    inet {} scope global {}\n",
+                        u.iface, u.addr, u.iface
+                    )
+                })
+                .collect()),
+            ["link", "show", "dev", iface] => {
+                match state.uplinks.iter().find(|u| u.iface == *iface) {
+                    Some(u) => Ok(format!(
+                        "2: {}: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 state UP\n",
+                        u.iface
+                    )),
+                    None => bail!("Device \"{}\" does not exist.", iface),
+                }
+            }
+            ["neigh", "show", "dev", iface] => Ok(state
+                .neighbors
+                .iter()
+                .filter(|(i, _)| i == iface)
+                .map(|(_, addr)| format!("{} lladdr 02:00:00:00:00:01 REACHABLE\n", addr))
+                .collect()),
+            ["addr", "del", ..] => bail!("RTNETLINK answers: Cannot assign requested address"),
+            _ => bail!("memory runner: unsupported command ip {}", args.join(" ")),
+        }
+    }
+}
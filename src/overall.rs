@@ -0,0 +1,152 @@
+//! The one-word summary `/status` gives as `overall`.
+//!
+//! - `down`: initialization has not completed, or health probing is on and
+//!   every probed WAN is down.
+//! - `degraded`: otherwise, when any condition listed in OVERALL_DEGRADED_ON
+//!   holds (`wan_down,drift` by default).
+//! - `healthy`: everything else.
+
+use crate::health::WanHealth;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overall {
+    Healthy,
+    Degraded,
+    Down,
+}
+
+/// What can make the service count as degraded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    // A probed WAN is down (but not all of them)
+    WanDown,
+    // READ_ONLY found the kernel out of line with the configuration
+    Drift,
+    // The LAN base rule has failed over away from BASE_WAN
+    Failover,
+    // Switching is paused
+    Paused,
+}
+
+pub const DEFAULT_DEGRADED_ON: &[Condition] = &[Condition::WanDown, Condition::Drift];
+
+/// OVERALL_DEGRADED_ON: comma-separated conditions, or `none`.
+pub fn parse_conditions(value: &str) -> Result<Vec<Condition>> {
+    if value.trim() == "none" {
+        return Ok(Vec::new());
+    }
+    let mut conditions = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let condition = match name {
+            "wan_down" => Condition::WanDown,
+            "drift" => Condition::Drift,
+            "failover" => Condition::Failover,
+            "paused" => Condition::Paused,
+            other => bail!(
+                "unknown condition {:?} (expected wan_down, drift, failover, paused or none)",
+                other
+            ),
+        };
+        if !conditions.contains(&condition) {
+            conditions.push(condition);
+        }
+    }
+    Ok(conditions)
+}
+
+/// The state the summary is derived from.
+pub struct Signals<'a> {
+    pub initialized: bool,
+    // Probe results; empty without HEALTH_TARGET
+    pub health: &'a BTreeMap<String, WanHealth>,
+    pub drift: bool,
+    pub failed_over: bool,
+    pub paused: bool,
+}
+
+impl Signals<'_> {
+    pub fn overall(&self, degraded_on: &[Condition]) -> Overall {
+        let down = self.health.values().filter(|wan| wan.is_down()).count();
+        if !self.initialized || (down > 0 && down == self.health.len()) {
+            return Overall::Down;
+        }
+        let holds = |condition: &Condition| match condition {
+            Condition::WanDown => down > 0,
+            Condition::Drift => self.drift,
+            Condition::Failover => self.failed_over,
+            Condition::Paused => self.paused,
+        };
+        if degraded_on.iter().any(holds) {
+            Overall::Degraded
+        } else {
+            Overall::Healthy
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wan(healthy: bool) -> WanHealth {
+        let mut wan = WanHealth::default();
+        wan.healthy = healthy;
+        wan.consecutive_failures = if healthy { 0 } else { 3 };
+        wan
+    }
+
+    fn signals(health: &BTreeMap<String, WanHealth>) -> Signals<'_> {
+        Signals {
+            initialized: true,
+            health,
+            drift: false,
+            failed_over: true,
+            paused: false,
+        }
+    }
+
+    #[test]
+    fn overall_follows_the_documented_rules() {
+        let mut health = BTreeMap::new();
+        assert_eq!(
+            signals(&health).overall(DEFAULT_DEGRADED_ON),
+            Overall::Healthy
+        );
+        assert_eq!(
+            signals(&health).overall(&[Condition::Failover]),
+            Overall::Degraded
+        );
+
+        health.insert("wan0".to_string(), wan(false));
+        health.insert("wan1".to_string(), wan(true));
+        assert_eq!(
+            signals(&health).overall(DEFAULT_DEGRADED_ON),
+            Overall::Degraded
+        );
+        assert_eq!(signals(&health).overall(&[]), Overall::Healthy);
+
+        health.insert("wan1".to_string(), wan(false));
+        assert_eq!(signals(&health).overall(&[]), Overall::Down);
+
+        // Not probed yet is not down
+        health.insert("wan1".to_string(), WanHealth::default());
+        assert_eq!(signals(&health).overall(&[]), Overall::Healthy);
+
+        let uninitialized = Signals {
+            initialized: false,
+            ..signals(&health)
+        };
+        assert_eq!(uninitialized.overall(&[]), Overall::Down);
+
+        assert_eq!(
+            parse_conditions("drift, paused,drift").unwrap(),
+            [Condition::Drift, Condition::Paused]
+        );
+        assert!(parse_conditions("none").unwrap().is_empty());
+        assert!(parse_conditions("netlink").is_err());
+    }
+}
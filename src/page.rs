@@ -0,0 +1,77 @@
+//! `?limit=&offset=` paging for the listings that grow with the deployment:
+//! the mappings in `/status` and the rules in `/rules`.
+//!
+//! Callers sort what they list first (hosts by address, rules by priority),
+//! so a page holds the same entries for as long as nothing changes.
+
+use crate::ApiError;
+use serde::{Deserialize, Serialize};
+
+/// Page size when `limit` is not given.
+pub const DEFAULT_LIMIT: usize = 500;
+/// Largest `limit` accepted.
+pub const MAX_LIMIT: usize = 5000;
+
+#[derive(Deserialize)]
+pub struct PageParams {
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+/// Where a page sits in the whole listing.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+    pub total: usize,
+    // Offset of the following page; None on the last one
+    pub next_offset: Option<usize>,
+}
+
+/// The page of `items` that `params` asks for.
+pub fn paginate<T>(items: Vec<T>, params: &PageParams) -> Result<(Vec<T>, Page), ApiError> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(ApiError::bad_request(format!(
+            "limit must be 1-{}, got {}",
+            MAX_LIMIT, limit
+        )));
+    }
+    let total = items.len();
+    let end = params.offset.saturating_add(limit).min(total);
+    let page = items.into_iter().skip(params.offset).take(limit).collect();
+    Ok((
+        page,
+        Page {
+            offset: params.offset,
+            limit,
+            total,
+            next_offset: (end < total).then_some(end),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_cover_the_listing_once() {
+        let params = |limit, offset| PageParams {
+            limit: Some(limit),
+            offset,
+        };
+        let (items, page) = paginate((0..5).collect(), &params(2, 0)).unwrap();
+        assert_eq!(items, [0, 1]);
+        assert_eq!(page.next_offset, Some(2));
+        let (items, page) = paginate((0..5).collect(), &params(2, 4)).unwrap();
+        assert_eq!(items, [4]);
+        assert_eq!(page.next_offset, None);
+        let (items, page) = paginate((0..5).collect::<Vec<_>>(), &params(2, 9)).unwrap();
+        assert!(items.is_empty());
+        assert_eq!((page.total, page.next_offset), (5, None));
+        assert!(paginate(vec![1], &params(0, 0)).is_err());
+        assert!(paginate(vec![1], &params(MAX_LIMIT + 1, 0)).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! Saving mappings to STATE_FILE and restoring them at startup.
+//!
+//! Every write happens while the caller holds the `mappings` lock, so a
+//! mutation's write and a periodic checkpoint can never interleave or leave
+//! an older snapshot on disk after a newer one.
+
+use crate::auto_select::AutoPin;
+use crate::dscp::{self, DscpPolicies};
+use crate::exceptions::{self, Exceptions};
+use crate::health::SavedHealth;
+use crate::mapping::{self, Mapping, Mappings, Origin};
+use crate::schedule::Schedules;
+use crate::{canonical_net, reconcile_host_rules, AppState};
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PersistedState {
+    pub mappings: HashMap<String, SavedMapping>,
+    #[serde(default)]
+    pub auto_assigned: Vec<String>,
+    #[serde(default)]
+    pub locked: Vec<String>,
+    #[serde(default)]
+    pub lease_pins: BTreeMap<String, String>,
+    #[serde(default)]
+    pub auto_pins: BTreeMap<String, AutoPin>,
+    #[serde(default)]
+    pub schedules: Schedules,
+    #[serde(default)]
+    pub exceptions: Exceptions,
+    #[serde(default)]
+    pub dscp_policies: DscpPolicies,
+    // Taken up by health::restore, not restore
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<SavedHealth>,
+}
+
+/// A saved mapping: the whole record, or only the WAN in files written
+/// before mappings carried more.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SavedMapping {
+    Nic(String),
+    Full(Mapping),
+}
+
+impl SavedMapping {
+    pub fn nic(&self) -> &str {
+        match self {
+            SavedMapping::Nic(nic) => nic,
+            SavedMapping::Full(mapping) => &mapping.nic,
+        }
+    }
+}
+
+/// Write `state` to `path` via a temporary file and rename, so readers never
+/// see a partial file. Both the file and the rename are flushed to disk
+/// before returning, so a power cut leaves the old state or the new one.
+pub fn write_atomic(path: &Path, state: &PersistedState) -> Result<()> {
+    use std::io::Write;
+    let tmp = tmp_path(path);
+    let json = serde_json::to_vec_pretty(state)?;
+    let mut file =
+        std::fs::File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+    file.write_all(&json)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("rename {} to {}", tmp.display(), path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("sync {}", dir.display()))?;
+    Ok(())
+}
+
+/// `<path>.tmp`, next to `path`. Appended rather than swapped for the
+/// extension, which would be `path` itself for a STATE_FILE ending in `.tmp`.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+pub fn read(path: &Path) -> Result<Option<PersistedState>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(Some(
+            serde_json::from_slice(&bytes).with_context(|| format!("parse {}", path.display()))?,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("read {}", path.display())),
+    }
+}
+
+/// Save the current mappings if STATE_FILE is configured. Callers must hold
+/// the `mappings` lock (and pass its contents) for the ordering guarantee.
+pub fn save(state: &AppState, mappings: &Mappings, auto_assigned: &HashMap<String, Instant>) {
+    let Some(path) = &state.config.state_file else {
+        return;
+    };
+    let mut auto: Vec<String> = auto_assigned.keys().cloned().collect();
+    auto.sort();
+    let snapshot = PersistedState {
+        mappings: mappings
+            .iter()
+            .map(|(net, m)| (mapping::host(net), SavedMapping::Full(m.clone())))
+            .collect(),
+        auto_assigned: auto,
+        locked: state.locked.lock().unwrap().iter().cloned().collect(),
+        lease_pins: state.lease_pins.lock().unwrap().clone(),
+        auto_pins: state.auto_pins.lock().unwrap().clone(),
+        schedules: state.schedules.lock().unwrap().clone(),
+        exceptions: state.exceptions.lock().unwrap().clone(),
+        dscp_policies: state.dscp_policies.lock().unwrap().clone(),
+        health: state.saved_health.lock().unwrap().clone(),
+    };
+    if let Err(e) = write_atomic(path, &snapshot) {
+        eprintln!("Failed to save state: {:#}", e);
+    }
+}
+
+/// `host` as the service spells it. Files written by hand may use the
+/// IPv4-mapped form, which would otherwise become an IPv6 rule.
+fn canonical(host: &str) -> String {
+    if let Ok(net) = host.parse::<IpNet>() {
+        return mapping::host(&canonical_net(net));
+    }
+    host.parse::<IpAddr>()
+        .map_or_else(|_| host.to_string(), |ip| ip.to_canonical().to_string())
+}
+
+/// Re-install the pins of a saved state and load it into `state`. Entries
+/// outside the LAN or with an unknown nic are skipped. The kernel is brought
+/// in line in one pass, so restoring many mappings lists the rules once.
+pub async fn restore(state: &AppState, saved: PersistedState) {
+    let runner = state.runner.as_ref();
+    let mut mappings = state.mappings.lock().await;
+    let mut auto_assigned = state.auto_assigned.lock().await;
+    let mut valid = HashMap::new();
+    let mut records = HashMap::new();
+    let saved = PersistedState {
+        mappings: saved
+            .mappings
+            .into_iter()
+            .map(|(host, m)| (canonical(&host), m))
+            .collect(),
+        auto_assigned: saved.auto_assigned.iter().map(|h| canonical(h)).collect(),
+        locked: saved.locked.iter().map(|h| canonical(h)).collect(),
+        lease_pins: saved
+            .lease_pins
+            .into_iter()
+            .map(|(name, host)| (name, canonical(&host)))
+            .collect(),
+        auto_pins: saved
+            .auto_pins
+            .into_iter()
+            .map(|(host, pin)| (canonical(&host), pin))
+            .collect(),
+        exceptions: saved
+            .exceptions
+            .into_iter()
+            .map(|(host, prefixes)| (canonical(&host), prefixes))
+            .collect(),
+        schedules: saved
+            .schedules
+            .into_iter()
+            .map(|(name, mut schedule)| {
+                schedule.hosts = schedule.hosts.iter().map(|h| canonical(h)).collect();
+                schedule.applied = schedule.applied.map(|applied| {
+                    applied
+                        .into_iter()
+                        .map(|(host, nic)| (canonical(&host), nic))
+                        .collect()
+                });
+                (name, schedule)
+            })
+            .collect(),
+        ..saved
+    };
+    for (host, saved) in saved.mappings {
+        let nic = saved.nic();
+        let net = mapping::parse_prefix(&host).filter(|net| state.config.mappable(net.addr()));
+        let Some(net) = net.filter(|_| nic == "wan0" || nic == "wan1") else {
+            eprintln!("Skipping saved mapping {} -> {}", host, nic);
+            continue;
+        };
+        valid.insert(host.clone(), nic.to_string());
+        records.insert(host, (net, saved));
+    }
+
+    let mut outcome = match reconcile_host_rules(runner, &state.config, &valid) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("Failed to restore saved mappings: {:#}", e);
+            return;
+        }
+    };
+    for (host, e) in outcome.failed.drain(..) {
+        eprintln!("Failed to restore {} -> {}: {:#}", host, valid[&host], e);
+        valid.remove(&host);
+    }
+    for (host, nic) in valid {
+        if saved.auto_assigned.contains(&host) {
+            auto_assigned.insert(host.clone(), Instant::now());
+        }
+        println!("Restored saved mapping {} -> {}", host, nic);
+        let priority = outcome.priorities.get(&host).copied();
+        let (net, saved) = records.remove(&host).expect("recorded with its nic");
+        let mapping = match saved {
+            SavedMapping::Full(mapping) => Mapping {
+                priority,
+                prefix_len: net.prefix_len(),
+                ..mapping
+            },
+            SavedMapping::Nic(nic) => Mapping {
+                prefix_len: net.prefix_len(),
+                ..Mapping::new(&nic, priority, Origin::Restored)
+            },
+        };
+        mappings.insert(net, mapping);
+    }
+    // Locks stand on their own; a locked host need not be mapped
+    let mappable = |host: &str| {
+        host.parse::<IpAddr>()
+            .is_ok_and(|ip| state.config.mappable(ip))
+    };
+    let mut locked = state.locked.lock().unwrap();
+    locked.extend(saved.locked.into_iter().filter(|host| mappable(host)));
+    // A lease pin only makes sense while its address is mapped
+    let mut lease_pins = state.lease_pins.lock().unwrap();
+    lease_pins.extend(
+        saved
+            .lease_pins
+            .into_iter()
+            .filter(|(_, host)| mapping::contains(&mappings, host)),
+    );
+    // An auto pin only stands while its host is still on the chosen WAN
+    let mut auto_pins = state.auto_pins.lock().unwrap();
+    auto_pins.extend(
+        saved
+            .auto_pins
+            .into_iter()
+            .filter(|(host, pin)| mapping::nic(&mappings, host) == Some(pin.nic.as_str())),
+    );
+    // Applied ones are reverted by the scheduler if their window has ended
+    state.schedules.lock().unwrap().extend(saved.schedules);
+    // Exceptions are put back along with their host's pin
+    for (host, prefixes) in saved.exceptions {
+        let Some(record) = mapping::parse_prefix(&host).and_then(|net| mappings.get(&net)) else {
+            continue;
+        };
+        if let Err(e) = exceptions::reinstall(state, &host, &record.nic, &prefixes, record.priority)
+        {
+            eprintln!("Failed to restore exceptions of {}: {:#}", host, e);
+        }
+    }
+    // DSCP policies do not depend on any mapping
+    for (value, nic) in saved.dscp_policies {
+        if !(1..64).contains(&value) || (nic != "wan0" && nic != "wan1") {
+            eprintln!("Skipping saved DSCP policy {} -> {}", value, nic);
+            continue;
+        }
+        if let Err(e) = dscp::install(state, value, &nic) {
+            eprintln!(
+                "Failed to restore DSCP policy {} -> {}: {:#}",
+                value, nic, e
+            );
+        }
+    }
+    state
+        .reconciled_at
+        .store(crate::unix_now(), std::sync::atomic::Ordering::SeqCst);
+    println!(
+        "Restore reconciled rules: {} added, {} removed",
+        outcome.added, outcome.removed
+    );
+}
+
+/// Write the current state every `interval`, whether or not anything changed
+/// through the API (adoption, eviction and restores are covered too).
+pub async fn checkpoint_loop(state: AppState, interval: std::time::Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let mappings = state.mappings.lock().await;
+        let auto_assigned = state.auto_assigned.lock().await;
+        save(&state, &mappings, &auto_assigned);
+    }
+}
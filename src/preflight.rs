@@ -0,0 +1,242 @@
+//! Startup capability probe (PREFLIGHT=true).
+//!
+//! Stripped-down kernels may lack policy routing, and minimal images may lack
+//! the tools an enabled mode shells out to. Both otherwise only show up as
+//! cryptic failures at the first /switch or probe, so this checks them up
+//! front and names what is missing.
+
+use crate::{ip_rules, CommandRunner, Config, PRIO_MAIN, TABLE_WAN0};
+use std::ffi::OsStr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// Sources no LAN host can have, so the scratch rules never match real traffic
+const SCRATCH_FROM_V4: &str = "127.255.255.254";
+const SCRATCH_FROM_V6: &str = "100::1";
+
+/// One capability the configuration needs but the system lacks.
+#[derive(Debug, PartialEq)]
+pub struct Missing {
+    pub feature: String,
+    pub detail: String,
+}
+
+impl std::fmt::Display for Missing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.feature, self.detail)
+    }
+}
+
+/// Everything the configuration needs that is missing; empty when the
+/// service can run. `path` is the PATH executables are looked up in.
+pub fn probe(runner: &dyn CommandRunner, config: &Config, path: &OsStr) -> Vec<Missing> {
+    let mut missing = Vec::new();
+    if !find_tool("ip", path) {
+        missing.push(tool_missing("ip", "iproute2", "required"));
+        // Nothing else can be probed without it
+        return missing;
+    }
+    if let Some(m) = probe_rule_add(runner, "-4", SCRATCH_FROM_V4, "CONFIG_IP_MULTIPLE_TABLES") {
+        missing.push(m);
+    }
+    if config.ipv6 {
+        if let Some(m) =
+            probe_rule_add(runner, "-6", SCRATCH_FROM_V6, "CONFIG_IPV6_MULTIPLE_TABLES")
+        {
+            missing.push(m);
+        }
+    }
+    if config.health.is_some() && !find_tool("ping", path) {
+        missing.push(tool_missing(
+            "ping",
+            "iputils-ping",
+            "used by HEALTH_TARGET",
+        ));
+    }
+    if let Some(hook) = &config.post_switch_hook {
+        if !find_tool(hook, path) {
+            missing.push(Missing {
+                feature: format!("POST_SWITCH_HOOK {}", hook),
+                detail: "not found or not executable".to_string(),
+            });
+        }
+    }
+    missing
+}
+
+/// Add and remove a rule at an unused priority. The kernel refuses rules
+/// pointing at a non-main table when built without multiple-table support.
+fn probe_rule_add(
+    runner: &dyn CommandRunner,
+    family: &str,
+    from: &str,
+    kernel_option: &str,
+) -> Option<Missing> {
+    let used: Vec<u32> = match ip_rules(runner) {
+        Ok(rules) => rules.iter().map(|r| r.priority).collect(),
+        Err(e) => {
+            return Some(Missing {
+                feature: "ip rule".to_string(),
+                detail: format!("listing rules failed: {:#}", e),
+            })
+        }
+    };
+    // Just below the main rule, clear of the service's own priorities
+    let prio = (1..PRIO_MAIN)
+        .rev()
+        .find(|p| !used.contains(p))?
+        .to_string();
+    let args = ["from", from, "lookup", TABLE_WAN0, "priority", &prio];
+    let add: Vec<&str> = [family, "rule", "add"]
+        .iter()
+        .chain(&args)
+        .copied()
+        .collect();
+    if let Err(e) = runner.run("ip", &add) {
+        let err = format!("{:#}", e);
+        let detail = if err.contains("not supported") {
+            format!(
+                "kernel lacks policy routing; rebuild with {}=y",
+                kernel_option
+            )
+        } else if err.contains("not permitted") {
+            "adding rules was refused; run as root or with CAP_NET_ADMIN".to_string()
+        } else {
+            format!("test rule could not be added: {}", err.trim())
+        };
+        return Some(Missing {
+            feature: format!("policy routing ({})", kernel_option),
+            detail,
+        });
+    }
+    let del: Vec<&str> = [family, "rule", "del"]
+        .iter()
+        .chain(&args)
+        .copied()
+        .collect();
+    if let Err(e) = runner.run("ip", &del) {
+        eprintln!(
+            "Failed to remove the preflight test rule at {}: {:#}",
+            prio, e
+        );
+    }
+    None
+}
+
+fn tool_missing(tool: &str, package: &str, why: &str) -> Missing {
+    Missing {
+        feature: format!("{} command", tool),
+        detail: format!("{}; install {}", why, package),
+    }
+}
+
+/// Like `command -v`: a path is checked as is, a bare name against PATH.
+fn find_tool(tool: &str, path: &OsStr) -> bool {
+    if tool.contains('/') {
+        return is_executable(Path::new(tool));
+    }
+    std::env::split_paths(path).any(|dir| is_executable(&dir.join(tool)))
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_runner::MemoryRunner;
+    use std::path::PathBuf;
+
+    fn config(pairs: &[(&str, &str)]) -> Config {
+        Config::from_vars(&|key| {
+            pairs
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        })
+        .unwrap()
+    }
+
+    /// A PATH directory holding executables with the given names.
+    fn bin_dir(name: &str, tools: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "wan-switcher-preflight-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        for tool in tools {
+            let path = dir.join(tool);
+            std::fs::write(&path, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn tools_must_be_executable_files() {
+        let dir = bin_dir("exec", &["probe-tool"]);
+        let tool = dir.join("probe-tool");
+        assert!(find_tool("probe-tool", dir.as_os_str()));
+        assert!(find_tool(tool.to_str().unwrap(), OsStr::new("")));
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(!find_tool("probe-tool", dir.as_os_str()));
+        assert!(!find_tool("probe-tool", OsStr::new("/nonexistent")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rule_probe_leaves_no_rule_behind() {
+        let runner = MemoryRunner::new();
+        let before = runner.rules();
+        assert_eq!(probe_rule_add(&runner, "-4", SCRATCH_FROM_V4, "X"), None);
+        assert_eq!(runner.rules(), before);
+        // 32766 is main's, so the scratch rule went right below it
+        assert!(runner
+            .calls()
+            .iter()
+            .any(|c| c.ends_with("lookup 100 priority 32765")));
+    }
+
+    #[test]
+    fn refused_rule_add_is_reported() {
+        let dir = bin_dir("refused", &["ip"]);
+        let runner = MemoryRunner::new();
+        runner.fail_commands("ip -4 rule add");
+        let missing = probe(&runner, &config(&[]), dir.as_os_str());
+        assert_eq!(missing.len(), 1, "{:?}", missing);
+        assert_eq!(
+            missing[0].feature,
+            "policy routing (CONFIG_IP_MULTIPLE_TABLES)"
+        );
+        assert!(missing[0].detail.contains("CAP_NET_ADMIN"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tools_are_required_only_by_enabled_modes() {
+        let dir = bin_dir("modes", &["ip"]);
+        let runner = MemoryRunner::new();
+        assert_eq!(probe(&runner, &config(&[]), dir.as_os_str()), vec![]);
+
+        let config = config(&[
+            ("HEALTH_TARGET", "192.0.2.1"),
+            ("POST_SWITCH_HOOK", "/nonexistent/hook"),
+        ]);
+        let missing: Vec<String> = probe(&runner, &config, dir.as_os_str())
+            .iter()
+            .map(|m| m.feature.clone())
+            .collect();
+        assert_eq!(
+            missing,
+            ["ping command", "POST_SWITCH_HOOK /nonexistent/hook"]
+        );
+
+        // Without ip nothing else is probed
+        let missing = probe(&runner, &config, OsStr::new(""));
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].feature, "ip command");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
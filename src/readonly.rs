@@ -0,0 +1,269 @@
+//! READ_ONLY mode: watching the kernel's routing state without changing it.
+//!
+//! Instead of initializing, the service discovers the gateways and reads the
+//! host rules already in place, then re-reads them every READ_ONLY_INTERVAL
+//! seconds (30 by default). What initialization or `/gc` would change is
+//! reported as drift in `/status`; every endpoint that changes state answers
+//! 403.
+
+use crate::{
+    adopt_rules, base_rule_cmd, default_route_matches, exceptions, extra_routes, ip_rules,
+    link_route_cmds, mapping, resolve_nexthop, resolve_src, route_entries, rule_del_exact_cmd,
+    stale_rules, table_default_route_cmd, AppState, CommandRunner, Config, Gateway, InitReport,
+    IpRule, WanInit, TABLE_WAN0, TABLE_WAN1,
+};
+use anyhow::{Context, Result};
+use ipnet::Ipv4Net;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// What initialization would set up, found without setting anything up.
+pub fn discover(runner: &dyn CommandRunner, config: &Config) -> Result<InitReport> {
+    let mut report = InitReport::default();
+    for (nic, iface, table, gateway, src, extra) in [
+        (
+            "wan0",
+            &config.wan0,
+            TABLE_WAN0,
+            config.gateway_wan0.as_ref(),
+            config.src_wan0.as_ref(),
+            &config.extra_routes_wan0,
+        ),
+        (
+            "wan1",
+            &config.wan1,
+            TABLE_WAN1,
+            config.gateway_wan1.as_ref(),
+            config.src_wan1.as_ref(),
+            &config.extra_routes_wan1,
+        ),
+    ] {
+        let gw = resolve_nexthop(runner, iface, gateway)
+            .with_context(|| format!("get gateway for {}", iface))?;
+        report.wans.insert(
+            nic.to_string(),
+            WanInit {
+                interface: iface.to_string(),
+                table: table.to_string(),
+                gateway: gw.to_string(),
+                nexthops: gw.gateways().iter().map(Gateway::to_string).collect(),
+                source: resolve_src(runner, iface, src)
+                    .with_context(|| format!("get source address for {}", iface))?,
+                link_routes: link_route_cmds(runner, iface, table)?.len(),
+                extra_routes: extra.len(),
+                ipv6_gateway: None,
+            },
+        );
+    }
+    for selector in config.base_selectors() {
+        report.base_rules.push(
+            base_rule_cmd(
+                "add",
+                &selector,
+                config.base_table(),
+                config.priorities.lan_default,
+            )
+            .to_string(),
+        );
+        if config.fallback_to_primary {
+            report.base_rules.push(
+                base_rule_cmd(
+                    "add",
+                    &selector,
+                    config.base_table(),
+                    config.priorities.fallback(),
+                )
+                .to_string(),
+            );
+        }
+    }
+    report.completed_at = crate::unix_now();
+    Ok(report)
+}
+
+/// Destination exceptions implied by the rules in place: rules to a prefix,
+/// at the exception priority, sending a mapped host to its other table.
+fn adopt_exceptions(
+    config: &Config,
+    rules: &[IpRule],
+    mappings: &HashMap<String, String>,
+) -> exceptions::Exceptions {
+    let mut adopted = exceptions::Exceptions::new();
+    for rule in rules {
+        let (Some(to), Some(nic)) = (&rule.to, mappings.get(&rule.from)) else {
+            continue;
+        };
+        if rule.priority != config.priorities.exception()
+            || rule.table != exceptions::other_table(nic)
+        {
+            continue;
+        }
+        if let Ok(prefix) = to.parse::<Ipv4Net>() {
+            adopted.entry(rule.from.clone()).or_default().push(prefix);
+        }
+    }
+    adopted
+}
+
+/// The commands that would bring the kernel in line with the configuration:
+/// missing or different table default routes, missing EXTRA_ROUTES_*,
+/// missing LAN rules and rules `/gc` would remove. A WAN whose gateway cannot be found is reported as
+/// such.
+fn drift(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    rules: &[IpRule],
+    mappings: &HashMap<String, String>,
+    exceptions: &exceptions::Exceptions,
+) -> Vec<String> {
+    let mut drift = Vec::new();
+    for (iface, table, gateway, src, mode) in [
+        (
+            &config.wan0,
+            TABLE_WAN0,
+            config.gateway_wan0.as_ref(),
+            config.src_wan0.as_ref(),
+            config.route_mode_wan0,
+        ),
+        (
+            &config.wan1,
+            TABLE_WAN1,
+            config.gateway_wan1.as_ref(),
+            config.src_wan1.as_ref(),
+            config.route_mode_wan1,
+        ),
+    ] {
+        let checked = resolve_nexthop(runner, iface, gateway).and_then(|gw| {
+            let routes = runner.run("ip", &["route", "show", "table", table, "default"])?;
+            let in_line = route_entries(&routes)
+                .iter()
+                .any(|r| r.starts_with("default") && default_route_matches(r, iface, &gw));
+            if in_line {
+                return Ok(None);
+            }
+            let src = resolve_src(runner, iface, src)?;
+            let cmd = table_default_route_cmd(iface, table, &gw, src.as_deref(), mode, false);
+            Ok(Some(cmd.to_string()))
+        });
+        match checked {
+            Ok(None) => {}
+            Ok(Some(cmd)) => drift.push(cmd),
+            Err(e) => drift.push(format!("table {}: {:#}", table, e)),
+        }
+    }
+    for (table, extra) in [
+        (TABLE_WAN0, &config.extra_routes_wan0),
+        (TABLE_WAN1, &config.extra_routes_wan1),
+    ] {
+        match extra_routes::missing(runner, extra, table) {
+            Ok(cmds) => drift.extend(cmds.iter().map(|c| c.to_string())),
+            Err(e) => drift.push(format!("table {}: {:#}", table, e)),
+        }
+    }
+
+    let mut expected = vec![config.priorities.lan_default];
+    if config.fallback_to_primary {
+        expected.push(config.priorities.fallback());
+    }
+    for selector in config.base_selectors() {
+        for &prio in &expected {
+            let present = rules.iter().any(|r| {
+                r.priority == prio && r.table == config.base_table() && selector.matches(r)
+            });
+            if !present {
+                drift.push(base_rule_cmd("add", &selector, config.base_table(), prio).to_string());
+            }
+        }
+    }
+    for rule in stale_rules(config, rules, mappings, config.base_table()) {
+        if !exceptions::is_known(config, exceptions, mappings, &rule) {
+            drift.push(rule_del_exact_cmd(&rule).to_string());
+        }
+    }
+    drift
+}
+
+/// Re-read the host rules into the service's state and recheck drift.
+pub async fn refresh(state: &AppState) -> Result<()> {
+    let runner = state.runner.as_ref();
+    let rules = ip_rules(runner)?;
+    let adopted = adopt_rules(&state.config, &rules);
+    let nics = mapping::nics(&adopted.mappings);
+    let exceptions = adopt_exceptions(&state.config, &rules, &nics);
+    let drift = drift(runner, &state.config, &rules, &nics, &exceptions);
+
+    // Same lock order as switch_handler
+    let mut dns_steered = state.dns_steered.lock().await;
+    let mut mappings = state.mappings.lock().await;
+    *dns_steered = adopted.dns_steered;
+    mappings.retain(|ip, _| adopted.mappings.contains_key(ip));
+    mapping::adopt(&mut mappings, adopted.mappings);
+    *state.exceptions.lock().unwrap() = exceptions;
+    *state.dscp_policies.lock().unwrap() = adopted.dscp_policies;
+    *state.drift.lock().unwrap() = drift;
+    state
+        .reconciled_at
+        .store(crate::unix_now(), std::sync::atomic::Ordering::SeqCst);
+    Ok(())
+}
+
+pub async fn watch_loop(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick is immediate; startup has just refreshed
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = refresh(&state).await {
+            eprintln!("Failed to re-read routing state: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialize_lan_to_wan0;
+    use crate::memory_runner::MemoryRunner;
+
+    #[tokio::test]
+    async fn drift_lists_what_init_and_gc_would_change() {
+        let runner = MemoryRunner::new();
+        let config = Config::from_vars(&|_| None).unwrap();
+        let check = |mappings: &HashMap<String, String>| {
+            let rules = ip_rules(&runner).unwrap();
+            drift(&runner, &config, &rules, mappings, &Default::default())
+        };
+        assert_eq!(
+            check(&HashMap::new()),
+            [
+                "ip route replace default via 192.0.2.1 dev eth0 table 100",
+                "ip route replace default via 198.51.100.1 dev eth1 table 200",
+                "ip rule add from 10.40.0.0/20 lookup 100 priority 2000",
+            ]
+        );
+
+        initialize_lan_to_wan0(&runner, &config).await.unwrap();
+        runner
+            .run(
+                "ip",
+                &[
+                    "rule",
+                    "add",
+                    "from",
+                    "10.40.0.9/32",
+                    "lookup",
+                    "200",
+                    "priority",
+                    "1000",
+                ],
+            )
+            .unwrap();
+        // A host rule is drift until it is read into the mappings
+        assert_eq!(
+            check(&HashMap::new()),
+            ["ip rule del priority 1000 from 10.40.0.9 lookup 200"]
+        );
+        let adopted = adopt_rules(&config, &ip_rules(&runner).unwrap());
+        assert!(check(&mapping::nics(&adopted.mappings)).is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+//! Startup lock-out check (SAFE_MODE=true).
+//!
+//! Initialization deletes addresses from the WAN interfaces and installs
+//! `from <subnet>` base rules that also catch the router's own traffic from
+//! addresses in those subnets. A wrong LAN_SUBNETS can thereby cut the box
+//! off its own management session. This looks at the current default route
+//! and, with MANAGEMENT_HOST, at the route to that host, and names what the
+//! initialization plan would break before anything is changed.
+
+use crate::{addrs, build_plan, verify, CommandRunner, Config, IpCommand, LanRuleMode};
+use ipnet::Ipv4Net;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// What the initialization would break; empty when it is safe to go on.
+pub fn check(runner: &dyn CommandRunner, config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    match runner.run("ip", &["route", "show", "default"]) {
+        Ok(out) if !out.trim().is_empty() => {}
+        Ok(_) => problems.push("the main table has no default route".to_string()),
+        Err(e) => problems.push(format!("cannot read the default route: {:#}", e)),
+    }
+    for iface in [&config.wan0, &config.wan1] {
+        let addrs = match addrs::get_iface_addrs(runner, iface) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                problems.push(format!("cannot list the addresses of {}: {:#}", iface, e));
+                continue;
+            }
+        };
+        for addr in addrs {
+            let Some(subnet) = config.lan_subnets.iter().find(|n| n.contains(&addr.addr())) else {
+                continue;
+            };
+            if addr.addr() == subnet.addr() && addr.prefix_len() == subnet.prefix_len() {
+                problems.push(format!(
+                    "initialization would delete {} from {}",
+                    addr, iface
+                ));
+            } else if config.lan_rule_mode == LanRuleMode::Subnet {
+                problems.push(format!(
+                    "{} on {} is inside LAN subnet {}: the base rule would catch the router's own traffic from it",
+                    addr, iface, subnet
+                ));
+            }
+        }
+    }
+    if let Some(host) = config.management_host {
+        if let Err(problem) = check_management(runner, config, host) {
+            problems.push(problem);
+        }
+    }
+    problems
+}
+
+/// Whether the router still reaches `host` through the same device once the
+/// base rules are in place.
+fn check_management(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    host: Ipv4Addr,
+) -> Result<(), String> {
+    let out = runner
+        .run("ip", &["route", "get", &host.to_string()])
+        .map_err(|e| format!("no route to management host {}: {:#}", host, e))?;
+    let dev = verify::route_value(&out, "dev")
+        .ok_or_else(|| format!("no device in the route to management host {}", host))?;
+    // Without a source the lookup is not one a `from` rule can catch
+    let Some(src) = verify::route_value(&out, "src").and_then(|s| s.parse::<IpAddr>().ok()) else {
+        return Ok(());
+    };
+    if config.lan_rule_mode != LanRuleMode::Subnet
+        || !config.lan_subnets.iter().any(|n| n.contains(&src))
+    {
+        return Ok(());
+    }
+    let plan = build_plan(runner, config, &HashMap::new(), &HashMap::new())
+        .map_err(|e| format!("cannot build the initialization plan: {:#}", e))?;
+    let table = config.base_table();
+    match planned_dev(&plan, table, host) {
+        Some(planned) if planned == dev => Ok(()),
+        planned => Err(format!(
+            "management host {} is reached from {} via {} now, but the base rule would send it through table {} via {}",
+            host,
+            src,
+            dev,
+            table,
+            planned.as_deref().unwrap_or("nothing")
+        )),
+    }
+}
+
+/// The device of the most specific route in `plan` for `table` that covers
+/// `host`.
+fn planned_dev(plan: &[IpCommand], table: &str, host: Ipv4Addr) -> Option<String> {
+    plan.iter()
+        .filter(|cmd| cmd.args.first().map(String::as_str) == Some("route"))
+        .filter(|cmd| {
+            let args = &cmd.args;
+            args.windows(2).any(|p| p[0] == "table" && p[1] == table)
+        })
+        .filter_map(|cmd| {
+            let dest = match cmd.args.get(2)?.as_str() {
+                "default" => Ipv4Net::default(),
+                dest => dest
+                    .parse()
+                    .or_else(|_| dest.parse::<Ipv4Addr>().map(Ipv4Net::from))
+                    .ok()?,
+            };
+            let dev = cmd.args.windows(2).find(|p| p[0] == "dev")?[1].clone();
+            dest.contains(&host).then_some((dest.prefix_len(), dev))
+        })
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, dev)| dev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_runner::MemoryRunner;
+
+    fn config(pairs: &[(&str, &str)]) -> Config {
+        Config::from_vars(&|key| {
+            pairs
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn initialization_that_would_lock_the_router_out_is_named() {
+        let runner = MemoryRunner::new()
+            .with_host_route_from("10.40.0.50", "eth2", "10.40.0.1")
+            .with_host_route_from("192.0.2.50", "eth0", "192.0.2.10");
+        let safe = [("SAFE_MODE", "true")];
+        assert!(check(&runner, &config(&safe)).is_empty());
+
+        // Replies from the router's LAN address would follow the base rule
+        let managed = [("SAFE_MODE", "true"), ("MANAGEMENT_HOST", "10.40.0.50")];
+        let problems = check(&runner, &config(&managed));
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("via eth2 now"), "{}", problems[0]);
+        assert!(problems[0].contains("via eth0"), "{}", problems[0]);
+        let iif = [
+            ("SAFE_MODE", "true"),
+            ("MANAGEMENT_HOST", "10.40.0.50"),
+            ("LAN_RULE_MODE", "iif"),
+        ];
+        assert!(check(&runner, &config(&iif)).is_empty());
+        let wan_side = [("SAFE_MODE", "true"), ("MANAGEMENT_HOST", "192.0.2.50")];
+        assert!(check(&runner, &config(&wan_side)).is_empty());
+        let unknown = [("SAFE_MODE", "true"), ("MANAGEMENT_HOST", "203.0.113.9")];
+        assert!(check(&runner, &config(&unknown))[0].contains("no route"));
+
+        // A LAN subnet covering a WAN's own address
+        let overlap = [("SAFE_MODE", "true"), ("LAN_SUBNETS", "192.0.2.0/24")];
+        let problems = check(&runner, &config(&overlap));
+        assert!(
+            problems[0].contains("192.0.2.10/24 on eth0"),
+            "{:?}",
+            problems
+        );
+
+        assert!(Config::from_vars(
+            &|key| (key == "MANAGEMENT_HOST").then(|| "10.40.0.50".to_string())
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,386 @@
+//! Time-of-day pins: during a daily window a set of hosts is pinned to a
+//! WAN, and put back where it was once the window ends.
+//!
+//! Windows are `HH:MM-HH:MM` in SCHEDULE_UTC_OFFSET (UTC by default) and may
+//! cross midnight. The scheduler looks every TICK, so a transition lands
+//! within that of its boundary. Schedules are saved with the mappings.
+
+use crate::mapping::{self, Mappings, Origin};
+use crate::{
+    del_ip_rule_quiet, dns_rule_cmds, exceptions, persist, repin_host, unix_now, ApiError,
+    AppState, TABLE_WAN0, TABLE_WAN1,
+};
+use anyhow::{bail, Context, Result};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+const TICK: Duration = Duration::from_secs(15);
+const DAY: i64 = 24 * 60 * 60;
+
+/// A daily window, as minutes of the day; `end` before `start` crosses
+/// midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window {
+    start: u32,
+    end: u32,
+}
+
+fn parse_minute(value: &str) -> Option<u32> {
+    let (h, m) = value.trim().split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    (h < 24 && m < 60 && value.trim().len() == 5).then_some(h * 60 + m)
+}
+
+impl std::str::FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let window = value
+            .split_once('-')
+            .and_then(|(start, end)| Some((parse_minute(start)?, parse_minute(end)?)))
+            .map(|(start, end)| Window { start, end })
+            .with_context(|| format!("invalid window {:?}; expected HH:MM-HH:MM", value))?;
+        if window.start == window.end {
+            bail!("window {:?} is empty", value);
+        }
+        Ok(window)
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Window> for String {
+    fn from(window: Window) -> Self {
+        window.to_string()
+    }
+}
+
+impl std::fmt::Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Unix time of the first start or end after `now`.
+    fn next_transition(&self, now: u64, offset: i64) -> u64 {
+        let local = now as i64 + offset;
+        let midnight = local - local.rem_euclid(DAY);
+        [self.start, self.end]
+            .into_iter()
+            .map(|minute| {
+                let at = midnight + i64::from(minute) * 60;
+                if at > local {
+                    at
+                } else {
+                    at + DAY
+                }
+            })
+            .min()
+            .map_or(now, |at| (at - offset) as u64)
+    }
+}
+
+fn minute_of_day(now: u64, offset: i64) -> u32 {
+    ((now as i64 + offset).rem_euclid(DAY) / 60) as u32
+}
+
+/// SCHEDULE_UTC_OFFSET: `+09:00`, `-05:30`, `+9`; returns seconds.
+pub fn parse_utc_offset(value: &str) -> Result<i64> {
+    let invalid = || format!("invalid SCHEDULE_UTC_OFFSET: {:?}", value);
+    let (sign, rest) = match value.trim().split_at_checked(1) {
+        Some(("+", rest)) => (1, rest),
+        Some(("-", rest)) => (-1, rest),
+        _ => bail!(invalid()),
+    };
+    let (h, m) = rest.split_once(':').unwrap_or((rest, "0"));
+    let (h, m): (i64, i64) = (
+        h.parse().with_context(invalid)?,
+        m.parse().with_context(invalid)?,
+    );
+    if h > 14 || m >= 60 {
+        bail!(invalid());
+    }
+    Ok(sign * (h * 3600 + m * 60))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub hosts: Vec<String>,
+    pub nic: String,
+    pub window: Window,
+    // Set while the window is applied: each host's mapping from before it
+    // (None for hosts that had none)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied: Option<BTreeMap<String, Option<String>>>,
+}
+
+pub type Schedules = BTreeMap<String, Schedule>;
+
+/// A schedule as reported in `/status`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleStatus {
+    pub hosts: Vec<String>,
+    pub nic: String,
+    pub window: String,
+    pub active: bool,
+    // Unix time of the next start or end of the window
+    pub next_transition: u64,
+}
+
+impl Schedule {
+    pub fn status(&self, now: u64, offset: i64) -> ScheduleStatus {
+        ScheduleStatus {
+            hosts: self.hosts.clone(),
+            nic: self.nic.clone(),
+            window: self.window.to_string(),
+            active: self.applied.is_some(),
+            next_transition: self.window.next_transition(now, offset),
+        }
+    }
+}
+
+/// The mapping locks a transition works under, taken in the usual order.
+struct Locked<'a> {
+    dns_steered: tokio::sync::MutexGuard<'a, HashMap<String, String>>,
+    mappings: tokio::sync::MutexGuard<'a, Mappings>,
+    auto_assigned: tokio::sync::MutexGuard<'a, HashMap<String, std::time::Instant>>,
+}
+
+impl<'a> Locked<'a> {
+    async fn take(state: &'a AppState) -> Locked<'a> {
+        Locked {
+            dns_steered: state.dns_steered.lock().await,
+            mappings: state.mappings.lock().await,
+            auto_assigned: state.auto_assigned.lock().await,
+        }
+    }
+
+    fn save(&self, state: &AppState) {
+        persist::save(state, &self.mappings, &self.auto_assigned);
+    }
+
+    /// Pin the schedule's hosts, remembering what they had. Locked hosts
+    /// are left alone.
+    fn apply(&mut self, state: &AppState, name: &str, schedule: &mut Schedule) {
+        let mut before = BTreeMap::new();
+        for host in &schedule.hosts {
+            if state.locked.lock().unwrap().contains(host) {
+                println!("Schedule {}: {} is locked; left alone", name, host);
+                continue;
+            }
+            let previous = mapping::nic(&self.mappings, host).map(str::to_string);
+            match repin_host(state, &mut self.dns_steered, host, &schedule.nic, false) {
+                Ok(priority) => {
+                    mapping::pin(
+                        &mut self.mappings,
+                        host,
+                        &schedule.nic,
+                        priority,
+                        Origin::Schedule,
+                    );
+                    self.auto_assigned.remove(host);
+                    before.insert(host.clone(), previous);
+                }
+                Err(e) => eprintln!("Schedule {}: pinning {} failed: {:#}", name, host, e),
+            }
+        }
+        println!(
+            "Schedule {}: {} host(s) pinned to {} for {}",
+            name,
+            before.len(),
+            schedule.nic,
+            schedule.window
+        );
+        schedule.applied = Some(before);
+    }
+
+    /// Put the schedule's hosts back. A host switched by hand during the
+    /// window keeps that switch.
+    fn revert(&mut self, state: &AppState, name: &str, schedule: &mut Schedule) {
+        let Some(applied) = schedule.applied.take() else {
+            return;
+        };
+        for (host, previous) in applied {
+            if mapping::nic(&self.mappings, &host) != Some(schedule.nic.as_str()) {
+                continue;
+            }
+            let outcome = match &previous {
+                Some(nic) => repin_host(state, &mut self.dns_steered, &host, nic, false),
+                None => {
+                    self.unpin(state, &host);
+                    Ok(None)
+                }
+            };
+            match outcome {
+                Ok(priority) => match previous {
+                    Some(nic) => {
+                        mapping::pin(&mut self.mappings, &host, &nic, priority, Origin::Schedule);
+                    }
+                    None => {
+                        mapping::remove(&mut self.mappings, &host);
+                    }
+                },
+                Err(e) => eprintln!("Schedule {}: restoring {} failed: {:#}", name, host, e),
+            }
+        }
+        println!("Schedule {}: window over, hosts restored", name);
+    }
+
+    /// Drop a host's own rules, leaving it to the base rule.
+    fn unpin(&mut self, state: &AppState, host: &str) {
+        let runner = state.runner.as_ref();
+        let host_match = &state.config.host_match;
+        let target = mapping::selector(host);
+        exceptions::clear(state, host);
+        if let Some(table) = self.dns_steered.remove(host) {
+            let prio = state.config.priorities.dns();
+            for cmd in dns_rule_cmds("del", &target, host_match, &table, prio) {
+                let _ = cmd.run(runner);
+            }
+        }
+        del_ip_rule_quiet(runner, &target, host_match, TABLE_WAN0);
+        del_ip_rule_quiet(runner, &target, host_match, TABLE_WAN1);
+    }
+}
+
+/// Apply or revert every schedule whose window has opened or closed.
+pub async fn tick(state: &AppState, now: u64) {
+    let mut locked = Locked::take(state).await;
+    let mut schedules = state.schedules.lock().unwrap().clone();
+    let minute = minute_of_day(now, state.config.schedule_utc_offset);
+    let mut changed = false;
+    for (name, schedule) in schedules.iter_mut() {
+        match (schedule.applied.is_some(), schedule.window.contains(minute)) {
+            (false, true) => locked.apply(state, name, schedule),
+            (true, false) => locked.revert(state, name, schedule),
+            _ => continue,
+        }
+        changed = true;
+    }
+    if changed {
+        *state.schedules.lock().unwrap() = schedules;
+        locked.save(state);
+    }
+}
+
+/// Add, replace (`Some`) or remove (`None`) the schedule `name`. A replaced
+/// or removed schedule that is applied is reverted first; the next tick
+/// applies the new one. Returns whether `name` existed.
+pub async fn set(
+    state: &AppState,
+    name: &str,
+    schedule: Option<Schedule>,
+) -> Result<bool, ApiError> {
+    let mut locked = Locked::take(state).await;
+    let mut schedules = state.schedules.lock().unwrap().clone();
+    if let Some(new) = &schedule {
+        let taken = schedules
+            .iter()
+            .filter(|(other, _)| *other != name)
+            .find_map(|(other, s)| {
+                let host = new.hosts.iter().find(|h| s.hosts.contains(h))?;
+                Some((other, host))
+            });
+        if let Some((other, host)) = taken {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "schedule_conflict",
+                format!("{} is already in schedule {}", host, other),
+            ));
+        }
+    }
+    let existed = match schedules.remove(name) {
+        Some(mut old) => {
+            locked.revert(state, name, &mut old);
+            true
+        }
+        None => false,
+    };
+    if let Some(new) = schedule {
+        schedules.insert(name.to_string(), new);
+    }
+    *state.schedules.lock().unwrap() = schedules;
+    locked.save(state);
+    Ok(existed)
+}
+
+pub async fn scheduler_loop(state: AppState) {
+    let mut ticker = tokio::time::interval(TICK);
+    loop {
+        ticker.tick().await;
+        if state.paused.load(Ordering::SeqCst) {
+            continue;
+        }
+        tick(&state, unix_now()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_parse_and_may_cross_midnight() {
+        let night: Window = "22:00-06:30".parse().unwrap();
+        assert_eq!(night.to_string(), "22:00-06:30");
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(6 * 60 + 29));
+        assert!(!night.contains(6 * 60 + 30));
+        assert!(!night.contains(12 * 60));
+        let day: Window = "09:00-17:00".parse().unwrap();
+        assert!(day.contains(9 * 60) && !day.contains(17 * 60));
+        for bad in [
+            "9:00-17:00",
+            "09:00",
+            "24:00-01:00",
+            "08:00-08:00",
+            "08:60-09:00",
+        ] {
+            assert!(bad.parse::<Window>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn next_transition_honours_the_offset() {
+        let night: Window = "22:00-06:00".parse().unwrap();
+        // 2026-01-01 00:00 UTC
+        let midnight = 1_767_225_600;
+        assert_eq!(night.next_transition(midnight, 0), midnight + 6 * 3600);
+        assert_eq!(
+            night.next_transition(midnight + 6 * 3600, 0),
+            midnight + 22 * 3600
+        );
+        // 00:00 UTC is 09:00 at +09:00, so 22:00 local is 13:00 UTC
+        let jst = parse_utc_offset("+09:00").unwrap();
+        assert_eq!(night.next_transition(midnight, jst), midnight + 13 * 3600);
+        assert_eq!(minute_of_day(midnight, jst), 9 * 60);
+        assert_eq!(parse_utc_offset("-5:30").unwrap(), -(5 * 3600 + 30 * 60));
+        assert!(parse_utc_offset("09:00").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! `GET /export/script`: the current routing state as a shell script.
+//!
+//! Run on a clean box, the script recreates what the service has set up
+//! without the service: the WAN table routes and LAN base rules of the
+//! initialization (pointing wherever a failover has the base rule now), then
+//! each host's rules as recorded, then the DSCP policies. Comment lines
+//! delimit the sections and hosts so parts can be applied on their own.
+
+use crate::{dscp, host_diff, init_plan, mapping, unix_now, version, AppState, IpCommand};
+use anyhow::Result;
+use ipnet::IpNet;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+fn push(script: &mut String, cmds: &[IpCommand]) {
+    for cmd in cmds {
+        let _ = writeln!(script, "{}", cmd);
+    }
+}
+
+/// The script for the state as it is now.
+pub async fn export(state: &AppState) -> Result<String> {
+    // Same lock order as switch_handler
+    let dns_steered = state.dns_steered.lock().await.clone();
+    let mappings = state.mappings.lock().await.clone();
+    let base_table = state.health.lock().await.failover.active_table();
+    let exceptions = state.exceptions.lock().unwrap().clone();
+    let dscp_policies = state.dscp_policies.lock().unwrap().clone();
+    let config = &state.config;
+
+    let mut script = String::new();
+    let _ = writeln!(script, "#!/bin/sh");
+    let _ = writeln!(
+        script,
+        "# Routing state of {} ({}), exported at {}",
+        config.instance_name,
+        version::VERSION,
+        unix_now()
+    );
+    let _ = writeln!(
+        script,
+        "# Meant for a box without these rules and routes yet"
+    );
+    let _ = writeln!(script, "set -e");
+
+    let _ = writeln!(
+        script,
+        "\n# --- init: WAN table routes and LAN base rules ---"
+    );
+    push(
+        &mut script,
+        &init_plan(state.runner.as_ref(), config, base_table)?,
+    );
+
+    let hosts: BTreeSet<IpNet> = mappings
+        .keys()
+        .copied()
+        .chain(dns_steered.keys().filter_map(|h| mapping::parse_prefix(h)))
+        .collect();
+    let _ = writeln!(script, "\n# --- hosts ({}) ---", hosts.len());
+    for host in hosts {
+        let mapping = mappings.get(&host);
+        let key = mapping::host(&host);
+        let cmds = host_diff::host_cmds(
+            config,
+            host,
+            mapping,
+            dns_steered.get(&key).map(String::as_str),
+            exceptions.get(&key).map_or(&[][..], Vec::as_slice),
+        );
+        let _ = match mapping {
+            Some(m) if cmds.is_empty() => writeln!(script, "# {} -> {} (base rule)", key, m.nic),
+            Some(m) => writeln!(script, "# {} -> {}", key, m.nic),
+            None => writeln!(script, "# {}", key),
+        };
+        push(&mut script, &cmds);
+    }
+
+    if !dscp_policies.is_empty() {
+        let _ = writeln!(script, "\n# --- DSCP policies ---");
+        let cmds: Vec<IpCommand> = dscp_policies
+            .iter()
+            .map(|(value, nic)| dscp::add_cmd(config, *value, nic))
+            .collect();
+        push(&mut script, &cmds);
+    }
+    Ok(script)
+}
@@ -0,0 +1,256 @@
+//! Switch, failover and error events sent to syslog.
+//!
+//! With SYSLOG_FACILITY set (`user`, `daemon` or `local0`-`local7`), each
+//! event is written to the syslog socket (SYSLOG_SOCKET, `/dev/log` by
+//! default) through the `syslog` crate's RFC 3164 logger. The message text
+//! is `<event> key=value ...`,
+//! starting with `instance=<INSTANCE_NAME>`. Values with spaces, quotes or
+//! `=` are double-quoted, with `"` and `\` escaped; error events carry the
+//! error as `error=`.
+//! Events less severe than SYSLOG_SEVERITY (`info` by default) are not sent.
+//! The log lines printed for the same events go to stdout/stderr as before,
+//! unless SYSLOG_ONLY=true.
+//!
+//! One connection is kept for the life of the process and reopened after a
+//! failed send. Events are often sent with state locks held, so the socket
+//! never blocks: a message syslog has no room for is dropped and reported on
+//! stderr.
+
+use crate::{flag_var, Config};
+use anyhow::{bail, Result};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+
+/// Syslog severities, most severe first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+#[derive(Clone, Debug)]
+pub struct SyslogConfig {
+    facility: Facility,
+    // Least severe severity still sent
+    severity: Severity,
+    socket: PathBuf,
+    // Leave the events out of stdout/stderr
+    only: bool,
+}
+
+fn parse_facility(name: &str) -> Result<Facility> {
+    Ok(match name {
+        "user" => Facility::LOG_USER,
+        "daemon" => Facility::LOG_DAEMON,
+        "local0" => Facility::LOG_LOCAL0,
+        "local1" => Facility::LOG_LOCAL1,
+        "local2" => Facility::LOG_LOCAL2,
+        "local3" => Facility::LOG_LOCAL3,
+        "local4" => Facility::LOG_LOCAL4,
+        "local5" => Facility::LOG_LOCAL5,
+        "local6" => Facility::LOG_LOCAL6,
+        "local7" => Facility::LOG_LOCAL7,
+        _ => bail!("expected user, daemon or local0-local7"),
+    })
+}
+
+fn parse_severity(name: &str) -> Result<Severity> {
+    Ok(match name {
+        "err" | "error" => Severity::Err,
+        "warning" | "warn" => Severity::Warning,
+        "notice" => Severity::Notice,
+        "info" => Severity::Info,
+        _ => bail!("expected err, warning, notice or info"),
+    })
+}
+
+/// SYSLOG_FACILITY and friends; None unless SYSLOG_FACILITY is set.
+pub fn from_vars(var: &dyn Fn(&str) -> Option<String>) -> Result<Option<SyslogConfig>> {
+    let facility = match var("SYSLOG_FACILITY").as_deref().map(str::trim) {
+        None | Some("") => {
+            if flag_var(var, "SYSLOG_ONLY") {
+                bail!("SYSLOG_ONLY requires SYSLOG_FACILITY");
+            }
+            return Ok(None);
+        }
+        Some(v) => parse_facility(v)
+            .map_err(|e| anyhow::anyhow!("invalid SYSLOG_FACILITY: {:?} ({})", v, e))?,
+    };
+    let severity = match var("SYSLOG_SEVERITY").as_deref().map(str::trim) {
+        None | Some("") => Severity::Info,
+        Some(v) => parse_severity(v)
+            .map_err(|e| anyhow::anyhow!("invalid SYSLOG_SEVERITY: {:?} ({})", v, e))?,
+    };
+    Ok(Some(SyslogConfig {
+        facility,
+        severity,
+        socket: var("SYSLOG_SOCKET")
+            .filter(|v| !v.trim().is_empty())
+            .map_or_else(|| PathBuf::from("/dev/log"), |v| PathBuf::from(v.trim())),
+        only: flag_var(var, "SYSLOG_ONLY"),
+    }))
+}
+
+/// `<event> instance=<name> key=value ...`
+fn message(instance: &str, event: &str, fields: &[(&str, &str)]) -> String {
+    let mut message = format!("{} instance={}", event, quote(instance));
+    for (key, value) in fields {
+        message.push_str(&format!(" {}={}", key, quote(value)));
+    }
+    message
+}
+
+/// `value` as is when it is a single plain token, else double-quoted.
+/// Control characters (multi-line errors) become spaces.
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !matches!(c, '"' | '\\' | '='));
+    if plain {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push(' '),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+type SyslogLogger = Logger<LoggerBackend, Formatter3164>;
+
+// The open connection and the socket it goes to
+static LOGGER: Mutex<Option<(PathBuf, SyslogLogger)>> = Mutex::new(None);
+
+fn connect(config: &SyslogConfig) -> syslog::Result<SyslogLogger> {
+    let formatter = Formatter3164 {
+        facility: config.facility,
+        hostname: None,
+        process: "adaptiverouting".to_string(),
+        pid: std::process::id(),
+    };
+    let logger = syslog::unix_custom(formatter, &config.socket)?;
+    match &logger.backend {
+        LoggerBackend::Unix(socket) => socket.set_nonblocking(true)?,
+        LoggerBackend::UnixStream(stream) => stream.get_ref().set_nonblocking(true)?,
+        _ => {}
+    }
+    Ok(logger)
+}
+
+fn write(logger: &mut SyslogLogger, severity: Severity, message: String) -> syslog::Result<()> {
+    match severity {
+        Severity::Err => logger.err(message),
+        Severity::Warning => logger.warning(message),
+        Severity::Notice => logger.notice(message),
+        Severity::Info => logger.info(message),
+    }?;
+    logger.backend.flush()?;
+    Ok(())
+}
+
+/// Send an event to syslog, if configured and severe enough. Failures are
+/// reported on stderr only; syslog being away must not hold anything up.
+pub fn send(config: &Config, severity: Severity, event: &str, fields: &[(&str, &str)]) {
+    let Some(syslog) = &config.syslog else {
+        return;
+    };
+    if severity > syslog.severity {
+        return;
+    }
+    let message = message(&config.instance_name, event, fields);
+    let mut logger = LOGGER.lock().unwrap();
+    if logger.as_ref().map(|(path, _)| path.as_path()) != Some(syslog.socket.as_path()) {
+        *logger = None;
+    }
+    let sent = match &mut *logger {
+        Some((_, open)) => write(open, severity, message),
+        None => connect(syslog).and_then(|mut open| {
+            let sent = write(&mut open, severity, message);
+            *logger = Some((syslog.socket.clone(), open));
+            sent
+        }),
+    };
+    if let Err(e) = sent {
+        // Reconnect next time, in case syslog was restarted
+        *logger = None;
+        eprintln!(
+            "Failed to send to syslog at {}: {}",
+            syslog.socket.display(),
+            e
+        );
+    }
+}
+
+/// Print `line` as the service always has (errors to stderr), unless
+/// SYSLOG_ONLY, and send the event to syslog.
+pub fn log(config: &Config, severity: Severity, line: &str, event: &str, fields: &[(&str, &str)]) {
+    if !config.syslog.as_ref().is_some_and(|s| s.only) {
+        if severity == Severity::Err {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+    send(config, severity, event, fields);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_written_as_key_value_pairs() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                pairs
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert!(from_vars(&vars(&[])).unwrap().is_none());
+        let config = from_vars(&vars(&[("SYSLOG_FACILITY", "local3")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.facility as u32, Facility::LOG_LOCAL3 as u32);
+        assert_eq!(config.severity, Severity::Info);
+
+        assert_eq!(
+            message("site-a", "failover", &[("from", "wan0"), ("to", "wan1")]),
+            "failover instance=site-a from=wan0 to=wan1"
+        );
+        assert_eq!(
+            message(
+                "site a",
+                "switch_error",
+                &[
+                    ("ip", "10.40.0.3"),
+                    ("error", "ip rule add: \"x=1\"\nfailed"),
+                ],
+            ),
+            r#"switch_error instance="site a" ip=10.40.0.3 error="ip rule add: \"x=1\" failed""#
+        );
+
+        for bad in [
+            &[("SYSLOG_FACILITY", "local8")][..],
+            &[("SYSLOG_FACILITY", "daemon"), ("SYSLOG_SEVERITY", "loud")],
+            &[("SYSLOG_ONLY", "true")],
+        ] {
+            assert!(from_vars(&vars(bad)).is_err(), "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Transactions: switches and reverts staged under an id and applied
+//! together.
+//!
+//! `POST /txn` opens one. Steps staged with `POST /txn/<id>/switch` and
+//! `POST /txn/<id>/revert` are validated as they are staged but leave the
+//! kernel alone. `POST /txn/<id>/commit` applies them in order; if one fails,
+//! the hosts already changed are put back as they were, newest first. A
+//! transaction not committed within TXN_TTL seconds (300 by default) is
+//! dropped by the janitor.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// One staged operation.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Step {
+    // As /switch?ip=<ip>&nic=<nic>
+    Switch { ip: String, nic: String },
+    // Drop the host's mapping so it rides the base rule again
+    Revert { ip: String },
+}
+
+impl Step {
+    pub fn ip(&self) -> &str {
+        match self {
+            Step::Switch { ip, .. } | Step::Revert { ip } => ip,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Txn {
+    pub steps: Vec<Step>,
+    expires: Instant,
+}
+
+pub type Transactions = HashMap<String, Txn>;
+
+/// Open a transaction that lives for `ttl` from `now`; returns its id.
+pub fn open(txns: &mut Transactions, ttl: Duration, now: Instant) -> String {
+    let id = format!("{:032x}", rand::random::<u128>());
+    txns.insert(
+        id.clone(),
+        Txn {
+            steps: Vec::new(),
+            expires: now + ttl,
+        },
+    );
+    id
+}
+
+/// The transaction `id`, unless it does not exist or ran out by `now`.
+pub fn get<'a>(txns: &'a mut Transactions, id: &str, now: Instant) -> Option<&'a mut Txn> {
+    prune(txns, now);
+    txns.get_mut(id)
+}
+
+/// Drop the transactions that ran out by `now`; returns how many.
+pub fn prune(txns: &mut Transactions, now: Instant) -> usize {
+    let before = txns.len();
+    txns.retain(|_, txn| txn.expires > now);
+    before - txns.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transactions_expire_unless_committed_in_time() {
+        let mut txns = Transactions::new();
+        let ttl = Duration::from_secs(300);
+        let now = Instant::now();
+        let id = open(&mut txns, ttl, now);
+        get(&mut txns, &id, now).unwrap().steps.push(Step::Revert {
+            ip: "10.40.0.3".to_string(),
+        });
+        assert_eq!(get(&mut txns, &id, now).unwrap().steps[0].ip(), "10.40.0.3");
+        assert!(get(&mut txns, "unknown", now).is_none());
+
+        assert!(get(&mut txns, &id, now + ttl).is_none());
+        assert!(txns.is_empty());
+        open(&mut txns, ttl, now);
+        assert_eq!(prune(&mut txns, now + ttl * 2), 1);
+    }
+}
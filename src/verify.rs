@@ -0,0 +1,126 @@
+//! `/switch?verify=true`: after the rules are in place, ask the kernel which
+//! way the host's traffic would now leave, rather than trusting that the
+//! rule does what it should.
+//!
+//! `GET /route-get?dest=<ip>&from=<ip>` makes the same kind of lookup for any
+//! flow and returns what the kernel decided, field by field.
+
+use crate::{CommandRunner, Config, Interface};
+use anyhow::Result;
+use serde::Serialize;
+use std::net::Ipv4Addr;
+
+/// Outcome of the egress check, included in the switch response.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Verification {
+    // Destination the route lookup was made for (VERIFY_DEST)
+    pub dest: String,
+    // Device the kernel picked; absent if the lookup failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dev: Option<String>,
+    pub expected: String,
+    pub ok: bool,
+}
+
+/// Look up the route for traffic from `ip`, arriving on the LAN, to
+/// VERIFY_DEST, and compare its device with `iface`. A failed lookup is
+/// reported as a failed verification with the reason as warning.
+pub fn egress(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    ip: &str,
+    iface: &Interface,
+) -> (Verification, Option<String>) {
+    let dest = config.verify_dest.to_string();
+    let (dev, warning) = match lookup(runner, config, &dest, ip) {
+        Ok(out) => {
+            let dev = route_dev(&out);
+            let warning = match &dev {
+                Some(dev) if dev == &**iface => None,
+                Some(dev) => Some(format!(
+                    "Verification: traffic from {} to {} leaves via {}, not {}",
+                    ip, dest, dev, iface
+                )),
+                None => Some(format!(
+                    "Verification: no egress device in the route for {} from {}",
+                    dest, ip
+                )),
+            };
+            (dev, warning)
+        }
+        Err(e) => (
+            None,
+            Some(format!("Verification: route lookup failed: {:#}", e)),
+        ),
+    };
+    let verification = Verification {
+        dest,
+        ok: warning.is_none(),
+        dev,
+        expected: iface.to_string(),
+    };
+    (verification, warning)
+}
+
+/// `ip route get <dest> from <from>` for traffic arriving on the LAN, as a
+/// LAN host's would.
+fn lookup(runner: &dyn CommandRunner, config: &Config, dest: &str, from: &str) -> Result<String> {
+    let mut args = vec!["-4", "route", "get", dest, "from", from, "iif", &config.lan];
+    // A fwmark-scoped host rule only catches marked traffic
+    if let Some(mark) = &config.host_match.fwmark {
+        args.extend(["mark", mark.split('/').next().unwrap_or(mark)]);
+    }
+    runner.run("ip", &args)
+}
+
+/// The route the kernel picked for one flow, for `/route-get`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RouteGet {
+    pub dest: String,
+    pub from: String,
+    pub dev: Option<String>,
+    // Absent for a directly connected destination
+    pub gateway: Option<String>,
+    // `main` unless the kernel names another table
+    pub table: String,
+    // The WAN whose interface `dev` is, if any
+    pub wan: Option<&'static str>,
+}
+
+/// Ask the kernel for the route from `from` to `dest`.
+pub fn route_get(
+    runner: &dyn CommandRunner,
+    config: &Config,
+    dest: Ipv4Addr,
+    from: Ipv4Addr,
+) -> Result<RouteGet> {
+    let out = lookup(runner, config, &dest.to_string(), &from.to_string())?;
+    let dev = route_dev(&out);
+    let wan = match dev.as_deref() {
+        Some(dev) if dev == &*config.wan0 => Some("wan0"),
+        Some(dev) if dev == &*config.wan1 => Some("wan1"),
+        _ => None,
+    };
+    Ok(RouteGet {
+        dest: dest.to_string(),
+        from: from.to_string(),
+        gateway: route_value(&out, "via"),
+        table: route_value(&out, "table").unwrap_or_else(|| "main".to_string()),
+        dev,
+        wan,
+    })
+}
+
+/// The `dev` of `ip route get` output.
+fn route_dev(out: &str) -> Option<String> {
+    route_value(out, "dev")
+}
+
+/// The word after `key` in `ip route get` output.
+pub fn route_value(out: &str, key: &str) -> Option<String> {
+    let tokens: Vec<&str> = out.split_whitespace().collect();
+    tokens
+        .windows(2)
+        .find(|pair| pair[0] == key)
+        .map(|pair| pair[1].to_string())
+}